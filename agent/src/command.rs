@@ -30,4 +30,7 @@ pub struct CommandArgs {
     /// The username of the agent
     #[arg(short = 'u', long)]
     pub username: Option<String>,
+    /// The opaque label sent to the proxy in handshake
+    #[arg(short = 'g', long)]
+    pub connection_tag: Option<String>,
 }
@@ -1,21 +1,195 @@
 use crate::command::CommandArgs;
 use clap::Parser;
-use common::UserConfig;
-use common::config::CommonConfig;
+use common::balance::ProxyServerPolicy;
+use common::config::{CommonConfig, default_handshake_retry_delay_millis};
+use common::{ProxyConnectionConfig, SocketOptions, UserConfig};
 use core::panic;
 use protocol::Username;
 use serde::{Deserialize, Serialize};
 use std::fs::read_to_string;
-use std::sync::OnceLock;
+use std::time::Duration;
 
 /// The default configuration file patch
 const DEFAULT_CONFIG_FILE: &str = "./resources/agent.toml";
-/// The global configuration object
-static CONFIG: OnceLock<Config> = OnceLock::new();
 
-/// Get the configuration
-pub fn get_config() -> &'static Config {
-    CONFIG.get_or_init(|| {
+/// How the agent handles client connections whose first
+/// byte is neither a socks 4 nor a socks 5 version flag
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum UnknownProtocolMode {
+    /// Always try to handle the connection as http
+    #[default]
+    Http,
+    /// Handle the connection as http only when the first byte can start
+    /// a http method, otherwise close the connection
+    Reject,
+}
+
+/// The protocol forced on the client connections,
+/// the connections are dispatched without peeking the first byte
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ForcedProtocol {
+    Socks4,
+    Socks5,
+    Http,
+}
+
+/// The configuration object
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Config {
+    #[serde(flatten)]
+    common: CommonConfig,
+    proxy_connect_timeout: u64,
+    username: Username,
+    /// Log the content encoding, the content length and the body
+    /// bytes actually forwarded of the http responses to client
+    #[serde(default)]
+    http_response_stats: bool,
+    /// The max length of the error response bodies returned to the http
+    /// clients, only the reason is cut so the status and the request id are
+    /// always returned, `0` returns the error responses without body
+    #[serde(default = "default_http_error_body_max_length")]
+    http_error_body_max_length: usize,
+    /// The opaque label sent to the proxy in handshake
+    connection_tag: Option<String>,
+    #[serde(default)]
+    unknown_protocol_mode: UnknownProtocolMode,
+    /// Dispatch all the client connections with this protocol
+    /// instead of detecting the protocol
+    #[serde(default)]
+    forced_protocol: Option<ForcedProtocol>,
+    /// The IP ToS/DSCP value of the socks5 tunnels, it overrides
+    /// `socket_ip_tos` on both the client and the proxy sockets
+    #[serde(default)]
+    socks5_socket_ip_tos: Option<u32>,
+    /// The IP ToS/DSCP value of the http/https tunnels, it overrides
+    /// `socket_ip_tos` on both the client and the proxy sockets
+    #[serde(default)]
+    http_socket_ip_tos: Option<u32>,
+    /// The number of the proxy connections created in advance,
+    /// `None` creates the proxy connection for each request
+    #[serde(default)]
+    proxy_connection_pool_size: Option<usize>,
+    /// The seconds a pooled proxy connection can stay idle before it is
+    /// evicted, keep it below the idle timeout of the proxy server
+    #[serde(default = "default_proxy_connection_pool_max_idle_secs")]
+    proxy_connection_pool_max_idle_secs: u64,
+    /// The seconds a pooled proxy connection lives before the pool closes
+    /// and replaces it even when it is healthy, `None` means no limit
+    #[serde(default)]
+    pool_connection_max_lifetime_secs: Option<u64>,
+    /// The retries of the handshake failing to decrypt the proxy data
+    #[serde(default)]
+    handshake_decrypt_retries: u32,
+    #[serde(default = "default_handshake_retry_delay_millis")]
+    handshake_retry_delay_millis: u64,
+    /// The retries of the proxy connection of a request while the proxy is
+    /// unreachable, the retry backoff doubles from the base on every retry
+    #[serde(default = "default_proxy_connect_retries")]
+    proxy_connect_retries: u32,
+    #[serde(default = "default_proxy_connect_retry_backoff_millis")]
+    proxy_connect_retry_backoff_millis: u64,
+    /// The milliseconds the proxy connection of a request may take with all
+    /// its failover passes and retries, `0` disables it
+    #[serde(default = "default_proxy_connect_deadline_millis")]
+    proxy_connect_deadline_millis: u64,
+    /// The milliseconds all the proxy connection attempts back off after
+    /// a failure, it doubles on every failure in a row, `0` disables it
+    #[serde(default = "default_proxy_backoff_initial_millis")]
+    proxy_backoff_initial_millis: u64,
+    #[serde(default = "default_proxy_backoff_max_millis")]
+    proxy_backoff_max_millis: u64,
+    /// The milliseconds the proxy tries to connect the destination, the proxy
+    /// uses its own connect timeout instead when it is shorter or it is `None`
+    #[serde(default)]
+    destination_connect_timeout_hint_millis: Option<u64>,
+    /// How the proxy servers of the user are ordered for a new proxy connection
+    #[serde(default)]
+    proxy_server_policy: ProxyServerPolicy,
+}
+
+fn default_proxy_connection_pool_max_idle_secs() -> u64 {
+    60
+}
+
+fn default_http_error_body_max_length() -> usize {
+    1024
+}
+
+fn default_proxy_connect_retries() -> u32 {
+    2
+}
+
+fn default_proxy_connect_retry_backoff_millis() -> u64 {
+    100
+}
+
+fn default_proxy_connect_deadline_millis() -> u64 {
+    30_000
+}
+
+fn default_proxy_backoff_initial_millis() -> u64 {
+    500
+}
+
+fn default_proxy_backoff_max_millis() -> u64 {
+    30_000
+}
+
+impl Config {
+    pub fn http_response_stats(&self) -> bool {
+        self.http_response_stats
+    }
+    pub fn http_error_body_max_length(&self) -> usize {
+        self.http_error_body_max_length
+    }
+    pub fn unknown_protocol_mode(&self) -> UnknownProtocolMode {
+        self.unknown_protocol_mode
+    }
+    pub fn forced_protocol(&self) -> Option<ForcedProtocol> {
+        self.forced_protocol
+    }
+    pub fn socks5_socket_ip_tos(&self) -> Option<u32> {
+        self.socks5_socket_ip_tos
+    }
+    pub fn http_socket_ip_tos(&self) -> Option<u32> {
+        self.http_socket_ip_tos
+    }
+    pub fn proxy_connection_pool_size(&self) -> Option<usize> {
+        self.proxy_connection_pool_size
+    }
+    pub fn proxy_connection_pool_max_idle_secs(&self) -> u64 {
+        self.proxy_connection_pool_max_idle_secs
+    }
+    pub fn pool_connection_max_lifetime_secs(&self) -> Option<u64> {
+        self.pool_connection_max_lifetime_secs
+    }
+    pub fn proxy_connect_retries(&self) -> u32 {
+        self.proxy_connect_retries
+    }
+    pub fn proxy_connect_retry_backoff_millis(&self) -> u64 {
+        self.proxy_connect_retry_backoff_millis
+    }
+    pub fn proxy_connect_deadline(&self) -> Option<Duration> {
+        (self.proxy_connect_deadline_millis > 0)
+            .then(|| Duration::from_millis(self.proxy_connect_deadline_millis))
+    }
+    pub fn destination_connect_timeout_hint_millis(&self) -> Option<u64> {
+        self.destination_connect_timeout_hint_millis
+    }
+    pub fn proxy_backoff_initial_millis(&self) -> u64 {
+        self.proxy_backoff_initial_millis
+    }
+    pub fn proxy_backoff_max_millis(&self) -> u64 {
+        self.proxy_backoff_max_millis
+    }
+    pub fn common(&self) -> &CommonConfig {
+        &self.common
+    }
+    /// Load the configuration file given in the command line, or the default
+    /// one, and override it with the command line arguments.
+    pub fn from_command_line() -> Self {
         let command_line = CommandArgs::parse();
         let config_content = match &command_line.config_file_path {
             None => read_to_string(DEFAULT_CONFIG_FILE).unwrap_or_else(|_| {
@@ -35,24 +209,6 @@ pub fn get_config() -> &'static Config {
             .expect("Fail to initialize agent configuration");
         config.merge_command_args(command_line);
         config
-    })
-}
-
-/// The configuration object
-#[derive(Serialize, Deserialize, Debug)]
-pub struct Config {
-    #[serde(flatten)]
-    common: CommonConfig,
-    proxy_connect_timeout: u64,
-    username: Username,
-}
-
-impl Config {
-    pub fn proxy_connect_timeout(&self) -> u64 {
-        self.proxy_connect_timeout
-    }
-    pub fn common(&self) -> &CommonConfig {
-        &self.common
     }
     pub fn merge_command_args(&mut self, command: CommandArgs) {
         if let Some(listening_address) = command.listening_address {
@@ -74,7 +230,10 @@ impl Config {
             self.common.user_repo_refresh_interval = user_repo_refresh_interval;
         }
         if let Some(username) = command.username {
-            self.username = Username(username);
+            self.username = username.into();
+        }
+        if let Some(connection_tag) = command.connection_tag {
+            self.connection_tag = Some(connection_tag);
         }
     }
 }
@@ -84,3 +243,45 @@ impl UserConfig for Config {
         &self.username
     }
 }
+
+impl ProxyConnectionConfig for Config {
+    fn proxy_connect_timeout(&self) -> u64 {
+        self.proxy_connect_timeout
+    }
+    fn relay_max_chunk_size(&self) -> Option<usize> {
+        self.common.relay_max_chunk_size
+    }
+    fn connection_tag(&self) -> Option<&str> {
+        self.connection_tag.as_deref()
+    }
+    fn relay_write_buffer_size(&self) -> Option<usize> {
+        self.common.relay_write_buffer_size
+    }
+    fn relay_flush_interval_millis(&self) -> Option<u64> {
+        self.common.relay_flush_interval_millis
+    }
+    fn handshake_max_frame_length(&self) -> usize {
+        self.common.handshake_max_frame_length
+    }
+    fn socket_options(&self) -> SocketOptions {
+        self.common.socket_options
+    }
+    fn relay_per_frame_iv(&self) -> bool {
+        self.common.relay_per_frame_iv
+    }
+    fn relay_length_field_length(&self) -> usize {
+        self.common.relay_length_field_length
+    }
+    fn handshake_decrypt_retries(&self) -> u32 {
+        self.handshake_decrypt_retries
+    }
+    fn handshake_retry_delay_millis(&self) -> u64 {
+        self.handshake_retry_delay_millis
+    }
+    fn relay_compression_level(&self) -> Option<i32> {
+        self.common.relay_compression_level
+    }
+    fn proxy_server_policy(&self) -> ProxyServerPolicy {
+        self.proxy_server_policy
+    }
+}
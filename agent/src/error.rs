@@ -11,10 +11,52 @@ pub enum Error {
     Io(#[from] std::io::Error),
     #[error(transparent)]
     Hyper(#[from] hyper::Error),
-    #[error(transparent)]
-    FastSocks(#[from] SocksServerError),
+    #[error("Socks authentication fail: {0}")]
+    SocksAuth(SocksServerError),
+    #[error("Socks protocol error: {0}")]
+    SocksProtocol(SocksServerError),
+    #[error("Socks i/o error: {0}")]
+    SocksIo(SocksServerError),
+    #[error("Socks 4 protocol error: {0}")]
+    Socks4Protocol(String),
     #[error("No destination host: {0}")]
     NoDestinationHost(Uri),
     #[error("Unknown error: {0}")]
     Unknown(String),
 }
+
+impl From<SocksServerError> for Error {
+    fn from(value: SocksServerError) -> Self {
+        match value {
+            SocksServerError::AuthMethodUnacceptable(_)
+            | SocksServerError::EmptyUsername
+            | SocksServerError::EmptyPassword
+            | SocksServerError::AuthenticationRejected => Error::SocksAuth(value),
+            SocksServerError::Io { .. } | SocksServerError::EOF => Error::SocksIo(value),
+            _ => Error::SocksProtocol(value),
+        }
+    }
+}
+
+#[test]
+fn test_socks_error_conversion() {
+    assert!(matches!(
+        Error::from(SocksServerError::AuthenticationRejected),
+        Error::SocksAuth(_)
+    ));
+    assert!(matches!(
+        Error::from(SocksServerError::UnsupportedSocksVersion(4)),
+        Error::SocksProtocol(_)
+    ));
+    assert!(matches!(
+        Error::from(SocksServerError::UnknownCommand(9)),
+        Error::SocksProtocol(_)
+    ));
+    assert!(matches!(
+        Error::from(SocksServerError::Io {
+            source: std::io::Error::other("test"),
+            context: "test",
+        }),
+        Error::SocksIo(_)
+    ));
+}
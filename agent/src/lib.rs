@@ -1,5 +1,6 @@
 pub mod command;
 pub mod config;
 pub mod error;
+pub mod server;
 pub mod tunnel;
 pub mod user;
@@ -0,0 +1,241 @@
+use crate::config::Config;
+use crate::error::Error;
+use crate::tunnel;
+use crate::user::{AgentUser, check_agent_user};
+use common::backoff::ProxyBackoff;
+use common::config::CommonConfig;
+use common::pool::ProxyConnectionPool;
+use common::user::UserRepository;
+use common::user::repo::FileSystemUserRepository;
+use common::{ServerGuard, UserConfig, start_server};
+use std::sync::{Arc, OnceLock};
+use std::time::Duration;
+use tracing::debug;
+
+/// The state of one agent instance, the agents in the same process share
+/// nothing but the process-wide log, metrics and encryption preference.
+pub struct AgentContext {
+    config: Arc<Config>,
+    user_repo: FileSystemUserRepository<AgentUser, CommonConfig>,
+    /// The backoff shared by the pool filler and the connections of the requests
+    proxy_backoff: Arc<ProxyBackoff>,
+    /// The pool of the proxy connections, it is initialized when the
+    /// server starts and the proxy connection pool size is configured
+    proxy_connection_pool: OnceLock<ProxyConnectionPool>,
+}
+
+impl AgentContext {
+    /// Create the agent with the configuration, the users are loaded here
+    pub fn new(config: Config) -> Result<Self, Error> {
+        let user_repo = FileSystemUserRepository::<AgentUser, CommonConfig>::new(Arc::new(
+            config.common().clone(),
+        ))?;
+        let proxy_backoff = Arc::new(ProxyBackoff::new(
+            Duration::from_millis(config.proxy_backoff_initial_millis()),
+            Duration::from_millis(config.proxy_backoff_max_millis()),
+        ));
+        Ok(Self {
+            config: Arc::new(config),
+            user_repo,
+            proxy_backoff,
+            proxy_connection_pool: OnceLock::new(),
+        })
+    }
+
+    pub fn config(&self) -> &Config {
+        &self.config
+    }
+
+    pub fn user_repo(&self) -> &FileSystemUserRepository<AgentUser, CommonConfig> {
+        &self.user_repo
+    }
+
+    pub fn proxy_backoff(&self) -> &Arc<ProxyBackoff> {
+        &self.proxy_backoff
+    }
+
+    pub fn proxy_connection_pool(&self) -> Option<&ProxyConnectionPool> {
+        self.proxy_connection_pool.get()
+    }
+
+    /// Find the configured agent user in the user repository
+    pub fn agent_user(&self) -> Result<Arc<AgentUser>, Error> {
+        let username = self.config.username();
+        Ok(self
+            .user_repo
+            .find_user(username)
+            .ok_or(common::Error::UserNotExist(username.to_owned()))?)
+    }
+
+    /// Initialize the proxy connection pool when the pool size is configured,
+    /// it must be called inside the runtime because the pool filler is spawned.
+    fn init_proxy_connection_pool(&self) -> Result<(), Error> {
+        let Some(pool_size) = self.config.proxy_connection_pool_size() else {
+            return Ok(());
+        };
+        let proxy_connection_pool = ProxyConnectionPool::new(
+            self.agent_user()?,
+            self.config.clone(),
+            self.proxy_backoff.clone(),
+            pool_size,
+            Duration::from_secs(self.config.proxy_connection_pool_max_idle_secs()),
+            self.config
+                .pool_connection_max_lifetime_secs()
+                .map(Duration::from_secs),
+        );
+        self.proxy_connection_pool
+            .set(proxy_connection_pool)
+            .map_err(|_| Error::Unknown("Proxy connection pool already initialized".to_string()))
+    }
+}
+
+/// Start the agent server of the context inside the runtime, the configured
+/// agent user is checked first so a broken user repository fails the startup
+/// instead of failing every client request.
+pub fn start_agent_server(context: Arc<AgentContext>) -> Result<ServerGuard, Error> {
+    let username = context.config().username();
+    check_agent_user(context.user_repo().find_user(username).as_deref(), username)?;
+    let server_context = context.clone();
+    let server_guard = start_server(context.config().common(), move |server_state| {
+        let context = server_context.clone();
+        async move {
+            debug!("Handling agent connection: {server_state:?}.");
+            tunnel::process(context, server_state).await
+        }
+    });
+    // The pool filler records its handshake failures into the metrics of the server
+    if let Err(e) = server_guard.in_metrics_scope(|| context.init_proxy_connection_pool()) {
+        server_guard.stop_signal.cancel();
+        return Err(e);
+    }
+    Ok(server_guard)
+}
+
+/// The agent configuration of the tests, the users are loaded from the given directory
+#[cfg(test)]
+pub(crate) fn test_agent_config(
+    listening_address: std::net::SocketAddr,
+    user_repo_directory: &std::path::Path,
+) -> Config {
+    toml::from_str(&format!(
+        r#"
+        listening_address = "{listening_address}"
+        client_max_connections = 16
+        worker_threads = 1
+        log_directory = "log"
+        log_name_prefix = "ppaass-agent-test.log"
+        max_log_level = "ERROR"
+        user_repo_directory = "{}"
+        user_repo_refresh_interval = 0
+        user_info_file_name = "user_info.toml"
+        user_info_public_key_file_name = "ProxyPublicKey.pem"
+        user_info_private_key_file_name = "AgentPrivateKey.pem"
+        username = "user1"
+        proxy_connect_timeout = 5
+        "#,
+        user_repo_directory.display()
+    ))
+    .expect("Fail to parse test agent configuration")
+}
+
+#[tokio::test]
+async fn test_agent_and_proxy_in_one_process() -> Result<(), Error> {
+    use proxy::server::{ProxyContext, start_proxy_server};
+    use std::net::SocketAddr;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::{TcpListener, TcpStream};
+    let proxy_error = |e: proxy::error::Error| Error::Unknown(format!("{e:?}"));
+    let free_addr = || -> Result<SocketAddr, Error> {
+        Ok(std::net::TcpListener::bind("127.0.0.1:0")?.local_addr()?)
+    };
+    // The servers bind in background, retry until they are listening
+    let connect = |addr: SocketAddr| async move {
+        for _ in 0..50 {
+            if let Ok(stream) = TcpStream::connect(addr).await {
+                return Ok(stream);
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        TcpStream::connect(addr).await
+    };
+    let destination_listener = TcpListener::bind("127.0.0.1:0").await?;
+    let destination_addr = destination_listener.local_addr()?;
+    tokio::spawn(async move {
+        while let Ok((mut destination_stream, _)) = destination_listener.accept().await {
+            tokio::spawn(async move {
+                let (mut reader, mut writer) = destination_stream.split();
+                let _ = tokio::io::copy(&mut reader, &mut writer).await;
+            });
+        }
+    });
+    let proxy_addr = free_addr()?;
+    let proxy_config: proxy::config::Config = toml::from_str(&format!(
+        r#"
+        listening_address = "{proxy_addr}"
+        client_max_connections = 16
+        worker_threads = 1
+        log_directory = "log"
+        log_name_prefix = "ppaass-proxy-test.log"
+        max_log_level = "ERROR"
+        user_repo_directory = "../resources/proxy/user"
+        user_repo_refresh_interval = 0
+        user_info_file_name = "user_info.toml"
+        user_info_public_key_file_name = "AgentPublicKey.pem"
+        user_info_private_key_file_name = "ProxyPrivateKey.pem"
+        destination_connect_timeout = 5
+        destination_address_preference = "system"
+        "#
+    ))
+    .expect("Fail to parse test proxy configuration");
+    let proxy_context = Arc::new(ProxyContext::new(proxy_config).map_err(proxy_error)?);
+    let proxy_server_guard = start_proxy_server(proxy_context)
+        .await
+        .map_err(proxy_error)?;
+    // The agent user connects to the proxy started above
+    let agent_user_repo_directory =
+        std::env::temp_dir().join(format!("ppaass-agent-server-test-{}", std::process::id()));
+    let agent_user_dir = agent_user_repo_directory.join("user1");
+    std::fs::create_dir_all(&agent_user_dir)?;
+    for key_file_name in ["ProxyPublicKey.pem", "AgentPrivateKey.pem"] {
+        std::fs::copy(
+            std::path::Path::new("../resources/agent/user/user1").join(key_file_name),
+            agent_user_dir.join(key_file_name),
+        )?;
+    }
+    std::fs::write(
+        agent_user_dir.join("user_info.toml"),
+        format!("username = \"user1\"\nproxy_servers = [\"{proxy_addr}\"]"),
+    )?;
+    // Two agents run side by side with their own state
+    let mut agent_server_guards = Vec::new();
+    for _ in 0..2 {
+        let agent_addr = free_addr()?;
+        let agent_context = Arc::new(AgentContext::new(test_agent_config(
+            agent_addr,
+            &agent_user_repo_directory,
+        ))?);
+        agent_server_guards.push(start_agent_server(agent_context)?);
+        let mut client = connect(agent_addr).await?;
+        let SocketAddr::V4(destination_addr) = destination_addr else {
+            unreachable!("The destination is bound on ipv4");
+        };
+        let mut socks4_request = vec![4, 1];
+        socks4_request.extend_from_slice(&destination_addr.port().to_be_bytes());
+        socks4_request.extend_from_slice(&destination_addr.ip().octets());
+        socks4_request.push(0);
+        client.write_all(&socks4_request).await?;
+        let mut socks4_reply = [0u8; 8];
+        client.read_exact(&mut socks4_reply).await?;
+        assert_eq!(0x5a, socks4_reply[1]);
+        client.write_all(b"relayed end to end").await?;
+        let mut echoed = [0u8; 18];
+        client.read_exact(&mut echoed).await?;
+        assert_eq!(b"relayed end to end", &echoed);
+    }
+    for agent_server_guard in agent_server_guards {
+        agent_server_guard.shutdown(Duration::from_secs(1)).await;
+    }
+    proxy_server_guard.shutdown(Duration::from_secs(1)).await;
+    std::fs::remove_dir_all(agent_user_repo_directory)?;
+    Ok(())
+}
@@ -1,28 +1,45 @@
 use crate::error::Error;
-use crate::tunnel::fetch_proxy_connection;
-use common::ServerState;
+use crate::server::AgentContext;
+use crate::tunnel::{fetch_proxy_connection, log_relay_failure};
+use common::metrics::with_current_metrics_recorder;
 use common::proxy::DestinationType;
+use common::{ServerState, copy_bidirectional_with_idle_timeout};
 use http_body_util::combinators::BoxBody;
-use http_body_util::{BodyExt, Empty};
-use hyper::body::Incoming;
+use http_body_util::{BodyExt, Empty, Full};
+use hyper::body::{Body, Frame, Incoming, SizeHint};
 use hyper::client::conn::http1::Builder;
+use hyper::header::{CONTENT_ENCODING, CONTENT_LENGTH, CONTENT_TYPE, HeaderValue};
 use hyper::server::conn::http1;
 use hyper::service::service_fn;
-use hyper::{Method, Request, Response};
+use hyper::{HeaderMap, Method, Request, Response, StatusCode};
 use hyper_util::rt::TokioIo;
 use protocol::UnifiedAddress;
 use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll, ready};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite};
 use tokio::sync::oneshot::channel;
 use tokio_util::bytes::Bytes;
 use tower::ServiceBuilder;
 use tracing::{debug, error, info};
 
-pub async fn process_http_tunnel(server_state: ServerState) -> Result<(), Error> {
+pub async fn process_http_tunnel<S>(
+    context: Arc<AgentContext>,
+    server_state: ServerState<S>,
+) -> Result<(), Error>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
     let client_tcp_io = TokioIo::new(server_state.incoming_stream);
-    let service_fn = ServiceBuilder::new().service(service_fn(|request| async {
-        client_http_request_handler(server_state.incoming_connection_addr, request)
-            .await
-            .map_err(|e| format!("{e:?}"))
+    let service_fn = ServiceBuilder::new().service(service_fn(|request| {
+        let context = context.clone();
+        async move {
+            client_http_request_handler(&context, server_state.incoming_connection_addr, request)
+                .await
+                .map_err(|e| format!("{e:?}"))
+        }
     }));
     http1::Builder::new()
         .preserve_header_case(true)
@@ -33,13 +50,183 @@ pub async fn process_http_tunnel(server_state: ServerState) -> Result<(), Error>
     Ok(())
 }
 
+/// The size statistics of the http response forwarded to client,
+/// collected from the response headers without touching the body.
+#[derive(Debug, PartialEq, Eq)]
+struct ResponseSizeStats {
+    content_encoding: Option<String>,
+    content_length: Option<u64>,
+}
+
+impl ResponseSizeStats {
+    fn from_headers(headers: &HeaderMap) -> Self {
+        let content_encoding = headers
+            .get(CONTENT_ENCODING)
+            .and_then(|value| value.to_str().ok())
+            .map(|value| value.to_ascii_lowercase());
+        let content_length = headers
+            .get(CONTENT_LENGTH)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.parse::<u64>().ok());
+        Self {
+            content_encoding,
+            content_length,
+        }
+    }
+
+    /// The body is already compressed by the destination
+    fn compressed(&self) -> bool {
+        matches!(&self.content_encoding, Some(encoding) if encoding != "identity")
+    }
+}
+
+/// The response body forwarded to the client, it counts the body bytes
+/// passing through without buffering them, and logs the count with the
+/// size statistics of the headers once the body is dropped.
+struct CountingBody {
+    body: BoxBody<Bytes, hyper::Error>,
+    response_size_stats: ResponseSizeStats,
+    forwarded_bytes: u64,
+    destination_address: UnifiedAddress,
+    client_addr: SocketAddr,
+}
+
+impl Body for CountingBody {
+    type Data = Bytes;
+    type Error = hyper::Error;
+
+    fn poll_frame(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Self::Data>, Self::Error>>> {
+        let frame = ready!(Pin::new(&mut self.body).poll_frame(cx));
+        if let Some(Ok(frame)) = &frame
+            && let Some(data) = frame.data_ref()
+        {
+            self.forwarded_bytes += data.len() as u64;
+        }
+        Poll::Ready(frame)
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.body.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.body.size_hint()
+    }
+}
+
+impl Drop for CountingBody {
+    fn drop(&mut self) {
+        info!(
+            "Forward http response from destination [{}] to client [{}], compressed: {}, content encoding: {:?}, content length: {:?}, forwarded bytes: {}",
+            self.destination_address,
+            self.client_addr,
+            self.response_size_stats.compressed(),
+            self.response_size_stats.content_encoding,
+            self.response_size_stats.content_length,
+            self.forwarded_bytes
+        );
+    }
+}
+
 fn success_empty_body() -> BoxBody<Bytes, hyper::Error> {
     Empty::<Bytes>::new()
         .map_err(|never| match never {})
         .boxed()
 }
 
+/// The status and the reason returned to the client for the error, the
+/// reason is fixed for each kind of error so nothing internal is leaked
+fn error_status_and_reason(error: &Error) -> (StatusCode, &'static str) {
+    match error {
+        Error::NoDestinationHost(_) => (
+            StatusCode::BAD_REQUEST,
+            "The request has no destination host",
+        ),
+        Error::Common(common::Error::ConnectDestination(_)) => (
+            StatusCode::BAD_GATEWAY,
+            "The proxy fails to connect the destination",
+        ),
+        Error::Common(common::Error::DestinationObserved(_)) => (
+            StatusCode::FORBIDDEN,
+            "The proxy observes the destination without connecting it",
+        ),
+        Error::Common(common::Error::ConnectionExhausted(_)) => (
+            StatusCode::BAD_GATEWAY,
+            "The proxy closes the connection unexpectedly",
+        ),
+        Error::Common(common::Error::ProxyUnhealthy(_)) => (
+            StatusCode::BAD_GATEWAY,
+            "The proxy is unavailable, retry later",
+        ),
+        Error::Hyper(_) => (
+            StatusCode::BAD_GATEWAY,
+            "Fail to forward the request to the destination",
+        ),
+        _ => (StatusCode::BAD_GATEWAY, "Fail to connect the proxy"),
+    }
+}
+
+/// The error response of the request, the body carries the reason and the
+/// request id logged with the error. Only the reason is cut to fit the max
+/// length, the status and the request id are always kept so the client can
+/// still quote the id, and the max length `0` returns no body.
+fn error_response(
+    error: &Error,
+    request_id: &str,
+    body_max_length: usize,
+) -> Response<BoxBody<Bytes, hyper::Error>> {
+    let (status, reason) = error_status_and_reason(error);
+    let status_line = format!("{status}\n");
+    let request_id_line = format!("Request id: {request_id}\n");
+    let reason_max_length = body_max_length
+        .saturating_sub(status_line.len() + "Reason: \n".len() + request_id_line.len());
+    let body = if body_max_length == 0 {
+        String::new()
+    } else if reason_max_length == 0 {
+        format!("{status_line}{request_id_line}")
+    } else {
+        // The reason is ascii, any length is a char boundary
+        let reason = &reason[..reason.len().min(reason_max_length)];
+        format!("{status_line}Reason: {reason}\n{request_id_line}")
+    };
+    let mut response = Response::new(
+        Full::new(Bytes::from(body))
+            .map_err(|never| match never {})
+            .boxed(),
+    );
+    *response.status_mut() = status;
+    response
+        .headers_mut()
+        .insert(CONTENT_TYPE, HeaderValue::from_static("text/plain"));
+    response
+}
+
+/// Handle the client request, the error is logged with a random request id
+/// and returned to the client as the error response carrying the same id.
 async fn client_http_request_handler(
+    context: &Arc<AgentContext>,
+    client_addr: SocketAddr,
+    client_http_request: Request<Incoming>,
+) -> Result<Response<BoxBody<Bytes, hyper::Error>>, Error> {
+    match relay_client_http_request(context, client_addr, client_http_request).await {
+        Ok(response) => Ok(response),
+        Err(e) => {
+            let request_id = format!("{:016x}", rand::random::<u64>());
+            error!("Fail to handle http request [{request_id}] from client [{client_addr}]: {e:?}");
+            Ok(error_response(
+                &e,
+                &request_id,
+                context.config().http_error_body_max_length(),
+            ))
+        }
+    }
+}
+
+async fn relay_client_http_request(
+    context: &Arc<AgentContext>,
     client_addr: SocketAddr,
     client_http_request: Request<Incoming>,
 ) -> Result<Response<BoxBody<Bytes, hyper::Error>>, Error> {
@@ -63,7 +250,15 @@ async fn client_http_request_handler(
         "Receive client http request to destination: {destination_address:?}, client socket address: {client_addr}"
     );
     let (proxy_connection_tx, proxy_connection_rx) = channel();
-    fetch_proxy_connection(proxy_connection_tx).await?;
+    fetch_proxy_connection(
+        context,
+        proxy_connection_tx,
+        context.config().http_socket_ip_tos(),
+    )
+    .await?;
+    let proxy_connection = proxy_connection_rx
+        .await
+        .map_err(|_| Error::Unknown("Failed to receive proxy connection".to_string()))?;
     if Method::CONNECT == client_http_request.method() {
         // Received an HTTP request like:
         // ```
@@ -77,43 +272,33 @@ async fn client_http_request_handler(
         //
         // Note: only after client received an empty body with STATUS_OK can the
         // connection be upgraded, so we can't return a response inside
-        // `on_upgrade` future.
-        tokio::task::spawn(async move {
+        // `on_upgrade` future. The destination is connected before the response
+        // so the failure can still be returned to the client.
+        let mut proxy_connection = proxy_connection
+            .connect_destination(destination_address.clone(), DestinationType::Tcp)
+            .await?;
+        let relay_idle_timeout = context
+            .config()
+            .common()
+            .relay_idle_timeout_secs
+            .map(Duration::from_secs);
+        tokio::task::spawn(with_current_metrics_recorder(async move {
             match hyper::upgrade::on(client_http_request).await {
                 Err(e) => {
                     error!("Failed to upgrade client http request: {e}");
                 }
                 Ok(upgraded_client_io) => {
-                    let proxy_connection = match proxy_connection_rx.await {
-                        Ok(proxy_connection) => proxy_connection,
-                        Err(_) => {
-                            error!("Failed to receive proxy connection");
-                            return;
-                        }
-                    };
-                    let mut proxy_connection = match proxy_connection
-                        .connect_destination(destination_address.clone(), DestinationType::Tcp)
-                        .await
-                    {
-                        Ok(proxy_connection) => proxy_connection,
-                        Err(e) => {
-                            error!(
-                                "Failed to setup destination [{destination_address}] on proxy connection: {e}"
-                            );
-                            return;
-                        }
-                    };
-                    // Connect to remote server
                     let mut upgraded_client_io = TokioIo::new(upgraded_client_io);
                     // Proxying data
-                    let (from_client, from_proxy) = match tokio::io::copy_bidirectional(
+                    let (from_client, from_proxy) = match copy_bidirectional_with_idle_timeout(
                         &mut upgraded_client_io,
                         &mut proxy_connection,
+                        relay_idle_timeout,
                     )
                     .await
                     {
                         Err(e) => {
-                            error!("Fail to proxy data between agent and proxy: {e:?}");
+                            log_relay_failure(&e, &destination_address);
                             return;
                         }
                         Ok((from_client, from_proxy)) => (from_client, from_proxy),
@@ -125,12 +310,9 @@ async fn client_http_request_handler(
                     );
                 }
             }
-        });
+        }));
         Ok(Response::new(success_empty_body()))
     } else {
-        let proxy_connection = proxy_connection_rx
-            .await
-            .map_err(|_| Error::Unknown("Failed to receive proxy connection".to_string()))?;
         let proxy_connection = proxy_connection
             .connect_destination(destination_address.clone(), DestinationType::Tcp)
             .await?;
@@ -148,6 +330,154 @@ async fn client_http_request_handler(
         let proxy_response = proxy_connection_sender
             .send_request(client_http_request)
             .await?;
+        if context.config().http_response_stats() {
+            let response_size_stats = ResponseSizeStats::from_headers(proxy_response.headers());
+            return Ok(proxy_response.map(|body| {
+                CountingBody {
+                    body: body.boxed(),
+                    response_size_stats,
+                    forwarded_bytes: 0,
+                    destination_address,
+                    client_addr,
+                }
+                .boxed()
+            }));
+        }
         Ok(proxy_response.map(|b| b.boxed()))
     }
 }
+
+#[test]
+fn test_response_size_stats() {
+    let response = Response::builder()
+        .header(CONTENT_ENCODING, "GZIP")
+        .header(CONTENT_LENGTH, "1024")
+        .body(())
+        .unwrap();
+    let response_size_stats = ResponseSizeStats::from_headers(response.headers());
+    assert!(response_size_stats.compressed());
+    assert_eq!(
+        ResponseSizeStats {
+            content_encoding: Some("gzip".to_string()),
+            content_length: Some(1024),
+        },
+        response_size_stats
+    );
+    let response = Response::builder()
+        .header(CONTENT_LENGTH, "2048")
+        .body(())
+        .unwrap();
+    let response_size_stats = ResponseSizeStats::from_headers(response.headers());
+    assert!(!response_size_stats.compressed());
+    assert_eq!(Some(2048), response_size_stats.content_length);
+}
+
+#[tokio::test]
+async fn test_counting_body() {
+    use std::sync::Mutex;
+    /// The log lines written while the test runs
+    #[derive(Clone, Default)]
+    struct CapturedLog(Arc<Mutex<Vec<u8>>>);
+    impl std::io::Write for CapturedLog {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+    let captured_log = CapturedLog::default();
+    let log_writer = captured_log.clone();
+    let _log_guard = tracing::subscriber::set_default(
+        tracing_subscriber::fmt()
+            .with_ansi(false)
+            .with_writer(move || log_writer.clone())
+            .finish(),
+    );
+    let response = Response::builder()
+        .header(CONTENT_ENCODING, "gzip")
+        .header(CONTENT_LENGTH, "1024")
+        .body(())
+        .unwrap();
+    let counting_body = CountingBody {
+        body: Full::new(Bytes::from_static(b"the gzip body"))
+            .map_err(|never| match never {})
+            .boxed(),
+        response_size_stats: ResponseSizeStats::from_headers(response.headers()),
+        forwarded_bytes: 0,
+        destination_address: UnifiedAddress::Domain {
+            host: "www.example.com".to_string(),
+            port: 80,
+        },
+        client_addr: SocketAddr::from(([127, 0, 0, 1], 10080)),
+    };
+    // The body is forwarded as is, the count is the bytes actually forwarded
+    let body = counting_body.collect().await.unwrap().to_bytes();
+    assert_eq!(b"the gzip body", body.as_ref());
+    let captured_log = String::from_utf8(captured_log.0.lock().unwrap().clone()).unwrap();
+    assert!(
+        captured_log.contains(
+            "compressed: true, content encoding: Some(\"gzip\"), content length: Some(1024), forwarded bytes: 13"
+        ),
+        "{captured_log}"
+    );
+}
+
+#[tokio::test]
+async fn test_error_response() {
+    let destination_address = UnifiedAddress::Domain {
+        host: "internal.example.com".to_string(),
+        port: 8443,
+    };
+    let error = Error::Common(common::Error::ConnectDestination(destination_address));
+    let response = error_response(&error, "00000000075bcd15", 1024);
+    assert_eq!(StatusCode::BAD_GATEWAY, response.status());
+    let body = response.into_body().collect().await.unwrap().to_bytes();
+    let body = String::from_utf8(body.to_vec()).unwrap();
+    assert_eq!(
+        "502 Bad Gateway\nReason: The proxy fails to connect the destination\nRequest id: 00000000075bcd15\n",
+        body
+    );
+    // The destination of the failed request is not leaked
+    assert!(!body.contains("internal.example.com"));
+    // Only the reason is cut, the request id is kept
+    let response = error_response(&error, "00000000075bcd15", 64);
+    let body = response.into_body().collect().await.unwrap().to_bytes();
+    assert_eq!(
+        b"502 Bad Gateway\nReason: The proxy \nRequest id: 00000000075bcd15\n",
+        body.as_ref()
+    );
+    let response = error_response(&error, "00000000075bcd15", 15);
+    let body = response.into_body().collect().await.unwrap().to_bytes();
+    assert_eq!(
+        b"502 Bad Gateway\nRequest id: 00000000075bcd15\n",
+        body.as_ref()
+    );
+    let response = error_response(&error, "00000000075bcd15", 0);
+    assert!(
+        response
+            .into_body()
+            .collect()
+            .await
+            .unwrap()
+            .to_bytes()
+            .is_empty()
+    );
+    let response = error_response(
+        &Error::NoDestinationHost(hyper::Uri::from_static("/index.html")),
+        "00000000075bcd15",
+        1024,
+    );
+    assert_eq!(StatusCode::BAD_REQUEST, response.status());
+    // The destination refused by the observing proxy is told apart from the failed one
+    let response = error_response(
+        &Error::Common(common::Error::DestinationObserved(UnifiedAddress::Domain {
+            host: "internal.example.com".to_string(),
+            port: 8443,
+        })),
+        "00000000075bcd15",
+        1024,
+    );
+    assert_eq!(StatusCode::FORBIDDEN, response.status());
+}
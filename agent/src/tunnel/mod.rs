@@ -1,73 +1,293 @@
 mod http;
+mod socks4;
 mod socks5;
 
-use crate::config::get_config;
+use crate::config::{ForcedProtocol, UnknownProtocolMode};
 use crate::error::Error;
-use crate::user::get_agent_user_repo;
+use crate::server::AgentContext;
+use common::metrics::with_current_metrics_recorder;
 use common::proxy::{ProxyConnection, ProxyFramed};
-use common::user::UserRepository;
-use common::{ServerState, UserConfig};
-use tokio::io::AsyncWriteExt;
+use common::{AsTcpStream, PeekableStream, ServerState, set_socket_ip_tos};
+use protocol::UnifiedAddress;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt};
 use tokio::sync::oneshot::Sender;
 use tracing::{debug, error};
 
+/// The time to wait for a pooled connection before creating a fresh one
+const POOL_FETCH_WAIT: Duration = Duration::from_millis(100);
+
 const SOCKS4_VERSION_FLAG: u8 = 4;
 const SOCKS5_VERSION_FLAG: u8 = 5;
 
-pub async fn process(mut server_state: ServerState) -> Result<(), Error> {
+/// The protocol spoken by the client connection
+#[derive(Debug, PartialEq, Eq)]
+enum ClientProtocol {
+    Socks4,
+    Socks5,
+    Http,
+    /// The first byte of the unrecognized protocol
+    Unknown(u8),
+}
+
+impl From<ForcedProtocol> for ClientProtocol {
+    fn from(value: ForcedProtocol) -> Self {
+        match value {
+            ForcedProtocol::Socks4 => ClientProtocol::Socks4,
+            ForcedProtocol::Socks5 => ClientProtocol::Socks5,
+            ForcedProtocol::Http => ClientProtocol::Http,
+        }
+    }
+}
+
+/// Detect the client protocol with the first byte of the connection
+fn detect_protocol(
+    protocol_flag: u8,
+    unknown_protocol_mode: UnknownProtocolMode,
+) -> ClientProtocol {
+    match protocol_flag {
+        SOCKS4_VERSION_FLAG => ClientProtocol::Socks4,
+        SOCKS5_VERSION_FLAG => ClientProtocol::Socks5,
+        // All the http methods are start with upper case letters
+        protocol_flag
+            if unknown_protocol_mode == UnknownProtocolMode::Reject
+                && !protocol_flag.is_ascii_uppercase() =>
+        {
+            ClientProtocol::Unknown(protocol_flag)
+        }
+        _ => ClientProtocol::Http,
+    }
+}
+
+/// Resolve the client protocol, the forced protocol is used directly
+/// without peeking the first byte of the connection, `None` means the
+/// client closed the connection before sending anything
+async fn resolve_protocol<S>(
+    incoming_stream: &mut PeekableStream<S>,
+    forced_protocol: Option<ForcedProtocol>,
+    unknown_protocol_mode: UnknownProtocolMode,
+) -> Result<Option<ClientProtocol>, Error>
+where
+    S: AsyncRead + Unpin,
+{
+    if let Some(forced_protocol) = forced_protocol {
+        return Ok(Some(forced_protocol.into()));
+    }
     let mut protocol_flag_buf = [0u8; 1];
-    let flag_size = server_state
-        .incoming_stream
-        .peek(&mut protocol_flag_buf)
-        .await?;
+    let flag_size = incoming_stream.peek(&mut protocol_flag_buf).await?;
     if flag_size == 0 {
-        return Ok(());
+        return Ok(None);
     }
-    let protocol_flag = protocol_flag_buf[0];
-    match protocol_flag {
-        SOCKS4_VERSION_FLAG => {
-            error!("Socks 4 protocol not supported");
-            server_state.incoming_stream.shutdown().await?;
+    Ok(Some(detect_protocol(
+        protocol_flag_buf[0],
+        unknown_protocol_mode,
+    )))
+}
+
+/// Process the client connection, the stream can be any stream produced by
+/// the acceptor, the socket options are applied when it is backed by tcp.
+pub async fn process<S>(
+    context: Arc<AgentContext>,
+    server_state: ServerState<S>,
+) -> Result<(), Error>
+where
+    S: AsyncRead + AsyncWrite + AsTcpStream + Unpin + Send + 'static,
+{
+    let mut server_state = ServerState {
+        incoming_stream: PeekableStream::new(server_state.incoming_stream),
+        incoming_connection_addr: server_state.incoming_connection_addr,
+    };
+    let Some(client_protocol) = resolve_protocol(
+        &mut server_state.incoming_stream,
+        context.config().forced_protocol(),
+        context.config().unknown_protocol_mode(),
+    )
+    .await?
+    else {
+        return Ok(());
+    };
+    match client_protocol {
+        ClientProtocol::Socks4 => {
+            debug!(
+                "Accept socks 4 protocol client connection [{}].",
+                server_state.incoming_connection_addr
+            );
+            socks4::process_socks4_tunnel(&context, server_state).await?;
         }
-        SOCKS5_VERSION_FLAG => {
+        ClientProtocol::Socks5 => {
             debug!(
                 "Accept socks 5 protocol client connection [{}].",
                 server_state.incoming_connection_addr
             );
-            socks5::process_socks5_tunnel(server_state).await?;
+            if let Some(ip_tos) = context.config().socks5_socket_ip_tos()
+                && let Some(tcp_stream) = server_state.incoming_stream.as_tcp_stream()
+            {
+                set_socket_ip_tos(tcp_stream, ip_tos)?;
+            }
+            socks5::process_socks5_tunnel(&context, server_state).await?;
         }
-        _ => {
+        ClientProtocol::Http => {
             debug!(
                 "Accept http/https protocol client connection [{}].",
                 server_state.incoming_connection_addr
             );
-            http::process_http_tunnel(server_state).await?;
+            if let Some(ip_tos) = context.config().http_socket_ip_tos()
+                && let Some(tcp_stream) = server_state.incoming_stream.as_tcp_stream()
+            {
+                set_socket_ip_tos(tcp_stream, ip_tos)?;
+            }
+            http::process_http_tunnel(context, server_state).await?;
+        }
+        ClientProtocol::Unknown(protocol_flag) => {
+            error!(
+                "Unrecognized protocol from client connection [{}], first byte: {protocol_flag:#04x}",
+                server_state.incoming_connection_addr
+            );
+            server_state.incoming_stream.shutdown().await?;
         }
     }
     Ok(())
 }
 
+/// Create the proxy connection in background, the given IP ToS/DSCP
+/// value overrides the one of the socket options
+/// Log the failure relaying between the agent and the proxy, the frame
+/// decrypt failure is logged with the destination of the relay.
+fn log_relay_failure(e: &std::io::Error, destination_address: &UnifiedAddress) {
+    if common::Error::is_decrypt_failure(e) {
+        error!(
+            "Frame decrypt failed mid-relay between agent and proxy, destination [{destination_address}]: {e:?}"
+        );
+    } else {
+        error!("Fail to proxy data between agent and proxy: {e:?}");
+    }
+}
+
 async fn fetch_proxy_connection(
+    context: &Arc<AgentContext>,
     proxy_connection_tx: Sender<ProxyConnection<ProxyFramed<'static>>>,
+    ip_tos: Option<u32>,
 ) -> Result<(), Error> {
-    let config = get_config();
-    let agent_user = get_agent_user_repo()
-        .find_user(config.username())
-        .ok_or(common::Error::UserNotExist(config.username().to_owned()))?;
-    tokio::spawn(async move {
-        let connection = match ProxyConnection::new(agent_user, config.proxy_connect_timeout())
+    let agent_user = context.agent_user()?;
+    let context = context.clone();
+    tokio::spawn(with_current_metrics_recorder(async move {
+        let pooled_connection = match context.proxy_connection_pool() {
+            Some(pool) => {
+                let pooled_connection = pool.fetch_connection(POOL_FETCH_WAIT).await;
+                match pooled_connection {
+                    Some(_) => debug!("Proxy connection pool hit."),
+                    None => debug!("Proxy connection pool miss, create a fresh proxy connection."),
+                }
+                pooled_connection
+            }
+            None => None,
+        };
+        let connection = match pooled_connection {
+            Some(connection) => connection,
+            None => match ProxyConnection::new_with_retry(
+                &*agent_user,
+                context.config(),
+                context.proxy_backoff(),
+                context.config().proxy_connect_retries(),
+                Duration::from_millis(context.config().proxy_connect_retry_backoff_millis()),
+                context.config().proxy_connect_deadline(),
+            )
             .await
             .map_err(Error::Common)
-        {
-            Ok(connection) => connection,
-            Err(e) => {
-                error!("Fail to initialize proxy connection: {e:?}");
-                return;
-            }
+            {
+                Ok(connection) => connection,
+                Err(e) => {
+                    error!("Fail to initialize proxy connection: {e:?}");
+                    return;
+                }
+            },
         };
+        let connection = connection.with_connect_timeout_hint(
+            context
+                .config()
+                .destination_connect_timeout_hint_millis()
+                .map(Duration::from_millis),
+        );
+        if let Some(ip_tos) = ip_tos
+            && let Err(e) = connection.set_ip_tos(ip_tos)
+        {
+            error!("Fail to set ip tos of proxy connection: {e:?}");
+        }
         if proxy_connection_tx.send(connection).is_err() {
             error!("Fail to send proxy connection to channel");
         }
-    });
+    }));
+    Ok(())
+}
+
+#[test]
+fn test_detect_protocol() {
+    assert_eq!(
+        ClientProtocol::Socks5,
+        detect_protocol(SOCKS5_VERSION_FLAG, UnknownProtocolMode::Reject)
+    );
+    assert_eq!(
+        ClientProtocol::Http,
+        detect_protocol(b'G', UnknownProtocolMode::Reject)
+    );
+    assert_eq!(
+        ClientProtocol::Http,
+        detect_protocol(0xff, UnknownProtocolMode::Http)
+    );
+    assert_eq!(
+        ClientProtocol::Unknown(0xff),
+        detect_protocol(0xff, UnknownProtocolMode::Reject)
+    );
+    assert_eq!(
+        ClientProtocol::Unknown(b'g'),
+        detect_protocol(b'g', UnknownProtocolMode::Reject)
+    );
+}
+
+#[tokio::test]
+async fn test_resolve_forced_protocol() -> Result<(), Error> {
+    use std::time::Duration;
+    use tokio::time::timeout;
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+    let client_stream = tokio::net::TcpStream::connect(listener.local_addr()?).await?;
+    let (incoming_stream, _) = listener.accept().await?;
+    let mut incoming_stream = PeekableStream::new(incoming_stream);
+    // The client sends nothing, so only the forced protocol can be resolved without peeking
+    let client_protocol = timeout(
+        Duration::from_secs(1),
+        resolve_protocol(
+            &mut incoming_stream,
+            Some(ForcedProtocol::Http),
+            UnknownProtocolMode::Reject,
+        ),
+    )
+    .await
+    .expect("Forced protocol should not peek the connection")?;
+    assert_eq!(Some(ClientProtocol::Http), client_protocol);
+    let peek_result = timeout(
+        Duration::from_millis(100),
+        resolve_protocol(&mut incoming_stream, None, UnknownProtocolMode::Reject),
+    )
+    .await;
+    assert!(peek_result.is_err());
+    drop(client_stream);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_resolve_protocol_over_duplex() -> Result<(), Error> {
+    let (mut client, incoming_stream) = tokio::io::duplex(1024);
+    let mut incoming_stream = PeekableStream::new(incoming_stream);
+    client.write_all(&[SOCKS5_VERSION_FLAG, 1, 0]).await?;
+    assert_eq!(
+        Some(ClientProtocol::Socks5),
+        resolve_protocol(&mut incoming_stream, None, UnknownProtocolMode::Reject).await?
+    );
+    drop(client);
+    // The peeked byte is still read by the protocol handler
+    let mut socks5_greeting = Vec::new();
+    tokio::io::AsyncReadExt::read_to_end(&mut incoming_stream, &mut socks5_greeting).await?;
+    assert_eq!(vec![SOCKS5_VERSION_FLAG, 1, 0], socks5_greeting);
     Ok(())
 }
@@ -0,0 +1,234 @@
+use crate::error::Error;
+use crate::server::AgentContext;
+use crate::tunnel::{fetch_proxy_connection, log_relay_failure};
+use common::proxy::DestinationType;
+use common::{ServerState, copy_bidirectional_with_idle_timeout};
+use protocol::UnifiedAddress;
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::sync::oneshot::channel;
+use tracing::{debug, error, info};
+
+const SOCKS4_VERSION: u8 = 4;
+const SOCKS4_REPLY_VERSION: u8 = 0;
+const SOCKS4_REQUEST_GRANTED: u8 = 0x5a;
+const SOCKS4_REQUEST_REJECTED: u8 = 0x5b;
+/// The max length of the user id and the socks 4a domain, both are null-terminated
+const SOCKS4_MAX_FIELD_LENGTH: usize = 255;
+
+#[derive(Debug, PartialEq, Eq)]
+enum Socks4Command {
+    Connect,
+    Bind,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+struct Socks4Request {
+    command: Socks4Command,
+    destination_address: UnifiedAddress,
+}
+
+/// Read the null-terminated field of the socks 4 request
+async fn read_null_terminated<R>(stream: &mut R, field_name: &str) -> Result<Vec<u8>, Error>
+where
+    R: AsyncRead + Unpin,
+{
+    let mut field = Vec::new();
+    loop {
+        let byte = stream.read_u8().await?;
+        if byte == 0 {
+            return Ok(field);
+        }
+        if field.len() >= SOCKS4_MAX_FIELD_LENGTH {
+            return Err(Error::Socks4Protocol(format!("{field_name} too long")));
+        }
+        field.push(byte);
+    }
+}
+
+/// Read the socks 4 request, the destination ip `0.0.0.x` (x is not 0)
+/// means the socks 4a request with the domain after the user id.
+async fn read_socks4_request<R>(stream: &mut R) -> Result<Socks4Request, Error>
+where
+    R: AsyncRead + Unpin,
+{
+    let version = stream.read_u8().await?;
+    if version != SOCKS4_VERSION {
+        return Err(Error::Socks4Protocol(format!(
+            "unsupported version: {version}"
+        )));
+    }
+    let command = match stream.read_u8().await? {
+        1 => Socks4Command::Connect,
+        2 => Socks4Command::Bind,
+        command => {
+            return Err(Error::Socks4Protocol(format!("unknown command: {command}")));
+        }
+    };
+    let port = stream.read_u16().await?;
+    let ip = Ipv4Addr::from(stream.read_u32().await?);
+    // The user id is not used, the agent authenticates with its own user
+    read_null_terminated(stream, "user id").await?;
+    let [a, b, c, d] = ip.octets();
+    let destination_address = if a == 0 && b == 0 && c == 0 && d != 0 {
+        let host = read_null_terminated(stream, "domain").await?;
+        let host = String::from_utf8(host)
+            .map_err(|_| Error::Socks4Protocol("domain is not utf8".to_string()))?;
+        UnifiedAddress::Domain { host, port }
+    } else {
+        SocketAddr::V4(SocketAddrV4::new(ip, port)).into()
+    };
+    Ok(Socks4Request {
+        command,
+        destination_address,
+    })
+}
+
+/// The socks 4 reply, the destination port and ip are ignored by the client on CONNECT
+fn socks4_reply(status: u8) -> [u8; 8] {
+    [SOCKS4_REPLY_VERSION, status, 0, 0, 0, 0, 0, 0]
+}
+
+pub async fn process_socks4_tunnel<S>(
+    context: &Arc<AgentContext>,
+    mut server_state: ServerState<S>,
+) -> Result<(), Error>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    debug!(
+        "Client connect to agent with socks 4 protocol: {}",
+        server_state.incoming_connection_addr
+    );
+    let socks4_request = read_socks4_request(&mut server_state.incoming_stream).await?;
+    if socks4_request.command == Socks4Command::Bind {
+        error!(
+            "Socks4 bind protocol not supported, client_addr: {}",
+            server_state.incoming_connection_addr
+        );
+        server_state
+            .incoming_stream
+            .write_all(&socks4_reply(SOCKS4_REQUEST_REJECTED))
+            .await?;
+        return Ok(());
+    }
+    debug!(
+        "Receive socks4 CONNECT command: {}",
+        server_state.incoming_connection_addr
+    );
+    let destination_address = socks4_request.destination_address;
+    let (proxy_connection_tx, proxy_connection_rx) = channel();
+    fetch_proxy_connection(context, proxy_connection_tx, None).await?;
+    let proxy_connection = match proxy_connection_rx.await {
+        Ok(proxy_connection) => proxy_connection,
+        Err(_) => {
+            server_state
+                .incoming_stream
+                .write_all(&socks4_reply(SOCKS4_REQUEST_REJECTED))
+                .await?;
+            return Err(Error::Unknown(
+                "Failed to receive proxy connection".to_string(),
+            ));
+        }
+    };
+    let mut proxy_connection = match proxy_connection
+        .connect_destination(destination_address.clone(), DestinationType::Tcp)
+        .await
+    {
+        Ok(proxy_connection) => proxy_connection,
+        Err(e) => {
+            server_state
+                .incoming_stream
+                .write_all(&socks4_reply(SOCKS4_REQUEST_REJECTED))
+                .await?;
+            return Err(e.into());
+        }
+    };
+    server_state
+        .incoming_stream
+        .write_all(&socks4_reply(SOCKS4_REQUEST_GRANTED))
+        .await?;
+    let (from_client, from_proxy) = match copy_bidirectional_with_idle_timeout(
+        &mut server_state.incoming_stream,
+        &mut proxy_connection,
+        context
+            .config()
+            .common()
+            .relay_idle_timeout_secs
+            .map(Duration::from_secs),
+    )
+    .await
+    {
+        Err(e) => {
+            log_relay_failure(&e, &destination_address);
+            return Ok(());
+        }
+        Ok((from_client, from_proxy)) => (from_client, from_proxy),
+    };
+    info!(
+        "Agent wrote {} bytes to proxy, received {} bytes from proxy",
+        from_client, from_proxy
+    );
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_read_socks4_request() -> Result<(), Error> {
+    // CONNECT 10.0.0.1:80, user id "u"
+    let mut ip_request: &[u8] = &[4, 1, 0, 80, 10, 0, 0, 1, b'u', 0];
+    assert_eq!(
+        Socks4Request {
+            command: Socks4Command::Connect,
+            destination_address: SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::new(10, 0, 0, 1), 80))
+                .into(),
+        },
+        read_socks4_request(&mut ip_request).await?
+    );
+    // CONNECT example.com:443 with socks 4a, empty user id
+    let mut domain_request = vec![4, 1, 1, 187, 0, 0, 0, 1, 0];
+    domain_request.extend_from_slice(b"example.com\0");
+    assert_eq!(
+        Socks4Request {
+            command: Socks4Command::Connect,
+            destination_address: UnifiedAddress::Domain {
+                host: "example.com".to_string(),
+                port: 443,
+            },
+        },
+        read_socks4_request(&mut domain_request.as_slice()).await?
+    );
+    let mut socks5_request: &[u8] = &[5, 1, 0, 80, 10, 0, 0, 1, 0];
+    assert!(matches!(
+        read_socks4_request(&mut socks5_request).await,
+        Err(Error::Socks4Protocol(_))
+    ));
+    let mut unterminated_user_id = vec![4, 1, 0, 80, 10, 0, 0, 1];
+    unterminated_user_id.extend_from_slice(&[b'u'; 300]);
+    assert!(matches!(
+        read_socks4_request(&mut unterminated_user_id.as_slice()).await,
+        Err(Error::Socks4Protocol(_))
+    ));
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_process_socks4_bind_rejected() -> Result<(), Error> {
+    let (mut client, incoming_stream) = tokio::io::duplex(1024);
+    let server_state = ServerState {
+        incoming_stream,
+        incoming_connection_addr: "127.0.0.1:10000".parse().unwrap(),
+    };
+    // BIND 10.0.0.1:80, empty user id
+    client.write_all(&[4, 2, 0, 80, 10, 0, 0, 1, 0]).await?;
+    let context = Arc::new(AgentContext::new(crate::server::test_agent_config(
+        "127.0.0.1:0".parse().unwrap(),
+        std::path::Path::new("../resources/agent/user"),
+    ))?);
+    process_socks4_tunnel(&context, server_state).await?;
+    let mut reply = [0u8; 8];
+    client.read_exact(&mut reply).await?;
+    assert_eq!(socks4_reply(SOCKS4_REQUEST_REJECTED), reply);
+    Ok(())
+}
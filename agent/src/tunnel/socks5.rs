@@ -1,18 +1,30 @@
-use crate::config::get_config;
 use crate::error::Error;
-use crate::tunnel::fetch_proxy_connection;
+use crate::server::AgentContext;
+use crate::tunnel::{fetch_proxy_connection, log_relay_failure};
+use common::metrics::{Counter, relay_bytes_counters};
 use common::proxy::DestinationType;
-use common::{ServerConfig, ServerState};
-use fast_socks5::server::{Socks5ServerProtocol, SocksServerError, run_udp_proxy_custom};
+use common::{
+    DEFAULT_UDP_RELAY_BUFFER_SIZE, RelayIdleTimer, ServerConfig, ServerState, UdpRelayPacket,
+    copy_bidirectional_with_idle_timeout, read_udp_relay_packet, write_udp_relay_packet,
+};
+use fast_socks5::server::{
+    ErrorContext, Socks5ServerProtocol, SocksServerError, run_udp_proxy_custom,
+};
 use fast_socks5::util::target_addr::TargetAddr;
-use fast_socks5::{Socks5Command, parse_udp_request};
+use fast_socks5::{Socks5Command, new_udp_header, parse_udp_request};
 use protocol::UnifiedAddress;
 use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
-use tokio::io::{AsyncReadExt, AsyncWriteExt, copy_bidirectional};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite};
 use tokio::net::UdpSocket;
 use tokio::sync::oneshot::channel;
+use tokio_util::bytes::Bytes;
 use tracing::{debug, error, info};
 
+/// The max size of one socks5 udp datagram, the socks5 udp header included
+const SOCKS5_UDP_PACKET_MAX_SIZE: usize = 65536;
+
 fn convert_address(address: &TargetAddr) -> UnifiedAddress {
     match address {
         TargetAddr::Ip(dst_addr) => dst_addr.into(),
@@ -23,7 +35,138 @@ fn convert_address(address: &TargetAddr) -> UnifiedAddress {
     }
 }
 
-pub async fn process_socks5_tunnel(server_state: ServerState) -> Result<(), Error> {
+fn convert_to_target_address(address: &UnifiedAddress) -> TargetAddr {
+    match address {
+        UnifiedAddress::SocketAddress(socket_addr) => TargetAddr::Ip(*socket_addr),
+        UnifiedAddress::Domain { host, port } => TargetAddr::Domain(host.clone(), *port),
+    }
+}
+
+/// Relay the datagrams of the udp association through one proxy connection,
+/// it runs until the control connection closes and the relay is dropped, or
+/// no datagram moves within the relay idle timeout.
+async fn relay_udp_association(
+    context: &Arc<AgentContext>,
+    client_udp_socket: UdpSocket,
+) -> Result<(), Error> {
+    let mut client_udp_socks5_packet = vec![0u8; SOCKS5_UDP_PACKET_MAX_SIZE];
+    // The proxy connection is setup with the destination of the first datagram,
+    // the datagram itself is relayed by the loop
+    let (client_udp_socks5_packet_size, _) = client_udp_socket
+        .peek_from(&mut client_udp_socks5_packet)
+        .await?;
+    let (_, dst_addr, _) =
+        parse_udp_request(&client_udp_socks5_packet[..client_udp_socks5_packet_size])
+            .await
+            .map_err(SocksServerError::from)?;
+    let (proxy_connection_tx, proxy_connection_rx) = channel();
+    fetch_proxy_connection(
+        context,
+        proxy_connection_tx,
+        context.config().socks5_socket_ip_tos(),
+    )
+    .await?;
+    let proxy_connection = proxy_connection_rx
+        .await
+        .map_err(|_| Error::Unknown("Failed to receive proxy connection".to_string()))?;
+    let proxy_connection = proxy_connection
+        .connect_destination(convert_address(&dst_addr), DestinationType::Udp)
+        .await?;
+    let (mut proxy_reader, mut proxy_writer) = tokio::io::split(proxy_connection);
+    let idle_timer = RelayIdleTimer::new();
+    let idle_timeout = context
+        .config()
+        .common()
+        .relay_idle_timeout_secs
+        .map(Duration::from_secs);
+    let (client_to_destination_bytes, destination_to_client_bytes) = relay_bytes_counters();
+    tokio::select! {
+        result = relay_udp_client_to_proxy(&client_udp_socket, &mut proxy_writer, &idle_timer, &client_to_destination_bytes) => result,
+        result = relay_udp_proxy_to_client(&client_udp_socket, &mut proxy_reader, &idle_timer, &destination_to_client_bytes) => result,
+        e = idle_timer.expired(idle_timeout) => Err(e.into()),
+    }
+}
+
+async fn relay_udp_client_to_proxy<W>(
+    client_udp_socket: &UdpSocket,
+    proxy_writer: &mut W,
+    idle_timer: &RelayIdleTimer,
+    relayed_bytes: &Counter,
+) -> Result<(), Error>
+where
+    W: AsyncWrite + Unpin,
+{
+    let mut client_udp_socks5_packet = vec![0u8; SOCKS5_UDP_PACKET_MAX_SIZE];
+    loop {
+        let (client_udp_socks5_packet_size, client_udp_addr) = client_udp_socket
+            .recv_from(&mut client_udp_socks5_packet)
+            .await?;
+        idle_timer.mark_moved();
+        let (frag, dst_addr, client_udp_data) =
+            match parse_udp_request(&client_udp_socks5_packet[..client_udp_socks5_packet_size])
+                .await
+            {
+                Ok(udp_request) => udp_request,
+                Err(e) => {
+                    error!("Drop invalid socks5 udp datagram from [{client_udp_addr}]: {e:?}");
+                    continue;
+                }
+            };
+        if frag != 0 {
+            error!("Drop fragmented socks5 udp datagram from [{client_udp_addr}]");
+            continue;
+        }
+        relayed_bytes.increment(client_udp_data.len() as u64);
+        write_udp_relay_packet(
+            proxy_writer,
+            UdpRelayPacket {
+                src_addr: client_udp_addr.into(),
+                dst_addr: convert_address(&dst_addr),
+                payload: Bytes::copy_from_slice(client_udp_data),
+            },
+        )
+        .await?;
+    }
+}
+
+async fn relay_udp_proxy_to_client<R>(
+    client_udp_socket: &UdpSocket,
+    proxy_reader: &mut R,
+    idle_timer: &RelayIdleTimer,
+    relayed_bytes: &Counter,
+) -> Result<(), Error>
+where
+    R: AsyncRead + Unpin,
+{
+    loop {
+        let packet = read_udp_relay_packet(proxy_reader, DEFAULT_UDP_RELAY_BUFFER_SIZE).await?;
+        idle_timer.mark_moved();
+        // The destination of the replied datagram is the client source
+        let UnifiedAddress::SocketAddress(client_udp_addr) = packet.dst_addr else {
+            error!(
+                "Drop udp datagram replied to unknown client source [{}]",
+                packet.dst_addr
+            );
+            continue;
+        };
+        let mut client_udp_socks5_packet =
+            new_udp_header(convert_to_target_address(&packet.src_addr))
+                .map_err(SocksServerError::from)?;
+        client_udp_socks5_packet.extend_from_slice(&packet.payload);
+        relayed_bytes.increment(packet.payload.len() as u64);
+        client_udp_socket
+            .send_to(&client_udp_socks5_packet, client_udp_addr)
+            .await?;
+    }
+}
+
+pub async fn process_socks5_tunnel<S>(
+    context: &Arc<AgentContext>,
+    server_state: ServerState<S>,
+) -> Result<(), Error>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send,
+{
     debug!(
         "Client connect to agent with socks 5 protocol: {}",
         server_state.incoming_connection_addr
@@ -39,7 +182,12 @@ pub async fn process_socks5_tunnel(server_state: ServerState) -> Result<(), Erro
                 server_state.incoming_connection_addr
             );
             let (proxy_connection_tx, proxy_connection_rx) = channel();
-            fetch_proxy_connection(proxy_connection_tx).await?;
+            fetch_proxy_connection(
+                context,
+                proxy_connection_tx,
+                context.config().socks5_socket_ip_tos(),
+            )
+            .await?;
             let destination_address = convert_address(&dst_addr);
             let mut socks5_client_stream = socks5_client_stream
                 .reply_success(SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, 0)))
@@ -51,14 +199,23 @@ pub async fn process_socks5_tunnel(server_state: ServerState) -> Result<(), Erro
             let mut proxy_connection = proxy_connection
                 .connect_destination(destination_address.clone(), DestinationType::Tcp)
                 .await?;
-            let (from_client, from_proxy) =
-                match copy_bidirectional(&mut socks5_client_stream, &mut proxy_connection).await {
-                    Err(e) => {
-                        error!("Fail to proxy data between agent and proxy: {e:?}");
-                        return Ok(());
-                    }
-                    Ok((from_client, from_proxy)) => (from_client, from_proxy),
-                };
+            let (from_client, from_proxy) = match copy_bidirectional_with_idle_timeout(
+                &mut socks5_client_stream,
+                &mut proxy_connection,
+                context
+                    .config()
+                    .common()
+                    .relay_idle_timeout_secs
+                    .map(Duration::from_secs),
+            )
+            .await
+            {
+                Err(e) => {
+                    log_relay_failure(&e, &destination_address);
+                    return Ok(());
+                }
+                Ok((from_client, from_proxy)) => (from_client, from_proxy),
+            };
             info!(
                 "Agent wrote {} bytes to proxy, received {} bytes from proxy",
                 from_client, from_proxy
@@ -75,74 +232,14 @@ pub async fn process_socks5_tunnel(server_state: ServerState) -> Result<(), Erro
                 socks5_client_stream,
                 &dst_addr,
                 None,
-                get_config().common().listening_address().ip(),
+                context.config().common().listening_address().ip(),
                 |client_udp_socket| async move {
-                    let client_udp_socket =
-                        UdpSocket::from_std(client_udp_socket.into()).map_err(|e| {
-                            SocksServerError::Io {
-                                source: e,
-                                context: "Fail to create client udp socket.",
-                            }
-                        })?;
-                    let mut client_udp_socks5_packet = vec![0u8; 8192];
-                    client_udp_socket
-                        .recv(&mut client_udp_socks5_packet)
-                        .await
-                        .map_err(|e| SocksServerError::Io {
-                            source: e,
-                            context: "Fail to read client udp data to proxy.",
-                        })?;
-                    let (_, dst_addr, client_udp_data) =
-                        parse_udp_request(&client_udp_socks5_packet).await?;
-                    let (proxy_connection_tx, proxy_connection_rx) = channel();
-                    fetch_proxy_connection(proxy_connection_tx)
-                        .await
-                        .map_err(|e| SocksServerError::Io {
-                            source: std::io::Error::other(format!(
-                                "Fail to build proxy connection: {e:?}"
-                            )),
-                            context: "Fail to build proxy connection.",
-                        })?;
-                    let destination_address = convert_address(&dst_addr);
-                    let proxy_connection =
-                        proxy_connection_rx
-                            .await
-                            .map_err(|e| SocksServerError::Io {
-                                source: std::io::Error::other(format!(
-                                    "Fail to receive proxy connection: {e:?}"
-                                )),
-                                context: "Fail to receive proxy connection.",
-                            })?;
-                    let mut proxy_connection = proxy_connection
-                        .connect_destination(destination_address, DestinationType::Udp)
-                        .await
-                        .map_err(|e| SocksServerError::Io {
-                            source: std::io::Error::other(format!(
-                                "Fail to do setup destination with proxy connection: {e:?}"
-                            )),
-                            context: "Fail to do setup destination with proxy connection.",
-                        })?;
-                    proxy_connection.write(client_udp_data).await.map_err(|e| {
-                        SocksServerError::Io {
-                            source: e,
-                            context: "Fail to write client udp data to proxy.",
-                        }
-                    })?;
-                    let mut proxy_udp_data_buf = vec![0u8; 8192];
-                    proxy_connection
-                        .read(&mut proxy_udp_data_buf)
-                        .await
-                        .map_err(|e| SocksServerError::Io {
-                            source: e,
-                            context: "Fail to read proxy udp data.",
-                        })?;
-                    client_udp_socket
-                        .send(&proxy_udp_data_buf)
+                    let client_udp_socket = UdpSocket::from_std(client_udp_socket.into())
+                        .err_when("creating client udp socket")?;
+                    relay_udp_association(context, client_udp_socket)
                         .await
-                        .map_err(|e| SocksServerError::Io {
-                            source: e,
-                            context: "Fail to write proxy udp data to client.",
-                        })?;
+                        .map_err(std::io::Error::other)
+                        .err_when("relaying udp association")?;
                     Ok(())
                 },
             )
@@ -1,35 +1,36 @@
-use crate::config::get_config;
-use common::config::CommonConfig;
-use common::user::repo::FileSystemUserRepository;
-use common::user::{User, UserRepository, UserWithProxyServers};
+use common::Error as CommonError;
+use common::user::{ProxyServer, User, UserWithProxyServers, validate_proxy_servers};
 use crypto::RsaCrypto;
 use protocol::Username;
 use serde::{Deserialize, Serialize};
-use std::net::SocketAddr;
-use std::sync::OnceLock;
+use std::sync::atomic::AtomicUsize;
 
-pub static AGENT_USER_REPO: OnceLock<FileSystemUserRepository<AgentUser, CommonConfig>> =
-    OnceLock::new();
-
-pub fn get_agent_user_repo() -> &'static FileSystemUserRepository<AgentUser, CommonConfig> {
-    AGENT_USER_REPO.get_or_init(|| {
-        FileSystemUserRepository::<AgentUser, CommonConfig>::new(get_config().common())
-            .expect("Fail to create user repository from file system")
-    })
+/// Check the agent user can be used to connect to the proxy
+pub(crate) fn check_agent_user(
+    agent_user: Option<&AgentUser>,
+    username: &Username,
+) -> Result<(), CommonError> {
+    let agent_user = agent_user.ok_or(CommonError::UserNotExist(username.clone()))?;
+    validate_proxy_servers(agent_user)
 }
 
 #[derive(Serialize, Deserialize, Debug)]
 pub struct AgentUser {
-    proxy_servers: Vec<SocketAddr>,
+    proxy_servers: Vec<ProxyServer>,
     username: Username,
     #[serde(skip)]
     rsa_crypto: Option<RsaCrypto>,
+    #[serde(skip)]
+    round_robin_next: AtomicUsize,
 }
 
 impl UserWithProxyServers for AgentUser {
-    fn proxy_servers(&self) -> &[SocketAddr] {
+    fn proxy_servers(&self) -> &[ProxyServer] {
         &self.proxy_servers
     }
+    fn round_robin_next(&self) -> &AtomicUsize {
+        &self.round_robin_next
+    }
 }
 
 impl User for AgentUser {
@@ -43,3 +44,29 @@ impl User for AgentUser {
         self.rsa_crypto = Some(rsa_crypto)
     }
 }
+
+#[test]
+fn test_check_agent_user() {
+    let username = Username::from("user1");
+    let mut agent_user = AgentUser {
+        proxy_servers: Vec::new(),
+        username: username.clone(),
+        rsa_crypto: None,
+        round_robin_next: Default::default(),
+    };
+    assert!(matches!(
+        check_agent_user(Some(&agent_user), &username),
+        Err(CommonError::NoProxyServersConfigured(_))
+    ));
+    assert!(matches!(
+        check_agent_user(None, &username),
+        Err(CommonError::UserNotExist(_))
+    ));
+    agent_user.proxy_servers.push(
+        "127.0.0.1:80"
+            .parse::<std::net::SocketAddr>()
+            .unwrap()
+            .into(),
+    );
+    assert!(check_agent_user(Some(&agent_user), &username).is_ok());
+}
@@ -0,0 +1,52 @@
+use common::SecureLengthDelimitedCodec;
+use criterion::{BenchmarkId, Criterion, Throughput, criterion_group, criterion_main};
+use ppaass_protocol::Encryption;
+use std::borrow::Cow;
+use std::hint::black_box;
+use tokio_util::bytes::BytesMut;
+use tokio_util::codec::{Decoder, Encoder};
+
+const PAYLOAD_SIZES: [usize; 3] = [64, 1024, 64 * 1024];
+
+fn bench_codec_round_trip(c: &mut Criterion) {
+    let encryptions = [
+        (
+            "aes",
+            Encryption::Aes(ppaass_crypto::generate_aes_encryption_token()),
+        ),
+        (
+            "blowfish",
+            Encryption::Blowfish(ppaass_crypto::generate_blowfish_encryption_token()),
+        ),
+        (
+            "chacha20_poly1305",
+            Encryption::ChaCha20Poly1305(ppaass_crypto::generate_chacha20_encryption_token()),
+        ),
+    ];
+    let mut group = c.benchmark_group("codec_round_trip");
+    for (name, encryption) in &encryptions {
+        let mut codec =
+            SecureLengthDelimitedCodec::new(Cow::Borrowed(encryption), Cow::Borrowed(encryption));
+        for payload_size in PAYLOAD_SIZES {
+            let payload = vec![7u8; payload_size];
+            group.throughput(Throughput::Bytes(payload_size as u64));
+            group.bench_with_input(
+                BenchmarkId::new(*name, payload_size),
+                &payload,
+                |b, payload| {
+                    let mut buf = BytesMut::new();
+                    b.iter(|| {
+                        codec
+                            .encode(black_box(payload.as_slice()), &mut buf)
+                            .unwrap();
+                        codec.decode(&mut buf).unwrap().unwrap()
+                    })
+                },
+            );
+        }
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench_codec_round_trip);
+criterion_main!(benches);
@@ -0,0 +1,95 @@
+use common::{BatchedFlushWriter, SecureLengthDelimitedCodec, get_handshake_encryption};
+use criterion::{Criterion, criterion_group, criterion_main};
+use std::borrow::Cow;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt, ReadBuf};
+use tokio::runtime::Runtime;
+use tokio_util::codec::Framed;
+
+const RELAY_CHUNK_SIZE: usize = 256;
+const RELAY_CHUNKS: usize = 1024;
+
+/// The socket counting the writes reaching it, every write of a real
+/// socket is a syscall
+struct CountingSocket {
+    writes: Arc<AtomicUsize>,
+}
+
+impl AsyncRead for CountingSocket {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        _cx: &mut Context<'_>,
+        _buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl AsyncWrite for CountingSocket {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        _cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        self.writes.fetch_add(1, Ordering::Relaxed);
+        Poll::Ready(Ok(buf.len()))
+    }
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+    fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+}
+
+/// Relay the small chunks arriving one by one into the frames like the relay
+/// loop does, the writer is flushed whenever no more data is ready to read.
+/// Returns the writes reaching the socket.
+async fn relay_small_chunks(flush_interval: Option<Duration>) -> usize {
+    let writes = Arc::new(AtomicUsize::new(0));
+    let socket = CountingSocket {
+        writes: writes.clone(),
+    };
+    let codec = SecureLengthDelimitedCodec::new(
+        Cow::Borrowed(get_handshake_encryption()),
+        Cow::Borrowed(get_handshake_encryption()),
+    );
+    let mut writer = BatchedFlushWriter::new(Framed::new(socket, codec), flush_interval);
+    let (mut source, mut source_reader) = tokio::io::duplex(RELAY_CHUNK_SIZE);
+    let source_task = tokio::spawn(async move {
+        let chunk = [7u8; RELAY_CHUNK_SIZE];
+        for _ in 0..RELAY_CHUNKS {
+            source.write_all(&chunk).await.unwrap();
+            tokio::task::yield_now().await;
+        }
+    });
+    tokio::io::copy(&mut source_reader, &mut writer)
+        .await
+        .unwrap();
+    writer.shutdown().await.unwrap();
+    source_task.await.unwrap();
+    writes.load(Ordering::Relaxed)
+}
+
+fn bench_relay_flush(c: &mut Criterion) {
+    let runtime = Runtime::new().unwrap();
+    let mut group = c.benchmark_group("relay_flush");
+    for (name, flush_interval) in [
+        ("flush_every_write", None),
+        ("flush_interval_1ms", Some(Duration::from_millis(1))),
+    ] {
+        let socket_writes = runtime.block_on(relay_small_chunks(flush_interval));
+        println!("relay_flush/{name}: {socket_writes} socket writes for {RELAY_CHUNKS} chunks");
+        group.bench_function(name, |b| {
+            b.iter(|| runtime.block_on(relay_small_chunks(flush_interval)))
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench_relay_flush);
+criterion_main!(benches);
@@ -0,0 +1,112 @@
+use common::config::UserRepoConfig;
+use common::user::repo::FileSystemUserRepository;
+use common::user::{User, UserRepository};
+use common::{Error, FsUserRepoConfig};
+use criterion::{BenchmarkId, Criterion, criterion_group, criterion_main};
+use ppaass_crypto::RsaCrypto;
+use ppaass_protocol::Username;
+use serde::Deserialize;
+use std::path::{Path, PathBuf};
+
+const USER_COUNT: usize = 256;
+const PARALLELISMS: [usize; 4] = [1, 2, 4, 8];
+
+#[derive(Deserialize)]
+struct BenchUser {
+    username: Username,
+    #[serde(skip)]
+    rsa_crypto: Option<RsaCrypto>,
+}
+
+impl User for BenchUser {
+    fn username(&self) -> &Username {
+        &self.username
+    }
+    fn rsa_crypto(&self) -> Option<&RsaCrypto> {
+        self.rsa_crypto.as_ref()
+    }
+    fn set_rsa_crypto(&mut self, rsa_crypto: RsaCrypto) {
+        self.rsa_crypto = Some(rsa_crypto)
+    }
+}
+
+struct BenchConfig {
+    user_repo_directory: PathBuf,
+    user_load_parallelism: usize,
+}
+
+impl UserRepoConfig for BenchConfig {
+    fn refresh_interval_sec(&self) -> u64 {
+        0
+    }
+}
+
+impl FsUserRepoConfig for BenchConfig {
+    fn user_repo_directory(&self) -> &Path {
+        &self.user_repo_directory
+    }
+    fn public_key_file_name(&self) -> &str {
+        "AgentPublicKey.pem"
+    }
+    fn private_key_file_name(&self) -> &str {
+        "ProxyPrivateKey.pem"
+    }
+    fn user_info_file_name(&self) -> &str {
+        "user_info.toml"
+    }
+    fn max_users(&self) -> Option<usize> {
+        None
+    }
+    fn user_load_parallelism(&self) -> Option<usize> {
+        Some(self.user_load_parallelism)
+    }
+}
+
+fn create_user_repo() -> Result<PathBuf, Error> {
+    let source_user_dir =
+        Path::new(env!("CARGO_MANIFEST_DIR")).join("../resources/proxy/user/user1");
+    let user_repo_directory = std::env::temp_dir().join("ppaass-bench-user-repo");
+    let _ = std::fs::remove_dir_all(&user_repo_directory);
+    for index in 0..USER_COUNT {
+        let user_dir = user_repo_directory.join(format!("user{index}"));
+        std::fs::create_dir_all(&user_dir)?;
+        for key_file_name in ["AgentPublicKey.pem", "ProxyPrivateKey.pem"] {
+            std::fs::copy(
+                source_user_dir.join(key_file_name),
+                user_dir.join(key_file_name),
+            )?;
+        }
+        std::fs::write(
+            user_dir.join("user_info.toml"),
+            format!("username = \"user{index}\""),
+        )?;
+    }
+    Ok(user_repo_directory)
+}
+
+fn bench_user_repo_load(c: &mut Criterion) {
+    let user_repo_directory = create_user_repo().expect("Fail to create bench user repository");
+    let mut group = c.benchmark_group("user_repo_load");
+    group.sample_size(10);
+    for user_load_parallelism in PARALLELISMS {
+        group.bench_with_input(
+            BenchmarkId::new("parallelism", user_load_parallelism),
+            &user_load_parallelism,
+            |b, user_load_parallelism| {
+                b.iter(|| {
+                    let config = BenchConfig {
+                        user_repo_directory: user_repo_directory.clone(),
+                        user_load_parallelism: *user_load_parallelism,
+                    };
+                    FileSystemUserRepository::<BenchUser, BenchConfig>::new(Box::new(config))
+                        .expect("Fail to load bench user repository")
+                })
+            },
+        );
+    }
+    group.finish();
+    let _ = std::fs::remove_dir_all(&user_repo_directory);
+}
+
+criterion_group!(benches, bench_user_repo_load);
+criterion_main!(benches);
@@ -0,0 +1,220 @@
+use crate::Error;
+use std::sync::{Mutex, PoisonError};
+use std::time::{Duration, Instant};
+use tracing::{debug, warn};
+
+#[derive(Debug, Default)]
+struct BackoffState {
+    /// The connection attempts failed in a row
+    consecutive_failures: u32,
+    /// The attempts are rejected until this time after a failure
+    backoff_until: Option<Instant>,
+    /// Whether the single attempt probing the recovery is in progress
+    probing: bool,
+}
+
+/// The backoff shared by all the connection attempts to the proxies. Once an
+/// attempt fails, the following attempts are rejected without connecting
+/// until the backoff expires, then a single attempt probes the proxies while
+/// the others are still rejected. The backoff doubles on every failure in a
+/// row up to the max and it is reset by the first success.
+#[derive(Debug)]
+pub struct ProxyBackoff {
+    initial: Duration,
+    max: Duration,
+    state: Mutex<BackoffState>,
+}
+
+/// Release the probe when the attempt is dropped before it finishes
+struct ProbeGuard<'a> {
+    backoff: &'a ProxyBackoff,
+    probing: bool,
+}
+
+impl Drop for ProbeGuard<'_> {
+    fn drop(&mut self) {
+        if self.probing {
+            self.backoff.lock_state().probing = false;
+        }
+    }
+}
+
+impl ProxyBackoff {
+    /// Create the backoff, the zero initial backoff disables it
+    pub fn new(initial: Duration, max: Duration) -> Self {
+        Self {
+            initial,
+            max: max.max(initial),
+            state: Mutex::new(BackoffState::default()),
+        }
+    }
+
+    fn lock_state(&self) -> std::sync::MutexGuard<'_, BackoffState> {
+        self.state.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Check whether a new attempt can connect now, `Ok(true)` means the
+    /// attempt is the probe after the backoff expired.
+    fn admit(&self) -> Result<bool, Error> {
+        let mut state = self.lock_state();
+        let Some(backoff_until) = state.backoff_until else {
+            return Ok(false);
+        };
+        let now = Instant::now();
+        if now < backoff_until {
+            return Err(Error::ProxyUnhealthy(backoff_until - now));
+        }
+        if state.probing {
+            return Err(Error::ProxyUnhealthy(Duration::ZERO));
+        }
+        state.probing = true;
+        Ok(true)
+    }
+
+    fn record_success(&self) {
+        let mut state = self.lock_state();
+        if state.consecutive_failures > 0 {
+            debug!(
+                "Proxy recovers after {} failed connection attempts",
+                state.consecutive_failures
+            );
+        }
+        *state = BackoffState::default();
+    }
+
+    fn record_failure(&self, probing: bool) {
+        let mut state = self.lock_state();
+        // The attempts started before the backoff fail together, only the
+        // first of them and the probes extend the backoff
+        if !probing
+            && state
+                .backoff_until
+                .is_some_and(|backoff_until| Instant::now() < backoff_until)
+        {
+            return;
+        }
+        state.consecutive_failures = state.consecutive_failures.saturating_add(1);
+        let backoff = self
+            .initial
+            .saturating_mul(2u32.saturating_pow(state.consecutive_failures - 1))
+            .min(self.max);
+        state.backoff_until = Some(Instant::now() + backoff);
+        state.probing = false;
+        warn!(
+            "Back off connecting to proxy for {backoff:?} after {} failed attempts",
+            state.consecutive_failures
+        );
+    }
+
+    /// Run the connection attempt unless the proxies are backed off, the
+    /// rejected attempt fails with [`Error::ProxyUnhealthy`] without running.
+    /// The handshake rejected by the proxy proves the proxy is reachable so
+    /// it does not count as a failure.
+    pub async fn attempt<T, Fut>(&self, connect: Fut) -> Result<T, Error>
+    where
+        Fut: Future<Output = Result<T, Error>>,
+    {
+        if self.initial.is_zero() {
+            return connect.await;
+        }
+        let mut probe_guard = ProbeGuard {
+            backoff: self,
+            probing: self.admit()?,
+        };
+        let result = connect.await;
+        match &result {
+            Ok(_) | Err(Error::HandshakeRejected(_)) => self.record_success(),
+            Err(_) => self.record_failure(probe_guard.probing),
+        }
+        probe_guard.probing = false;
+        result
+    }
+}
+
+#[tokio::test]
+async fn test_attempts_backed_off_together() {
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    let backoff = Arc::new(ProxyBackoff::new(
+        Duration::from_millis(200),
+        Duration::from_millis(300),
+    ));
+    let connected = Arc::new(AtomicUsize::new(0));
+    // The proxy is down, every connection attempt reaching it fails slowly
+    let connect_down_proxy = |connected: Arc<AtomicUsize>| async move {
+        connected.fetch_add(1, Ordering::SeqCst);
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        Err::<(), _>(Error::ConnectTimeout(1))
+    };
+    let attempt_all = |count: usize| {
+        let attempts = (0..count)
+            .map(|_| {
+                let backoff = backoff.clone();
+                let connected = connected.clone();
+                tokio::spawn(async move { backoff.attempt(connect_down_proxy(connected)).await })
+            })
+            .collect::<Vec<_>>();
+        async move {
+            let mut results = Vec::new();
+            for attempt in attempts {
+                results.push(attempt.await.unwrap());
+            }
+            results
+        }
+    };
+    assert!(matches!(
+        backoff.attempt(connect_down_proxy(connected.clone())).await,
+        Err(Error::ConnectTimeout(1))
+    ));
+    // All the attempts in the backoff are rejected without connecting
+    let results = attempt_all(20).await;
+    assert!(
+        results
+            .iter()
+            .all(|result| matches!(result, Err(Error::ProxyUnhealthy(_))))
+    );
+    assert_eq!(1, connected.load(Ordering::SeqCst));
+    // Only one of the attempts after the backoff probes the proxy
+    tokio::time::sleep(Duration::from_millis(250)).await;
+    let results = attempt_all(20).await;
+    assert_eq!(2, connected.load(Ordering::SeqCst));
+    assert_eq!(
+        1,
+        results
+            .iter()
+            .filter(|result| matches!(result, Err(Error::ConnectTimeout(1))))
+            .count()
+    );
+    // The failed probe doubles the backoff up to the max
+    assert!(matches!(
+        backoff.attempt(async { Ok(()) }).await,
+        Err(Error::ProxyUnhealthy(remaining)) if remaining > Duration::from_millis(200)
+    ));
+    tokio::time::sleep(Duration::from_millis(300)).await;
+    // The successful probe closes the backoff for all the attempts
+    assert!(backoff.attempt(async { Ok(()) }).await.is_ok());
+    attempt_all(5).await;
+    assert_eq!(7, connected.load(Ordering::SeqCst));
+}
+
+#[tokio::test]
+async fn test_dropped_probe_released() {
+    let backoff = ProxyBackoff::new(Duration::from_millis(50), Duration::from_millis(50));
+    let _ = backoff
+        .attempt(async { Err::<(), _>(Error::ConnectTimeout(1)) })
+        .await;
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    // The probe is cancelled before it finishes
+    let probe = tokio::time::timeout(
+        Duration::from_millis(20),
+        backoff.attempt(std::future::pending::<Result<(), Error>>()),
+    )
+    .await;
+    assert!(probe.is_err());
+    assert!(backoff.attempt(async { Ok(()) }).await.is_ok());
+    let disabled = ProxyBackoff::new(Duration::ZERO, Duration::ZERO);
+    let _ = disabled
+        .attempt(async { Err::<(), _>(Error::ConnectTimeout(1)) })
+        .await;
+    assert!(disabled.attempt(async { Ok(()) }).await.is_ok());
+}
@@ -0,0 +1,195 @@
+use crate::user::ProxyServer;
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// How the proxy servers are ordered for a new proxy connection, the
+/// connection fails over to the next server of the order when the connect
+/// or the handshake with a server fails. The servers weighted `0` are
+/// always tried last in the configured order.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ProxyServerPolicy {
+    /// Try the servers in the configured order
+    FirstHealthy,
+    /// Start from the server after the one the previous connection started from
+    RoundRobin,
+    /// Pick the servers at random in proportion to the weights
+    #[default]
+    Random,
+}
+
+impl ProxyServerPolicy {
+    /// Order the proxy servers to connect to with the policy, the round robin
+    /// starts from the position of the cursor and advances it.
+    pub fn order(
+        self,
+        proxy_servers: &[ProxyServer],
+        round_robin_next: &AtomicUsize,
+    ) -> Vec<SocketAddr> {
+        match self {
+            ProxyServerPolicy::FirstHealthy => round_robin_order(proxy_servers, 0),
+            ProxyServerPolicy::RoundRobin => round_robin_order(
+                proxy_servers,
+                round_robin_next.fetch_add(1, Ordering::Relaxed),
+            ),
+            ProxyServerPolicy::Random => weighted_order(proxy_servers, &mut rand::rng()),
+        }
+    }
+}
+
+/// Order the proxy servers in the configured order rotated to start from
+/// the given position, the servers weighted `0` are not rotated.
+fn round_robin_order(proxy_servers: &[ProxyServer], start: usize) -> Vec<SocketAddr> {
+    let mut order = proxy_servers
+        .iter()
+        .filter(|proxy_server| proxy_server.weight > 0)
+        .map(|proxy_server| proxy_server.address)
+        .collect::<Vec<_>>();
+    if !order.is_empty() {
+        let start = start % order.len();
+        order.rotate_left(start);
+    }
+    order.extend(
+        proxy_servers
+            .iter()
+            .filter(|proxy_server| proxy_server.weight == 0)
+            .map(|proxy_server| proxy_server.address),
+    );
+    order
+}
+
+/// Order the proxy servers to connect to, the first one is picked at
+/// random in proportion to the weights and the others follow as the
+/// fallbacks in the same way. The servers weighted `0` are only tried
+/// after all the others, in the configured order.
+pub fn weighted_order<R>(proxy_servers: &[ProxyServer], rng: &mut R) -> Vec<SocketAddr>
+where
+    R: Rng + ?Sized,
+{
+    let mut weighted = proxy_servers
+        .iter()
+        .filter(|proxy_server| proxy_server.weight > 0)
+        .collect::<Vec<_>>();
+    let mut order = Vec::with_capacity(proxy_servers.len());
+    while !weighted.is_empty() {
+        let total_weight = weighted
+            .iter()
+            .map(|proxy_server| u64::from(proxy_server.weight))
+            .sum::<u64>();
+        let mut point = rng.random_range(0..total_weight);
+        let picked = weighted
+            .iter()
+            .position(|proxy_server| {
+                let weight = u64::from(proxy_server.weight);
+                if point < weight {
+                    return true;
+                }
+                point -= weight;
+                false
+            })
+            .unwrap_or_default();
+        order.push(weighted.remove(picked).address);
+    }
+    order.extend(
+        proxy_servers
+            .iter()
+            .filter(|proxy_server| proxy_server.weight == 0)
+            .map(|proxy_server| proxy_server.address),
+    );
+    order
+}
+
+#[test]
+fn test_weighted_order() {
+    use rand::SeedableRng;
+    use rand::rngs::StdRng;
+    let proxy_servers = [
+        ProxyServer {
+            address: "10.0.0.1:80".parse().unwrap(),
+            weight: 3,
+        },
+        ProxyServer {
+            address: "10.0.0.2:80".parse().unwrap(),
+            weight: 1,
+        },
+        ProxyServer {
+            address: "10.0.0.3:80".parse().unwrap(),
+            weight: 0,
+        },
+    ];
+    let mut rng = StdRng::seed_from_u64(7);
+    let selections = 10_000;
+    let mut first_picked = [0usize; 3];
+    for _ in 0..selections {
+        let order = weighted_order(&proxy_servers, &mut rng);
+        // Every server stays in the order as the fallback
+        assert_eq!(3, order.len());
+        assert_eq!(proxy_servers[2].address, order[2]);
+        let picked = proxy_servers
+            .iter()
+            .position(|proxy_server| proxy_server.address == order[0])
+            .unwrap();
+        first_picked[picked] += 1;
+    }
+    let share = |picked: usize| picked as f64 / selections as f64;
+    assert!((share(first_picked[0]) - 0.75).abs() < 0.02);
+    assert!((share(first_picked[1]) - 0.25).abs() < 0.02);
+    assert_eq!(0, first_picked[2]);
+    // The servers without weight configured share the connections equally
+    let unweighted = proxy_servers.map(|proxy_server| ProxyServer::from(proxy_server.address));
+    let mut first_picked = [0usize; 3];
+    for _ in 0..selections {
+        let order = weighted_order(&unweighted, &mut rng);
+        let picked = unweighted
+            .iter()
+            .position(|proxy_server| proxy_server.address == order[0])
+            .unwrap();
+        first_picked[picked] += 1;
+    }
+    for picked in first_picked {
+        assert!((share(picked) - 1.0 / 3.0).abs() < 0.02);
+    }
+}
+
+#[test]
+fn test_round_robin_order() {
+    let proxy_servers = [
+        ProxyServer::from("10.0.0.1:80".parse::<SocketAddr>().unwrap()),
+        ProxyServer {
+            address: "10.0.0.2:80".parse().unwrap(),
+            weight: 0,
+        },
+        ProxyServer::from("10.0.0.3:80".parse::<SocketAddr>().unwrap()),
+    ];
+    let [first, backup, third] = proxy_servers.map(|proxy_server| proxy_server.address);
+    assert_eq!(
+        vec![first, third, backup],
+        round_robin_order(&proxy_servers, 0)
+    );
+    assert_eq!(
+        vec![third, first, backup],
+        round_robin_order(&proxy_servers, 1)
+    );
+    assert_eq!(
+        vec![first, third, backup],
+        round_robin_order(&proxy_servers, 2)
+    );
+    assert_eq!(
+        vec![first, third, backup],
+        ProxyServerPolicy::FirstHealthy.order(&proxy_servers, &AtomicUsize::new(1))
+    );
+    // The consecutive connections start from the different servers
+    let round_robin_next = AtomicUsize::new(0);
+    let starts = (0..4)
+        .map(|_| ProxyServerPolicy::RoundRobin.order(&proxy_servers, &round_robin_next)[0])
+        .collect::<Vec<_>>();
+    assert_eq!(vec![first, third, first, third], starts);
+    // Every cursor keeps its own position
+    assert_eq!(
+        first,
+        ProxyServerPolicy::RoundRobin.order(&proxy_servers, &AtomicUsize::new(0))[0]
+    );
+    assert_eq!(Vec::<SocketAddr>::new(), round_robin_order(&[], 3));
+}
@@ -1,16 +1,57 @@
 use crate::error::Error;
 use ppaass_crypto::{
-    decrypt_with_aes, decrypt_with_blowfish, encrypt_with_aes, encrypt_with_blowfish,
+    AesCipher, decrypt_with_blowfish, decrypt_with_chacha20, encrypt_with_blowfish,
+    encrypt_with_chacha20,
 };
-use ppaass_protocol::Encryption;
+use ppaass_protocol::{Encryption, MAX_CONTROL_MESSAGE_LENGTH};
 use std::borrow::Cow;
-use tokio_util::bytes::{Bytes, BytesMut};
-use tokio_util::codec::{Decoder, Encoder, LengthDelimitedCodec};
+use tokio_util::bytes::{Buf, Bytes, BytesMut};
+use tokio_util::codec::{Decoder, Encoder, LengthDelimitedCodec, LengthDelimitedCodecError};
+use zstd::bulk::{Compressor, Decompressor};
+
+/// The default max length of the frames exchanged in handshake, the
+/// handshake messages are tiny so a small bound limits the memory an
+/// unauthenticated peer can make the server buffer.
+pub const DEFAULT_HANDSHAKE_MAX_FRAME_LENGTH: usize = 4 * 1024;
+
+/// The default size in bytes of the length prefix of the frames
+pub const DEFAULT_LENGTH_FIELD_LENGTH: usize = 4;
+
+/// The smallest size in bytes of the length prefix of the frames, the 1 byte
+/// prefix can not carry the largest control message in one frame
+pub const MIN_LENGTH_FIELD_LENGTH: usize = 2;
+
+/// The bound of the bytes the compression flag and the encryption add to a
+/// chunk, the chunks are split so the frame still fits the length prefix
+const MAX_FRAME_OVERHEAD: usize = 128;
+
+/// The chunks smaller than this are never compressed, it can not pay off
+const COMPRESSION_MIN_CHUNK_SIZE: usize = 64;
+/// The first byte of the compressed frame plaintext marks whether the chunk is compressed
+const CHUNK_UNCOMPRESSED: u8 = 0;
+const CHUNK_COMPRESSED: u8 = 1;
 
 pub struct SecureLengthDelimitedCodec<'a> {
     decoder_encryption: Cow<'a, Encryption>,
     encoder_encryption: Cow<'a, Encryption>,
     length_delimited: LengthDelimitedCodec,
+    length_field_length: usize,
+    max_chunk_size: Option<usize>,
+    /// The counters of the frames sealed and opened with ChaCha20-Poly1305
+    /// and AES, the per-frame nonce of ChaCha20-Poly1305 is derived from
+    /// them and the AES tag covers them
+    encoder_frame_counter: u64,
+    decoder_frame_counter: u64,
+    per_frame_iv: bool,
+    /// The AES ciphers, created on the first AES frame, so the token is
+    /// checked and the HMAC key is derived once per connection
+    encoder_aes_cipher: Option<AesCipher>,
+    decoder_aes_cipher: Option<AesCipher>,
+    /// The zstd level to compress the chunks, `None` means no compression
+    compression_level: Option<i32>,
+    /// The zstd contexts, created on the first compressed frame
+    compressor: Option<Compressor<'static>>,
+    decompressor: Option<Decompressor<'static>>,
 }
 
 impl<'a> SecureLengthDelimitedCodec<'a> {
@@ -22,47 +63,753 @@ impl<'a> SecureLengthDelimitedCodec<'a> {
             decoder_encryption,
             encoder_encryption,
             length_delimited: LengthDelimitedCodec::new(),
+            length_field_length: DEFAULT_LENGTH_FIELD_LENGTH,
+            max_chunk_size: None,
+            encoder_frame_counter: 0,
+            decoder_frame_counter: 0,
+            per_frame_iv: false,
+            encoder_aes_cipher: None,
+            decoder_aes_cipher: None,
+            compression_level: None,
+            compressor: None,
+            decompressor: None,
         }
     }
+
+    /// Bound the size of the raw data carried by one frame, the encoder
+    /// splits larger data into multiple frames and the decoder rejects
+    /// the frames carrying larger data. The max frame length is derived
+    /// from it, so the oversized frame is rejected by its length header
+    /// before its body is buffered.
+    pub fn with_max_chunk_size(mut self, max_chunk_size: Option<usize>) -> Self {
+        self.max_chunk_size = max_chunk_size.map(|max_chunk_size| max_chunk_size.max(1));
+        if let Some(max_chunk_size) = self.max_chunk_size {
+            self.length_delimited
+                .set_max_frame_length(max_chunk_size.saturating_add(MAX_FRAME_OVERHEAD));
+        }
+        self
+    }
+
+    /// Bound the length of one encoded frame, the decoder rejects a larger
+    /// frame with `Error::FrameTooLarge` as soon as its length header is
+    /// read instead of buffering it.
+    pub fn with_max_frame_length(mut self, max_frame_length: usize) -> Self {
+        self.length_delimited.set_max_frame_length(max_frame_length);
+        self
+    }
+
+    /// Set the size in bytes of the length prefix of every frame, from 2 to 8,
+    /// both sides of the connection must use the same value. A smaller prefix
+    /// saves bandwidth on tiny frames, the encoder splits the data into more
+    /// frames so each of them fits what the prefix can represent.
+    pub fn with_length_field_length(mut self, length_field_length: usize) -> Result<Self, Error> {
+        validate_length_field_length(length_field_length)?;
+        let max_frame_length = self.length_delimited.max_frame_length();
+        self.length_delimited = LengthDelimitedCodec::builder()
+            .length_field_length(length_field_length)
+            .max_frame_length(max_frame_length)
+            .new_codec();
+        self.length_field_length = length_field_length;
+        Ok(self)
+    }
+
+    /// The max size of the raw data carried by one frame, bounded by the max
+    /// chunk size and by the frame length the length prefix can represent.
+    fn chunk_size_limit(&self) -> usize {
+        let frame_chunk_size_limit = prefix_max_frame_length(self.length_field_length)
+            .min(self.length_delimited.max_frame_length())
+            .saturating_sub(MAX_FRAME_OVERHEAD)
+            .max(1);
+        match self.max_chunk_size {
+            Some(max_chunk_size) => max_chunk_size.min(frame_chunk_size_limit),
+            None => frame_chunk_size_limit,
+        }
+    }
+
+    /// Encrypt every AES frame with a fresh random iv carried in the frame
+    /// instead of the iv of the token, both sides of the connection must
+    /// use the same value, disable it to interoperate with older peers.
+    pub fn with_per_frame_iv(mut self, per_frame_iv: bool) -> Self {
+        self.per_frame_iv = per_frame_iv;
+        self
+    }
+
+    /// Compress every chunk with zstd at the level before it is encrypted,
+    /// the chunk stays uncompressed when it is small or does not shrink.
+    /// Both sides of the connection must use compression, so it is only
+    /// enabled when it is negotiated in handshake.
+    ///
+    /// Compressing before encrypting makes the frame length a CRIME-style
+    /// oracle: a peer who can inject data next to a secret in the same chunk,
+    /// and watch the frame lengths, can recover the secret byte by byte.
+    pub fn with_compression(mut self, level: i32) -> Self {
+        self.compression_level = Some(level);
+        self
+    }
+
+    /// Prefix the chunk with the compression flag, the flag is encrypted
+    /// together with the chunk, but the length of the encrypted frame still
+    /// shows how much the chunk shrinks.
+    fn compress_chunk(&mut self, chunk: &[u8], level: i32) -> Result<Vec<u8>, Error> {
+        if chunk.len() >= COMPRESSION_MIN_CHUNK_SIZE {
+            let compressor = match &mut self.compressor {
+                Some(compressor) => compressor,
+                None => self.compressor.insert(Compressor::new(level)?),
+            };
+            let compressed_chunk = compressor.compress(chunk)?;
+            if compressed_chunk.len() < chunk.len() {
+                return Ok([&[CHUNK_COMPRESSED], compressed_chunk.as_slice()].concat());
+            }
+        }
+        Ok([&[CHUNK_UNCOMPRESSED], chunk].concat())
+    }
+
+    fn decompress_chunk(&mut self, mut chunk: BytesMut) -> Result<BytesMut, Error> {
+        if chunk.is_empty() {
+            return Err(Error::InvalidCompressedChunk(
+                "missing compression flag".to_string(),
+            ));
+        }
+        let compression_flag = chunk.get_u8();
+        match compression_flag {
+            CHUNK_UNCOMPRESSED => Ok(chunk),
+            CHUNK_COMPRESSED => {
+                // The raw chunk can not be larger than the peer is allowed to send
+                let capacity = self
+                    .max_chunk_size
+                    .unwrap_or(self.length_delimited.max_frame_length());
+                let decompressor = match &mut self.decompressor {
+                    Some(decompressor) => decompressor,
+                    None => self.decompressor.insert(Decompressor::new()?),
+                };
+                let raw_chunk = decompressor
+                    .decompress(&chunk, capacity)
+                    .map_err(|e| Error::InvalidCompressedChunk(e.to_string()))?;
+                Ok(BytesMut::from(raw_chunk.as_slice()))
+            }
+            compression_flag => Err(Error::InvalidCompressedChunk(format!(
+                "unknown compression flag: {compression_flag}"
+            ))),
+        }
+    }
+
+    fn encode_chunk(&mut self, chunk: &[u8], dst: &mut BytesMut) -> Result<(), Error> {
+        let compressed_chunk;
+        let chunk = match self.compression_level {
+            None => chunk,
+            Some(level) => {
+                compressed_chunk = self.compress_chunk(chunk, level)?;
+                compressed_chunk.as_slice()
+            }
+        };
+        match &*self.encoder_encryption {
+            Encryption::Plain => Ok(self
+                .length_delimited
+                .encode(Bytes::from(chunk.to_vec()), dst)?),
+            Encryption::Aes(token) => {
+                let aes_cipher = match &self.encoder_aes_cipher {
+                    Some(aes_cipher) => aes_cipher,
+                    None => self
+                        .encoder_aes_cipher
+                        .insert(new_aes_cipher(token, self.per_frame_iv)?),
+                };
+                let frame_counter = next_frame_counter(&mut self.encoder_frame_counter)?;
+                let encrypted_bytes = aes_cipher.encrypt(frame_counter, chunk)?;
+                Ok(self.length_delimited.encode(encrypted_bytes, dst)?)
+            }
+            Encryption::Blowfish(token) => {
+                let encrypted_bytes = encrypt_with_blowfish(token, chunk)?;
+                Ok(self.length_delimited.encode(encrypted_bytes, dst)?)
+            }
+            Encryption::ChaCha20Poly1305(token) => {
+                let frame_counter = next_frame_counter(&mut self.encoder_frame_counter)?;
+                let encrypted_bytes = encrypt_with_chacha20(token, frame_counter, chunk)?;
+                Ok(self.length_delimited.encode(encrypted_bytes, dst)?)
+            }
+        }
+    }
+}
+
+/// Take the counter of the next frame, the connection fails before the
+/// counter wraps, as a repeated nonce breaks ChaCha20-Poly1305 and a
+/// repeated AES tag input lets a frame be replayed
+fn next_frame_counter(frame_counter: &mut u64) -> Result<u64, Error> {
+    let current_frame_counter = *frame_counter;
+    *frame_counter = current_frame_counter
+        .checked_add(1)
+        .ok_or(Error::FrameCounterExhausted)?;
+    Ok(current_frame_counter)
+}
+
+/// Create the AES cipher of the token, a malformed token from the
+/// peer fails the connection instead of panicking
+fn new_aes_cipher(token: &[u8], per_frame_iv: bool) -> Result<AesCipher, Error> {
+    let aes_cipher = if per_frame_iv {
+        AesCipher::with_per_frame_iv(token)?
+    } else {
+        AesCipher::new(token)?
+    };
+    Ok(aes_cipher)
+}
+
+/// Check the max chunk size can carry the largest control message, the
+/// control messages are decoded from one frame so they must never be split.
+pub fn validate_max_chunk_size(max_chunk_size: usize) -> Result<(), Error> {
+    if max_chunk_size < MAX_CONTROL_MESSAGE_LENGTH {
+        return Err(Error::InvalidMaxChunkSize(max_chunk_size));
+    }
+    Ok(())
+}
+
+/// The max frame length the length prefix of the size can represent
+fn prefix_max_frame_length(length_field_length: usize) -> usize {
+    match u32::try_from(length_field_length * 8) {
+        Ok(bits) if bits < usize::BITS => (1usize << bits) - 1,
+        _ => usize::MAX,
+    }
+}
+
+/// Check the size of the length prefix of the frames is from 2 to 8 bytes.
+pub fn validate_length_field_length(length_field_length: usize) -> Result<(), Error> {
+    if !(MIN_LENGTH_FIELD_LENGTH..=8).contains(&length_field_length) {
+        return Err(Error::InvalidLengthFieldLength(length_field_length));
+    }
+    Ok(())
 }
 
 impl<'a> Decoder for SecureLengthDelimitedCodec<'a> {
     type Item = BytesMut;
     type Error = Error;
     fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
-        let decrypted_bytes = self.length_delimited.decode(src)?;
-        match decrypted_bytes {
-            None => Ok(None),
+        let decrypted_bytes = self.length_delimited.decode(src).map_err(|e| {
+            if e.get_ref()
+                .is_some_and(|inner| inner.is::<LengthDelimitedCodecError>())
+            {
+                Error::FrameTooLarge(self.length_delimited.max_frame_length())
+            } else {
+                Error::Io(e)
+            }
+        })?;
+        let raw_bytes = match decrypted_bytes {
+            None => return Ok(None),
             Some(decrypted_bytes) => match &*self.decoder_encryption {
-                Encryption::Plain => Ok(Some(decrypted_bytes)),
+                Encryption::Plain => decrypted_bytes,
                 Encryption::Aes(token) => {
-                    let raw_bytes = decrypt_with_aes(token, &decrypted_bytes)?;
-                    Ok(Some(BytesMut::from(raw_bytes)))
+                    let aes_cipher = match &self.decoder_aes_cipher {
+                        Some(aes_cipher) => aes_cipher,
+                        None => self
+                            .decoder_aes_cipher
+                            .insert(new_aes_cipher(token, self.per_frame_iv)?),
+                    };
+                    let frame_counter = next_frame_counter(&mut self.decoder_frame_counter)?;
+                    BytesMut::from(aes_cipher.decrypt(frame_counter, &decrypted_bytes)?)
                 }
                 Encryption::Blowfish(token) => {
-                    let raw_bytes = decrypt_with_blowfish(token, &decrypted_bytes)?;
-                    Ok(Some(BytesMut::from(raw_bytes)))
+                    BytesMut::from(decrypt_with_blowfish(token, &decrypted_bytes)?)
+                }
+                Encryption::ChaCha20Poly1305(token) => {
+                    let frame_counter = next_frame_counter(&mut self.decoder_frame_counter)?;
+                    let raw_bytes = decrypt_with_chacha20(token, frame_counter, &decrypted_bytes)?;
+                    BytesMut::from(raw_bytes)
                 }
             },
+        };
+        let raw_bytes = match self.compression_level {
+            None => raw_bytes,
+            Some(_) => self.decompress_chunk(raw_bytes)?,
+        };
+        if let Some(max_chunk_size) = self.max_chunk_size
+            && raw_bytes.len() > max_chunk_size
+        {
+            return Err(Error::ChunkTooLarge(raw_bytes.len(), max_chunk_size));
         }
+        Ok(Some(raw_bytes))
     }
 }
 
 impl<'a> Encoder<&[u8]> for SecureLengthDelimitedCodec<'a> {
     type Error = Error;
     fn encode(&mut self, item: &[u8], dst: &mut BytesMut) -> Result<(), Self::Error> {
-        match &*self.encoder_encryption {
-            Encryption::Plain => Ok(self
-                .length_delimited
-                .encode(Bytes::from(item.to_vec()), dst)?),
-            Encryption::Aes(token) => {
-                let encrypted_bytes = encrypt_with_aes(token, item)?;
-                Ok(self.length_delimited.encode(encrypted_bytes, dst)?)
-            }
-            Encryption::Blowfish(token) => {
-                let encrypted_bytes = encrypt_with_blowfish(token, item)?;
-                Ok(self.length_delimited.encode(encrypted_bytes, dst)?)
+        let chunk_size_limit = self.chunk_size_limit();
+        if item.len() <= chunk_size_limit {
+            return self.encode_chunk(item, dst);
+        }
+        for chunk in item.chunks(chunk_size_limit) {
+            self.encode_chunk(chunk, dst)?;
+        }
+        Ok(())
+    }
+}
+
+#[test]
+fn test_max_chunk_size() -> Result<(), Error> {
+    let encryption = crate::random_generate_encryption();
+    let mut codec =
+        SecureLengthDelimitedCodec::new(Cow::Borrowed(&encryption), Cow::Borrowed(&encryption))
+            .with_max_chunk_size(Some(4));
+    let mut dst = BytesMut::new();
+    codec.encode(b"0123456789".as_slice(), &mut dst)?;
+    let mut chunks = Vec::new();
+    while let Some(chunk) = codec.decode(&mut dst)? {
+        chunks.push(chunk.to_vec());
+    }
+    assert_eq!(
+        vec![b"0123".to_vec(), b"4567".to_vec(), b"89".to_vec()],
+        chunks
+    );
+    let mut unbounded_codec =
+        SecureLengthDelimitedCodec::new(Cow::Borrowed(&encryption), Cow::Borrowed(&encryption));
+    unbounded_codec.encode(b"0123456789".as_slice(), &mut dst)?;
+    // The decoder of the unbounded peer opens its frames from the first frame counter
+    let mut codec =
+        SecureLengthDelimitedCodec::new(Cow::Borrowed(&encryption), Cow::Borrowed(&encryption))
+            .with_max_chunk_size(Some(4));
+    assert!(matches!(
+        codec.decode(&mut dst),
+        Err(Error::ChunkTooLarge(10, 4))
+    ));
+    // The frame longer than the max chunk size allows is rejected by its length header
+    let mut oversized_frame = BytesMut::new();
+    unbounded_codec.encode(vec![0u8; 1024].as_slice(), &mut oversized_frame)?;
+    oversized_frame.truncate(8);
+    assert!(matches!(
+        codec.decode(&mut oversized_frame),
+        Err(Error::FrameTooLarge(132))
+    ));
+    Ok(())
+}
+
+#[test]
+fn test_max_frame_length() -> Result<(), Error> {
+    use tokio_util::bytes::BufMut;
+    let encryption = crate::random_generate_encryption();
+    let mut codec =
+        SecureLengthDelimitedCodec::new(Cow::Borrowed(&encryption), Cow::Borrowed(&encryption))
+            .with_max_frame_length(DEFAULT_HANDSHAKE_MAX_FRAME_LENGTH);
+    let mut dst = BytesMut::new();
+    codec.encode(b"handshake".as_slice(), &mut dst)?;
+    assert_eq!(
+        b"handshake".as_slice(),
+        &codec.decode(&mut dst)?.unwrap()[..]
+    );
+    // Only the length header of the oversized frame arrives, it is rejected without waiting the body
+    let mut oversized_frame = BytesMut::new();
+    oversized_frame.put_u32(8 * 1024 * 1024);
+    oversized_frame.put_slice(b"partial body");
+    assert!(matches!(
+        codec.decode(&mut oversized_frame),
+        Err(Error::FrameTooLarge(DEFAULT_HANDSHAKE_MAX_FRAME_LENGTH))
+    ));
+    Ok(())
+}
+
+#[test]
+fn test_chacha20_frame_nonce() -> Result<(), Error> {
+    let encryption =
+        Encryption::ChaCha20Poly1305(ppaass_crypto::generate_chacha20_encryption_token());
+    let mut codec =
+        SecureLengthDelimitedCodec::new(Cow::Borrowed(&encryption), Cow::Borrowed(&encryption));
+    let mut frame_1 = BytesMut::new();
+    codec.encode(b"same plaintext".as_slice(), &mut frame_1)?;
+    let mut frame_2 = BytesMut::new();
+    codec.encode(b"same plaintext".as_slice(), &mut frame_2)?;
+    assert_ne!(frame_1, frame_2);
+    let mut dst = BytesMut::new();
+    dst.extend_from_slice(&frame_1);
+    dst.extend_from_slice(&frame_2);
+    assert_eq!(
+        b"same plaintext".as_slice(),
+        &codec.decode(&mut dst)?.unwrap()[..]
+    );
+    assert_eq!(
+        b"same plaintext".as_slice(),
+        &codec.decode(&mut dst)?.unwrap()[..]
+    );
+    // A replayed frame is opened with the next nonce and fails
+    dst.extend_from_slice(&frame_1);
+    assert!(codec.decode(&mut dst).is_err());
+    Ok(())
+}
+
+#[test]
+fn test_aes_frame_replay() -> Result<(), Error> {
+    for per_frame_iv in [false, true] {
+        let encryption = Encryption::Aes(ppaass_crypto::generate_aes_encryption_token());
+        let codec = || {
+            SecureLengthDelimitedCodec::new(Cow::Borrowed(&encryption), Cow::Borrowed(&encryption))
+                .with_per_frame_iv(per_frame_iv)
+        };
+        let mut encoder = codec();
+        let mut frame_1 = BytesMut::new();
+        encoder.encode(b"same plaintext".as_slice(), &mut frame_1)?;
+        let mut frame_2 = BytesMut::new();
+        encoder.encode(b"same plaintext".as_slice(), &mut frame_2)?;
+        assert_ne!(frame_1, frame_2);
+        // The frame 1 replayed in place of the frame 2 fails the tag
+        let mut decoder = codec();
+        let mut dst = BytesMut::new();
+        dst.extend_from_slice(&frame_1);
+        dst.extend_from_slice(&frame_1);
+        assert_eq!(
+            b"same plaintext".as_slice(),
+            &decoder.decode(&mut dst)?.unwrap()[..]
+        );
+        assert!(matches!(
+            decoder.decode(&mut dst),
+            Err(Error::Crypto(ppaass_crypto::Error::MacMismatch))
+        ));
+        // The reordered frames fail the tag too
+        let mut decoder = codec();
+        dst.clear();
+        dst.extend_from_slice(&frame_2);
+        assert!(matches!(
+            decoder.decode(&mut dst),
+            Err(Error::Crypto(ppaass_crypto::Error::MacMismatch))
+        ));
+    }
+    Ok(())
+}
+
+#[test]
+fn test_chacha20_frame_counter_exhausted() -> Result<(), Error> {
+    let encryption =
+        Encryption::ChaCha20Poly1305(ppaass_crypto::generate_chacha20_encryption_token());
+    let mut codec =
+        SecureLengthDelimitedCodec::new(Cow::Borrowed(&encryption), Cow::Borrowed(&encryption));
+    codec.encoder_frame_counter = u64::MAX - 1;
+    codec.decoder_frame_counter = u64::MAX - 1;
+    let mut dst = BytesMut::new();
+    codec.encode(b"last frame".as_slice(), &mut dst)?;
+    assert_eq!(
+        b"last frame".as_slice(),
+        &codec.decode(&mut dst)?.unwrap()[..]
+    );
+    // The counter never wraps to reuse the nonce of the first frame
+    for _ in 0..2 {
+        assert!(matches!(
+            codec.encode(b"wrapped frame".as_slice(), &mut BytesMut::new()),
+            Err(Error::FrameCounterExhausted)
+        ));
+    }
+    let mut frame = BytesMut::new();
+    SecureLengthDelimitedCodec::new(Cow::Borrowed(&encryption), Cow::Borrowed(&encryption))
+        .encode(b"wrapped frame".as_slice(), &mut frame)?;
+    assert!(matches!(
+        codec.decode(&mut frame),
+        Err(Error::FrameCounterExhausted)
+    ));
+    Ok(())
+}
+
+#[test]
+fn test_per_frame_iv() -> Result<(), Error> {
+    let encryption = Encryption::Aes(ppaass_crypto::generate_aes_encryption_token());
+    let codec =
+        || SecureLengthDelimitedCodec::new(Cow::Borrowed(&encryption), Cow::Borrowed(&encryption));
+    let mut per_frame_iv_codec = codec().with_per_frame_iv(true);
+    let mut frame_1 = BytesMut::new();
+    per_frame_iv_codec.encode(b"same plaintext".as_slice(), &mut frame_1)?;
+    let mut frame_2 = BytesMut::new();
+    per_frame_iv_codec.encode(b"same plaintext".as_slice(), &mut frame_2)?;
+    assert_ne!(frame_1, frame_2);
+    let mut token_iv_frame = BytesMut::new();
+    let mut token_iv_codec = codec();
+    token_iv_codec.encode(b"same plaintext".as_slice(), &mut token_iv_frame)?;
+    assert_eq!(
+        b"same plaintext".as_slice(),
+        &per_frame_iv_codec.decode(&mut frame_1)?.unwrap()[..]
+    );
+    assert_eq!(
+        b"same plaintext".as_slice(),
+        &token_iv_codec.decode(&mut token_iv_frame.clone())?.unwrap()[..]
+    );
+    // Both sides must agree on the frame layout
+    assert!(per_frame_iv_codec.decode(&mut token_iv_frame).is_err());
+    assert!(token_iv_codec.decode(&mut frame_2).is_err());
+    Ok(())
+}
+
+#[test]
+fn test_malformed_aes_token() -> Result<(), Error> {
+    let key_encryption = Encryption::Aes(Bytes::from_static(&[0x55; 32]));
+    let short_encryption = Encryption::Aes(Bytes::from_static(&[0x55; 16]));
+    let codec = |encryption| {
+        SecureLengthDelimitedCodec::new(Cow::Borrowed(encryption), Cow::Borrowed(encryption))
+    };
+    // The key only token works with the per-frame iv
+    let mut key_codec = codec(&key_encryption).with_per_frame_iv(true);
+    let mut frame = BytesMut::new();
+    key_codec.encode(b"key only token".as_slice(), &mut frame)?;
+    assert_eq!(
+        b"key only token".as_slice(),
+        &key_codec.decode(&mut frame)?.unwrap()[..]
+    );
+    // The malformed tokens fail the frame instead of panicking
+    let mut frame = BytesMut::new();
+    assert!(matches!(
+        codec(&key_encryption).encode(b"no iv".as_slice(), &mut frame),
+        Err(Error::Crypto(_))
+    ));
+    for per_frame_iv in [false, true] {
+        let mut short_codec = codec(&short_encryption).with_per_frame_iv(per_frame_iv);
+        assert!(matches!(
+            short_codec.encode(b"short token".as_slice(), &mut BytesMut::new()),
+            Err(Error::Crypto(_))
+        ));
+        let mut frame = BytesMut::new();
+        codec(&Encryption::Plain).encode([0u8; 64].as_slice(), &mut frame)?;
+        assert!(matches!(
+            short_codec.decode(&mut frame),
+            Err(Error::Crypto(_))
+        ));
+    }
+    Ok(())
+}
+
+#[test]
+fn test_length_field_length() -> Result<(), Error> {
+    let encryption = Encryption::Aes(ppaass_crypto::generate_aes_encryption_token());
+    let codec =
+        || SecureLengthDelimitedCodec::new(Cow::Borrowed(&encryption), Cow::Borrowed(&encryption));
+    let mut two_bytes_codec = codec().with_length_field_length(2)?;
+    let mut two_bytes_frame = BytesMut::new();
+    two_bytes_codec.encode(b"tiny frame".as_slice(), &mut two_bytes_frame)?;
+    let mut default_frame = BytesMut::new();
+    codec().encode(b"tiny frame".as_slice(), &mut default_frame)?;
+    assert_eq!(default_frame.len() - 2, two_bytes_frame.len());
+    assert_eq!(&default_frame[4..], &two_bytes_frame[2..]);
+    assert_eq!(
+        b"tiny frame".as_slice(),
+        &two_bytes_codec.decode(&mut two_bytes_frame)?.unwrap()[..]
+    );
+    // The data larger than the 2 bytes prefix can represent is split into more frames
+    for encryption in [
+        Encryption::Plain,
+        Encryption::Aes(ppaass_crypto::generate_aes_encryption_token()),
+        Encryption::Blowfish(ppaass_crypto::generate_blowfish_encryption_token()),
+        Encryption::ChaCha20Poly1305(ppaass_crypto::generate_chacha20_encryption_token()),
+    ] {
+        for per_frame_iv in [false, true] {
+            let codec = || {
+                SecureLengthDelimitedCodec::new(
+                    Cow::Borrowed(&encryption),
+                    Cow::Borrowed(&encryption),
+                )
+                .with_per_frame_iv(per_frame_iv)
+                .with_compression(3)
+                .with_length_field_length(MIN_LENGTH_FIELD_LENGTH)
+            };
+            let incompressible_data: Vec<u8> =
+                (0..64 * 1024).map(|_| rand::random::<u8>()).collect();
+            let mut frames = BytesMut::new();
+            codec()?.encode(incompressible_data.as_slice(), &mut frames)?;
+            let mut decoder = codec()?;
+            let mut decoded_data = Vec::new();
+            while let Some(chunk) = decoder.decode(&mut frames)? {
+                decoded_data.extend_from_slice(&chunk);
             }
+            assert_eq!(incompressible_data, decoded_data);
         }
     }
+    assert!(matches!(
+        codec().with_length_field_length(0),
+        Err(Error::InvalidLengthFieldLength(0))
+    ));
+    assert!(matches!(
+        codec().with_length_field_length(1),
+        Err(Error::InvalidLengthFieldLength(1))
+    ));
+    assert!(
+        prefix_max_frame_length(MIN_LENGTH_FIELD_LENGTH) - MAX_FRAME_OVERHEAD
+            >= MAX_CONTROL_MESSAGE_LENGTH
+    );
+    assert!(matches!(
+        codec().with_length_field_length(9),
+        Err(Error::InvalidLengthFieldLength(9))
+    ));
+    let mut three_bytes_codec = codec().with_length_field_length(3)?;
+    let mut three_bytes_frame = BytesMut::new();
+    three_bytes_codec.encode(vec![7u8; 64 * 1024].as_slice(), &mut three_bytes_frame)?;
+    assert_eq!(
+        vec![7u8; 64 * 1024].as_slice(),
+        &three_bytes_codec.decode(&mut three_bytes_frame)?.unwrap()[..]
+    );
+    Ok(())
+}
+
+#[test]
+fn test_compression() -> Result<(), Error> {
+    let encryption = Encryption::Aes(ppaass_crypto::generate_aes_encryption_token());
+    let codec =
+        || SecureLengthDelimitedCodec::new(Cow::Borrowed(&encryption), Cow::Borrowed(&encryption));
+    let compressible_chunk = b"GET / HTTP/1.1\r\nHost: www.example.com\r\n".repeat(64);
+    let mut compressed_frame = BytesMut::new();
+    codec()
+        .with_compression(3)
+        .encode(compressible_chunk.as_slice(), &mut compressed_frame)?;
+    let mut plain_frame = BytesMut::new();
+    codec().encode(compressible_chunk.as_slice(), &mut plain_frame)?;
+    assert!(compressed_frame.len() < plain_frame.len() / 4);
+    assert_eq!(
+        compressible_chunk,
+        codec()
+            .with_compression(3)
+            .decode(&mut compressed_frame)?
+            .unwrap()
+            .to_vec()
+    );
+    // The small and the incompressible chunks only carry the compression flag
+    let incompressible_chunk = (0..4096).map(|_| rand::random::<u8>()).collect::<Vec<u8>>();
+    for chunk in [b"tiny".as_slice(), incompressible_chunk.as_slice()] {
+        let mut compressed_frame = BytesMut::new();
+        codec()
+            .with_compression(3)
+            .encode(chunk, &mut compressed_frame)?;
+        let mut plain_frame = BytesMut::new();
+        codec().encode(
+            [&[CHUNK_UNCOMPRESSED], chunk].concat().as_slice(),
+            &mut plain_frame,
+        )?;
+        assert_eq!(plain_frame.len(), compressed_frame.len());
+        assert_eq!(
+            chunk,
+            &codec()
+                .with_compression(3)
+                .decode(&mut compressed_frame)?
+                .unwrap()[..]
+        );
+    }
+    // The compressed chunk larger than the max chunk size is rejected while decompressing
+    let mut compressed_frame = BytesMut::new();
+    codec()
+        .with_compression(3)
+        .encode(compressible_chunk.as_slice(), &mut compressed_frame)?;
+    assert!(matches!(
+        codec()
+            .with_compression(3)
+            .with_max_chunk_size(Some(1024))
+            .decode(&mut compressed_frame),
+        Err(Error::InvalidCompressedChunk(_))
+    ));
+    let mut unknown_flag_frame = BytesMut::new();
+    codec().encode(b"\x07data".as_slice(), &mut unknown_flag_frame)?;
+    assert!(matches!(
+        codec().with_compression(3).decode(&mut unknown_flag_frame),
+        Err(Error::InvalidCompressedChunk(_))
+    ));
+    Ok(())
+}
+
+/// The golden frames of one connection from the handshake to the relay are
+/// the wire format contract, they must only change together with a deliberate
+/// change of the protocol. Each side keeps one codec per stage like the real
+/// connection, so the frame counters covered by the tags are pinned as well.
+#[test]
+fn test_wire_transcript_golden() -> Result<(), Error> {
+    use ppaass_protocol::{
+        ConnectDestinationRequest, ConnectDestinationResponse, ExtendedConnectDestinationRequest,
+        HandshakeRequest, HandshakeResponse, PROTOCOL_VERSION, UnifiedAddress, Username,
+    };
+    let agent_encryption = Encryption::Aes(Bytes::from_static(&[0x55; 48]));
+    let proxy_encryption = Encryption::Aes(Bytes::from_static(&[0x66; 48]));
+    let handshake_codec = || {
+        SecureLengthDelimitedCodec::new(
+            Cow::Borrowed(crate::get_handshake_encryption()),
+            Cow::Borrowed(crate::get_handshake_encryption()),
+        )
+    };
+    let handshake_request: Vec<u8> = HandshakeRequest {
+        version: PROTOCOL_VERSION,
+        username: Username::from("user1"),
+        encryption: Encryption::Aes(Bytes::from_static(&[0x11; 48])),
+        tag: None,
+        challenge: Bytes::from_static(&[0x22; 16]),
+        compression: false,
+    }
+    .try_into()?;
+    let handshake_response: Vec<u8> = HandshakeResponse::Success {
+        version: PROTOCOL_VERSION,
+        encryption: Encryption::Aes(Bytes::from_static(&[0x33; 48])),
+        challenge_signature: Bytes::from_static(&[0x44; 8]),
+        compression: false,
+    }
+    .try_into()?;
+    let connect_destination_request: Vec<u8> = ExtendedConnectDestinationRequest {
+        request: ConnectDestinationRequest::Tcp(UnifiedAddress::Domain {
+            host: "www.example.com".to_string(),
+            port: 443,
+        }),
+        connect_timeout_hint_millis: Some(1500),
+    }
+    .try_into()?;
+    let connect_destination_response: Vec<u8> = ConnectDestinationResponse::Success.try_into()?;
+    // The frame name, the plaintext and whether the agent sends it
+    let transcript = [
+        ("agent_handshake_request", handshake_request, true),
+        ("proxy_handshake_response", handshake_response, false),
+        (
+            "agent_connect_destination_request",
+            connect_destination_request,
+            true,
+        ),
+        (
+            "proxy_connect_destination_response",
+            connect_destination_response,
+            false,
+        ),
+        ("agent_tcp_relay_1", b"GET / HTTP/1.1\r\n".to_vec(), true),
+        ("agent_tcp_relay_2", b"\r\n".to_vec(), true),
+        ("proxy_tcp_relay_1", b"HTTP/1.1 200 OK\r\n".to_vec(), false),
+        ("proxy_tcp_relay_2", b"\r\n".to_vec(), false),
+        (
+            "agent_tcp_relay_3",
+            b"GET / HTTP/1.1\r\n\r\n".to_vec(),
+            true,
+        ),
+        (
+            "proxy_tcp_relay_3",
+            b"HTTP/1.1 200 OK\r\n\r\n".to_vec(),
+            false,
+        ),
+    ];
+    let golden_frames = include_str!("../golden/wire_transcript.txt")
+        .lines()
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(|line| {
+            line.split_once(' ')
+                .expect("golden line is `<frame name> <hex>`")
+        })
+        .collect::<Vec<_>>();
+    assert_eq!(transcript.len(), golden_frames.len());
+    let mut agent_handshake_codec = handshake_codec();
+    let mut proxy_handshake_codec = handshake_codec();
+    let mut agent_relay_codec = SecureLengthDelimitedCodec::new(
+        Cow::Borrowed(&proxy_encryption),
+        Cow::Borrowed(&agent_encryption),
+    );
+    let mut proxy_relay_codec = SecureLengthDelimitedCodec::new(
+        Cow::Borrowed(&agent_encryption),
+        Cow::Borrowed(&proxy_encryption),
+    );
+    for (index, ((name, plaintext, sent_by_agent), (golden_name, golden_hex))) in
+        transcript.into_iter().zip(golden_frames).enumerate()
+    {
+        assert_eq!(golden_name, name);
+        // The handshake codecs are replaced by the relay codecs after the handshake
+        let (encoder, decoder) = match (index < 2, sent_by_agent) {
+            (true, true) => (&mut agent_handshake_codec, &mut proxy_handshake_codec),
+            (true, false) => (&mut proxy_handshake_codec, &mut agent_handshake_codec),
+            (false, true) => (&mut agent_relay_codec, &mut proxy_relay_codec),
+            (false, false) => (&mut proxy_relay_codec, &mut agent_relay_codec),
+        };
+        let mut frame = BytesMut::new();
+        encoder.encode(plaintext.as_slice(), &mut frame)?;
+        let frame_hex = frame
+            .iter()
+            .map(|byte| format!("{byte:02x}"))
+            .collect::<String>();
+        assert_eq!(golden_hex, frame_hex, "wire format of {name} changed");
+        assert_eq!(plaintext, decoder.decode(&mut frame)?.unwrap().to_vec());
+    }
+    Ok(())
 }
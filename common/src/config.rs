@@ -1,5 +1,10 @@
+use crate::balance::ProxyServerPolicy;
+use crate::{
+    DEFAULT_HANDSHAKE_MAX_FRAME_LENGTH, DEFAULT_LENGTH_FIELD_LENGTH, EncryptionPreference,
+    SocketOptions, validate_length_field_length, validate_max_chunk_size,
+};
 use ppaass_protocol::Username;
-use serde::{Deserialize, Serialize};
+use serde::{Deserialize, Deserializer, Serialize};
 use std::net::SocketAddr;
 use std::path::{Path, PathBuf};
 
@@ -109,6 +114,81 @@ pub trait ServerConfig {
     /// A string slice representing the maximum log level.
     ///
     fn max_log_level(&self) -> &str;
+    /// Returns the maximum number of new connections the server accepts per second.
+    ///
+    /// The accepted connections exceeding the rate are closed immediately
+    /// before any handler is spawned for them, which smooths connection bursts.
+    ///
+    /// # Returns
+    ///
+    /// * `Option<u32>` - The accept rate, `None` or `0` means no limit.
+    ///
+    fn client_accept_rate(&self) -> Option<u32>;
+    /// Returns whether the log directory should be created when it is missing.
+    ///
+    /// # Returns
+    ///
+    /// * `bool` - `true` to create the missing log directory, `false` to fail.
+    ///
+    fn log_directory_auto_create(&self) -> bool;
+    /// Returns whether the log should be written to stderr when the
+    /// log directory is not writable.
+    ///
+    /// # Returns
+    ///
+    /// * `bool` - `true` to fall back to stderr, `false` to fail the startup.
+    ///
+    fn log_stderr_fallback(&self) -> bool;
+    /// Returns the filter applied to the address of each accepted connection.
+    ///
+    /// The connections whose address is rejected by the filter are closed
+    /// immediately before any handler is spawned for them, which makes it
+    /// cheap to gate clients by source IP.
+    ///
+    /// # Returns
+    ///
+    /// * `Option<fn(SocketAddr) -> bool>` - The accept filter, `None` means all addresses are allowed.
+    ///
+    fn client_accept_filter(&self) -> Option<fn(SocketAddr) -> bool>;
+    /// Returns the options applied to the accepted client sockets.
+    ///
+    /// # Returns
+    ///
+    /// * `SocketOptions` - The socket options, the unset options keep the OS value.
+    ///
+    fn socket_options(&self) -> SocketOptions;
+    /// Returns the soft cap of the relay bytes buffered by the connections of the server.
+    ///
+    /// The connections buffering the most are shed while the buffered relay bytes
+    /// exceed the cap, it protects the process from OOM under many slow connections.
+    ///
+    /// # Returns
+    ///
+    /// * `Option<usize>` - The soft cap in bytes, `None` means no cap.
+    ///
+    fn relay_memory_soft_cap(&self) -> Option<usize>;
+    /// Returns the pem file of the certificate chain to terminate tls
+    /// on the accepted connections, it works with the `tls_key`.
+    ///
+    /// # Returns
+    ///
+    /// * `Option<&Path>` - The certificate file, `None` means plaintext connections.
+    ///
+    fn tls_cert(&self) -> Option<&Path>;
+    /// Returns the pem file of the private key of the tls certificate.
+    ///
+    /// # Returns
+    ///
+    /// * `Option<&Path>` - The private key file, `None` means plaintext connections.
+    ///
+    fn tls_key(&self) -> Option<&Path>;
+    /// Returns the address to serve the prometheus metrics of the process on.
+    ///
+    /// # Returns
+    ///
+    /// * `Option<SocketAddr>` - The metrics address, `None` means no metrics endpoint.
+    ///
+    fn metrics_address(&self) -> Option<SocketAddr>;
 }
 
 ///
@@ -184,6 +264,151 @@ pub trait UserConfig {
     fn username(&self) -> &Username;
 }
 
+/// A trait that defines the configuration used when creating a connection
+/// to the proxy, it is implemented by the agent and by the forward
+/// configuration of the proxy.
+///
+/// # Methods
+///
+/// * `proxy_connect_timeout` - The timeout in seconds to connect to the proxy.
+/// * `relay_max_chunk_size` - The max size of the relay data carried by one frame.
+/// * `connection_tag` - The opaque label sent to the proxy in handshake.
+/// * `relay_write_buffer_size` - The bytes of frames buffered before they are written out.
+/// * `relay_flush_interval_millis` - The milliseconds the flushes of the relay frames are deferred by.
+/// * `handshake_max_frame_length` - The max length of the frames exchanged in handshake.
+/// * `socket_options` - The options applied to the socket connected to the proxy.
+/// * `relay_per_frame_iv` - Whether the AES frames carry their own random iv.
+/// * `relay_length_field_length` - The size of the length prefix of the relay frames.
+/// * `handshake_decrypt_retries` - The retries of the handshake failing to decrypt.
+/// * `handshake_retry_delay_millis` - The delay before retrying the handshake.
+/// * `relay_compression_level` - The zstd level to compress the relay frames.
+///
+pub trait ProxyConnectionConfig {
+    /// Returns the timeout in seconds to connect to the proxy.
+    fn proxy_connect_timeout(&self) -> u64;
+    /// Returns the max size of the relay data carried by one frame, larger
+    /// writes are split into multiple frames and larger frames received are
+    /// rejected. Both sides of the connection should use the same value.
+    ///
+    /// # Returns
+    ///
+    /// * `Option<usize>` - The max chunk size, `None` means no limit.
+    fn relay_max_chunk_size(&self) -> Option<usize>;
+    /// Returns the opaque label of the connection, it is sent to the proxy
+    /// in handshake so the proxy can distinguish the traffic of different
+    /// agent purposes in its log.
+    ///
+    /// # Returns
+    ///
+    /// * `Option<&str>` - The connection tag, `None` means no tag.
+    fn connection_tag(&self) -> Option<&str>;
+    /// Returns the size in bytes of the relay frames buffered before they are
+    /// forced to be written to the socket. The frames stay separated but a
+    /// larger buffer batches more of them into one write.
+    ///
+    /// # Returns
+    ///
+    /// * `Option<usize>` - The write buffer size, `None` means the codec default.
+    fn relay_write_buffer_size(&self) -> Option<usize>;
+    /// Returns the milliseconds the flushes of the relay frames are deferred
+    /// by, the frames written within the interval are flushed with one write,
+    /// and they are flushed earlier once the relay write buffer size is
+    /// reached. Every flush waits up to the interval.
+    ///
+    /// # Returns
+    ///
+    /// * `Option<u64>` - The flush interval, `None` means every flush is written out at once.
+    fn relay_flush_interval_millis(&self) -> Option<u64>;
+    /// Returns the max length of the frames exchanged in handshake, a larger
+    /// handshake frame from the proxy is rejected before it is buffered.
+    ///
+    /// # Returns
+    ///
+    /// * `usize` - The max handshake frame length in bytes.
+    fn handshake_max_frame_length(&self) -> usize;
+    /// Returns the options applied to the socket connected to the proxy.
+    ///
+    /// # Returns
+    ///
+    /// * `SocketOptions` - The socket options, the unset options keep the OS value.
+    fn socket_options(&self) -> SocketOptions;
+    /// Returns whether every AES relay frame is encrypted with a fresh random
+    /// iv carried in the frame instead of the iv of the token. Both sides of
+    /// the connection should use the same value.
+    ///
+    /// # Returns
+    ///
+    /// * `bool` - `true` to use per-frame iv, `false` for the token iv layout.
+    fn relay_per_frame_iv(&self) -> bool;
+    /// Returns the size in bytes of the length prefix of the relay frames, the
+    /// handshake frames always use the default size. Both sides of the
+    /// connection should use the same value.
+    ///
+    /// # Returns
+    ///
+    /// * `usize` - The length prefix size, from 2 to 8 bytes.
+    fn relay_length_field_length(&self) -> usize;
+    /// Returns how many times the handshake is retried when it fails to
+    /// decrypt the data from the proxy, which can happen while the proxy
+    /// rotates its keys. The connect failures are never retried.
+    ///
+    /// # Returns
+    ///
+    /// * `u32` - The number of retries, `0` means no retry.
+    fn handshake_decrypt_retries(&self) -> u32;
+    /// Returns the delay in milliseconds before retrying the handshake.
+    ///
+    /// # Returns
+    ///
+    /// * `u64` - The retry delay in milliseconds.
+    fn handshake_retry_delay_millis(&self) -> u64;
+    /// Returns the zstd level to compress the relay frames, the agent asks
+    /// for compression in handshake and it is only used when the proxy accepts.
+    /// The compressed frame lengths leak how well the relayed data compresses.
+    ///
+    /// # Returns
+    ///
+    /// * `Option<i32>` - The compression level, `None` means no compression.
+    fn relay_compression_level(&self) -> Option<i32>;
+    /// Returns how the proxy servers of the user are ordered for a new
+    /// connection, the next server is tried when one fails.
+    ///
+    /// # Returns
+    ///
+    /// * `ProxyServerPolicy` - The policy ordering the proxy servers.
+    fn proxy_server_policy(&self) -> ProxyServerPolicy;
+}
+
+/// The default delay in milliseconds before retrying the handshake
+pub fn default_handshake_retry_delay_millis() -> u64 {
+    200
+}
+
+/// A trait that extends `UserRepoConfig` to provide the configuration of
+/// the user repository backed by a sqlite database.
+///
+/// # Methods
+///
+/// * `database_path` - The path of the sqlite database file.
+/// * `max_users` - The maximum number of users loaded from the database.
+///
+#[cfg(feature = "sqlite")]
+pub trait SqliteUserRepoConfig: UserRepoConfig {
+    /// Returns the path of the sqlite database file holding the user table.
+    ///
+    /// # Returns
+    ///
+    /// * `&Path` - The database file path.
+    fn database_path(&self) -> &Path;
+    /// Returns the maximum number of users loaded from the database, the
+    /// users are loaded in the username order.
+    ///
+    /// # Returns
+    ///
+    /// * `Option<usize>` - The maximum number of users, `None` means no limit.
+    fn max_users(&self) -> Option<usize>;
+}
+
 /// A trait that extends `WithUserRepositoryConfig` to provide file system-specific
 /// configuration for a user repository. This trait is designed to be implemented by
 /// types that need to specify the directory and file names used for storing user
@@ -256,9 +481,30 @@ pub trait FsUserRepoConfig: UserRepoConfig {
     /// Note: The actual file name returned may vary based on the implementation.
     ///
     fn user_info_file_name(&self) -> &str;
+    /// Returns the maximum number of users loaded from the user repository directory.
+    ///
+    /// The users exceeding the limit are not loaded, which protects the memory
+    /// when the user repository directory is misconfigured to a huge tree.
+    ///
+    /// # Returns
+    ///
+    /// * `Option<usize>` - The maximum number of users, `None` means no limit.
+    ///
+    fn max_users(&self) -> Option<usize>;
+    /// Returns the number of the threads loading the user directories.
+    ///
+    /// The user directories are independent, loading them in parallel cuts
+    /// the startup time of the large user repositories.
+    ///
+    /// # Returns
+    ///
+    /// * `Option<usize>` - The number of the threads, `None` means the
+    ///   available parallelism of the machine.
+    ///
+    fn user_load_parallelism(&self) -> Option<usize>;
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct CommonConfig {
     pub client_max_connections: usize,
     pub listening_address: SocketAddr,
@@ -271,6 +517,107 @@ pub struct CommonConfig {
     pub user_repo_directory: PathBuf,
     pub user_repo_refresh_interval: u64,
     pub worker_threads: usize,
+    #[serde(default)]
+    pub client_accept_rate: Option<u32>,
+    #[serde(default)]
+    pub encryption_preference: EncryptionPreference,
+    #[serde(default, deserialize_with = "deserialize_relay_max_chunk_size")]
+    pub relay_max_chunk_size: Option<usize>,
+    #[serde(default)]
+    pub relay_write_buffer_size: Option<usize>,
+    /// Defer the flushes of the relay frames by these milliseconds, so the
+    /// frames written within the interval reach the socket with one write
+    #[serde(default)]
+    pub relay_flush_interval_millis: Option<u64>,
+    #[serde(default = "default_enabled")]
+    pub log_directory_auto_create: bool,
+    #[serde(default = "default_enabled")]
+    pub log_stderr_fallback: bool,
+    #[serde(skip)]
+    pub client_accept_filter: Option<fn(SocketAddr) -> bool>,
+    #[serde(default)]
+    pub max_users: Option<usize>,
+    #[serde(default)]
+    pub user_load_parallelism: Option<usize>,
+    #[serde(default = "default_handshake_max_frame_length")]
+    pub handshake_max_frame_length: usize,
+    #[serde(flatten)]
+    pub socket_options: SocketOptions,
+    #[serde(default)]
+    pub relay_per_frame_iv: bool,
+    /// The size in bytes of the length prefix of the relay frames, from 2 to 8
+    #[serde(
+        default = "default_relay_length_field_length",
+        deserialize_with = "deserialize_relay_length_field_length"
+    )]
+    pub relay_length_field_length: usize,
+    /// Compress the relay frames with zstd at this level, the agent asks for
+    /// it in handshake and the proxy accepts only when it is set as well.
+    /// The length of the compressed frames leaks how well the data compresses,
+    /// a CRIME-style oracle when the relayed data mixes secrets with data an
+    /// observer can influence, keep it off for such traffic
+    #[serde(default)]
+    pub relay_compression_level: Option<i32>,
+    /// The soft cap of the relay bytes buffered by the connections of the server
+    #[serde(default)]
+    pub relay_memory_soft_cap: Option<usize>,
+    /// Close the relay when no data moves in either direction within
+    /// these seconds, udp associations included, `None` means the relay
+    /// never idles out
+    #[serde(default)]
+    pub relay_idle_timeout_secs: Option<u64>,
+    /// The pem file of the certificate chain to terminate tls on the accepted connections
+    #[serde(default)]
+    pub tls_cert: Option<PathBuf>,
+    /// The pem file of the private key of the tls certificate
+    #[serde(default)]
+    pub tls_key: Option<PathBuf>,
+    /// The address to serve the prometheus metrics on
+    #[serde(default)]
+    pub metrics_address: Option<SocketAddr>,
+    /// The seconds to wait for the in-flight connections on shutdown
+    #[serde(default = "default_shutdown_timeout")]
+    pub shutdown_timeout: u64,
+}
+
+fn default_enabled() -> bool {
+    true
+}
+
+fn default_shutdown_timeout() -> u64 {
+    30
+}
+
+/// The default max length of the frames exchanged in handshake
+pub fn default_handshake_max_frame_length() -> usize {
+    DEFAULT_HANDSHAKE_MAX_FRAME_LENGTH
+}
+
+/// The default size of the length prefix of the relay frames
+pub fn default_relay_length_field_length() -> usize {
+    DEFAULT_LENGTH_FIELD_LENGTH
+}
+
+/// Reject the max chunk size too small for the control messages at config load
+pub fn deserialize_relay_max_chunk_size<'de, D>(deserializer: D) -> Result<Option<usize>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let relay_max_chunk_size = Option::<usize>::deserialize(deserializer)?;
+    if let Some(relay_max_chunk_size) = relay_max_chunk_size {
+        validate_max_chunk_size(relay_max_chunk_size).map_err(serde::de::Error::custom)?;
+    }
+    Ok(relay_max_chunk_size)
+}
+
+/// Reject the length prefix size the relay codec can not use at config load
+pub fn deserialize_relay_length_field_length<'de, D>(deserializer: D) -> Result<usize, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let relay_length_field_length = usize::deserialize(deserializer)?;
+    validate_length_field_length(relay_length_field_length).map_err(serde::de::Error::custom)?;
+    Ok(relay_length_field_length)
 }
 
 impl ServerConfig for CommonConfig {
@@ -292,6 +639,33 @@ impl ServerConfig for CommonConfig {
     fn max_log_level(&self) -> &str {
         &self.max_log_level
     }
+    fn client_accept_rate(&self) -> Option<u32> {
+        self.client_accept_rate
+    }
+    fn log_directory_auto_create(&self) -> bool {
+        self.log_directory_auto_create
+    }
+    fn log_stderr_fallback(&self) -> bool {
+        self.log_stderr_fallback
+    }
+    fn client_accept_filter(&self) -> Option<fn(SocketAddr) -> bool> {
+        self.client_accept_filter
+    }
+    fn socket_options(&self) -> SocketOptions {
+        self.socket_options
+    }
+    fn relay_memory_soft_cap(&self) -> Option<usize> {
+        self.relay_memory_soft_cap
+    }
+    fn tls_cert(&self) -> Option<&Path> {
+        self.tls_cert.as_deref()
+    }
+    fn tls_key(&self) -> Option<&Path> {
+        self.tls_key.as_deref()
+    }
+    fn metrics_address(&self) -> Option<SocketAddr> {
+        self.metrics_address
+    }
 }
 
 impl UserRepoConfig for CommonConfig {
@@ -313,4 +687,38 @@ impl FsUserRepoConfig for CommonConfig {
     fn user_info_file_name(&self) -> &str {
         &self.user_info_file_name
     }
+    fn max_users(&self) -> Option<usize> {
+        self.max_users
+    }
+    fn user_load_parallelism(&self) -> Option<usize> {
+        self.user_load_parallelism
+    }
+}
+
+#[test]
+fn test_relay_max_chunk_size_too_small() {
+    use ppaass_protocol::MAX_CONTROL_MESSAGE_LENGTH;
+    #[derive(Deserialize)]
+    struct RelayConfig {
+        #[serde(default, deserialize_with = "deserialize_relay_max_chunk_size")]
+        relay_max_chunk_size: Option<usize>,
+    }
+    let relay_config = |content: &str| toml::from_str::<RelayConfig>(content);
+    assert_eq!(None, relay_config("").unwrap().relay_max_chunk_size);
+    assert_eq!(
+        Some(MAX_CONTROL_MESSAGE_LENGTH),
+        relay_config(&format!(
+            "relay_max_chunk_size = {MAX_CONTROL_MESSAGE_LENGTH}"
+        ))
+        .unwrap()
+        .relay_max_chunk_size
+    );
+    // The connect destination request would be split over the frames
+    assert!(
+        relay_config(&format!(
+            "relay_max_chunk_size = {}",
+            MAX_CONTROL_MESSAGE_LENGTH - 1
+        ))
+        .is_err()
+    );
 }
@@ -1,5 +1,7 @@
 use ppaass_crypto::Error as CryptoError;
-use ppaass_protocol::{UnifiedAddress, Username};
+use ppaass_protocol::{HandshakeError, UnifiedAddress, Username};
+use std::path::PathBuf;
+use std::time::Duration;
 use thiserror::Error;
 use tracing::metadata::ParseLevelError;
 
@@ -11,24 +13,103 @@ pub enum Error {
     ParseLevel(#[from] ParseLevelError),
     #[error(transparent)]
     Crypto(#[from] CryptoError),
+    #[cfg(feature = "sqlite")]
+    #[error(transparent)]
+    Sqlite(#[from] rusqlite::Error),
     #[error("User not exist: {0:?}")]
     UserNotExist(Username),
     #[error("User rsa crypto not exist: {0:?}")]
     UserRsaCryptoNotExist(Username),
+    #[error("No proxy servers configured for user: {0}")]
+    NoProxyServersConfigured(Username),
     #[error("Connection exhausted: [{0}]")]
     ConnectionExhausted(String),
     #[error("Fail to setup destination: [{0}]")]
     ConnectDestination(UnifiedAddress),
+    #[error("Destination refused by the proxy in observe mode: [{0}]")]
+    DestinationObserved(UnifiedAddress),
     #[error("Connect to remote endpoint timeout in {0} seconds.")]
     ConnectTimeout(u64),
+    #[error("Relay chunk size {0} exceeds the max chunk size {1}")]
+    ChunkTooLarge(usize, usize),
+    #[error("Frame exceeds the max frame length {0}")]
+    FrameTooLarge(usize),
+    #[error(
+        "Invalid relay max chunk size {0}, it must carry the largest control message of {max} bytes",
+        max = ppaass_protocol::MAX_CONTROL_MESSAGE_LENGTH
+    )]
+    InvalidMaxChunkSize(usize),
+    #[error("Invalid frame length field length {0}, it must be from 2 to 8 bytes")]
+    InvalidLengthFieldLength(usize),
+    #[error("The frame counter is exhausted, the nonce can not be reused")]
+    FrameCounterExhausted,
+    #[error("Invalid compressed relay chunk: {0}")]
+    InvalidCompressedChunk(String),
+    #[error("Udp payload size {0} exceeds the max udp relay buffer size {1}")]
+    UdpPayloadTooLarge(usize, usize),
+    #[error("Receive tcp relay data in udp association")]
+    UnexpectedTcpRelay,
+    #[error("Log directory {0:?} is not writable: {1}")]
+    LogDirectoryNotWritable(PathBuf, std::io::Error),
+    #[error("Invalid handshake challenge length: {0}")]
+    InvalidHandshakeChallenge(usize),
+    #[error("Proxy fail to prove the possession of its private key in handshake")]
+    ProxyAuthenticationFail,
+    #[error("Proxy is unhealthy, connecting is backed off for {0:?}")]
+    ProxyUnhealthy(Duration),
+    #[error("Proxy connection not established within the deadline {0:?}")]
+    ProxyConnectDeadline(Duration),
+    #[error("Proxy rejects the handshake: {0}")]
+    HandshakeRejected(HandshakeError),
+    #[error("Tls error: {0}")]
+    Tls(String),
+    #[error("Metrics error: {0}")]
+    Metrics(String),
     #[error("Lock error: [{0}]")]
     Lock(String),
     #[error(transparent)]
     Protocol(#[from] ppaass_protocol::Error),
 }
 
+impl Error {
+    /// Check whether the handshake failed to decrypt the data from the proxy,
+    /// or the proxy failed to decrypt the encryption of the agent, it can be
+    /// transient while the keys are rotating. The generic rejection of the
+    /// proxy may hide the decrypt failure so it is retried as well.
+    pub fn is_handshake_decrypt_failure(&self) -> bool {
+        matches!(
+            self,
+            Error::Crypto(_)
+                | Error::HandshakeRejected(
+                    HandshakeError::InvalidEncryption | HandshakeError::Rejected
+                )
+        )
+    }
+
+    /// Check whether the proxy can not be reached or it drops the connection
+    /// in handshake, it can be transient while the proxy is restarting
+    pub fn is_proxy_unreachable(&self) -> bool {
+        matches!(
+            self,
+            Error::Io(_) | Error::ConnectTimeout(_) | Error::ConnectionExhausted(_)
+        )
+    }
+
+    /// Check whether the io error surfaced by a relay endpoint is caused by a
+    /// frame failing to decrypt, which means the relay is corrupted or out of sync
+    pub fn is_decrypt_failure(io_error: &std::io::Error) -> bool {
+        io_error
+            .get_ref()
+            .and_then(|inner| inner.downcast_ref::<Error>())
+            .is_some_and(|inner| matches!(inner, Error::Crypto(_)))
+    }
+}
+
 impl From<Error> for std::io::Error {
     fn from(value: Error) -> Self {
-        std::io::Error::other(format!("{value:?}"))
+        match value {
+            Error::Io(e) => e,
+            value => std::io::Error::other(value),
+        }
     }
 }
@@ -0,0 +1,205 @@
+use std::future::Future;
+use std::pin::Pin;
+use std::task::{Context, Poll, ready};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::time::{Sleep, sleep};
+use tokio_util::bytes::BytesMut;
+use tokio_util::codec::{Decoder, Framed};
+use tokio_util::io::{SinkWriter, StreamReader};
+
+/// The relay frames written and read as a byte stream, the flushes of the
+/// frames are deferred by the flush interval, so the frames written within
+/// the interval reach the socket with one write instead of one write per
+/// flush. The frames stay separated, only the flushes are batched, and the
+/// frames are flushed at once when the write buffer reaches its backpressure
+/// boundary.
+///
+/// The relay loop only waits for a flush while it has nothing to read, so a
+/// deferred flush does not hold back the data of the other direction, but
+/// every flush waits up to the interval, keep it to a few milliseconds.
+#[derive(Debug)]
+pub struct BatchedFlushWriter<T, U> {
+    inner: SinkWriter<StreamReader<Framed<T, U>, BytesMut>>,
+    flush_interval: Option<Duration>,
+    /// The deadline of the deferred flush, armed by the first flush of the
+    /// buffered frames and dropped once they are flushed
+    flush_deadline: Option<Pin<Box<Sleep>>>,
+}
+
+impl<T, U> BatchedFlushWriter<T, U>
+where
+    T: AsyncRead,
+    U: Decoder<Item = BytesMut>,
+    U::Error: Into<std::io::Error>,
+{
+    /// Create the writer of the relay frames, `None` flush interval flushes
+    /// the frames on every flush.
+    pub fn new(framed: Framed<T, U>, flush_interval: Option<Duration>) -> Self {
+        Self {
+            inner: SinkWriter::new(StreamReader::new(framed)),
+            flush_interval,
+            flush_deadline: None,
+        }
+    }
+}
+
+impl<T, U> BatchedFlushWriter<T, U> {
+    pub fn get_ref(&self) -> &SinkWriter<StreamReader<Framed<T, U>, BytesMut>> {
+        &self.inner
+    }
+
+    /// Wait until the buffered frames are due to be flushed, they are due at
+    /// once without a flush interval, when nothing is buffered or when the
+    /// buffer reaches its backpressure boundary, otherwise once the flush
+    /// interval passes since the first deferred flush. The pending flush
+    /// wakes the task at the deadline.
+    fn poll_flush_due(&mut self, cx: &mut Context<'_>) -> Poll<()> {
+        let Some(flush_interval) = self.flush_interval else {
+            return Poll::Ready(());
+        };
+        let relay_framed = self.inner.get_ref().get_ref();
+        let buffered_bytes = relay_framed.write_buffer().len();
+        if buffered_bytes == 0 || buffered_bytes >= relay_framed.backpressure_boundary() {
+            return Poll::Ready(());
+        }
+        // The elapsed deadline stays until the flush completes, so a flush
+        // blocked by the socket is not deferred again
+        let flush_deadline = self
+            .flush_deadline
+            .get_or_insert_with(|| Box::pin(sleep(flush_interval)));
+        flush_deadline.as_mut().poll(cx)
+    }
+}
+
+impl<T, U> AsyncRead for BatchedFlushWriter<T, U>
+where
+    SinkWriter<StreamReader<Framed<T, U>, BytesMut>>: AsyncRead + Unpin,
+{
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_read(cx, buf)
+    }
+}
+
+impl<T, U> AsyncWrite for BatchedFlushWriter<T, U>
+where
+    SinkWriter<StreamReader<Framed<T, U>, BytesMut>>: AsyncWrite + Unpin,
+{
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        Pin::new(&mut self.get_mut().inner).poll_write(cx, buf)
+    }
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_flush_due(cx));
+        ready!(Pin::new(&mut this.inner).poll_flush(cx))?;
+        this.flush_deadline = None;
+        Poll::Ready(Ok(()))
+    }
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        let this = self.get_mut();
+        ready!(Pin::new(&mut this.inner).poll_shutdown(cx))?;
+        this.flush_deadline = None;
+        Poll::Ready(Ok(()))
+    }
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_batched_flush() -> std::io::Result<()> {
+    use crate::{SecureLengthDelimitedCodec, get_handshake_encryption};
+    use futures_util::StreamExt;
+    use std::borrow::Cow;
+    use tokio::io::AsyncWriteExt;
+    let codec = || {
+        SecureLengthDelimitedCodec::new(
+            Cow::Borrowed(get_handshake_encryption()),
+            Cow::Borrowed(get_handshake_encryption()),
+        )
+    };
+    let flush_interval = Duration::from_millis(5);
+    let (agent_stream, proxy_stream) = tokio::io::duplex(64 * 1024);
+    let mut proxy_framed = Framed::new(proxy_stream, codec());
+    let mut writer =
+        BatchedFlushWriter::new(Framed::new(agent_stream, codec()), Some(flush_interval));
+    let flush_start = tokio::time::Instant::now();
+    writer.write_all(b"first frame").await?;
+    writer.write_all(b"second frame").await?;
+    // The flush waits for the interval and both frames arrive separated
+    writer.flush().await?;
+    assert_eq!(flush_interval, flush_start.elapsed());
+    assert_eq!(b"first frame", &proxy_framed.next().await.unwrap()?[..]);
+    assert_eq!(b"second frame", &proxy_framed.next().await.unwrap()?[..]);
+    // Nothing is buffered, the flush does not wait
+    let flush_start = tokio::time::Instant::now();
+    writer.flush().await?;
+    assert_eq!(Duration::ZERO, flush_start.elapsed());
+    // The buffer reaching the backpressure boundary is flushed at once
+    let boundary = writer.get_ref().get_ref().get_ref().backpressure_boundary();
+    let flush_start = tokio::time::Instant::now();
+    writer.write_all(&vec![7u8; boundary]).await?;
+    writer.flush().await?;
+    assert_eq!(Duration::ZERO, flush_start.elapsed());
+    assert_eq!(
+        vec![7u8; boundary],
+        proxy_framed.next().await.unwrap()?.to_vec()
+    );
+    // The shutdown flushes without waiting
+    writer.write_all(b"last frame").await?;
+    let flush_start = tokio::time::Instant::now();
+    writer.shutdown().await?;
+    assert_eq!(Duration::ZERO, flush_start.elapsed());
+    assert_eq!(b"last frame", &proxy_framed.next().await.unwrap()?[..]);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_batched_flush_relay_intact() -> std::io::Result<()> {
+    use crate::{SecureLengthDelimitedCodec, get_handshake_encryption};
+    use futures_util::StreamExt;
+    use std::borrow::Cow;
+    use tokio::io::AsyncWriteExt;
+    let codec = || {
+        SecureLengthDelimitedCodec::new(
+            Cow::Borrowed(get_handshake_encryption()),
+            Cow::Borrowed(get_handshake_encryption()),
+        )
+    };
+    let data = (0..200_000).map(|i| (i % 251) as u8).collect::<Vec<u8>>();
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+    let agent_stream = tokio::net::TcpStream::connect(listener.local_addr()?).await?;
+    let (proxy_stream, _) = listener.accept().await?;
+    let (mut source, mut source_reader) = tokio::io::duplex(1024);
+    let expected_data = data.clone();
+    let agent_task = tokio::spawn(async move {
+        let mut agent_framed = Framed::new(agent_stream, codec());
+        let mut received_data = Vec::new();
+        while let Some(frame) = agent_framed.next().await {
+            received_data.extend_from_slice(&frame.unwrap());
+        }
+        assert_eq!(expected_data, received_data);
+    });
+    let source_task = tokio::spawn(async move {
+        for chunk in data.chunks(100) {
+            source.write_all(chunk).await?;
+            tokio::task::yield_now().await;
+        }
+        Ok::<_, std::io::Error>(())
+    });
+    let mut writer = BatchedFlushWriter::new(
+        Framed::new(proxy_stream, codec()),
+        Some(Duration::from_millis(2)),
+    );
+    tokio::io::copy(&mut source_reader, &mut writer).await?;
+    writer.shutdown().await?;
+    drop(writer);
+    source_task.await??;
+    agent_task.await?;
+    Ok(())
+}
@@ -1,28 +1,63 @@
+pub mod backoff;
+pub mod balance;
 mod codec;
 pub mod config;
 mod error;
+mod flush;
 pub mod log;
+pub mod memory;
+pub mod metrics;
+pub mod pool;
 pub mod proxy;
+mod relay;
 mod runtime;
 mod server;
+mod shutdown;
+mod socket;
+mod stream;
+mod tls;
+mod udp;
 pub mod user;
 
+pub use codec::DEFAULT_HANDSHAKE_MAX_FRAME_LENGTH;
+pub use codec::DEFAULT_LENGTH_FIELD_LENGTH;
+pub use codec::MIN_LENGTH_FIELD_LENGTH;
 pub use codec::SecureLengthDelimitedCodec;
+pub use codec::validate_length_field_length;
+pub use codec::validate_max_chunk_size;
 pub use config::FsUserRepoConfig;
+pub use config::ProxyConnectionConfig;
 pub use config::ServerConfig;
 pub use config::UserConfig;
 pub use config::UserRepoConfig;
 pub use error::Error;
-use ppaass_crypto::{RsaCrypto, generate_aes_encryption_token, generate_blowfish_encryption_token};
+pub use flush::BatchedFlushWriter;
+use ppaass_crypto::{
+    RsaCrypto, RsaPadding, generate_aes_encryption_token_with,
+    generate_aes_key_encryption_token_with, generate_blowfish_encryption_token_with,
+    generate_chacha20_encryption_token_with,
+};
 use ppaass_protocol::Encryption;
-use rand::random;
+use rand::Rng;
+pub use relay::{RelayIdleTimer, copy_bidirectional_with_idle_timeout};
 pub use runtime::build_server_runtime;
+use serde::{Deserialize, Serialize};
 pub use server::ServerGuard;
 pub use server::ServerState;
 pub use server::start_server;
+pub use shutdown::Shutdown;
+pub use socket::{SocketOptions, close_gracefully, set_socket_ip_tos};
 use std::borrow::Cow;
 use std::sync::Arc;
-use std::sync::LazyLock;
+use std::sync::{LazyLock, OnceLock};
+pub use stream::{AsTcpStream, PeekableStream};
+pub use tls::IncomingStream;
+use tokio_util::bytes::Bytes;
+use tracing::warn;
+pub use udp::{
+    DEFAULT_UDP_RELAY_BUFFER_SIZE, UdpRelayPacket, read_udp_relay_packet, read_udp_relay_payload,
+    write_udp_relay_packet, write_udp_relay_payload,
+};
 
 static HANDSHAKE_ENCRYPTION: LazyLock<Arc<Encryption>> = LazyLock::new(|| {
     Arc::new(Encryption::Blowfish({
@@ -36,17 +71,209 @@ pub fn get_handshake_encryption<'a>() -> &'a Encryption {
     &HANDSHAKE_ENCRYPTION
 }
 
-/// Randomly generate a raw encryption
+static ENCRYPTION_PREFERENCE: OnceLock<EncryptionPreference> = OnceLock::new();
+
+/// The preference of the encryption generated for a connection
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum EncryptionPreference {
+    /// Prefer AES when the CPU has AES hardware support, otherwise
+    /// fall back to ChaCha20-Poly1305, which is fast in software
+    #[default]
+    Auto,
+    /// Randomly choose between AES, Blowfish and ChaCha20-Poly1305
+    Random,
+    /// Always use AES
+    Aes,
+    /// Always use Blowfish
+    Blowfish,
+    /// Always use ChaCha20-Poly1305
+    #[serde(rename = "chacha20_poly1305")]
+    ChaCha20Poly1305,
+}
+
+/// Set the encryption preference of the process, it can only be set once
+/// before any connection is created.
+pub fn set_encryption_preference(encryption_preference: EncryptionPreference) {
+    if ENCRYPTION_PREFERENCE.set(encryption_preference).is_err() {
+        warn!("Encryption preference already set, ignore: {encryption_preference:?}");
+    }
+}
+
+/// Detect whether the CPU has AES hardware support at runtime
+fn aes_hardware_supported() -> bool {
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    {
+        std::arch::is_x86_feature_detected!("aes")
+    }
+    #[cfg(target_arch = "aarch64")]
+    {
+        std::arch::is_aarch64_feature_detected!("aes")
+    }
+    #[cfg(not(any(target_arch = "x86", target_arch = "x86_64", target_arch = "aarch64")))]
+    {
+        false
+    }
+}
+
+/// Generate the AES token, the key only token when every frame carries its own iv
 #[inline(always)]
-pub fn random_generate_encryption() -> Encryption {
-    let random_number = random::<u64>();
-    if random_number.is_multiple_of(2) {
-        Encryption::Aes(generate_aes_encryption_token())
+fn generate_aes_token<R: Rng + ?Sized>(per_frame_iv: bool, rng: &mut R) -> Bytes {
+    if per_frame_iv {
+        generate_aes_key_encryption_token_with(rng)
     } else {
-        Encryption::Blowfish(generate_blowfish_encryption_token())
+        generate_aes_encryption_token_with(rng)
     }
 }
 
+#[inline(always)]
+fn generate_preferred_encryption<R: Rng + ?Sized>(
+    encryption_preference: EncryptionPreference,
+    aes_hardware_supported: bool,
+    per_frame_iv: bool,
+    rng: &mut R,
+) -> Encryption {
+    match encryption_preference {
+        EncryptionPreference::Auto if aes_hardware_supported => {
+            Encryption::Aes(generate_aes_token(per_frame_iv, rng))
+        }
+        EncryptionPreference::Auto => {
+            Encryption::ChaCha20Poly1305(generate_chacha20_encryption_token_with(rng))
+        }
+        EncryptionPreference::Random => match rng.random_range(0..3) {
+            0 => Encryption::Aes(generate_aes_token(per_frame_iv, rng)),
+            1 => Encryption::Blowfish(generate_blowfish_encryption_token_with(rng)),
+            _ => Encryption::ChaCha20Poly1305(generate_chacha20_encryption_token_with(rng)),
+        },
+        EncryptionPreference::Aes => Encryption::Aes(generate_aes_token(per_frame_iv, rng)),
+        EncryptionPreference::Blowfish => {
+            Encryption::Blowfish(generate_blowfish_encryption_token_with(rng))
+        }
+        EncryptionPreference::ChaCha20Poly1305 => {
+            Encryption::ChaCha20Poly1305(generate_chacha20_encryption_token_with(rng))
+        }
+    }
+}
+
+/// Generate a raw encryption with a random token, the cipher
+/// is chosen by the encryption preference of the process
+#[inline(always)]
+pub fn random_generate_encryption() -> Encryption {
+    generate_encryption_with(
+        ENCRYPTION_PREFERENCE.get().copied().unwrap_or_default(),
+        &mut rand::rng(),
+    )
+}
+
+/// Generate the encryption of the relay frames, the AES token is the key
+/// only token when the frames carry their own iv
+#[inline(always)]
+pub fn random_generate_relay_encryption(per_frame_iv: bool) -> Encryption {
+    generate_preferred_encryption(
+        ENCRYPTION_PREFERENCE.get().copied().unwrap_or_default(),
+        aes_hardware_supported(),
+        per_frame_iv,
+        &mut rand::rng(),
+    )
+}
+
+/// Generate a raw encryption with the given random generator, a seeded
+/// generator produces reproducible encryption tokens, it is used by tests
+/// which need to assert on exact wire bytes.
+#[inline(always)]
+pub fn generate_encryption_with<R: Rng + ?Sized>(
+    encryption_preference: EncryptionPreference,
+    rng: &mut R,
+) -> Encryption {
+    generate_preferred_encryption(encryption_preference, aes_hardware_supported(), false, rng)
+}
+
+/// The length of the random challenge sent by the agent in handshake
+pub const HANDSHAKE_CHALLENGE_LENGTH: usize = 32;
+/// The context prepended to the handshake transcript before signing, so
+/// the signature can not be reused for any other purpose of the key
+const HANDSHAKE_TRANSCRIPT_CONTEXT: &[u8] = b"ppaass-handshake-transcript:";
+
+/// Generate the random challenge the proxy must sign in handshake
+pub fn generate_handshake_challenge() -> Bytes {
+    Bytes::from(
+        rand::rng()
+            .random::<[u8; HANDSHAKE_CHALLENGE_LENGTH]>()
+            .to_vec(),
+    )
+}
+
+/// The handshake fields signed by the proxy, the signature binds the whole
+/// handshake request of the agent to the negotiated version, the rsa
+/// encrypted proxy token and the compression flag, so neither the request
+/// nor the response can be replaced in transit.
+#[derive(Debug, Clone, Copy)]
+pub struct HandshakeTranscript<'a> {
+    pub challenge: &'a [u8],
+    /// The encoded handshake request of the agent, it carries the challenge,
+    /// the version, the rsa encrypted agent token and the compression request
+    pub request: &'a [u8],
+    /// The version negotiated by the proxy in the handshake response
+    pub version: u16,
+    /// The rsa encrypted token of the proxy in the handshake response
+    pub encryption: &'a Encryption,
+    pub compression: bool,
+}
+
+impl HandshakeTranscript<'_> {
+    /// The bytes of the transcript, every variable length field is
+    /// prefixed with its length so the fields can not be shifted
+    fn to_bytes(self) -> Result<Vec<u8>, Error> {
+        if self.challenge.len() != HANDSHAKE_CHALLENGE_LENGTH {
+            return Err(Error::InvalidHandshakeChallenge(self.challenge.len()));
+        }
+        let (encryption_type, token): (u8, &[u8]) = match self.encryption {
+            Encryption::Plain => (0, &[]),
+            Encryption::Aes(token) => (1, token),
+            Encryption::Blowfish(token) => (2, token),
+            Encryption::ChaCha20Poly1305(token) => (3, token),
+        };
+        let mut transcript = Vec::with_capacity(
+            HANDSHAKE_TRANSCRIPT_CONTEXT.len()
+                + HANDSHAKE_CHALLENGE_LENGTH
+                + self.request.len()
+                + token.len()
+                + 20,
+        );
+        transcript.extend_from_slice(HANDSHAKE_TRANSCRIPT_CONTEXT);
+        transcript.extend_from_slice(self.challenge);
+        transcript.extend_from_slice(&(self.request.len() as u64).to_be_bytes());
+        transcript.extend_from_slice(self.request);
+        transcript.extend_from_slice(&self.version.to_be_bytes());
+        transcript.push(encryption_type);
+        transcript.extend_from_slice(&(token.len() as u64).to_be_bytes());
+        transcript.extend_from_slice(token);
+        transcript.push(u8::from(self.compression));
+        Ok(transcript)
+    }
+}
+
+/// Sign the SHA-256 digest of the handshake transcript with the rsa private key of the proxy
+pub fn sign_handshake_transcript(
+    transcript: HandshakeTranscript<'_>,
+    rsa_crypto: &RsaCrypto,
+) -> Result<Bytes, Error> {
+    Ok(rsa_crypto.sign_sha256(&transcript.to_bytes()?)?)
+}
+
+/// Verify the signature of the handshake transcript with the rsa public key
+/// of the proxy, it proves the proxy holds the matching private key and
+/// the signed fields of the handshake response are the ones it sent
+pub fn verify_handshake_transcript(
+    transcript: HandshakeTranscript<'_>,
+    transcript_signature: &[u8],
+    rsa_crypto: &RsaCrypto,
+) -> Result<(), Error> {
+    rsa_crypto
+        .verify_sha256(&transcript.to_bytes()?, transcript_signature)
+        .map_err(|_| Error::ProxyAuthenticationFail)
+}
+
 #[inline(always)]
 pub fn rsa_encrypt_encryption<'a>(
     raw_encryption: &'a Encryption,
@@ -55,13 +282,17 @@ pub fn rsa_encrypt_encryption<'a>(
     match raw_encryption {
         Encryption::Plain => Ok(Cow::Borrowed(raw_encryption)),
         Encryption::Aes(token) => {
-            let encrypted_token = rsa_crypto.encrypt(token)?;
+            let encrypted_token = rsa_crypto.encrypt_with_padding(token, RsaPadding::OaepSha256)?;
             Ok(Cow::Owned(Encryption::Aes(encrypted_token)))
         }
         Encryption::Blowfish(token) => {
-            let encrypted_token = rsa_crypto.encrypt(token)?;
+            let encrypted_token = rsa_crypto.encrypt_with_padding(token, RsaPadding::OaepSha256)?;
             Ok(Cow::Owned(Encryption::Blowfish(encrypted_token)))
         }
+        Encryption::ChaCha20Poly1305(token) => {
+            let encrypted_token = rsa_crypto.encrypt_with_padding(token, RsaPadding::OaepSha256)?;
+            Ok(Cow::Owned(Encryption::ChaCha20Poly1305(encrypted_token)))
+        }
     }
 }
 
@@ -73,12 +304,193 @@ pub fn rsa_decrypt_encryption(
     match encrypted_encryption {
         Encryption::Plain => Ok(encrypted_encryption),
         Encryption::Aes(token) => {
-            let decrypted_token = rsa_crypto.decrypt(&token)?;
+            let decrypted_token =
+                rsa_crypto.decrypt_with_padding(&token, RsaPadding::OaepSha256)?;
             Ok(Encryption::Aes(decrypted_token))
         }
         Encryption::Blowfish(token) => {
-            let decrypted_token = rsa_crypto.decrypt(&token)?;
+            let decrypted_token =
+                rsa_crypto.decrypt_with_padding(&token, RsaPadding::OaepSha256)?;
             Ok(Encryption::Blowfish(decrypted_token))
         }
+        Encryption::ChaCha20Poly1305(token) => {
+            let decrypted_token =
+                rsa_crypto.decrypt_with_padding(&token, RsaPadding::OaepSha256)?;
+            Ok(Encryption::ChaCha20Poly1305(decrypted_token))
+        }
+    }
+}
+
+#[test]
+fn test_generate_preferred_encryption() {
+    assert!(matches!(
+        generate_preferred_encryption(EncryptionPreference::Auto, true, false, &mut rand::rng()),
+        Encryption::Aes(_)
+    ));
+    assert!(matches!(
+        generate_preferred_encryption(EncryptionPreference::Auto, false, false, &mut rand::rng()),
+        Encryption::ChaCha20Poly1305(_)
+    ));
+    assert!(matches!(
+        generate_preferred_encryption(EncryptionPreference::Aes, false, false, &mut rand::rng()),
+        Encryption::Aes(_)
+    ));
+    assert!(matches!(
+        generate_preferred_encryption(
+            EncryptionPreference::Blowfish,
+            true,
+            false,
+            &mut rand::rng()
+        ),
+        Encryption::Blowfish(_)
+    ));
+    assert!(matches!(
+        generate_preferred_encryption(
+            EncryptionPreference::ChaCha20Poly1305,
+            true,
+            false,
+            &mut rand::rng()
+        ),
+        Encryption::ChaCha20Poly1305(_)
+    ));
+    // The frames carrying their own iv only need the key
+    assert!(matches!(
+        generate_preferred_encryption(EncryptionPreference::Aes, true, true, &mut rand::rng()),
+        Encryption::Aes(token) if token.len() == 32
+    ));
+    assert!(matches!(
+        generate_preferred_encryption(EncryptionPreference::Aes, true, false, &mut rand::rng()),
+        Encryption::Aes(token) if token.len() == 48
+    ));
+}
+
+#[test]
+fn test_generate_encryption_with_seed() {
+    use rand::SeedableRng;
+    use rand::rngs::StdRng;
+    let token = |encryption: Encryption| match encryption {
+        Encryption::Plain => Vec::new(),
+        Encryption::Aes(token)
+        | Encryption::Blowfish(token)
+        | Encryption::ChaCha20Poly1305(token) => token.to_vec(),
+    };
+    for encryption_preference in [
+        EncryptionPreference::Random,
+        EncryptionPreference::Aes,
+        EncryptionPreference::Blowfish,
+        EncryptionPreference::ChaCha20Poly1305,
+    ] {
+        let encryption_1 =
+            generate_encryption_with(encryption_preference, &mut StdRng::seed_from_u64(7));
+        let encryption_2 =
+            generate_encryption_with(encryption_preference, &mut StdRng::seed_from_u64(7));
+        let encryption_3 =
+            generate_encryption_with(encryption_preference, &mut StdRng::seed_from_u64(8));
+        let token_1 = token(encryption_1);
+        assert_eq!(token_1, token(encryption_2));
+        assert_ne!(token_1, token(encryption_3));
     }
 }
+
+#[test]
+fn test_handshake_transcript() -> Result<(), Error> {
+    use std::fs::File;
+    let agent_rsa_crypto = RsaCrypto::new(
+        File::open("../resources/agent/user/user1/ProxyPublicKey.pem")?,
+        File::open("../resources/agent/user/user1/AgentPrivateKey.pem")?,
+    )?;
+    let proxy_rsa_crypto = RsaCrypto::new(
+        File::open("../resources/proxy/user/user1/AgentPublicKey.pem")?,
+        File::open("../resources/proxy/user/user1/ProxyPrivateKey.pem")?,
+    )?;
+    let challenge = generate_handshake_challenge();
+    let encryption = Encryption::Aes(Bytes::from_static(b"the rsa encrypted proxy token"));
+    let request = b"the encoded handshake request";
+    let transcript = HandshakeTranscript {
+        challenge: &challenge,
+        request,
+        version: 4,
+        encryption: &encryption,
+        compression: false,
+    };
+    let transcript_signature = sign_handshake_transcript(transcript, &proxy_rsa_crypto)?;
+    verify_handshake_transcript(transcript, &transcript_signature, &agent_rsa_crypto)?;
+    // A proxy without the proxy private key can not produce a valid signature
+    let forged_signature = sign_handshake_transcript(transcript, &agent_rsa_crypto)?;
+    assert!(matches!(
+        verify_handshake_transcript(transcript, &forged_signature, &agent_rsa_crypto),
+        Err(Error::ProxyAuthenticationFail)
+    ));
+    // The signature of the real proxy does not cover any other field
+    let substituted_encryption =
+        Encryption::Aes(Bytes::from_static(b"the token of the man in the middle"));
+    let other_challenge = generate_handshake_challenge();
+    for tampered in [
+        HandshakeTranscript {
+            challenge: &other_challenge,
+            ..transcript
+        },
+        HandshakeTranscript {
+            encryption: &substituted_encryption,
+            ..transcript
+        },
+        HandshakeTranscript {
+            request: b"the encoded handshake request of the man in the middle",
+            ..transcript
+        },
+        HandshakeTranscript {
+            version: 3,
+            ..transcript
+        },
+        HandshakeTranscript {
+            compression: true,
+            ..transcript
+        },
+    ] {
+        assert!(matches!(
+            verify_handshake_transcript(tampered, &transcript_signature, &agent_rsa_crypto),
+            Err(Error::ProxyAuthenticationFail)
+        ));
+    }
+    assert!(matches!(
+        sign_handshake_transcript(
+            HandshakeTranscript {
+                challenge: b"short",
+                ..transcript
+            },
+            &proxy_rsa_crypto
+        ),
+        Err(Error::InvalidHandshakeChallenge(5))
+    ));
+    Ok(())
+}
+
+#[test]
+fn test_handshake_rsa_oaep() -> Result<(), Error> {
+    use std::fs::File;
+    let agent_rsa_crypto = RsaCrypto::new(
+        File::open("../resources/agent/user/user1/ProxyPublicKey.pem")?,
+        File::open("../resources/agent/user/user1/AgentPrivateKey.pem")?,
+    )?;
+    let proxy_rsa_crypto = RsaCrypto::new(
+        File::open("../resources/proxy/user/user1/AgentPublicKey.pem")?,
+        File::open("../resources/proxy/user/user1/ProxyPrivateKey.pem")?,
+    )?;
+    let encryption = Encryption::Aes(Bytes::from_static(b"the aes token of the connection"));
+    let encrypted_encryption = rsa_encrypt_encryption(&encryption, &agent_rsa_crypto)?.into_owned();
+    assert_eq!(
+        encryption,
+        rsa_decrypt_encryption(encrypted_encryption, &proxy_rsa_crypto)?
+    );
+    // The token encrypted with PKCS#1 v1.5 is not accepted
+    let Encryption::Aes(token) = &encryption else {
+        unreachable!()
+    };
+    let pkcs1v15_encryption =
+        Encryption::Aes(agent_rsa_crypto.encrypt_with_padding(token, RsaPadding::Pkcs1v15)?);
+    assert!(matches!(
+        rsa_decrypt_encryption(pkcs1v15_encryption, &proxy_rsa_crypto),
+        Err(Error::Crypto(_))
+    ));
+    Ok(())
+}
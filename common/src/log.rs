@@ -1,15 +1,61 @@
 use crate::{Error, ServerConfig};
+use std::fs::{OpenOptions, create_dir_all, remove_file};
+use std::path::Path;
 use tracing_appender::non_blocking::WorkerGuard;
 use tracing_subscriber::EnvFilter;
 use tracing_subscriber::fmt::time::ChronoUtc;
 use tracing_subscriber::layer::SubscriberExt;
 use tracing_subscriber::util::SubscriberInitExt;
 
+const LOG_DIRECTORY_PROBE_FILE: &str = ".ppaass-log-probe";
+
+/// Make sure the log directory exists and is writable, the missing
+/// directory is created when `auto_create` is enabled.
+fn prepare_log_directory(log_directory: &Path, auto_create: bool) -> Result<(), Error> {
+    let not_writable = |e| Error::LogDirectoryNotWritable(log_directory.to_path_buf(), e);
+    if !log_directory.exists() && auto_create {
+        create_dir_all(log_directory).map_err(not_writable)?;
+    }
+    let probe_file_path = log_directory.join(LOG_DIRECTORY_PROBE_FILE);
+    OpenOptions::new()
+        .create(true)
+        .truncate(true)
+        .write(true)
+        .open(&probe_file_path)
+        .map_err(not_writable)?;
+    remove_file(&probe_file_path).map_err(not_writable)?;
+    Ok(())
+}
+
+/// Where the log is written
+#[derive(Debug, PartialEq, Eq)]
+enum LogTarget {
+    Directory,
+    Stderr,
+}
+
+/// Choose where the log is written, the log falls back to stderr when the
+/// log directory is not writable and the stderr fallback is enabled.
+fn log_target<C: ServerConfig>(config: &C) -> Result<LogTarget, Error> {
+    match prepare_log_directory(config.log_directory(), config.log_directory_auto_create()) {
+        Ok(()) => Ok(LogTarget::Directory),
+        Err(e) if config.log_stderr_fallback() => {
+            eprintln!("{e}, fall back to write log to stderr.");
+            Ok(LogTarget::Stderr)
+        }
+        Err(e) => Err(e),
+    }
+}
+
 pub fn init<C: ServerConfig>(config: &C) -> Result<WorkerGuard, Error> {
     let tracing_subscriber_registry = tracing_subscriber::registry();
-    let (trace_file_appender, trace_appender_guard) = tracing_appender::non_blocking(
-        tracing_appender::rolling::daily(config.log_directory(), config.log_name_prefix()),
-    );
+    let (trace_file_appender, trace_appender_guard) = match log_target(config)? {
+        LogTarget::Directory => tracing_appender::non_blocking(tracing_appender::rolling::daily(
+            config.log_directory(),
+            config.log_name_prefix(),
+        )),
+        LogTarget::Stderr => tracing_appender::non_blocking(std::io::stderr()),
+    };
     tracing_subscriber_registry
         .with(
             EnvFilter::try_from_default_env()
@@ -28,3 +74,58 @@ pub fn init<C: ServerConfig>(config: &C) -> Result<WorkerGuard, Error> {
         .init();
     Ok(trace_appender_guard)
 }
+
+#[test]
+fn test_prepare_log_directory() -> Result<(), Error> {
+    let test_directory =
+        std::env::temp_dir().join(format!("ppaass-log-test-{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&test_directory);
+    let log_directory = test_directory.join("log");
+    assert!(matches!(
+        prepare_log_directory(&log_directory, false),
+        Err(Error::LogDirectoryNotWritable(..))
+    ));
+    prepare_log_directory(&log_directory, true)?;
+    assert!(log_directory.is_dir());
+    // A regular file can never be used as the log directory
+    let log_file = test_directory.join("log_file");
+    std::fs::write(&log_file, b"")?;
+    assert!(matches!(
+        prepare_log_directory(&log_file, true),
+        Err(Error::LogDirectoryNotWritable(..))
+    ));
+    std::fs::remove_dir_all(&test_directory)?;
+    Ok(())
+}
+
+#[test]
+fn test_log_directory_not_writable() -> Result<(), Error> {
+    use crate::config::CommonConfig;
+    // Even the root user can not write into a regular file as the log directory
+    let log_directory =
+        std::env::temp_dir().join(format!("ppaass-file-log-test-{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&log_directory);
+    let _ = std::fs::remove_file(&log_directory);
+    std::fs::write(&log_directory, b"")?;
+    assert!(matches!(
+        prepare_log_directory(&log_directory, true),
+        Err(Error::LogDirectoryNotWritable(path, _)) if path == log_directory
+    ));
+    let config = CommonConfig {
+        log_directory: log_directory.clone(),
+        log_stderr_fallback: true,
+        ..crate::server::test_common_config("127.0.0.1:0".parse().unwrap())
+    };
+    assert_eq!(LogTarget::Stderr, log_target(&config)?);
+    assert!(log_directory.is_file());
+    let config = CommonConfig {
+        log_stderr_fallback: false,
+        ..config
+    };
+    assert!(matches!(
+        log_target(&config),
+        Err(Error::LogDirectoryNotWritable(path, _)) if path == log_directory
+    ));
+    std::fs::remove_file(&log_directory)?;
+    Ok(())
+}
@@ -0,0 +1,142 @@
+use crate::BatchedFlushWriter;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+tokio::task_local! {
+    /// The relay memory of the connection handled by the current task, the
+    /// server scopes every connection handler with it.
+    pub(crate) static CONNECTION_RELAY_MEMORY: Arc<ConnectionRelayMemory>;
+}
+
+/// The accounting of the relay bytes buffered in memory by the connections
+/// of one server, so the server can shed connections when the total exceeds
+/// the soft cap.
+#[derive(Debug, Default)]
+pub struct RelayMemory {
+    buffered_bytes: AtomicUsize,
+}
+
+impl RelayMemory {
+    /// The total relay bytes currently buffered
+    pub fn buffered_bytes(&self) -> usize {
+        self.buffered_bytes.load(Ordering::Relaxed)
+    }
+}
+
+/// The relay bytes buffered by one connection, counted into the relay
+/// memory of its server as well.
+#[derive(Debug)]
+pub struct ConnectionRelayMemory {
+    relay_memory: Arc<RelayMemory>,
+    buffered_bytes: AtomicUsize,
+}
+
+impl ConnectionRelayMemory {
+    pub fn new(relay_memory: Arc<RelayMemory>) -> Self {
+        Self {
+            relay_memory,
+            buffered_bytes: AtomicUsize::new(0),
+        }
+    }
+
+    /// The relay bytes currently buffered by the connection
+    pub fn buffered_bytes(&self) -> usize {
+        self.buffered_bytes.load(Ordering::Relaxed)
+    }
+
+    fn add(&self, bytes: usize) {
+        self.buffered_bytes.fetch_add(bytes, Ordering::Relaxed);
+        self.relay_memory
+            .buffered_bytes
+            .fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    fn sub(&self, bytes: usize) {
+        self.buffered_bytes.fetch_sub(bytes, Ordering::Relaxed);
+        self.relay_memory
+            .buffered_bytes
+            .fetch_sub(bytes, Ordering::Relaxed);
+    }
+}
+
+/// The bytes buffered by one relay endpoint, the reported
+/// bytes are released from the accounting on drop.
+#[derive(Debug, Default)]
+pub struct BufferedRelayBytes {
+    /// The connection the bytes are counted into, bound on the first report
+    /// to the connection of the reporting task, as the pooled endpoints are
+    /// created before the connection which relays through them
+    connection_relay_memory: Option<Arc<ConnectionRelayMemory>>,
+    reported: usize,
+}
+
+impl BufferedRelayBytes {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Count the bytes into the given connection instead of the connection
+    /// of the reporting task
+    pub fn with_connection(connection_relay_memory: Arc<ConnectionRelayMemory>) -> Self {
+        Self {
+            connection_relay_memory: Some(connection_relay_memory),
+            reported: 0,
+        }
+    }
+
+    /// Report the current size of the buffers of the relay endpoint, nothing
+    /// is counted outside of the connections of a server
+    pub fn update(&mut self, buffered: usize) {
+        if self.connection_relay_memory.is_none() {
+            self.connection_relay_memory = CONNECTION_RELAY_MEMORY.try_with(Arc::clone).ok();
+        }
+        if let Some(connection_relay_memory) = &self.connection_relay_memory {
+            if buffered > self.reported {
+                connection_relay_memory.add(buffered - self.reported);
+            } else {
+                connection_relay_memory.sub(self.reported - buffered);
+            }
+        }
+        self.reported = buffered;
+    }
+}
+
+impl Drop for BufferedRelayBytes {
+    fn drop(&mut self) {
+        self.update(0);
+    }
+}
+
+/// The bytes buffered in the read and write buffers of the relay frames
+pub fn framed_buffered_bytes<T, U>(relay_read_write: &BatchedFlushWriter<T, U>) -> usize {
+    let relay_framed = relay_read_write.get_ref().get_ref().get_ref();
+    relay_framed.read_buffer().len() + relay_framed.write_buffer().len()
+}
+
+#[tokio::test]
+async fn test_buffered_relay_bytes() {
+    let relay_memory = Arc::new(RelayMemory::default());
+    let first_connection = Arc::new(ConnectionRelayMemory::new(relay_memory.clone()));
+    let second_connection = Arc::new(ConnectionRelayMemory::new(relay_memory.clone()));
+    let mut first = BufferedRelayBytes::with_connection(first_connection.clone());
+    // The endpoint created outside binds to the connection it reports in
+    let mut second = BufferedRelayBytes::new();
+    CONNECTION_RELAY_MEMORY
+        .scope(second_connection.clone(), async { second.update(500) })
+        .await;
+    first.update(1000);
+    assert_eq!(1500, relay_memory.buffered_bytes());
+    assert_eq!(1000, first_connection.buffered_bytes());
+    assert_eq!(500, second_connection.buffered_bytes());
+    first.update(200);
+    assert_eq!(700, relay_memory.buffered_bytes());
+    drop(second);
+    assert_eq!(200, relay_memory.buffered_bytes());
+    assert_eq!(0, second_connection.buffered_bytes());
+    drop(first);
+    assert_eq!(0, relay_memory.buffered_bytes());
+    // Nothing is counted outside of the connections
+    let mut untracked = BufferedRelayBytes::new();
+    untracked.update(300);
+    assert_eq!(0, relay_memory.buffered_bytes());
+}
@@ -0,0 +1,437 @@
+use crate::error::Error;
+use metrics::{counter, describe_counter, describe_gauge, gauge};
+use metrics_exporter_prometheus::{PrometheusBuilder, PrometheusHandle, PrometheusRecorder};
+use ppaass_crypto::Error as CryptoError;
+use ppaass_protocol::{Error as ProtocolError, HandshakeError};
+use std::net::SocketAddr;
+use std::pin::pin;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio_util::sync::CancellationToken;
+use tracing::debug;
+
+pub use metrics::Counter;
+
+/// The connections being handled by the server
+pub const ACTIVE_CONNECTIONS: &str = "ppaass_active_connections";
+/// The bytes relayed from the client side to the destination side
+pub const RELAY_CLIENT_TO_DESTINATION_BYTES: &str =
+    "ppaass_relay_client_to_destination_bytes_total";
+/// The bytes relayed from the destination side to the client side
+pub const RELAY_DESTINATION_TO_CLIENT_BYTES: &str =
+    "ppaass_relay_destination_to_client_bytes_total";
+/// The handshakes between the agent and the proxy which failed, labeled by the reason
+pub const HANDSHAKE_FAILURES: &str = "ppaass_handshake_failures_total";
+/// The proxy connections fetched from the pool
+pub const POOL_HITS: &str = "ppaass_pool_hits_total";
+/// The fetches from the pool which found no ready proxy connection
+pub const POOL_MISSES: &str = "ppaass_pool_misses_total";
+/// The connections shed because the buffered relay bytes exceed the soft cap
+pub const RELAY_MEMORY_SHED_CONNECTIONS: &str = "ppaass_relay_memory_shed_connections_total";
+/// The relay bytes buffered in memory by the connections of the server
+pub const RELAY_MEMORY_BUFFERED_BYTES: &str = "ppaass_relay_memory_buffered_bytes";
+
+/// The max size of the metrics request head, the larger one is not answered
+const METRICS_REQUEST_MAX_SIZE: usize = 8 * 1024;
+/// The time for the metrics client to send the request head
+const METRICS_REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+tokio::task_local! {
+    /// The metrics recorder of the server running the current task, the
+    /// tasks spawned by it inherit the recorder through
+    /// [with_current_metrics_recorder].
+    static METRICS_RECORDER: Arc<PrometheusRecorder>;
+}
+
+/// Build the prometheus recorder of one server, every server keeps its own
+/// recorder instead of installing the recorder of the process, so the
+/// servers running in one process do not share or conflict their metrics.
+pub(crate) fn build_metrics_recorder() -> Arc<PrometheusRecorder> {
+    let recorder = PrometheusBuilder::new().build_recorder();
+    metrics::with_local_recorder(&recorder, || {
+        describe_gauge!(ACTIVE_CONNECTIONS, "The connections being handled");
+        describe_counter!(
+            RELAY_CLIENT_TO_DESTINATION_BYTES,
+            "The bytes relayed from the client to the destination"
+        );
+        describe_counter!(
+            RELAY_DESTINATION_TO_CLIENT_BYTES,
+            "The bytes relayed from the destination to the client"
+        );
+        describe_counter!(HANDSHAKE_FAILURES, "The failed handshakes by reason");
+        describe_counter!(POOL_HITS, "The proxy connections fetched from the pool");
+        describe_counter!(
+            POOL_MISSES,
+            "The pool fetches without a ready proxy connection"
+        );
+        describe_counter!(
+            RELAY_MEMORY_SHED_CONNECTIONS,
+            "The connections shed over the relay memory soft cap"
+        );
+        describe_gauge!(
+            RELAY_MEMORY_BUFFERED_BYTES,
+            "The relay bytes buffered in memory"
+        );
+    });
+    Arc::new(recorder)
+}
+
+/// Run the future with the metrics recorded into the recorder, the
+/// recorder is set for every poll of the future.
+pub(crate) async fn with_metrics_recorder<F: Future>(
+    recorder: Arc<PrometheusRecorder>,
+    future: F,
+) -> F::Output {
+    let mut future = pin!(METRICS_RECORDER.scope(recorder.clone(), future));
+    std::future::poll_fn(|cx| metrics::with_local_recorder(&*recorder, || future.as_mut().poll(cx)))
+        .await
+}
+
+/// Run the function with the metrics recorder, the tasks it spawns through
+/// [with_current_metrics_recorder] record into the recorder.
+pub(crate) fn with_metrics_recorder_sync<R>(
+    recorder: Option<&Arc<PrometheusRecorder>>,
+    f: impl FnOnce() -> R,
+) -> R {
+    match recorder {
+        None => f(),
+        Some(recorder) => METRICS_RECORDER.sync_scope(recorder.clone(), || {
+            metrics::with_local_recorder(&**recorder, f)
+        }),
+    }
+}
+
+/// Keep the metrics recorder of the current task for the future, it must
+/// wrap the future spawned by a connection handler so the metrics of the
+/// spawned task are recorded by the server of the connection.
+pub fn with_current_metrics_recorder<F: Future>(future: F) -> impl Future<Output = F::Output> {
+    let recorder = METRICS_RECORDER.try_with(Arc::clone).ok();
+    async move {
+        match recorder {
+            Some(recorder) => with_metrics_recorder(recorder, future).await,
+            None => future.await,
+        }
+    }
+}
+
+/// Serve the metrics of the recorder on the given address until the stop
+/// signal, the port `0` binds a free port, returns the address bound.
+pub(crate) async fn start_metrics_server(
+    metrics_address: SocketAddr,
+    recorder: &PrometheusRecorder,
+    stop_signal: CancellationToken,
+) -> Result<SocketAddr, Error> {
+    let metrics_listener = TcpListener::bind(metrics_address).await?;
+    let metrics_address = metrics_listener.local_addr()?;
+    let metrics_handle = recorder.handle();
+    tokio::spawn(async move {
+        loop {
+            let metrics_stream = tokio::select! {
+                _ = stop_signal.cancelled() => return,
+                metrics_stream = metrics_listener.accept() => metrics_stream,
+            };
+            let (metrics_stream, metrics_client_addr) = match metrics_stream {
+                Ok(metrics_stream) => metrics_stream,
+                Err(e) => {
+                    debug!("Fail to accept metrics connection: {e:?}");
+                    continue;
+                }
+            };
+            let metrics_handle = metrics_handle.clone();
+            tokio::spawn(async move {
+                if let Err(e) = serve_metrics(metrics_stream, &metrics_handle).await {
+                    debug!("Fail to serve metrics to [{metrics_client_addr}]: {e:?}");
+                }
+            });
+        }
+    });
+    Ok(metrics_address)
+}
+
+/// Answer one metrics request with the rendered metrics whatever the path
+async fn serve_metrics(
+    mut metrics_stream: TcpStream,
+    metrics_handle: &PrometheusHandle,
+) -> std::io::Result<()> {
+    let mut request = Vec::new();
+    let mut buf = [0u8; 1024];
+    while !request.windows(4).any(|window| window == b"\r\n\r\n") {
+        let size =
+            tokio::time::timeout(METRICS_REQUEST_TIMEOUT, metrics_stream.read(&mut buf)).await??;
+        if size == 0 || request.len() + size > METRICS_REQUEST_MAX_SIZE {
+            return Ok(());
+        }
+        request.extend_from_slice(&buf[..size]);
+    }
+    let metrics = metrics_handle.render();
+    let response = format!(
+        "HTTP/1.1 200 OK\r\ncontent-type: text/plain; version=0.0.4\r\ncontent-length: {}\r\nconnection: close\r\n\r\n{metrics}",
+        metrics.len()
+    );
+    metrics_stream.write_all(response.as_bytes()).await?;
+    metrics_stream.shutdown().await
+}
+
+/// The active connection of the server, it is removed from
+/// the active connections gauge on drop.
+pub(crate) struct ActiveConnection;
+
+impl ActiveConnection {
+    pub(crate) fn start() -> Self {
+        gauge!(ACTIVE_CONNECTIONS).increment(1.0);
+        ActiveConnection
+    }
+}
+
+impl Drop for ActiveConnection {
+    fn drop(&mut self) {
+        gauge!(ACTIVE_CONNECTIONS).decrement(1.0);
+    }
+}
+
+/// The counters of the bytes relayed in both directions
+pub fn relay_bytes_counters() -> (Counter, Counter) {
+    (
+        counter!(RELAY_CLIENT_TO_DESTINATION_BYTES),
+        counter!(RELAY_DESTINATION_TO_CLIENT_BYTES),
+    )
+}
+
+/// The reason label of the failed handshake
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HandshakeFailureReason {
+    /// The user is not known or has no rsa keys
+    UnknownUser,
+    /// The user is expired
+    Expired,
+    /// The handshake frames or the encryption of the peer can not be decrypted
+    Decrypt,
+    /// The protocol version of the peer is not supported
+    Version,
+    /// The cipher of the encryption is rejected, like a key of the wrong length
+    Cipher,
+    /// The other failures, like the peer closing the connection
+    Other,
+}
+
+impl HandshakeFailureReason {
+    pub fn label(self) -> &'static str {
+        match self {
+            HandshakeFailureReason::UnknownUser => "unknown_user",
+            HandshakeFailureReason::Expired => "expired",
+            HandshakeFailureReason::Decrypt => "decrypt",
+            HandshakeFailureReason::Version => "version",
+            HandshakeFailureReason::Cipher => "cipher",
+            HandshakeFailureReason::Other => "other",
+        }
+    }
+}
+
+impl From<&Error> for HandshakeFailureReason {
+    fn from(value: &Error) -> Self {
+        match value {
+            Error::UserNotExist(_)
+            | Error::UserRsaCryptoNotExist(_)
+            | Error::HandshakeRejected(
+                HandshakeError::UserNotExist | HandshakeError::UserRsaCryptoNotExist,
+            ) => HandshakeFailureReason::UnknownUser,
+            Error::HandshakeRejected(HandshakeError::UserExpired) => {
+                HandshakeFailureReason::Expired
+            }
+            Error::Crypto(CryptoError::InvalidLength(_)) => HandshakeFailureReason::Cipher,
+            Error::Crypto(
+                CryptoError::Rsa(_)
+                | CryptoError::Unpad(_)
+                | CryptoError::MacMismatch
+                | CryptoError::Aead,
+            )
+            | Error::HandshakeRejected(HandshakeError::InvalidEncryption) => {
+                HandshakeFailureReason::Decrypt
+            }
+            Error::Io(e) if Error::is_decrypt_failure(e) => HandshakeFailureReason::Decrypt,
+            Error::Protocol(ProtocolError::UnsupportedProtocolVersion(_))
+            | Error::HandshakeRejected(HandshakeError::UnsupportedProtocolVersion) => {
+                HandshakeFailureReason::Version
+            }
+            _ => HandshakeFailureReason::Other,
+        }
+    }
+}
+
+pub fn record_handshake_failure(reason: HandshakeFailureReason) {
+    counter!(HANDSHAKE_FAILURES, "reason" => reason.label()).increment(1);
+}
+
+pub(crate) fn record_pool_fetch(hit: bool) {
+    if hit {
+        counter!(POOL_HITS).increment(1);
+    } else {
+        counter!(POOL_MISSES).increment(1);
+    }
+}
+
+pub(crate) fn record_relay_memory_shed() {
+    counter!(RELAY_MEMORY_SHED_CONNECTIONS).increment(1);
+}
+
+pub(crate) fn record_relay_memory_buffered_bytes(buffered_bytes: usize) {
+    gauge!(RELAY_MEMORY_BUFFERED_BYTES).set(buffered_bytes as f64);
+}
+
+#[test]
+fn test_record_metrics() -> Result<(), Error> {
+    let recorder = PrometheusBuilder::new().build_recorder();
+    let metrics_handle = recorder.handle();
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()?;
+    let rendered_metrics = metrics::with_local_recorder(&recorder, || {
+        let active_connection = ActiveConnection::start();
+        let _other_active_connection = ActiveConnection::start();
+        drop(active_connection);
+        record_handshake_failure(HandshakeFailureReason::Other);
+        record_pool_fetch(true);
+        record_pool_fetch(false);
+        record_pool_fetch(false);
+        record_relay_memory_shed();
+        record_relay_memory_buffered_bytes(4096);
+        runtime.block_on(async {
+            let (mut a, mut a_peer) = tokio::io::duplex(1024);
+            let (mut b, mut b_peer) = tokio::io::duplex(1024);
+            a_peer.write_all(b"request").await?;
+            a_peer.shutdown().await?;
+            b_peer.write_all(b"response!").await?;
+            b_peer.shutdown().await?;
+            crate::copy_bidirectional_with_idle_timeout(&mut a, &mut b, None).await?;
+            let mut relayed = Vec::new();
+            b_peer.read_to_end(&mut relayed).await?;
+            assert_eq!(b"request", relayed.as_slice());
+            Ok::<_, std::io::Error>(())
+        })?;
+        Ok::<_, Error>(metrics_handle.render())
+    })?;
+    for expected in [
+        "ppaass_active_connections 1\n",
+        "ppaass_handshake_failures_total{reason=\"other\"} 1\n",
+        "ppaass_pool_hits_total 1\n",
+        "ppaass_pool_misses_total 2\n",
+        "ppaass_relay_client_to_destination_bytes_total 7\n",
+        "ppaass_relay_destination_to_client_bytes_total 9\n",
+        "ppaass_relay_memory_shed_connections_total 1\n",
+        "ppaass_relay_memory_buffered_bytes 4096\n",
+    ] {
+        assert!(
+            rendered_metrics.contains(expected),
+            "{expected:?} not in {rendered_metrics}"
+        );
+    }
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_metrics_server() -> Result<(), Error> {
+    let stop_signal = CancellationToken::new();
+    let scrape = |recorder: Arc<PrometheusRecorder>| {
+        let stop_signal = stop_signal.clone();
+        async move {
+            let metrics_address = start_metrics_server(
+                SocketAddr::from(([127, 0, 0, 1], 0)),
+                &recorder,
+                stop_signal,
+            )
+            .await?;
+            let mut metrics_stream = TcpStream::connect(metrics_address).await?;
+            metrics_stream
+                .write_all(b"GET /metrics HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n")
+                .await?;
+            let mut metrics_response = String::new();
+            metrics_stream.read_to_string(&mut metrics_response).await?;
+            Ok::<_, Error>(metrics_response)
+        }
+    };
+    // Two servers in one process keep their own metrics
+    let recorder = build_metrics_recorder();
+    let other_recorder = build_metrics_recorder();
+    with_metrics_recorder(recorder.clone(), async {
+        let _active_connection = ActiveConnection::start();
+        // The task spawned by the connection records into the same recorder
+        tokio::spawn(with_current_metrics_recorder(async {
+            record_handshake_failure(HandshakeFailureReason::Decrypt);
+        }))
+        .await
+    })
+    .await
+    .map_err(|e| Error::Metrics(e.to_string()))?;
+    let metrics_response = scrape(recorder).await?;
+    let other_metrics_response = scrape(other_recorder).await?;
+    stop_signal.cancel();
+    assert!(metrics_response.starts_with("HTTP/1.1 200"));
+    assert!(metrics_response.contains("# TYPE ppaass_active_connections gauge"));
+    assert!(metrics_response.contains("ppaass_handshake_failures_total{reason=\"decrypt\"} 1\n"));
+    assert!(other_metrics_response.starts_with("HTTP/1.1 200"));
+    assert!(!other_metrics_response.contains("ppaass_handshake_failures_total"));
+    Ok(())
+}
+
+#[test]
+fn test_record_handshake_failure_reasons() {
+    use ppaass_protocol::Username;
+    let recorder = PrometheusBuilder::new().build_recorder();
+    let metrics_handle = recorder.handle();
+    let decrypt_failure: std::io::Error = Error::Crypto(CryptoError::MacMismatch).into();
+    let failures = [
+        (
+            Error::UserNotExist(Username::from("user1")),
+            HandshakeFailureReason::UnknownUser,
+        ),
+        (
+            Error::HandshakeRejected(HandshakeError::UserRsaCryptoNotExist),
+            HandshakeFailureReason::UnknownUser,
+        ),
+        (
+            Error::HandshakeRejected(HandshakeError::UserExpired),
+            HandshakeFailureReason::Expired,
+        ),
+        (
+            Error::Crypto(CryptoError::MacMismatch),
+            HandshakeFailureReason::Decrypt,
+        ),
+        (Error::Io(decrypt_failure), HandshakeFailureReason::Decrypt),
+        (
+            Error::Protocol(ProtocolError::UnsupportedProtocolVersion(4)),
+            HandshakeFailureReason::Version,
+        ),
+        (
+            // The aes token too short to build the cipher
+            Error::Crypto(ppaass_crypto::encrypt_with_aes(&[], 0, b"hello").unwrap_err()),
+            HandshakeFailureReason::Cipher,
+        ),
+        (
+            Error::HandshakeRejected(HandshakeError::Rejected),
+            HandshakeFailureReason::Other,
+        ),
+    ];
+    metrics::with_local_recorder(&recorder, || {
+        for (error, reason) in &failures {
+            assert_eq!(*reason, HandshakeFailureReason::from(error), "{error:?}");
+            record_handshake_failure(*reason);
+        }
+    });
+    let rendered_metrics = metrics_handle.render();
+    for (label, count) in [
+        ("unknown_user", 2),
+        ("expired", 1),
+        ("decrypt", 2),
+        ("version", 1),
+        ("cipher", 1),
+        ("other", 1),
+    ] {
+        let expected = format!("ppaass_handshake_failures_total{{reason=\"{label}\"}} {count}\n");
+        assert!(
+            rendered_metrics.contains(&expected),
+            "{expected:?} not in {rendered_metrics}"
+        );
+    }
+}
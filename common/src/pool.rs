@@ -0,0 +1,395 @@
+use crate::Error;
+use crate::backoff::ProxyBackoff;
+use crate::config::ProxyConnectionConfig;
+use crate::metrics::{record_pool_fetch, with_current_metrics_recorder};
+use crate::proxy::{ProxyConnection, ProxyFramed};
+use crate::user::UserWithProxyServers;
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::Notify;
+use tokio::time::Instant;
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, error};
+
+/// The interval before retrying when the pool fails to create a connection
+const POOL_FILL_RETRY_INTERVAL: Duration = Duration::from_secs(1);
+
+/// The connection which can be checked before it is handed out of the pool
+pub trait PooledConnection {
+    /// Check the connection without blocking, `false` means the
+    /// peer already closed it and the connection must be dropped.
+    fn is_alive(&self) -> bool;
+}
+
+impl PooledConnection for ProxyConnection<ProxyFramed<'_>> {
+    fn is_alive(&self) -> bool {
+        ProxyConnection::is_alive(self)
+    }
+}
+
+/// The connections ready in the pool with the time they are created,
+/// the oldest connection is at the front.
+struct PoolConnections<T> {
+    connections: Mutex<VecDeque<(Instant, T)>>,
+    /// Notified when a connection is put into the pool
+    connection_added: Notify,
+    /// Notified when a connection is taken out of the pool
+    connection_taken: Notify,
+}
+
+impl<T> PoolConnections<T> {
+    /// Put the connection into the pool once the pool has room for it, the
+    /// pooled connections past the max lifetime are closed meanwhile, so
+    /// their slots are refilled. The connection past the max lifetime while
+    /// waiting is closed as well. Returns `false` when the filler stops.
+    async fn put_when_room(
+        &self,
+        created_at: Instant,
+        connection: T,
+        pool_size: usize,
+        max_lifetime: Option<Duration>,
+        stop_signal: &CancellationToken,
+    ) -> bool {
+        let mut connection = Some(connection);
+        loop {
+            if let Some(max_lifetime) = max_lifetime
+                && created_at.elapsed() >= max_lifetime
+            {
+                debug!("Close the connection waiting for the pool past its max lifetime.");
+                return true;
+            }
+            let oldest_expire_at = {
+                let mut connections = self.connections.lock().expect("pool lock poisoned");
+                if let Some(max_lifetime) = max_lifetime {
+                    let pooled_connections = connections.len();
+                    connections.retain(|(created_at, _)| created_at.elapsed() < max_lifetime);
+                    if connections.len() < pooled_connections {
+                        debug!(
+                            "Close {} pooled proxy connections past their max lifetime.",
+                            pooled_connections - connections.len()
+                        );
+                    }
+                }
+                if connections.len() < pool_size.max(1) {
+                    if let Some(connection) = connection.take() {
+                        connections.push_back((created_at, connection));
+                    }
+                    self.connection_added.notify_one();
+                    return true;
+                }
+                connections
+                    .front()
+                    .zip(max_lifetime)
+                    .map(|((oldest_created_at, _), max_lifetime)| *oldest_created_at + max_lifetime)
+            };
+            let oldest_expired = async {
+                match oldest_expire_at {
+                    Some(oldest_expire_at) => tokio::time::sleep_until(oldest_expire_at).await,
+                    None => std::future::pending().await,
+                }
+            };
+            tokio::select! {
+                _ = stop_signal.cancelled() => return false,
+                _ = self.connection_taken.notified() => {}
+                _ = oldest_expired => {}
+            }
+        }
+    }
+}
+
+/// The pool of the connections created in advance, a background filler
+/// keeps up to `pool_size` connections ready, so the connection fetched
+/// from the pool does not pay for the handshake.
+pub struct ProxyConnectionPool<T = ProxyConnection<ProxyFramed<'static>>> {
+    pool_connections: Arc<PoolConnections<T>>,
+    max_idle: Duration,
+    max_lifetime: Option<Duration>,
+    stop_signal: CancellationToken,
+}
+
+impl ProxyConnectionPool {
+    /// Create the pool of the proxy connections to the proxy servers of the user,
+    /// the pool fills through the backoff shared with the other connection attempts
+    pub fn new<U, C>(
+        user_info: Arc<U>,
+        config: Arc<C>,
+        backoff: Arc<ProxyBackoff>,
+        pool_size: usize,
+        max_idle: Duration,
+        max_lifetime: Option<Duration>,
+    ) -> Self
+    where
+        U: UserWithProxyServers + Send + Sync + 'static,
+        C: ProxyConnectionConfig + Send + Sync + 'static,
+    {
+        Self::with_connector(pool_size, max_idle, max_lifetime, move || {
+            let user_info = user_info.clone();
+            let config = config.clone();
+            let backoff = backoff.clone();
+            async move {
+                backoff
+                    .attempt(ProxyConnection::new(&*user_info, &*config))
+                    .await
+            }
+        })
+    }
+}
+
+impl<T> ProxyConnectionPool<T>
+where
+    T: PooledConnection + Send + 'static,
+{
+    /// Create the pool filled by the given connector, the connections
+    /// idle in the pool for longer than `max_idle` are evicted. The filler
+    /// closes and replaces the connections older than `max_lifetime` even
+    /// when they are healthy, so the pool is refreshed periodically.
+    pub fn with_connector<F, Fut>(
+        pool_size: usize,
+        max_idle: Duration,
+        max_lifetime: Option<Duration>,
+        connector: F,
+    ) -> Self
+    where
+        F: Fn() -> Fut + Send + 'static,
+        Fut: Future<Output = Result<T, Error>> + Send,
+    {
+        let pool_connections = Arc::new(PoolConnections {
+            connections: Mutex::new(VecDeque::with_capacity(pool_size)),
+            connection_added: Notify::new(),
+            connection_taken: Notify::new(),
+        });
+        let filler_pool_connections = pool_connections.clone();
+        let stop_signal = CancellationToken::new();
+        let filler_stop_signal = stop_signal.clone();
+        tokio::spawn(with_current_metrics_recorder(async move {
+            loop {
+                let connection = tokio::select! {
+                    _ = filler_stop_signal.cancelled() => return,
+                    connection = connector() => connection,
+                };
+                match connection {
+                    Ok(connection) => {
+                        // Wait here until the pool has room for the connection
+                        if !filler_pool_connections
+                            .put_when_room(
+                                Instant::now(),
+                                connection,
+                                pool_size,
+                                max_lifetime,
+                                &filler_stop_signal,
+                            )
+                            .await
+                        {
+                            return;
+                        }
+                    }
+                    Err(Error::ProxyUnhealthy(backoff)) => {
+                        debug!("Pool filler backs off for {backoff:?} with the unhealthy proxy.");
+                        tokio::select! {
+                            _ = filler_stop_signal.cancelled() => return,
+                            _ = tokio::time::sleep(backoff.max(POOL_FILL_RETRY_INTERVAL)) => {}
+                        }
+                    }
+                    Err(e) => {
+                        error!("Fail to create pooled proxy connection: {e:?}");
+                        tokio::select! {
+                            _ = filler_stop_signal.cancelled() => return,
+                            _ = tokio::time::sleep(POOL_FILL_RETRY_INTERVAL) => {}
+                        }
+                    }
+                }
+            }
+        }));
+        Self {
+            pool_connections,
+            max_idle,
+            max_lifetime,
+            stop_signal,
+        }
+    }
+
+    /// Fetch a connection from the pool, `None` means the pool has no
+    /// healthy connection ready within the wait time. The connections
+    /// idle for too long, past the max lifetime or closed by the peer
+    /// are dropped and the next one is tried.
+    pub async fn fetch_connection(&self, wait: Duration) -> Option<T> {
+        let fetch = async {
+            loop {
+                let pooled_connection = self
+                    .pool_connections
+                    .connections
+                    .lock()
+                    .expect("pool lock poisoned")
+                    .pop_front();
+                let Some((created_at, connection)) = pooled_connection else {
+                    // The connections already in the pool can still be fetched after close
+                    if self.stop_signal.is_cancelled() {
+                        return None;
+                    }
+                    tokio::select! {
+                        _ = self.stop_signal.cancelled() => {}
+                        _ = self.pool_connections.connection_added.notified() => {}
+                    }
+                    continue;
+                };
+                self.pool_connections.connection_taken.notify_one();
+                if created_at.elapsed() > self.max_idle {
+                    debug!("Evict pooled proxy connection idle for too long.");
+                    continue;
+                }
+                if self
+                    .max_lifetime
+                    .is_some_and(|max_lifetime| created_at.elapsed() >= max_lifetime)
+                {
+                    debug!("Evict pooled proxy connection past its max lifetime.");
+                    continue;
+                }
+                if !connection.is_alive() {
+                    debug!("Evict pooled proxy connection closed by the peer.");
+                    continue;
+                }
+                return Some(connection);
+            }
+        };
+        let connection = tokio::time::timeout(wait, fetch).await.ok().flatten();
+        record_pool_fetch(connection.is_some());
+        connection
+    }
+}
+
+impl<T> ProxyConnectionPool<T> {
+    /// Stop the filler, the connections already in the pool can still be fetched
+    pub fn close(&self) {
+        self.stop_signal.cancel();
+    }
+}
+
+impl<T> Drop for ProxyConnectionPool<T> {
+    fn drop(&mut self) {
+        self.close();
+    }
+}
+
+#[cfg(test)]
+impl PooledConnection for usize {
+    fn is_alive(&self) -> bool {
+        true
+    }
+}
+
+/// The connection of the tests which is closed by the peer when the flag is `false`
+#[cfg(test)]
+impl PooledConnection for (usize, bool) {
+    fn is_alive(&self) -> bool {
+        self.1
+    }
+}
+
+#[tokio::test]
+async fn test_fetch_connection() {
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    let created = Arc::new(AtomicUsize::new(0));
+    let pool = ProxyConnectionPool::with_connector(2, Duration::from_secs(60), None, {
+        let created = created.clone();
+        move || {
+            let created = created.clone();
+            async move { Ok(created.fetch_add(1, Ordering::SeqCst)) }
+        }
+    });
+    assert_eq!(Some(0), pool.fetch_connection(Duration::from_secs(1)).await);
+    assert_eq!(Some(1), pool.fetch_connection(Duration::from_secs(1)).await);
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    // The filler refills the 2 free slots and stops with one more
+    // connection created and waiting for room
+    assert_eq!(5, created.load(Ordering::SeqCst));
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    assert_eq!(5, created.load(Ordering::SeqCst));
+    let failing_pool =
+        ProxyConnectionPool::<usize>::with_connector(2, Duration::from_secs(60), None, || async {
+            Err(Error::ConnectTimeout(1))
+        });
+    assert_eq!(
+        None,
+        failing_pool
+            .fetch_connection(Duration::from_millis(100))
+            .await
+    );
+}
+
+#[tokio::test]
+async fn test_fetch_connection_health_check() {
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    let counting_connector = |alive: fn(usize) -> bool| {
+        let created = Arc::new(AtomicUsize::new(0));
+        move || {
+            let id = created.fetch_add(1, Ordering::SeqCst);
+            async move { Ok((id, alive(id))) }
+        }
+    };
+    // The odd connections are closed by the peer and skipped
+    let pool = ProxyConnectionPool::with_connector(
+        4,
+        Duration::from_secs(60),
+        None,
+        counting_connector(|id| id % 2 == 0),
+    );
+    assert_eq!(
+        Some((0, true)),
+        pool.fetch_connection(Duration::from_secs(1)).await
+    );
+    assert_eq!(
+        Some((2, true)),
+        pool.fetch_connection(Duration::from_secs(1)).await
+    );
+    let stale_pool = ProxyConnectionPool::with_connector(
+        2,
+        Duration::from_millis(50),
+        None,
+        counting_connector(|_| true),
+    );
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    // The 2 pooled connections and the one waiting for room are idle
+    // for too long, the first fresh connection is created after them
+    assert_eq!(
+        Some((3, true)),
+        stale_pool.fetch_connection(Duration::from_secs(1)).await
+    );
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_fetch_connection_max_lifetime() {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    let created = Arc::new(AtomicUsize::new(0));
+    let pool = ProxyConnectionPool::with_connector(
+        2,
+        Duration::from_secs(600),
+        Some(Duration::from_secs(10)),
+        {
+            let created = created.clone();
+            move || {
+                let id = created.fetch_add(1, Ordering::SeqCst);
+                async move { Ok((id, true)) }
+            }
+        },
+    );
+    tokio::time::sleep(Duration::from_secs(1)).await;
+    // The 2 pooled connections and the one waiting for room
+    assert_eq!(3, created.load(Ordering::SeqCst));
+    tokio::time::sleep(Duration::from_millis(8_500)).await;
+    assert_eq!(3, created.load(Ordering::SeqCst));
+    tokio::time::sleep(Duration::from_secs(1)).await;
+    // The filler closes all of them past the max lifetime without any fetch
+    // and refills the pool with fresh ones
+    assert_eq!(6, created.load(Ordering::SeqCst));
+    assert_eq!(
+        Some((3, true)),
+        pool.fetch_connection(Duration::from_secs(1)).await
+    );
+    assert_eq!(
+        Some((4, true)),
+        pool.fetch_connection(Duration::from_secs(1)).await
+    );
+}
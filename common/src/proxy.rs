@@ -1,32 +1,38 @@
+use crate::backoff::ProxyBackoff;
+use crate::memory::{BufferedRelayBytes, framed_buffered_bytes};
+use crate::metrics::{HandshakeFailureReason, record_handshake_failure};
 use crate::user::UserWithProxyServers;
 use crate::{
-    Error, SecureLengthDelimitedCodec, get_handshake_encryption, random_generate_encryption,
-    rsa_decrypt_encryption, rsa_encrypt_encryption,
+    BatchedFlushWriter, Error, HandshakeTranscript, ProxyConnectionConfig,
+    SecureLengthDelimitedCodec, generate_handshake_challenge, get_handshake_encryption,
+    random_generate_relay_encryption, rsa_decrypt_encryption, rsa_encrypt_encryption,
+    set_socket_ip_tos, verify_handshake_transcript,
 };
 use futures_util::{SinkExt, StreamExt};
 use ppaass_protocol::{
-    ConnectDestinationRequest, ConnectDestinationResponse, HandshakeRequest, HandshakeResponse,
-    UnifiedAddress,
+    ConnectDestinationRequest, ConnectDestinationResponse, ExtendedConnectDestinationRequest,
+    HandshakeRequest, HandshakeResponse, PROTOCOL_VERSION, UnifiedAddress,
+    check_peer_protocol_version,
 };
+use rand::Rng;
 use std::borrow::Cow;
 use std::io::Error as StdIoError;
+use std::net::SocketAddr;
 use std::pin::Pin;
-use std::task::{Context, Poll};
+use std::task::{Context, Poll, Waker};
 use std::time::Duration;
 use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
 use tokio::net::TcpStream;
 use tokio::pin;
 use tokio::time::timeout;
-use tokio_util::bytes::BytesMut;
 use tokio_util::codec::Framed;
-use tokio_util::io::{SinkWriter, StreamReader};
+use tracing::{debug, warn};
 
 pub type ProxyFramed<'a> = Framed<TcpStream, SecureLengthDelimitedCodec<'a>>;
-pub type ProxyFramedReadWrite<'a> = SinkWriter<StreamReader<ProxyFramed<'a>, BytesMut>>;
+pub type ProxyFramedReadWrite<'a> = BatchedFlushWriter<TcpStream, SecureLengthDelimitedCodec<'a>>;
 
 pub enum DestinationType {
     Tcp,
-    #[allow(unused)]
     Udp,
 }
 
@@ -36,39 +42,191 @@ pub struct Init;
 /// The proxy connection.
 pub struct ProxyConnection<T> {
     state: T,
+    /// The relay bytes buffered by the connection, reported once it relays
+    buffered_relay_bytes: BufferedRelayBytes,
+    /// The connect timeout hint sent with the connect destination request
+    connect_timeout_hint: Option<Duration>,
+    /// The interval the flushes of the relay frames are deferred by
+    relay_flush_interval: Option<Duration>,
 }
 
 impl ProxyConnection<Init> {
-    pub async fn new<'a, U>(
+    /// Create the proxy connection to the proxy servers of the user, they are
+    /// tried in the order of the proxy server policy until one is connected.
+    pub async fn new<'a, U, C>(
         user_info: &U,
-        connect_timeout: u64,
+        config: &C,
     ) -> Result<ProxyConnection<ProxyFramed<'a>>, Error>
     where
         U: UserWithProxyServers + Send + Sync + 'static,
+        C: ProxyConnectionConfig,
     {
+        let proxy_servers = config
+            .proxy_server_policy()
+            .order(user_info.proxy_servers(), user_info.round_robin_next());
+        Self::new_with_proxy_servers(user_info, config, &proxy_servers).await
+    }
+
+    /// Create the proxy connection, the connect and the handshake are retried
+    /// up to `max_retries` times while the proxy is unreachable. The backoff
+    /// doubles from `base_backoff` on every retry with a random jitter of up
+    /// to half of it, the error of the last attempt is returned.
+    /// Every pass goes through the shared backoff, so the first failed pass
+    /// backs off the other attempts at once and the retries wait for the
+    /// shared backoff too. The whole attempt fails with
+    /// [`Error::ProxyConnectDeadline`] once the deadline passes.
+    pub async fn new_with_retry<'a, U, C>(
+        user_info: &U,
+        config: &C,
+        backoff: &ProxyBackoff,
+        max_retries: u32,
+        base_backoff: Duration,
+        deadline: Option<Duration>,
+    ) -> Result<ProxyConnection<ProxyFramed<'a>>, Error>
+    where
+        U: UserWithProxyServers + Send + Sync + 'static,
+        C: ProxyConnectionConfig,
+    {
+        let connect_with_retry = async {
+            let mut retried = 0;
+            loop {
+                match backoff.attempt(Self::new(user_info, config)).await {
+                    // The attempt backed off before its own failure fails fast
+                    Err(e)
+                        if (e.is_proxy_unreachable()
+                            || retried > 0 && matches!(e, Error::ProxyUnhealthy(_)))
+                            && retried < max_retries =>
+                    {
+                        let retry_backoff =
+                            base_backoff.saturating_mul(2u32.saturating_pow(retried));
+                        let mut retry_backoff = retry_backoff
+                            + retry_backoff.mul_f64(rand::rng().random_range(0.0..0.5));
+                        if let Error::ProxyUnhealthy(remaining) = &e {
+                            retry_backoff = retry_backoff.max(*remaining);
+                        }
+                        retried += 1;
+                        debug!(
+                            "Retry connecting to proxy [{retried}/{max_retries}] in {retry_backoff:?}: {e:?}"
+                        );
+                        tokio::time::sleep(retry_backoff).await;
+                    }
+                    result => return result,
+                }
+            }
+        };
+        match deadline {
+            None => connect_with_retry.await,
+            Some(deadline) => timeout(deadline, connect_with_retry)
+                .await
+                .map_err(|_| Error::ProxyConnectDeadline(deadline))?,
+        }
+    }
+
+    /// Create the proxy connection to one of the given proxy servers instead
+    /// of the proxy servers of the user. The servers are tried in order, any
+    /// failure of the connect or the handshake moves on to the next server
+    /// and the error of the last server is returned when all of them fail.
+    pub async fn new_with_proxy_servers<'a, U, C>(
+        user_info: &U,
+        config: &C,
+        proxy_servers: &[SocketAddr],
+    ) -> Result<ProxyConnection<ProxyFramed<'a>>, Error>
+    where
+        U: UserWithProxyServers + Send + Sync + 'static,
+        C: ProxyConnectionConfig,
+    {
+        let mut last_error = None;
+        for proxy_server in proxy_servers {
+            match Self::new_with_proxy_server(user_info, config, *proxy_server).await {
+                Ok(proxy_connection) => return Ok(proxy_connection),
+                Err(e) => {
+                    warn!("Fail to connect proxy server [{proxy_server}]: {e:?}");
+                    last_error = Some(e);
+                }
+            }
+        }
+        Err(last_error
+            .unwrap_or_else(|| Error::NoProxyServersConfigured(user_info.username().clone())))
+    }
+
+    /// Create the proxy connection to the proxy server, the handshake is
+    /// retried on the decrypt failures.
+    async fn new_with_proxy_server<'a, U, C>(
+        user_info: &U,
+        config: &C,
+        proxy_server: SocketAddr,
+    ) -> Result<ProxyConnection<ProxyFramed<'a>>, Error>
+    where
+        U: UserWithProxyServers + Send + Sync + 'static,
+        C: ProxyConnectionConfig,
+    {
+        let handshake_decrypt_retries = config.handshake_decrypt_retries();
+        let mut retried = 0;
+        loop {
+            match Self::handshake(user_info, config, proxy_server).await {
+                Err(e)
+                    if e.is_handshake_decrypt_failure() && retried < handshake_decrypt_retries =>
+                {
+                    retried += 1;
+                    warn!(
+                        "Retry handshake with proxy [{retried}/{handshake_decrypt_retries}] because of decrypt failure: {e:?}"
+                    );
+                    tokio::time::sleep(Duration::from_millis(
+                        config.handshake_retry_delay_millis(),
+                    ))
+                    .await;
+                }
+                result => {
+                    return result.inspect_err(|e| {
+                        record_handshake_failure(HandshakeFailureReason::from(e))
+                    });
+                }
+            }
+        }
+    }
+
+    /// Connect to the proxy and do the handshake once, the connect failures
+    /// and the decrypt failures are returned as different errors.
+    async fn handshake<'a, U, C>(
+        user_info: &U,
+        config: &C,
+        proxy_server: SocketAddr,
+    ) -> Result<ProxyConnection<ProxyFramed<'a>>, Error>
+    where
+        U: UserWithProxyServers + Send + Sync + 'static,
+        C: ProxyConnectionConfig,
+    {
+        let connect_timeout = config.proxy_connect_timeout();
         let mut proxy_stream = timeout(
             Duration::from_secs(connect_timeout),
-            TcpStream::connect(user_info.proxy_servers()),
+            config.socket_options().connect(proxy_server),
         )
         .await
         .map_err(|_| Error::ConnectTimeout(connect_timeout))??;
+        config.socket_options().apply(&proxy_stream)?;
         let mut handshake_framed = Framed::new(
             &mut proxy_stream,
             SecureLengthDelimitedCodec::new(
                 Cow::Borrowed(get_handshake_encryption()),
                 Cow::Borrowed(get_handshake_encryption()),
-            ),
+            )
+            .with_max_frame_length(config.handshake_max_frame_length()),
         );
-        let agent_encryption = random_generate_encryption();
+        let agent_encryption = random_generate_relay_encryption(config.relay_per_frame_iv());
         let rsa_encrypted_agent_encryption = rsa_encrypt_encryption(
             &agent_encryption,
             user_info
                 .rsa_crypto()
                 .ok_or(Error::UserRsaCryptoNotExist(user_info.username().clone()))?,
         )?;
+        let challenge = generate_handshake_challenge();
         let client_handshake_request = HandshakeRequest {
+            version: PROTOCOL_VERSION,
             username: user_info.username().to_owned(),
             encryption: rsa_encrypted_agent_encryption.into_owned(),
+            tag: config.connection_tag().map(ToOwned::to_owned),
+            challenge: challenge.clone(),
+            compression: config.relay_compression_level().is_some(),
         };
         let client_handshake_request_bytes: Vec<u8> = client_handshake_request.try_into()?;
         handshake_framed
@@ -82,41 +240,143 @@ impl ProxyConnection<Init> {
                     "Fail to read handshake message from proxy: {}",
                     proxy_stream.peer_addr()?
                 )))??;
-        let rsa_encrypted_proxy_handshake: HandshakeResponse = proxy_handshake_bytes.try_into()?;
-        let proxy_encryption = rsa_decrypt_encryption(
-            rsa_encrypted_proxy_handshake.encryption,
-            user_info.rsa_crypto().ok_or(Error::UserRsaCryptoNotExist(
-                user_info.username().to_owned(),
-            ))?,
+        let (version, rsa_encrypted_proxy_encryption, challenge_signature, compression_accepted) =
+            match proxy_handshake_bytes.try_into()? {
+                HandshakeResponse::Success {
+                    version,
+                    encryption,
+                    challenge_signature,
+                    compression,
+                } => (version, encryption, challenge_signature, compression),
+                HandshakeResponse::Failure { reason } => {
+                    return Err(Error::HandshakeRejected(reason));
+                }
+            };
+        let rsa_crypto = user_info.rsa_crypto().ok_or(Error::UserRsaCryptoNotExist(
+            user_info.username().to_owned(),
+        ))?;
+        check_peer_protocol_version(version)?;
+        verify_handshake_transcript(
+            HandshakeTranscript {
+                challenge: &challenge,
+                request: &client_handshake_request_bytes,
+                version,
+                encryption: &rsa_encrypted_proxy_encryption,
+                compression: compression_accepted,
+            },
+            &challenge_signature,
+            rsa_crypto,
         )?;
-        let proxy_framed = Framed::new(
-            proxy_stream,
-            SecureLengthDelimitedCodec::new(
-                Cow::Owned(proxy_encryption),
-                Cow::Owned(agent_encryption),
-            ),
-        );
+        debug!("Handshake with proxy in protocol version {version}");
+        let proxy_encryption = rsa_decrypt_encryption(rsa_encrypted_proxy_encryption, rsa_crypto)?;
+        let mut relay_codec = SecureLengthDelimitedCodec::new(
+            Cow::Owned(proxy_encryption),
+            Cow::Owned(agent_encryption),
+        )
+        .with_max_chunk_size(config.relay_max_chunk_size())
+        .with_per_frame_iv(config.relay_per_frame_iv())
+        .with_length_field_length(config.relay_length_field_length())?;
+        if let Some(relay_compression_level) = config.relay_compression_level()
+            && compression_accepted
+        {
+            relay_codec = relay_codec.with_compression(relay_compression_level);
+        }
+        let mut proxy_framed = Framed::new(proxy_stream, relay_codec);
+        if let Some(relay_write_buffer_size) = config.relay_write_buffer_size() {
+            proxy_framed.set_backpressure_boundary(relay_write_buffer_size);
+        }
         Ok(ProxyConnection {
             state: proxy_framed,
+            buffered_relay_bytes: BufferedRelayBytes::new(),
+            connect_timeout_hint: None,
+            relay_flush_interval: config
+                .relay_flush_interval_millis()
+                .map(Duration::from_millis),
         })
     }
 }
 
 impl<'a> ProxyConnection<ProxyFramed<'a>> {
+    /// Mark the packets sent to the proxy with the IP ToS/DSCP value,
+    /// it overrides the value of the socket options.
+    pub fn set_ip_tos(&self, ip_tos: u32) -> Result<(), Error> {
+        Ok(set_socket_ip_tos(self.state.get_ref(), ip_tos)?)
+    }
+
+    /// Ask the proxy to give up connecting the destination after the hint, the
+    /// proxy still gives up after its own connect timeout when it is shorter.
+    pub fn with_connect_timeout_hint(mut self, connect_timeout_hint: Option<Duration>) -> Self {
+        self.connect_timeout_hint = connect_timeout_hint;
+        self
+    }
+
+    /// Check the connection without blocking, the connection is not
+    /// alive when the proxy closed it or sent data nobody asked for.
+    pub fn is_alive(&self) -> bool {
+        let mut cx = Context::from_waker(Waker::noop());
+        let mut buf = [0u8; 1];
+        let mut buf = ReadBuf::new(&mut buf);
+        match self.state.get_ref().poll_peek(&mut cx, &mut buf) {
+            Poll::Pending => self.state.read_buffer().is_empty(),
+            Poll::Ready(_) => false,
+        }
+    }
+
     pub async fn connect_destination(
         self,
         destination_addr: UnifiedAddress,
         destination_type: DestinationType,
+    ) -> Result<ProxyConnection<ProxyFramedReadWrite<'a>>, Error> {
+        self.setup_destination(destination_addr, destination_type, None)
+            .await
+    }
+
+    /// Connect the destination with the initial payload which is known before
+    /// the destination connected, for example the TLS ClientHello. The payload
+    /// is sent right after the connect destination request without waiting for
+    /// the response of the proxy, so one round trip is saved, and it reaches the
+    /// destination before any further relayed bytes.
+    pub async fn connect_destination_with_data(
+        self,
+        destination_addr: UnifiedAddress,
+        destination_type: DestinationType,
+        initial_payload: &[u8],
+    ) -> Result<ProxyConnection<ProxyFramedReadWrite<'a>>, Error> {
+        self.setup_destination(destination_addr, destination_type, Some(initial_payload))
+            .await
+    }
+
+    async fn setup_destination(
+        self,
+        destination_addr: UnifiedAddress,
+        destination_type: DestinationType,
+        initial_payload: Option<&[u8]>,
     ) -> Result<ProxyConnection<ProxyFramedReadWrite<'a>>, Error> {
         let mut proxy_framed = self.state;
         let connect_destination_request = match destination_type {
             DestinationType::Tcp => ConnectDestinationRequest::Tcp(destination_addr.clone()),
             DestinationType::Udp => ConnectDestinationRequest::Udp(destination_addr.clone()),
         };
-        let connect_destination_request_bytes: Vec<u8> = connect_destination_request.try_into()?;
-        proxy_framed
-            .send(&connect_destination_request_bytes)
-            .await?;
+        let connect_destination_request_bytes: Vec<u8> = ExtendedConnectDestinationRequest {
+            request: connect_destination_request,
+            connect_timeout_hint_millis: self
+                .connect_timeout_hint
+                .map(|connect_timeout_hint| connect_timeout_hint.as_millis() as u64),
+        }
+        .try_into()?;
+        match initial_payload {
+            None => {
+                proxy_framed
+                    .send(&connect_destination_request_bytes)
+                    .await?;
+            }
+            Some(initial_payload) => {
+                proxy_framed
+                    .feed(&connect_destination_request_bytes)
+                    .await?;
+                proxy_framed.send(initial_payload).await?;
+            }
+        }
         let connect_destination_response_bytes = proxy_framed
             .next()
             .await
@@ -124,22 +384,38 @@ impl<'a> ProxyConnection<ProxyFramed<'a>> {
         let connect_destination_response = connect_destination_response_bytes.try_into()?;
         match connect_destination_response {
             ConnectDestinationResponse::Success => Ok(ProxyConnection {
-                state: SinkWriter::new(StreamReader::new(proxy_framed)),
+                state: BatchedFlushWriter::new(proxy_framed, self.relay_flush_interval),
+                buffered_relay_bytes: self.buffered_relay_bytes,
+                connect_timeout_hint: self.connect_timeout_hint,
+                relay_flush_interval: self.relay_flush_interval,
             }),
             ConnectDestinationResponse::Fail => Err(Error::ConnectDestination(destination_addr)),
+            ConnectDestinationResponse::Refused => {
+                Err(Error::DestinationObserved(destination_addr))
+            }
         }
     }
 }
 
+impl<'a> ProxyConnection<ProxyFramedReadWrite<'a>> {
+    fn report_buffered_relay_bytes(&mut self) {
+        self.buffered_relay_bytes
+            .update(framed_buffered_bytes(&self.state));
+    }
+}
+
 impl<'a> AsyncRead for ProxyConnection<ProxyFramedReadWrite<'a>> {
     fn poll_read(
         self: Pin<&mut Self>,
         cx: &mut Context<'_>,
         buf: &mut ReadBuf<'_>,
     ) -> Poll<std::io::Result<()>> {
-        let proxy_framed = &mut self.get_mut().state;
+        let this = self.get_mut();
+        let proxy_framed = &mut this.state;
         pin!(proxy_framed);
-        proxy_framed.poll_read(cx, buf)
+        let result = proxy_framed.poll_read(cx, buf);
+        this.report_buffered_relay_bytes();
+        result
     }
 }
 
@@ -149,18 +425,699 @@ impl<'a> AsyncWrite for ProxyConnection<ProxyFramedReadWrite<'a>> {
         cx: &mut Context<'_>,
         buf: &[u8],
     ) -> Poll<Result<usize, StdIoError>> {
-        let proxy_framed = &mut self.get_mut().state;
+        let this = self.get_mut();
+        let proxy_framed = &mut this.state;
         pin!(proxy_framed);
-        proxy_framed.poll_write(cx, buf)
+        let result = proxy_framed.poll_write(cx, buf);
+        this.report_buffered_relay_bytes();
+        result
     }
     fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), StdIoError>> {
-        let proxy_framed = &mut self.get_mut().state;
+        let this = self.get_mut();
+        let proxy_framed = &mut this.state;
         pin!(proxy_framed);
-        proxy_framed.poll_flush(cx)
+        let result = proxy_framed.poll_flush(cx);
+        this.report_buffered_relay_bytes();
+        result
     }
     fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), StdIoError>> {
-        let proxy_framed = &mut self.get_mut().state;
+        let this = self.get_mut();
+        let proxy_framed = &mut this.state;
         pin!(proxy_framed);
-        proxy_framed.poll_shutdown(cx)
+        let result = proxy_framed.poll_shutdown(cx);
+        this.report_buffered_relay_bytes();
+        result
+    }
+}
+
+#[tokio::test]
+async fn test_connect_destination_with_data() -> Result<(), Error> {
+    use tokio::io::AsyncWriteExt;
+    let codec = || {
+        SecureLengthDelimitedCodec::new(
+            Cow::Borrowed(get_handshake_encryption()),
+            Cow::Borrowed(get_handshake_encryption()),
+        )
+    };
+    let proxy_listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+    let proxy_addr = proxy_listener.local_addr()?;
+    let proxy_task = tokio::spawn(async move {
+        let (proxy_stream, _) = proxy_listener.accept().await?;
+        let mut proxy_framed = Framed::new(proxy_stream, codec());
+        let connect_destination_request: ConnectDestinationRequest =
+            proxy_framed.next().await.unwrap()?.try_into()?;
+        assert!(matches!(
+            connect_destination_request,
+            ConnectDestinationRequest::Tcp(_)
+        ));
+        let connect_destination_response_bytes: Vec<u8> =
+            ConnectDestinationResponse::Success.try_into()?;
+        proxy_framed
+            .send(&connect_destination_response_bytes)
+            .await?;
+        let mut relayed_frames = Vec::new();
+        while let Some(frame) = proxy_framed.next().await {
+            relayed_frames.push(frame?.to_vec());
+        }
+        Ok::<Vec<Vec<u8>>, Error>(relayed_frames)
+    });
+    let proxy_connection = ProxyConnection {
+        state: Framed::new(TcpStream::connect(proxy_addr).await?, codec()),
+        buffered_relay_bytes: BufferedRelayBytes::new(),
+        connect_timeout_hint: None,
+        relay_flush_interval: None,
+    };
+    let mut proxy_connection = proxy_connection
+        .connect_destination_with_data(
+            UnifiedAddress::Domain {
+                host: "www.example.com".to_string(),
+                port: 443,
+            },
+            DestinationType::Tcp,
+            b"client hello",
+        )
+        .await?;
+    proxy_connection.write_all(b"relay data").await?;
+    proxy_connection.shutdown().await?;
+    drop(proxy_connection);
+    let relayed_frames = proxy_task.await.unwrap()?;
+    assert_eq!(
+        vec![b"client hello".to_vec(), b"relay data".to_vec()],
+        relayed_frames
+    );
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_connect_destination_timeout_hint() -> Result<(), Error> {
+    let codec = || {
+        SecureLengthDelimitedCodec::new(
+            Cow::Borrowed(get_handshake_encryption()),
+            Cow::Borrowed(get_handshake_encryption()),
+        )
+    };
+    let dst_addr = UnifiedAddress::Domain {
+        host: "www.example.com".to_string(),
+        port: 443,
+    };
+    let proxy_listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+    let proxy_addr = proxy_listener.local_addr()?;
+    let proxy_task = tokio::spawn(async move {
+        let (proxy_stream, _) = proxy_listener.accept().await?;
+        let mut proxy_framed = Framed::new(proxy_stream, codec());
+        let connect_destination_request_bytes = proxy_framed.next().await.unwrap()?;
+        let extended_request: ExtendedConnectDestinationRequest =
+            connect_destination_request_bytes.try_into()?;
+        let connect_destination_response_bytes: Vec<u8> =
+            ConnectDestinationResponse::Success.try_into()?;
+        proxy_framed
+            .send(&connect_destination_response_bytes)
+            .await?;
+        Ok::<ExtendedConnectDestinationRequest, Error>(extended_request)
+    });
+    let proxy_connection = ProxyConnection {
+        state: Framed::new(TcpStream::connect(proxy_addr).await?, codec()),
+        buffered_relay_bytes: BufferedRelayBytes::new(),
+        connect_timeout_hint: None,
+        relay_flush_interval: None,
+    }
+    .with_connect_timeout_hint(Some(Duration::from_millis(1500)));
+    proxy_connection
+        .connect_destination(dst_addr.clone(), DestinationType::Tcp)
+        .await?;
+    let extended_request = proxy_task.await.unwrap()?;
+    assert_eq!(
+        ConnectDestinationRequest::Tcp(dst_addr),
+        extended_request.request
+    );
+    assert_eq!(Some(1500), extended_request.connect_timeout_hint_millis);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_connect_longest_domain() -> Result<(), Error> {
+    use ppaass_protocol::{DOMAIN_HOST_MAX_LENGTH, MAX_CONTROL_MESSAGE_LENGTH};
+    // The smallest frames allowed still carry the largest connect destination request whole
+    let codec = || {
+        SecureLengthDelimitedCodec::new(
+            Cow::Borrowed(get_handshake_encryption()),
+            Cow::Borrowed(get_handshake_encryption()),
+        )
+        .with_max_chunk_size(Some(MAX_CONTROL_MESSAGE_LENGTH))
+        .with_length_field_length(crate::MIN_LENGTH_FIELD_LENGTH)
+        .unwrap()
+    };
+    let dst_addr = UnifiedAddress::Domain {
+        host: "a".repeat(DOMAIN_HOST_MAX_LENGTH),
+        port: u16::MAX,
+    };
+    let proxy_listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+    let proxy_addr = proxy_listener.local_addr()?;
+    let proxy_task = tokio::spawn(async move {
+        let (proxy_stream, _) = proxy_listener.accept().await?;
+        let mut proxy_framed = Framed::new(proxy_stream, codec());
+        let connect_destination_request_bytes = proxy_framed.next().await.unwrap()?;
+        let extended_request: ExtendedConnectDestinationRequest =
+            connect_destination_request_bytes.try_into()?;
+        let connect_destination_response_bytes: Vec<u8> =
+            ConnectDestinationResponse::Success.try_into()?;
+        proxy_framed
+            .send(&connect_destination_response_bytes)
+            .await?;
+        Ok::<ExtendedConnectDestinationRequest, Error>(extended_request)
+    });
+    let proxy_connection = ProxyConnection {
+        state: Framed::new(TcpStream::connect(proxy_addr).await?, codec()),
+        buffered_relay_bytes: BufferedRelayBytes::new(),
+        connect_timeout_hint: None,
+        relay_flush_interval: None,
+    }
+    .with_connect_timeout_hint(Some(Duration::from_millis(u64::MAX)));
+    proxy_connection
+        .connect_destination(dst_addr.clone(), DestinationType::Udp)
+        .await?;
+    assert_eq!(
+        ConnectDestinationRequest::Udp(dst_addr),
+        proxy_task.await.unwrap()?.request
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+struct TestProxyUser {
+    proxy_servers: Vec<crate::user::ProxyServer>,
+    round_robin_next: std::sync::atomic::AtomicUsize,
+    rsa_crypto: ppaass_crypto::RsaCrypto,
+    username: ppaass_protocol::Username,
+}
+
+#[cfg(test)]
+impl crate::user::User for TestProxyUser {
+    fn username(&self) -> &ppaass_protocol::Username {
+        &self.username
+    }
+    fn rsa_crypto(&self) -> Option<&ppaass_crypto::RsaCrypto> {
+        Some(&self.rsa_crypto)
+    }
+    fn set_rsa_crypto(&mut self, rsa_crypto: ppaass_crypto::RsaCrypto) {
+        self.rsa_crypto = rsa_crypto;
+    }
+}
+
+#[cfg(test)]
+impl UserWithProxyServers for TestProxyUser {
+    fn proxy_servers(&self) -> &[crate::user::ProxyServer] {
+        &self.proxy_servers
+    }
+    fn round_robin_next(&self) -> &std::sync::atomic::AtomicUsize {
+        &self.round_robin_next
     }
 }
+
+#[cfg(test)]
+struct TestProxyConnectionConfig {
+    handshake_decrypt_retries: u32,
+}
+
+#[cfg(test)]
+impl ProxyConnectionConfig for TestProxyConnectionConfig {
+    fn proxy_connect_timeout(&self) -> u64 {
+        5
+    }
+    fn relay_max_chunk_size(&self) -> Option<usize> {
+        None
+    }
+    fn connection_tag(&self) -> Option<&str> {
+        None
+    }
+    fn relay_write_buffer_size(&self) -> Option<usize> {
+        None
+    }
+    fn relay_flush_interval_millis(&self) -> Option<u64> {
+        None
+    }
+    fn handshake_max_frame_length(&self) -> usize {
+        crate::DEFAULT_HANDSHAKE_MAX_FRAME_LENGTH
+    }
+    fn socket_options(&self) -> crate::SocketOptions {
+        crate::SocketOptions::default()
+    }
+    fn relay_per_frame_iv(&self) -> bool {
+        false
+    }
+    fn relay_length_field_length(&self) -> usize {
+        crate::DEFAULT_LENGTH_FIELD_LENGTH
+    }
+    fn handshake_decrypt_retries(&self) -> u32 {
+        self.handshake_decrypt_retries
+    }
+    fn handshake_retry_delay_millis(&self) -> u64 {
+        10
+    }
+    fn relay_compression_level(&self) -> Option<i32> {
+        None
+    }
+    fn proxy_server_policy(&self) -> crate::balance::ProxyServerPolicy {
+        crate::balance::ProxyServerPolicy::FirstHealthy
+    }
+}
+
+/// Reply the handshake of the agent as the proxy, the given encryption is
+/// replied instead of the one encrypted with the proxy key
+#[cfg(test)]
+async fn reply_test_handshake(
+    proxy_stream: TcpStream,
+    proxy_rsa_crypto: &ppaass_crypto::RsaCrypto,
+    encryption: Option<ppaass_protocol::Encryption>,
+) -> Result<(), Error> {
+    use crate::sign_handshake_transcript;
+    let mut handshake_framed = Framed::new(
+        proxy_stream,
+        SecureLengthDelimitedCodec::new(
+            Cow::Borrowed(get_handshake_encryption()),
+            Cow::Borrowed(get_handshake_encryption()),
+        ),
+    );
+    let handshake_request_bytes = handshake_framed.next().await.unwrap()?.freeze();
+    let handshake_request: HandshakeRequest = handshake_request_bytes.clone().try_into()?;
+    let encryption = match encryption {
+        Some(encryption) => encryption,
+        None => rsa_encrypt_encryption(&crate::random_generate_encryption(), proxy_rsa_crypto)?
+            .into_owned(),
+    };
+    let challenge_signature = sign_handshake_transcript(
+        HandshakeTranscript {
+            challenge: &handshake_request.challenge,
+            request: &handshake_request_bytes,
+            version: PROTOCOL_VERSION,
+            encryption: &encryption,
+            compression: false,
+        },
+        proxy_rsa_crypto,
+    )?;
+    let handshake_response = HandshakeResponse::Success {
+        version: PROTOCOL_VERSION,
+        encryption,
+        challenge_signature,
+        compression: false,
+    };
+    let handshake_response_bytes: Vec<u8> = handshake_response.try_into()?;
+    handshake_framed.send(&handshake_response_bytes).await?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_handshake_decrypt_retry() -> Result<(), Error> {
+    use ppaass_crypto::RsaCrypto;
+    use ppaass_protocol::Encryption;
+    use std::fs::File;
+    use std::path::Path;
+    let agent_user_dir = Path::new("../resources/agent/user/user1");
+    let proxy_user_dir = Path::new("../resources/proxy/user/user1");
+    let proxy_rsa_crypto = RsaCrypto::new(
+        File::open(proxy_user_dir.join("AgentPublicKey.pem"))?,
+        File::open(proxy_user_dir.join("ProxyPrivateKey.pem"))?,
+    )?;
+    let proxy_listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+    let proxy_addr = proxy_listener.local_addr()?;
+    // The first handshake is rejected as the proxy can not decrypt the agent
+    // encryption, the second replies the encryption the agent can not decrypt,
+    // as if they were encrypted with the keys being rotated out
+    let proxy_task = tokio::spawn(async move {
+        let (proxy_stream, _) = proxy_listener.accept().await?;
+        let mut handshake_framed = Framed::new(
+            proxy_stream,
+            SecureLengthDelimitedCodec::new(
+                Cow::Borrowed(get_handshake_encryption()),
+                Cow::Borrowed(get_handshake_encryption()),
+            ),
+        );
+        let _: HandshakeRequest = handshake_framed.next().await.unwrap()?.try_into()?;
+        let handshake_response_bytes: Vec<u8> = HandshakeResponse::Failure {
+            reason: ppaass_protocol::HandshakeError::InvalidEncryption,
+        }
+        .try_into()?;
+        handshake_framed.send(&handshake_response_bytes).await?;
+        for handshake_index in 0..2 {
+            let (proxy_stream, _) = proxy_listener.accept().await?;
+            let encryption = (handshake_index == 0).then(|| {
+                Encryption::Aes(tokio_util::bytes::Bytes::from_static(
+                    b"not encrypted with agent key",
+                ))
+            });
+            reply_test_handshake(proxy_stream, &proxy_rsa_crypto, encryption).await?;
+        }
+        Ok::<(), Error>(())
+    });
+    let user_info = TestProxyUser {
+        round_robin_next: Default::default(),
+        proxy_servers: vec![proxy_addr.into()],
+        rsa_crypto: RsaCrypto::new(
+            File::open(agent_user_dir.join("ProxyPublicKey.pem"))?,
+            File::open(agent_user_dir.join("AgentPrivateKey.pem"))?,
+        )?,
+        username: "user1".into(),
+    };
+    ProxyConnection::new(
+        &user_info,
+        &TestProxyConnectionConfig {
+            handshake_decrypt_retries: 2,
+        },
+    )
+    .await?;
+    proxy_task.await.unwrap()?;
+    // The refused connection fails fast without retry
+    let closed_listener = std::net::TcpListener::bind("127.0.0.1:0")?;
+    let closed_addr = closed_listener.local_addr()?;
+    drop(closed_listener);
+    let refused_user_info = TestProxyUser {
+        round_robin_next: Default::default(),
+        proxy_servers: vec![closed_addr.into()],
+        ..user_info
+    };
+    let result = ProxyConnection::new(
+        &refused_user_info,
+        &TestProxyConnectionConfig {
+            handshake_decrypt_retries: 2,
+        },
+    )
+    .await;
+    assert!(matches!(result, Err(Error::Io(_))));
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_handshake_substituted_token_rejected() -> Result<(), Error> {
+    use crate::sign_handshake_transcript;
+    use ppaass_crypto::RsaCrypto;
+    use std::fs::File;
+    use std::path::Path;
+    let agent_user_dir = Path::new("../resources/agent/user/user1");
+    let proxy_user_dir = Path::new("../resources/proxy/user/user1");
+    let proxy_rsa_crypto = RsaCrypto::new(
+        File::open(proxy_user_dir.join("AgentPublicKey.pem"))?,
+        File::open(proxy_user_dir.join("ProxyPrivateKey.pem"))?,
+    )?;
+    let agent_rsa_crypto = RsaCrypto::new(
+        File::open(agent_user_dir.join("ProxyPublicKey.pem"))?,
+        File::open(agent_user_dir.join("AgentPrivateKey.pem"))?,
+    )?;
+    let proxy_listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+    let proxy_addr = proxy_listener.local_addr()?;
+    // The man in the middle relays the signature of the real proxy but
+    // replaces the token with its own, encrypted with the public key
+    let mitm_task = tokio::spawn(async move {
+        let (proxy_stream, _) = proxy_listener.accept().await?;
+        let mut handshake_framed = Framed::new(
+            proxy_stream,
+            SecureLengthDelimitedCodec::new(
+                Cow::Borrowed(get_handshake_encryption()),
+                Cow::Borrowed(get_handshake_encryption()),
+            ),
+        );
+        let handshake_request_bytes = handshake_framed.next().await.unwrap()?.freeze();
+        let handshake_request: HandshakeRequest = handshake_request_bytes.clone().try_into()?;
+        let proxy_encryption =
+            rsa_encrypt_encryption(&crate::random_generate_encryption(), &proxy_rsa_crypto)?
+                .into_owned();
+        let challenge_signature = sign_handshake_transcript(
+            HandshakeTranscript {
+                challenge: &handshake_request.challenge,
+                request: &handshake_request_bytes,
+                version: PROTOCOL_VERSION,
+                encryption: &proxy_encryption,
+                compression: false,
+            },
+            &proxy_rsa_crypto,
+        )?;
+        let substituted_encryption =
+            rsa_encrypt_encryption(&crate::random_generate_encryption(), &proxy_rsa_crypto)?
+                .into_owned();
+        let handshake_response_bytes: Vec<u8> = HandshakeResponse::Success {
+            version: PROTOCOL_VERSION,
+            encryption: substituted_encryption,
+            challenge_signature,
+            compression: false,
+        }
+        .try_into()?;
+        handshake_framed.send(&handshake_response_bytes).await?;
+        Ok::<(), Error>(())
+    });
+    let user_info = TestProxyUser {
+        round_robin_next: Default::default(),
+        proxy_servers: vec![proxy_addr.into()],
+        rsa_crypto: agent_rsa_crypto,
+        username: "user1".into(),
+    };
+    let result = ProxyConnection::new(
+        &user_info,
+        &TestProxyConnectionConfig {
+            handshake_decrypt_retries: 0,
+        },
+    )
+    .await;
+    assert!(matches!(result, Err(Error::ProxyAuthenticationFail)));
+    mitm_task.await.unwrap()?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_new_with_retry() -> Result<(), Error> {
+    use ppaass_crypto::RsaCrypto;
+    use std::fs::File;
+    use std::path::Path;
+    let agent_user_dir = Path::new("../resources/agent/user/user1");
+    let proxy_user_dir = Path::new("../resources/proxy/user/user1");
+    let proxy_rsa_crypto = RsaCrypto::new(
+        File::open(proxy_user_dir.join("AgentPublicKey.pem"))?,
+        File::open(proxy_user_dir.join("ProxyPrivateKey.pem"))?,
+    )?;
+    let closed_listener = std::net::TcpListener::bind("127.0.0.1:0")?;
+    let proxy_addr = closed_listener.local_addr()?;
+    drop(closed_listener);
+    let user_info = TestProxyUser {
+        round_robin_next: Default::default(),
+        proxy_servers: vec![proxy_addr.into()],
+        rsa_crypto: RsaCrypto::new(
+            File::open(agent_user_dir.join("ProxyPublicKey.pem"))?,
+            File::open(agent_user_dir.join("AgentPrivateKey.pem"))?,
+        )?,
+        username: "user1".into(),
+    };
+    let config = TestProxyConnectionConfig {
+        handshake_decrypt_retries: 0,
+    };
+    let no_backoff = ProxyBackoff::new(Duration::ZERO, Duration::ZERO);
+    let result = ProxyConnection::new_with_retry(
+        &user_info,
+        &config,
+        &no_backoff,
+        0,
+        Duration::from_millis(50),
+        None,
+    )
+    .await;
+    assert!(matches!(result, Err(Error::Io(_))));
+    // The proxy comes back while the agent is retrying
+    let proxy_task = tokio::spawn(async move {
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        let proxy_listener = tokio::net::TcpListener::bind(proxy_addr).await?;
+        let (proxy_stream, _) = proxy_listener.accept().await?;
+        reply_test_handshake(proxy_stream, &proxy_rsa_crypto, None).await
+    });
+    ProxyConnection::new_with_retry(
+        &user_info,
+        &config,
+        &no_backoff,
+        5,
+        Duration::from_millis(50),
+        None,
+    )
+    .await?;
+    proxy_task.await.unwrap()?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_new_with_retry_backoff_and_deadline() -> Result<(), Error> {
+    use ppaass_crypto::RsaCrypto;
+    use std::fs::File;
+    use std::path::Path;
+    let agent_user_dir = Path::new("../resources/agent/user/user1");
+    let closed_listener = std::net::TcpListener::bind("127.0.0.1:0")?;
+    let closed_addr = closed_listener.local_addr()?;
+    drop(closed_listener);
+    let user_info = std::sync::Arc::new(TestProxyUser {
+        round_robin_next: Default::default(),
+        proxy_servers: vec![closed_addr.into()],
+        rsa_crypto: RsaCrypto::new(
+            File::open(agent_user_dir.join("ProxyPublicKey.pem"))?,
+            File::open(agent_user_dir.join("AgentPrivateKey.pem"))?,
+        )?,
+        username: "user1".into(),
+    });
+    let config = TestProxyConnectionConfig {
+        handshake_decrypt_retries: 0,
+    };
+    let backoff = std::sync::Arc::new(ProxyBackoff::new(
+        Duration::from_millis(100),
+        Duration::from_millis(200),
+    ));
+    let retry_task = tokio::spawn({
+        let user_info = user_info.clone();
+        let backoff = backoff.clone();
+        async move {
+            ProxyConnection::new_with_retry(
+                &*user_info,
+                &TestProxyConnectionConfig {
+                    handshake_decrypt_retries: 0,
+                },
+                &backoff,
+                2,
+                Duration::from_millis(10),
+                None,
+            )
+            .await
+            .map(|_| ())
+        }
+    });
+    // The first failed pass backs off the other attempts while it is retrying
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    assert!(!retry_task.is_finished());
+    assert!(matches!(
+        backoff.attempt(async { Ok(()) }).await,
+        Err(Error::ProxyUnhealthy(_))
+    ));
+    // The retries wait for the shared backoff and probe the proxy again
+    assert!(matches!(retry_task.await.unwrap(), Err(Error::Io(_))));
+    // The proxy accepting without any handshake reply is bounded by the deadline
+    let silent_listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+    let silent_user_info = TestProxyUser {
+        round_robin_next: Default::default(),
+        proxy_servers: vec![silent_listener.local_addr()?.into()],
+        rsa_crypto: RsaCrypto::new(
+            File::open(agent_user_dir.join("ProxyPublicKey.pem"))?,
+            File::open(agent_user_dir.join("AgentPrivateKey.pem"))?,
+        )?,
+        username: "user1".into(),
+    };
+    let deadline = Duration::from_millis(200);
+    let result = ProxyConnection::new_with_retry(
+        &silent_user_info,
+        &config,
+        &ProxyBackoff::new(Duration::ZERO, Duration::ZERO),
+        2,
+        Duration::from_millis(10),
+        Some(deadline),
+    )
+    .await;
+    assert!(matches!(result, Err(Error::ProxyConnectDeadline(d)) if d == deadline));
+    drop(silent_listener);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_proxy_server_failover() -> Result<(), Error> {
+    use ppaass_crypto::RsaCrypto;
+    use std::fs::File;
+    use std::path::Path;
+    let agent_user_dir = Path::new("../resources/agent/user/user1");
+    let proxy_user_dir = Path::new("../resources/proxy/user/user1");
+    let proxy_rsa_crypto = RsaCrypto::new(
+        File::open(proxy_user_dir.join("AgentPublicKey.pem"))?,
+        File::open(proxy_user_dir.join("ProxyPrivateKey.pem"))?,
+    )?;
+    // The first proxy server accepts the connection but closes it in handshake
+    let broken_listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+    let broken_addr = broken_listener.local_addr()?;
+    let broken_task = tokio::spawn(async move {
+        let (broken_stream, _) = broken_listener.accept().await?;
+        drop(broken_stream);
+        Ok::<(), Error>(())
+    });
+    let proxy_listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+    let proxy_addr = proxy_listener.local_addr()?;
+    let proxy_task = tokio::spawn(async move {
+        let (proxy_stream, _) = proxy_listener.accept().await?;
+        reply_test_handshake(proxy_stream, &proxy_rsa_crypto, None).await
+    });
+    let user_info = TestProxyUser {
+        round_robin_next: Default::default(),
+        proxy_servers: vec![broken_addr.into(), proxy_addr.into()],
+        rsa_crypto: RsaCrypto::new(
+            File::open(agent_user_dir.join("ProxyPublicKey.pem"))?,
+            File::open(agent_user_dir.join("AgentPrivateKey.pem"))?,
+        )?,
+        username: "user1".into(),
+    };
+    let config = TestProxyConnectionConfig {
+        handshake_decrypt_retries: 0,
+    };
+    let proxy_connection = ProxyConnection::new(&user_info, &config).await?;
+    assert_eq!(proxy_addr, proxy_connection.state.get_ref().peer_addr()?);
+    broken_task.await.unwrap()?;
+    proxy_task.await.unwrap()?;
+    // The error of the last proxy server is returned when all of them fail
+    let closed_listener = std::net::TcpListener::bind("127.0.0.1:0")?;
+    let closed_addr = closed_listener.local_addr()?;
+    drop(closed_listener);
+    let failed_user_info = TestProxyUser {
+        round_robin_next: Default::default(),
+        proxy_servers: vec![proxy_addr.into(), closed_addr.into()],
+        ..user_info
+    };
+    let result = ProxyConnection::new(&failed_user_info, &config).await;
+    assert!(matches!(result, Err(Error::Io(_))));
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_handshake_rejected() -> Result<(), Error> {
+    use ppaass_crypto::RsaCrypto;
+    use ppaass_protocol::HandshakeError;
+    use std::fs::File;
+    use std::path::Path;
+    let agent_user_dir = Path::new("../resources/agent/user/user1");
+    let proxy_listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+    let proxy_addr = proxy_listener.local_addr()?;
+    let proxy_task = tokio::spawn(async move {
+        let (proxy_stream, _) = proxy_listener.accept().await?;
+        let mut handshake_framed = Framed::new(
+            proxy_stream,
+            SecureLengthDelimitedCodec::new(
+                Cow::Borrowed(get_handshake_encryption()),
+                Cow::Borrowed(get_handshake_encryption()),
+            ),
+        );
+        let _: HandshakeRequest = handshake_framed.next().await.unwrap()?.try_into()?;
+        let handshake_response_bytes: Vec<u8> = HandshakeResponse::Failure {
+            reason: HandshakeError::UserExpired,
+        }
+        .try_into()?;
+        handshake_framed.send(&handshake_response_bytes).await?;
+        Ok::<(), Error>(())
+    });
+    let user_info = TestProxyUser {
+        round_robin_next: Default::default(),
+        proxy_servers: vec![proxy_addr.into()],
+        rsa_crypto: RsaCrypto::new(
+            File::open(agent_user_dir.join("ProxyPublicKey.pem"))?,
+            File::open(agent_user_dir.join("AgentPrivateKey.pem"))?,
+        )?,
+        username: "user1".into(),
+    };
+    // The rejected handshake is not retried, the proxy only accepts once
+    let result = ProxyConnection::new(
+        &user_info,
+        &TestProxyConnectionConfig {
+            handshake_decrypt_retries: 2,
+        },
+    )
+    .await;
+    assert!(matches!(
+        result,
+        Err(Error::HandshakeRejected(HandshakeError::UserExpired))
+    ));
+    proxy_task.await.unwrap()?;
+    Ok(())
+}
@@ -0,0 +1,191 @@
+use crate::metrics::relay_bytes_counters;
+use metrics::Counter;
+use std::io::{Error as StdIoError, ErrorKind};
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf, copy_bidirectional};
+use tokio::time::Instant;
+
+/// Record when the data last moved through a relay, shared by both
+/// directions of the relay so either of them keeps it alive.
+pub struct RelayIdleTimer {
+    relay_start: Instant,
+    /// The milliseconds since the relay start of the last moved data
+    last_moved_millis: AtomicU64,
+}
+
+impl RelayIdleTimer {
+    pub fn new() -> Self {
+        Self {
+            relay_start: Instant::now(),
+            last_moved_millis: AtomicU64::new(0),
+        }
+    }
+
+    pub fn mark_moved(&self) {
+        self.last_moved_millis.store(
+            self.relay_start.elapsed().as_millis() as u64,
+            Ordering::Relaxed,
+        );
+    }
+
+    /// Complete with `ErrorKind::TimedOut` once no data moved within the idle
+    /// timeout, never complete when there is no idle timeout.
+    pub async fn expired(&self, idle_timeout: Option<Duration>) -> StdIoError {
+        let Some(idle_timeout) = idle_timeout else {
+            return std::future::pending().await;
+        };
+        loop {
+            let last_moved = Duration::from_millis(self.last_moved_millis.load(Ordering::Relaxed));
+            let idle_deadline = self.relay_start + last_moved + idle_timeout;
+            if Instant::now() >= idle_deadline {
+                return StdIoError::new(
+                    ErrorKind::TimedOut,
+                    format!("No relay data moved within the idle timeout: {idle_timeout:?}"),
+                );
+            }
+            tokio::time::sleep_until(idle_deadline).await;
+        }
+    }
+}
+
+impl Default for RelayIdleTimer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// The relay endpoint recording when the bytes last moved through it
+/// and counting the bytes read from it
+struct RelayIdleWatch<'a, T> {
+    inner: &'a mut T,
+    idle_timer: &'a RelayIdleTimer,
+    read_bytes: Counter,
+}
+
+impl<T> RelayIdleWatch<'_, T> {
+    fn mark_moved(&self) {
+        self.idle_timer.mark_moved();
+    }
+}
+
+impl<T> AsyncRead for RelayIdleWatch<'_, T>
+where
+    T: AsyncRead + Unpin,
+{
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        let this = self.get_mut();
+        let filled = buf.filled().len();
+        let result = Pin::new(&mut *this.inner).poll_read(cx, buf);
+        if buf.filled().len() > filled {
+            this.mark_moved();
+            this.read_bytes
+                .increment((buf.filled().len() - filled) as u64);
+        }
+        result
+    }
+}
+
+impl<T> AsyncWrite for RelayIdleWatch<'_, T>
+where
+    T: AsyncWrite + Unpin,
+{
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        let this = self.get_mut();
+        let result = Pin::new(&mut *this.inner).poll_write(cx, buf);
+        if let Poll::Ready(Ok(written)) = result
+            && written > 0
+        {
+            this.mark_moved();
+        }
+        result
+    }
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut *self.get_mut().inner).poll_flush(cx)
+    }
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut *self.get_mut().inner).poll_shutdown(cx)
+    }
+}
+
+/// Copy the data in both directions like `copy_bidirectional`, when the idle
+/// timeout is given and no byte moves in either direction within it, the relay
+/// fails with `ErrorKind::TimedOut` so the caller drops and closes both sides.
+/// The bytes read from `a` are counted as relayed from the client side to the
+/// destination side, the bytes read from `b` as the other direction.
+pub async fn copy_bidirectional_with_idle_timeout<A, B>(
+    a: &mut A,
+    b: &mut B,
+    idle_timeout: Option<Duration>,
+) -> std::io::Result<(u64, u64)>
+where
+    A: AsyncRead + AsyncWrite + Unpin,
+    B: AsyncRead + AsyncWrite + Unpin,
+{
+    let idle_timer = RelayIdleTimer::new();
+    let (client_to_destination_bytes, destination_to_client_bytes) = relay_bytes_counters();
+    let mut a = RelayIdleWatch {
+        inner: a,
+        idle_timer: &idle_timer,
+        read_bytes: client_to_destination_bytes,
+    };
+    let mut b = RelayIdleWatch {
+        inner: b,
+        idle_timer: &idle_timer,
+        read_bytes: destination_to_client_bytes,
+    };
+    tokio::select! {
+        relay_result = copy_bidirectional(&mut a, &mut b) => relay_result,
+        e = idle_timer.expired(idle_timeout) => Err(e),
+    }
+}
+
+#[tokio::test]
+async fn test_copy_bidirectional_with_idle_timeout() -> std::io::Result<()> {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    let idle_timeout = Some(Duration::from_millis(200));
+    let (mut a, mut a_peer) = tokio::io::duplex(1024);
+    let (mut b, mut b_peer) = tokio::io::duplex(1024);
+    let a_task = tokio::spawn(async move {
+        // Keep the relay busy longer than the idle timeout, then go idle
+        for _ in 0..4 {
+            a_peer.write_all(b"data").await?;
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+        Ok::<_, std::io::Error>(a_peer)
+    });
+    let b_task = tokio::spawn(async move {
+        let mut received = [0u8; 16];
+        b_peer.read_exact(&mut received).await?;
+        Ok::<_, std::io::Error>((b_peer, received))
+    });
+    let relay_result = copy_bidirectional_with_idle_timeout(&mut a, &mut b, idle_timeout).await;
+    assert_eq!(
+        ErrorKind::TimedOut,
+        relay_result
+            .expect_err("the idle relay must time out")
+            .kind()
+    );
+    let _a_peer = a_task.await.unwrap()?;
+    let (_b_peer, received) = b_task.await.unwrap()?;
+    assert_eq!(b"datadatadatadata", &received);
+    let (mut a, a_peer) = tokio::io::duplex(1024);
+    let (mut b, b_peer) = tokio::io::duplex(1024);
+    drop(a_peer);
+    drop(b_peer);
+    assert_eq!(
+        (0, 0),
+        copy_bidirectional_with_idle_timeout(&mut a, &mut b, idle_timeout).await?
+    );
+    Ok(())
+}
@@ -1,38 +1,281 @@
 use crate::config::ServerConfig;
 use crate::error::Error;
+use crate::memory::{CONNECTION_RELAY_MEMORY, ConnectionRelayMemory, RelayMemory};
+use crate::metrics::{
+    ActiveConnection, build_metrics_recorder, record_relay_memory_buffered_bytes,
+    record_relay_memory_shed, start_metrics_server, with_metrics_recorder,
+    with_metrics_recorder_sync,
+};
+use crate::tls::{IncomingStream, load_tls_acceptor};
+use metrics_exporter_prometheus::PrometheusRecorder;
+use std::collections::BTreeMap;
 use std::error::Error as StdError;
 use std::net::SocketAddr;
-use std::sync::Arc;
-use tokio::net::{TcpListener, TcpStream};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, PoisonError};
+use std::time::{Duration, Instant};
+use tokio::net::TcpStream;
 use tokio::sync::Semaphore;
+use tokio_rustls::TlsAcceptor;
+use tokio_rustls::server::TlsStream;
 use tokio_util::sync::CancellationToken;
-use tracing::{debug, error, info};
+use tokio_util::task::TaskTracker;
+use tracing::{debug, error, info, warn};
 
+/// The state of one accepted connection, the stream is the one produced by
+/// the acceptor, the tcp or tls stream by default.
 #[derive(Debug)]
-pub struct ServerState {
-    pub incoming_stream: TcpStream,
+pub struct ServerState<S = IncomingStream> {
+    pub incoming_stream: S,
     pub incoming_connection_addr: SocketAddr,
 }
 
 pub struct ServerGuard {
     pub stop_signal: CancellationToken,
+    /// Cancel the connection handlers which are still running
+    force_stop_signal: CancellationToken,
+    connection_tracker: TaskTracker,
+    /// The recorder of the metrics of the server when the metrics are served
+    metrics_recorder: Option<Arc<PrometheusRecorder>>,
+}
+
+impl ServerGuard {
+    /// Run the function with the metrics recorder of the server, so the
+    /// background tasks it spawns for the server record the server metrics.
+    pub fn in_metrics_scope<R>(&self, f: impl FnOnce() -> R) -> R {
+        with_metrics_recorder_sync(self.metrics_recorder.as_ref(), f)
+    }
+
+    /// Stop accepting new connections and wait for the in-flight connections
+    /// to finish, the connections still running after the timeout are cancelled.
+    pub async fn shutdown(&self, timeout: Duration) {
+        self.stop_signal.cancel();
+        self.connection_tracker.close();
+        info!(
+            "Waiting for {} in-flight connections to finish.",
+            self.connection_tracker.len()
+        );
+        if tokio::time::timeout(timeout, self.connection_tracker.wait())
+            .await
+            .is_err()
+        {
+            warn!(
+                "Cancel {} in-flight connections because of shutdown timeout: {timeout:?}",
+                self.connection_tracker.len()
+            );
+            self.force_stop_signal.cancel();
+            self.connection_tracker.wait().await;
+        }
+    }
+}
+
+/// The token bucket to limit the rate of accepting new connections,
+/// the bucket can hold at most one second of tokens as burst.
+struct AcceptRateLimiter {
+    rate: f64,
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl AcceptRateLimiter {
+    fn new(rate: u32) -> Self {
+        Self {
+            rate: rate as f64,
+            tokens: rate as f64,
+            last_refill: Instant::now(),
+        }
+    }
+
+    fn try_acquire(&mut self, now: Instant) -> bool {
+        let elapsed = now.saturating_duration_since(self.last_refill);
+        self.tokens = (self.tokens + elapsed.as_secs_f64() * self.rate).min(self.rate);
+        self.last_refill = now;
+        if self.tokens < 1.0 {
+            return false;
+        }
+        self.tokens -= 1.0;
+        true
+    }
+}
+
+/// The interval to check the buffered relay bytes against the soft cap
+const RELAY_MEMORY_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+/// The time for the accepted connection to finish the tls handshake
+const TLS_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The connections being handled and the relay bytes they buffer, the
+/// connection buffering the most is shed first when the buffered relay
+/// bytes of the server exceed the soft cap.
+#[derive(Default)]
+struct ConnectionRegistry {
+    next_connection_id: AtomicU64,
+    connections: Mutex<BTreeMap<u64, RegisteredConnection>>,
+    relay_memory: Arc<RelayMemory>,
+}
+
+struct RegisteredConnection {
+    connection_addr: SocketAddr,
+    shed_signal: CancellationToken,
+    relay_memory: Arc<ConnectionRelayMemory>,
+}
+
+impl ConnectionRegistry {
+    fn register(
+        &self,
+        connection_addr: SocketAddr,
+    ) -> (u64, CancellationToken, Arc<ConnectionRelayMemory>) {
+        let connection_id = self.next_connection_id.fetch_add(1, Ordering::Relaxed);
+        let shed_signal = CancellationToken::new();
+        let relay_memory = Arc::new(ConnectionRelayMemory::new(self.relay_memory.clone()));
+        self.connections
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .insert(
+                connection_id,
+                RegisteredConnection {
+                    connection_addr,
+                    shed_signal: shed_signal.clone(),
+                    relay_memory: relay_memory.clone(),
+                },
+            );
+        (connection_id, shed_signal, relay_memory)
+    }
+
+    fn unregister(&self, connection_id: u64) {
+        self.connections
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .remove(&connection_id);
+    }
+
+    /// Shed the connection buffering the most relay bytes, the oldest one
+    /// among the equal ones, when the buffered relay bytes of the server
+    /// exceed the soft cap, returns the address of the connection shed.
+    fn shed_over_soft_cap(&self, soft_cap: usize) -> Option<SocketAddr> {
+        let buffered_bytes = self.relay_memory.buffered_bytes();
+        if buffered_bytes <= soft_cap {
+            return None;
+        }
+        let mut connections = self
+            .connections
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        let mut shed_connection: Option<(u64, usize)> = None;
+        for (connection_id, connection) in connections.iter() {
+            let connection_buffered_bytes = connection.relay_memory.buffered_bytes();
+            if shed_connection.is_none_or(|(_, most_buffered_bytes)| {
+                connection_buffered_bytes > most_buffered_bytes
+            }) {
+                shed_connection = Some((*connection_id, connection_buffered_bytes));
+            }
+        }
+        let (shed_connection_id, connection_buffered_bytes) = shed_connection?;
+        let shed_connection = connections.remove(&shed_connection_id)?;
+        shed_connection.shed_signal.cancel();
+        record_relay_memory_shed();
+        warn!(
+            "Shed the incoming connection from {} buffering {connection_buffered_bytes} relay bytes because buffered relay bytes {buffered_bytes} exceed the soft cap {soft_cap}",
+            shed_connection.connection_addr
+        );
+        Some(shed_connection.connection_addr)
+    }
+}
+
+/// Terminate the tls of the accepted connection, the connection which
+/// does not finish the tls handshake in time is closed.
+async fn accept_tls(
+    tls_acceptor: &TlsAcceptor,
+    incoming_stream: TcpStream,
+) -> Result<TlsStream<TcpStream>, Error> {
+    let tls_stream =
+        tokio::time::timeout(TLS_HANDSHAKE_TIMEOUT, tls_acceptor.accept(incoming_stream))
+            .await
+            .map_err(|_| {
+                Error::Tls(format!(
+                    "tls handshake not finished in {TLS_HANDSHAKE_TIMEOUT:?}"
+                ))
+            })??;
+    Ok(tls_stream)
 }
 
 pub fn start_server<C, F, Fut, Err>(config: &C, connection_handler: F) -> ServerGuard
 where
     C: ServerConfig,
-    F: Fn(ServerState) -> Fut + Send + Sync + Copy + 'static,
+    F: Fn(ServerState) -> Fut + Send + Sync + Clone + 'static,
     Fut: Future<Output = Result<(), Err>> + Send + 'static,
     Err: StdError + From<Error>,
 {
     let stop_single = CancellationToken::new();
+    let force_stop_signal = CancellationToken::new();
+    let connection_tracker = TaskTracker::new();
+    let metrics_address = config.metrics_address();
+    let metrics_recorder = metrics_address.map(|_| build_metrics_recorder());
     let server_guard = ServerGuard {
         stop_signal: stop_single.clone(),
+        force_stop_signal: force_stop_signal.clone(),
+        connection_tracker: connection_tracker.clone(),
+        metrics_recorder: metrics_recorder.clone(),
     };
     let listening_address = config.listening_address();
     let client_max_connections = Arc::new(Semaphore::new(config.client_max_connections()));
+    let mut accept_rate_limiter = config
+        .client_accept_rate()
+        .filter(|client_accept_rate| *client_accept_rate > 0)
+        .map(AcceptRateLimiter::new);
+    let client_accept_filter = config.client_accept_filter();
+    let socket_options = config.socket_options();
+    let tls_acceptor = match (config.tls_cert(), config.tls_key()) {
+        (Some(tls_cert), Some(tls_key)) => load_tls_acceptor(tls_cert, tls_key).map(Some),
+        (None, None) => Ok(None),
+        _ => Err(Error::Tls(
+            "tls_cert and tls_key must be configured together".to_string(),
+        )),
+    };
+    let connection_registry = Arc::new(ConnectionRegistry::default());
+    let relay_memory_soft_cap = config.relay_memory_soft_cap();
+    if relay_memory_soft_cap.is_some() || metrics_recorder.is_some() {
+        let connection_registry = connection_registry.clone();
+        let stop_signal = stop_single.clone();
+        let relay_memory_check = async move {
+            loop {
+                tokio::select! {
+                    _ = stop_signal.cancelled() => return,
+                    _ = tokio::time::sleep(RELAY_MEMORY_CHECK_INTERVAL) => {
+                        record_relay_memory_buffered_bytes(connection_registry.relay_memory.buffered_bytes());
+                        if let Some(relay_memory_soft_cap) = relay_memory_soft_cap {
+                            connection_registry.shed_over_soft_cap(relay_memory_soft_cap);
+                        }
+                    }
+                }
+            }
+        };
+        match metrics_recorder.clone() {
+            Some(metrics_recorder) => {
+                tokio::spawn(with_metrics_recorder(metrics_recorder, relay_memory_check))
+            }
+            None => tokio::spawn(relay_memory_check),
+        };
+    }
     tokio::spawn(async move {
-        let tcp_listener = match TcpListener::bind(listening_address).await {
+        let mut rate_limited_connections = 0u64;
+        let mut filtered_connections = 0u64;
+        let tls_acceptor = match tls_acceptor {
+            Ok(tls_acceptor) => tls_acceptor,
+            Err(e) => {
+                error!("Fail to start server [{listening_address}] because of tls error: {e:?}");
+                return;
+            }
+        };
+        if let (Some(metrics_address), Some(metrics_recorder)) =
+            (metrics_address, &metrics_recorder)
+        {
+            match start_metrics_server(metrics_address, metrics_recorder, stop_single.clone()).await
+            {
+                Ok(metrics_address) => info!("Serve metrics on [{metrics_address}]"),
+                Err(e) => error!("Fail to serve metrics on [{metrics_address}]: {e:?}"),
+            }
+        }
+        let tcp_listener = match socket_options.bind_listener(listening_address) {
             Ok(tcp_listener) => tcp_listener,
             Err(e) => {
                 error!("Fail to bind server [{listening_address}] because of error: {e:?}");
@@ -46,34 +289,336 @@ where
                     return;
                 }
                 client_connection = tcp_listener.accept() => {
-                    let client_connection_permit=match client_max_connections.clone().acquire_owned().await{
-                        Ok(client_connection_permit) => client_connection_permit,
+                    let (incoming_stream, incoming_connection_addr) = match client_connection {
+                        Ok((incoming_stream, incoming_connection_addr)) => (incoming_stream, incoming_connection_addr),
                         Err(e) => {
-                            error!("Fail to acquire client connection permit because of error: {e:?}");
+                            error!("Failed to accept incoming connection: {}", e);
                             continue;
                         }
                     };
-                    let (incoming_stream, incoming_connection_addr) = match client_connection {
-                        Ok((incoming_stream, incoming_connection_addr)) => (incoming_stream, incoming_connection_addr),
+                    if let Some(client_accept_filter) = client_accept_filter
+                        && !client_accept_filter(incoming_connection_addr)
+                    {
+                        filtered_connections += 1;
+                        debug!("Close incoming connection from {incoming_connection_addr} because of accept filter, total filtered connections: {filtered_connections}");
+                        continue;
+                    }
+                    if let Some(accept_rate_limiter) = accept_rate_limiter.as_mut()
+                        && !accept_rate_limiter.try_acquire(Instant::now())
+                    {
+                        rate_limited_connections += 1;
+                        debug!("Close incoming connection from {incoming_connection_addr} because of accept rate limit, total rate limited connections: {rate_limited_connections}");
+                        continue;
+                    }
+                    // The permit is taken after the checks, so the connections they
+                    // close are never held waiting for the busy server
+                    let client_connection_permit=match client_max_connections.clone().acquire_owned().await{
+                        Ok(client_connection_permit) => client_connection_permit,
                         Err(e) => {
-                            error!("Failed to accept incoming connection: {}", e);
+                            error!("Fail to acquire client connection permit because of error: {e:?}");
                             continue;
                         }
                     };
+                    if let Err(e) = socket_options.apply(&incoming_stream) {
+                        error!("Fail to apply socket options to incoming connection from {incoming_connection_addr}: {e:?}");
+                    }
                     debug!("Accept incoming connection from {}", incoming_connection_addr);
-                    tokio::spawn(async move {
-                        let server_state = ServerState {
-                            incoming_stream,
-                            incoming_connection_addr,
+                    let force_stop_signal = force_stop_signal.clone();
+                    let connection_registry = connection_registry.clone();
+                    let (connection_id, shed_signal, connection_relay_memory) = connection_registry.register(incoming_connection_addr);
+                    let tls_acceptor = tls_acceptor.clone();
+                    let connection_handler = connection_handler.clone();
+                    let connection_task = async move {
+                        let active_connection = ActiveConnection::start();
+                        let handle_connection = async move {
+                            let incoming_stream = match tls_acceptor {
+                                None => IncomingStream::Plain(incoming_stream),
+                                Some(tls_acceptor) => IncomingStream::Tls(Box::new(
+                                    accept_tls(&tls_acceptor, incoming_stream).await?,
+                                )),
+                            };
+                            let server_state = ServerState {
+                                incoming_stream,
+                                incoming_connection_addr,
+                            };
+                            CONNECTION_RELAY_MEMORY
+                                .scope(connection_relay_memory, connection_handler(server_state))
+                                .await
                         };
-                        if let Err(e) = connection_handler(server_state).await {
-                            error!("Failed to handle incoming connection: {:?}", e);
+                        tokio::select! {
+                            result = handle_connection => {
+                                if let Err(e) = result {
+                                    error!("Failed to handle incoming connection: {:?}", e);
+                                }
+                            }
+                            _ = force_stop_signal.cancelled() => {
+                                debug!("Cancel incoming connection from {incoming_connection_addr} because of force stop");
+                            }
+                            _ = shed_signal.cancelled() => {
+                                debug!("Cancel incoming connection from {incoming_connection_addr} because of relay memory shedding");
+                            }
                         }
+                        connection_registry.unregister(connection_id);
+                        drop(active_connection);
                         drop(client_connection_permit);
-                    });
+                    };
+                    match metrics_recorder.clone() {
+                        Some(metrics_recorder) => connection_tracker.spawn(with_metrics_recorder(metrics_recorder, connection_task)),
+                        None => connection_tracker.spawn(connection_task),
+                    };
                 }
             }
         }
     });
     server_guard
 }
+
+#[test]
+fn test_accept_rate_limiter() {
+    let now = Instant::now();
+    let mut accept_rate_limiter = AcceptRateLimiter::new(2);
+    accept_rate_limiter.last_refill = now;
+    assert!(accept_rate_limiter.try_acquire(now));
+    assert!(accept_rate_limiter.try_acquire(now));
+    assert!(!accept_rate_limiter.try_acquire(now));
+    let now = now + std::time::Duration::from_millis(500);
+    assert!(accept_rate_limiter.try_acquire(now));
+    assert!(!accept_rate_limiter.try_acquire(now));
+}
+
+/// The config of the servers started by the tests, listening on the address
+#[cfg(test)]
+pub(crate) fn test_common_config(
+    listening_address: std::net::SocketAddr,
+) -> crate::config::CommonConfig {
+    use crate::config::CommonConfig;
+    CommonConfig {
+        client_max_connections: 16,
+        listening_address,
+        log_directory: "log".into(),
+        log_name_prefix: "test.log".to_string(),
+        max_log_level: "ERROR".to_string(),
+        user_info_file_name: "user_info.toml".to_string(),
+        user_info_private_key_file_name: "ProxyPrivateKey.pem".to_string(),
+        user_info_public_key_file_name: "AgentPublicKey.pem".to_string(),
+        user_repo_directory: "resources/proxy/user".into(),
+        user_repo_refresh_interval: 10,
+        worker_threads: 1,
+        client_accept_rate: None,
+        encryption_preference: Default::default(),
+        relay_max_chunk_size: None,
+        relay_write_buffer_size: None,
+        relay_flush_interval_millis: None,
+        log_directory_auto_create: true,
+        log_stderr_fallback: true,
+        client_accept_filter: None,
+        max_users: None,
+        user_load_parallelism: None,
+        handshake_max_frame_length: crate::DEFAULT_HANDSHAKE_MAX_FRAME_LENGTH,
+        socket_options: Default::default(),
+        relay_per_frame_iv: false,
+        relay_length_field_length: crate::DEFAULT_LENGTH_FIELD_LENGTH,
+        relay_compression_level: None,
+        relay_memory_soft_cap: None,
+        relay_idle_timeout_secs: None,
+        tls_cert: None,
+        tls_key: None,
+        metrics_address: None,
+        shutdown_timeout: 30,
+    }
+}
+
+#[tokio::test]
+async fn test_accept_rate_limit() -> Result<(), Error> {
+    use crate::config::CommonConfig;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    let listening_address = std::net::TcpListener::bind("127.0.0.1:0")?.local_addr()?;
+    let config = CommonConfig {
+        client_accept_rate: Some(2),
+        ..test_common_config(listening_address)
+    };
+    let server_guard = start_server(&config, |mut server_state| async move {
+        server_state.incoming_stream.write_all(b"ok").await?;
+        server_state.incoming_stream.flush().await?;
+        Ok::<(), Error>(())
+    });
+    tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+    let mut accepted = 0;
+    let mut closed = 0;
+    for _ in 0..6 {
+        let mut client_stream = TcpStream::connect(listening_address).await?;
+        let mut buf = [0u8; 2];
+        match client_stream.read(&mut buf).await {
+            Ok(2) => accepted += 1,
+            _ => closed += 1,
+        }
+    }
+    server_guard.stop_signal.cancel();
+    assert!(accepted >= 2);
+    assert!(closed >= 1);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_zero_accept_rate_unlimited() -> Result<(), Error> {
+    use crate::config::CommonConfig;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    let listening_address = std::net::TcpListener::bind("127.0.0.1:0")?.local_addr()?;
+    let config = CommonConfig {
+        client_accept_rate: Some(0),
+        ..test_common_config(listening_address)
+    };
+    let server_guard = start_server(&config, |mut server_state| async move {
+        server_state.incoming_stream.write_all(b"ok").await?;
+        server_state.incoming_stream.flush().await?;
+        Ok::<(), Error>(())
+    });
+    tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+    for _ in 0..6 {
+        let mut client_stream = TcpStream::connect(listening_address).await?;
+        let mut buf = [0u8; 2];
+        assert_eq!(2, client_stream.read(&mut buf).await?);
+    }
+    server_guard.stop_signal.cancel();
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_accept_rate_limit_without_permit() -> Result<(), Error> {
+    use crate::config::CommonConfig;
+    use tokio::io::AsyncReadExt;
+    let listening_address = std::net::TcpListener::bind("127.0.0.1:0")?.local_addr()?;
+    let config = CommonConfig {
+        client_max_connections: 1,
+        client_accept_rate: Some(1),
+        ..test_common_config(listening_address)
+    };
+    // The first connection holds the only permit until the server stops
+    let server_guard = start_server(&config, |_| std::future::pending::<Result<(), Error>>());
+    tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+    let _held_stream = TcpStream::connect(listening_address).await?;
+    tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+    // The rate limited connection is closed without waiting for the permit
+    let mut client_stream = TcpStream::connect(listening_address).await?;
+    let mut buf = [0u8; 1];
+    let read_result = tokio::time::timeout(
+        std::time::Duration::from_secs(2),
+        client_stream.read(&mut buf),
+    )
+    .await;
+    server_guard.stop_signal.cancel();
+    assert!(matches!(read_result, Ok(Ok(0) | Err(_))));
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_accept_filter() -> Result<(), Error> {
+    use crate::config::CommonConfig;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use tokio::io::AsyncReadExt;
+    static HANDLED_CONNECTIONS: AtomicUsize = AtomicUsize::new(0);
+    let listening_address = std::net::TcpListener::bind("127.0.0.1:0")?.local_addr()?;
+    let config = CommonConfig {
+        client_accept_filter: Some(|addr| !addr.ip().is_loopback()),
+        ..test_common_config(listening_address)
+    };
+    let server_guard = start_server(&config, |_| async move {
+        HANDLED_CONNECTIONS.fetch_add(1, Ordering::SeqCst);
+        Ok::<(), Error>(())
+    });
+    tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+    let mut client_stream = TcpStream::connect(listening_address).await?;
+    let mut buf = [0u8; 1];
+    let read_result = client_stream.read(&mut buf).await;
+    server_guard.stop_signal.cancel();
+    assert!(matches!(read_result, Ok(0) | Err(_)));
+    assert_eq!(0, HANDLED_CONNECTIONS.load(Ordering::SeqCst));
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_graceful_shutdown() -> Result<(), Error> {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    let listening_address = std::net::TcpListener::bind("127.0.0.1:0")?.local_addr()?;
+    let config = test_common_config(listening_address);
+    // The client decides how long the handler runs with the first byte
+    let server_guard = start_server(&config, |mut server_state| async move {
+        let handle_millis = server_state.incoming_stream.read_u8().await? as u64 * 100;
+        tokio::time::sleep(Duration::from_millis(handle_millis)).await;
+        server_state.incoming_stream.write_all(b"done").await?;
+        Ok::<(), Error>(())
+    });
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    let mut draining_stream = TcpStream::connect(listening_address).await?;
+    draining_stream.write_u8(3).await?;
+    let mut cancelled_stream = TcpStream::connect(listening_address).await?;
+    cancelled_stream.write_u8(100).await?;
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    let started = Instant::now();
+    server_guard.shutdown(Duration::from_secs(1)).await;
+    assert!(started.elapsed() < Duration::from_secs(5));
+    // The in-flight connection finished within the timeout is not severed
+    let mut buf = Vec::new();
+    draining_stream.read_to_end(&mut buf).await?;
+    assert_eq!(b"done".as_slice(), &buf);
+    // The connection still running after the timeout is cancelled
+    buf.clear();
+    assert!(matches!(
+        cancelled_stream.read_to_end(&mut buf).await,
+        Ok(0) | Err(_)
+    ));
+    // No new connection is accepted after shutdown
+    assert!(TcpStream::connect(listening_address).await.is_err());
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_shed_over_soft_cap() {
+    use crate::memory::BufferedRelayBytes;
+    let recorder = metrics_exporter_prometheus::PrometheusBuilder::new().build_recorder();
+    let metrics_handle = recorder.handle();
+    let _recorder_guard = metrics::set_default_local_recorder(&recorder);
+    let connection_registry = ConnectionRegistry::default();
+    let connections = (0..3)
+        .map(|port| {
+            let connection_addr = SocketAddr::from(([127, 0, 0, 1], 10080 + port));
+            connection_registry.register(connection_addr)
+        })
+        .collect::<Vec<_>>();
+    let soft_cap = 64 * 1024;
+    // Simulate the slow connections buffering the relay data
+    let mut buffered_relay_bytes = connections
+        .iter()
+        .map(|(_, _, relay_memory)| BufferedRelayBytes::with_connection(relay_memory.clone()))
+        .collect::<Vec<_>>();
+    buffered_relay_bytes[0].update(16 * 1024);
+    buffered_relay_bytes[1].update(40 * 1024);
+    assert_eq!(None, connection_registry.shed_over_soft_cap(soft_cap));
+    buffered_relay_bytes[2].update(16 * 1024);
+    // The connection buffering the most is shed instead of the oldest one
+    assert_eq!(
+        Some(SocketAddr::from(([127, 0, 0, 1], 10081))),
+        connection_registry.shed_over_soft_cap(soft_cap)
+    );
+    assert!(!connections[0].1.is_cancelled());
+    assert!(connections[1].1.is_cancelled());
+    // The shed connection releases its buffers
+    drop(buffered_relay_bytes.remove(1));
+    assert_eq!(None, connection_registry.shed_over_soft_cap(soft_cap));
+    // The oldest connection is shed among the equal ones
+    buffered_relay_bytes[1].update(64 * 1024);
+    buffered_relay_bytes[0].update(64 * 1024);
+    assert_eq!(
+        Some(SocketAddr::from(([127, 0, 0, 1], 10080))),
+        connection_registry.shed_over_soft_cap(soft_cap)
+    );
+    assert!(
+        metrics_handle
+            .render()
+            .contains("ppaass_relay_memory_shed_connections_total 2\n")
+    );
+    // The other registry accounts its own connections only
+    let other_registry = ConnectionRegistry::default();
+    other_registry.register(SocketAddr::from(([127, 0, 0, 1], 10090)));
+    assert_eq!(None, other_registry.shed_over_soft_cap(0));
+}
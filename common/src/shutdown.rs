@@ -0,0 +1,135 @@
+use std::pin::Pin;
+use tokio_util::sync::CancellationToken;
+use tracing::info;
+
+type ShutdownHook = Box<dyn FnOnce() -> Pin<Box<dyn Future<Output = ()> + Send>> + Send>;
+
+/// Coordinate the teardown of the process, the registered hooks run
+/// in the registration order once a stop signal is received.
+pub struct Shutdown {
+    stop_signal: CancellationToken,
+    hooks: Vec<(&'static str, ShutdownHook)>,
+}
+
+impl Default for Shutdown {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Shutdown {
+    pub fn new() -> Self {
+        Self {
+            stop_signal: CancellationToken::new(),
+            hooks: Vec::new(),
+        }
+    }
+
+    /// The token cancelled when the shutdown begins, cancelling
+    /// it also makes `wait_for_signal` return.
+    pub fn stop_signal(&self) -> CancellationToken {
+        self.stop_signal.clone()
+    }
+
+    /// Register the hook which runs on shutdown after the hooks registered before it
+    pub fn register<F, Fut>(&mut self, name: &'static str, hook: F)
+    where
+        F: FnOnce() -> Fut + Send + 'static,
+        Fut: Future<Output = ()> + Send + 'static,
+    {
+        self.hooks.push((name, Box::new(move || Box::pin(hook()))));
+    }
+
+    /// Wait for SIGINT, SIGTERM or SIGHUP (ctrl-c only on the platforms
+    /// without unix signals), or for the stop signal to be cancelled.
+    pub async fn wait_for_signal(&self) -> std::io::Result<()> {
+        #[cfg(unix)]
+        {
+            use tokio::signal::unix::{SignalKind, signal};
+            let mut interrupt = signal(SignalKind::interrupt())?;
+            let mut terminate = signal(SignalKind::terminate())?;
+            let mut hangup = signal(SignalKind::hangup())?;
+            tokio::select! {
+                _ = interrupt.recv() => info!("Receive SIGINT."),
+                _ = terminate.recv() => info!("Receive SIGTERM."),
+                _ = hangup.recv() => info!("Receive SIGHUP."),
+                _ = self.stop_signal.cancelled() => info!("Receive stop signal."),
+            }
+        }
+        #[cfg(not(unix))]
+        {
+            tokio::select! {
+                result = tokio::signal::ctrl_c() => {
+                    result?;
+                    info!("Receive ctrl-c.");
+                }
+                _ = self.stop_signal.cancelled() => info!("Receive stop signal."),
+            }
+        }
+        Ok(())
+    }
+
+    /// Cancel the stop signal and run the registered hooks in order
+    pub async fn shutdown(self) {
+        self.stop_signal.cancel();
+        for (name, hook) in self.hooks {
+            info!("Running shutdown hook: {name}");
+            hook().await;
+        }
+    }
+}
+
+#[tokio::test]
+async fn test_shutdown_hooks_in_order() -> std::io::Result<()> {
+    use std::sync::{Arc, Mutex};
+    let executed = Arc::new(Mutex::new(Vec::new()));
+    let mut shutdown = Shutdown::new();
+    for name in ["server", "pool", "log"] {
+        let executed = executed.clone();
+        shutdown.register(name, move || async move {
+            // The hook yields, the next hook must still wait for it
+            tokio::task::yield_now().await;
+            executed.lock().unwrap().push(name);
+        });
+    }
+    let stop_signal = shutdown.stop_signal();
+    tokio::spawn(async move { stop_signal.cancel() });
+    shutdown.wait_for_signal().await?;
+    assert!(executed.lock().unwrap().is_empty());
+    shutdown.shutdown().await;
+    assert_eq!(vec!["server", "pool", "log"], *executed.lock().unwrap());
+    Ok(())
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn test_sigterm_starts_shutdown() -> std::io::Result<()> {
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::time::Duration;
+    // Keep the process alive when SIGTERM arrives before the wait registers its handler
+    let _terminate = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())?;
+    let hook_executed = Arc::new(AtomicBool::new(false));
+    let mut shutdown = Shutdown::new();
+    shutdown.register("server", {
+        let hook_executed = hook_executed.clone();
+        move || async move { hook_executed.store(true, Ordering::SeqCst) }
+    });
+    let stop_signal = shutdown.stop_signal();
+    let signal_task = tokio::spawn(async move {
+        while !stop_signal.is_cancelled() {
+            // SAFETY: raise only delivers the signal to the current process,
+            // SIGTERM is handled by the signal streams registered above
+            if unsafe { libc::raise(libc::SIGTERM) } != 0 {
+                return Err(std::io::Error::last_os_error());
+            }
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+        Ok::<_, std::io::Error>(())
+    });
+    tokio::time::timeout(Duration::from_secs(5), shutdown.wait_for_signal()).await??;
+    shutdown.shutdown().await;
+    signal_task.await??;
+    assert!(hook_executed.load(Ordering::SeqCst));
+    Ok(())
+}
@@ -0,0 +1,225 @@
+use serde::{Deserialize, Serialize};
+use socket2::SockRef;
+use std::net::SocketAddr;
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpSocket, TcpStream};
+use tokio::time::timeout;
+use tracing::debug;
+
+/// The options applied to the relay sockets, `None` keeps the value of the OS.
+/// The buffer sizes are set before connect or listen, as the TCP window scale
+/// is fixed by the handshake, the other options after the connection is set up.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct SocketOptions {
+    /// The size of the socket send buffer (`SO_SNDBUF`)
+    #[serde(default)]
+    pub socket_send_buffer_size: Option<usize>,
+    /// The size of the socket receive buffer (`SO_RCVBUF`)
+    #[serde(default)]
+    pub socket_recv_buffer_size: Option<usize>,
+    /// The IP ToS/DSCP value marked on the packets (`IP_TOS`, or
+    /// `IPV6_TCLASS` for IPv6 sockets), the DSCP is the upper 6 bits
+    #[serde(default)]
+    pub socket_ip_tos: Option<u32>,
+    /// Reset the connection on close instead of the graceful FIN, by the
+    /// zero `SO_LINGER`, a non-zero linger is never set as it would block
+    /// the worker thread in the close of the non-blocking socket
+    #[serde(default)]
+    pub socket_reset_on_close: bool,
+}
+
+/// Mark the packets sent from the tcp stream with the IP ToS/DSCP value.
+pub fn set_socket_ip_tos(tcp_stream: &TcpStream, ip_tos: u32) -> std::io::Result<()> {
+    let socket = SockRef::from(tcp_stream);
+    if tcp_stream.local_addr()?.is_ipv4() {
+        return socket.set_tos_v4(ip_tos);
+    }
+    #[cfg(any(
+        target_os = "android",
+        target_os = "freebsd",
+        target_os = "linux",
+        target_os = "macos"
+    ))]
+    socket.set_tclass_v6(ip_tos)?;
+    Ok(())
+}
+
+/// The backlog of the listeners, the same as the one of `TcpListener::bind`
+const LISTENER_BACKLOG: u32 = 1024;
+
+impl SocketOptions {
+    /// Bind the listener with the buffer sizes, the accepted sockets inherit
+    /// them before the handshake.
+    pub fn bind_listener(&self, listening_address: SocketAddr) -> std::io::Result<TcpListener> {
+        let socket = self.new_socket(listening_address)?;
+        #[cfg(not(windows))]
+        socket.set_reuseaddr(true)?;
+        socket.bind(listening_address)?;
+        socket.listen(LISTENER_BACKLOG)
+    }
+
+    /// Connect to the address with the buffer sizes set before the handshake.
+    pub async fn connect(&self, addr: SocketAddr) -> std::io::Result<TcpStream> {
+        self.new_socket(addr)?.connect(addr).await
+    }
+
+    /// Connect to the addresses in order like `TcpStream::connect`, the
+    /// error of the last address is returned when none of them connects.
+    pub async fn connect_any(&self, addrs: &[SocketAddr]) -> std::io::Result<TcpStream> {
+        let mut last_error = None;
+        for addr in addrs {
+            match self.connect(*addr).await {
+                Ok(tcp_stream) => return Ok(tcp_stream),
+                Err(e) => last_error = Some(e),
+            }
+        }
+        Err(last_error.unwrap_or_else(|| {
+            std::io::Error::new(std::io::ErrorKind::InvalidInput, "no address to connect")
+        }))
+    }
+
+    fn new_socket(&self, addr: SocketAddr) -> std::io::Result<TcpSocket> {
+        let socket = if addr.is_ipv4() {
+            TcpSocket::new_v4()?
+        } else {
+            TcpSocket::new_v6()?
+        };
+        let socket_ref = SockRef::from(&socket);
+        if let Some(socket_send_buffer_size) = self.socket_send_buffer_size {
+            socket_ref.set_send_buffer_size(socket_send_buffer_size)?;
+        }
+        if let Some(socket_recv_buffer_size) = self.socket_recv_buffer_size {
+            socket_ref.set_recv_buffer_size(socket_recv_buffer_size)?;
+        }
+        Ok(socket)
+    }
+
+    /// Apply the options set after the connection is set up to the tcp
+    /// stream, the buffer sizes are set by [Self::bind_listener] and
+    /// [Self::connect] instead.
+    pub fn apply(&self, tcp_stream: &TcpStream) -> std::io::Result<()> {
+        let socket = SockRef::from(tcp_stream);
+        if let Some(socket_ip_tos) = self.socket_ip_tos {
+            set_socket_ip_tos(tcp_stream, socket_ip_tos)?;
+        }
+        if self.socket_reset_on_close {
+            socket.set_linger(Some(Duration::ZERO))?;
+        }
+        Ok(())
+    }
+}
+
+/// Close the stream with FIN instead of RST, the OS resets the connection when
+/// the socket is closed with unread data, so the write side is shut down first
+/// and the data still sent by the peer is drained until the peer closes too or
+/// the drain timeout passes.
+pub async fn close_gracefully<S>(stream: &mut S, drain_timeout: Duration)
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let drain = async {
+        stream.shutdown().await?;
+        let mut drain_buf = [0u8; 4096];
+        while stream.read(&mut drain_buf).await? > 0 {}
+        Ok::<_, std::io::Error>(())
+    };
+    match timeout(drain_timeout, drain).await {
+        Ok(Ok(())) => {}
+        Ok(Err(e)) => debug!("Fail to close the relay stream gracefully: {e:?}"),
+        Err(_) => debug!("Peer not closed within the drain timeout: {drain_timeout:?}"),
+    }
+}
+
+#[tokio::test]
+async fn test_apply_socket_options() -> std::io::Result<()> {
+    let default_listener = SocketOptions::default().bind_listener(([127, 0, 0, 1], 0).into())?;
+    let tcp_stream = SocketOptions::default()
+        .connect(default_listener.local_addr()?)
+        .await?;
+    let default_send_buffer_size = SockRef::from(&tcp_stream).send_buffer_size()?;
+    SocketOptions::default().apply(&tcp_stream)?;
+    assert_eq!(
+        default_send_buffer_size,
+        SockRef::from(&tcp_stream).send_buffer_size()?
+    );
+    let socket_options = SocketOptions {
+        socket_send_buffer_size: Some(256 * 1024),
+        socket_recv_buffer_size: Some(128 * 1024),
+        socket_ip_tos: None,
+        socket_reset_on_close: true,
+    };
+    // The buffer sizes are set before the handshake on both sides
+    let listener = socket_options.bind_listener(([127, 0, 0, 1], 0).into())?;
+    let tcp_stream = socket_options
+        .connect_any(&["127.0.0.1:1".parse().unwrap(), listener.local_addr()?])
+        .await?;
+    let (accepted_stream, _) = listener.accept().await?;
+    socket_options.apply(&tcp_stream)?;
+    for tcp_stream in [&tcp_stream, &accepted_stream] {
+        let socket = SockRef::from(tcp_stream);
+        // The OS may round the requested size, for example linux doubles it
+        assert!(socket.send_buffer_size()? >= 256 * 1024);
+        assert!(socket.recv_buffer_size()? >= 128 * 1024);
+    }
+    assert_eq!(Some(Duration::ZERO), SockRef::from(&tcp_stream).linger()?);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_socket_reset_on_close() -> std::io::Result<()> {
+    let socket_options: SocketOptions = toml::from_str("socket_reset_on_close = true").unwrap();
+    assert!(socket_options.socket_reset_on_close);
+    let default_socket_options: SocketOptions = toml::from_str("").unwrap();
+    assert!(!default_socket_options.socket_reset_on_close);
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+    let tcp_stream = TcpStream::connect(listener.local_addr()?).await?;
+    let linger = SockRef::from(&tcp_stream).linger()?;
+    // The linger of the OS is kept by default
+    default_socket_options.apply(&tcp_stream)?;
+    assert_eq!(linger, SockRef::from(&tcp_stream).linger()?);
+    socket_options.apply(&tcp_stream)?;
+    assert_eq!(Some(Duration::ZERO), SockRef::from(&tcp_stream).linger()?);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_apply_socket_ip_tos() -> std::io::Result<()> {
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+    let tcp_stream = TcpStream::connect(listener.local_addr()?).await?;
+    // DSCP EF (46) in the upper 6 bits
+    let socket_options = SocketOptions {
+        socket_ip_tos: Some(0xb8),
+        ..Default::default()
+    };
+    socket_options.apply(&tcp_stream)?;
+    assert_eq!(0xb8, SockRef::from(&tcp_stream).tos_v4()?);
+    set_socket_ip_tos(&tcp_stream, 0x20)?;
+    assert_eq!(0x20, SockRef::from(&tcp_stream).tos_v4()?);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_close_gracefully() -> std::io::Result<()> {
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+    let mut client_stream = TcpStream::connect(listener.local_addr()?).await?;
+    let (mut relay_stream, _) = listener.accept().await?;
+    // The data never read by the relay makes the plain close send RST
+    client_stream.write_all(b"unread").await?;
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    let close_task = tokio::spawn(async move {
+        close_gracefully(&mut relay_stream, Duration::from_secs(5)).await;
+        drop(relay_stream);
+    });
+    // The client observes the clean EOF instead of the connection reset
+    let mut received = Vec::new();
+    client_stream.read_to_end(&mut received).await?;
+    assert!(received.is_empty());
+    client_stream.shutdown().await?;
+    close_task.await.unwrap();
+    // Nothing is reset after the relay stream is closed
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    assert_eq!(0, client_stream.read(&mut [0u8; 16]).await?);
+    assert!(client_stream.take_error()?.is_none());
+    Ok(())
+}
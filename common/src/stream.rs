@@ -0,0 +1,137 @@
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, DuplexStream, ReadBuf};
+use tokio::net::TcpStream;
+use tokio_util::bytes::{Buf, BytesMut};
+
+/// The stream which may be backed by a tcp socket, the socket level
+/// options are only applied when the tcp socket is available.
+pub trait AsTcpStream {
+    /// The tcp socket under the stream
+    fn as_tcp_stream(&self) -> Option<&TcpStream>;
+}
+
+impl AsTcpStream for TcpStream {
+    fn as_tcp_stream(&self) -> Option<&TcpStream> {
+        Some(self)
+    }
+}
+
+impl AsTcpStream for DuplexStream {
+    fn as_tcp_stream(&self) -> Option<&TcpStream> {
+        None
+    }
+}
+
+/// The size of the buffer read by one peek, a tiny peek still receives
+/// the data already arrived, so the following reads do not need a syscall each
+const PEEK_BUFFER_SIZE: usize = 4096;
+
+/// The stream which can peek the incoming data of any inner stream,
+/// the peeked data is buffered and returned again by the following reads.
+#[derive(Debug)]
+pub struct PeekableStream<S> {
+    inner: S,
+    peeked: BytesMut,
+}
+
+impl<S> PeekableStream<S>
+where
+    S: AsyncRead + Unpin,
+{
+    pub fn new(inner: S) -> Self {
+        Self {
+            inner,
+            peeked: BytesMut::new(),
+        }
+    }
+
+    /// Peek the incoming data without consuming it, `0` means the inner
+    /// stream reaches the end before any data is received
+    pub async fn peek(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        if self.peeked.is_empty() {
+            self.peeked.reserve(buf.len().max(PEEK_BUFFER_SIZE));
+            self.inner.read_buf(&mut self.peeked).await?;
+        }
+        let peeked_size = self.peeked.len().min(buf.len());
+        buf[..peeked_size].copy_from_slice(&self.peeked[..peeked_size]);
+        Ok(peeked_size)
+    }
+
+    pub fn get_ref(&self) -> &S {
+        &self.inner
+    }
+}
+
+impl<S> AsTcpStream for PeekableStream<S>
+where
+    S: AsTcpStream,
+{
+    fn as_tcp_stream(&self) -> Option<&TcpStream> {
+        self.inner.as_tcp_stream()
+    }
+}
+
+impl<S> AsyncRead for PeekableStream<S>
+where
+    S: AsyncRead + Unpin,
+{
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        let this = self.get_mut();
+        if this.peeked.is_empty() {
+            return Pin::new(&mut this.inner).poll_read(cx, buf);
+        }
+        let peeked_size = this.peeked.len().min(buf.remaining());
+        buf.put_slice(&this.peeked[..peeked_size]);
+        this.peeked.advance(peeked_size);
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S> AsyncWrite for PeekableStream<S>
+where
+    S: AsyncWrite + Unpin,
+{
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        Pin::new(&mut self.get_mut().inner).poll_write(cx, buf)
+    }
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+    }
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_shutdown(cx)
+    }
+}
+
+#[tokio::test]
+async fn test_peekable_stream() -> std::io::Result<()> {
+    use tokio::io::AsyncWriteExt;
+    let (client, incoming) = tokio::io::duplex(1024);
+    let mut client = client;
+    let mut incoming_stream = PeekableStream::new(incoming);
+    assert!(incoming_stream.as_tcp_stream().is_none());
+    client.write_all(b"GET / HTTP/1.1").await?;
+    let mut protocol_flag = [0u8; 1];
+    assert_eq!(1, incoming_stream.peek(&mut protocol_flag).await?);
+    assert_eq!(b"G", &protocol_flag);
+    // The tiny peek buffers all the data arrived
+    assert_eq!(14, incoming_stream.peeked.len());
+    // Peek again returns the same data
+    let mut method = [0u8; 3];
+    assert_eq!(3, incoming_stream.peek(&mut method).await?);
+    assert_eq!(b"GET", &method);
+    let mut received = [0u8; 14];
+    incoming_stream.read_exact(&mut received).await?;
+    assert_eq!(b"GET / HTTP/1.1", &received);
+    drop(client);
+    assert_eq!(0, incoming_stream.peek(&mut protocol_flag).await?);
+    Ok(())
+}
@@ -0,0 +1,148 @@
+use crate::error::Error;
+use crate::stream::AsTcpStream;
+use std::path::Path;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::net::TcpStream;
+use tokio_rustls::TlsAcceptor;
+use tokio_rustls::rustls::ServerConfig as TlsServerConfig;
+use tokio_rustls::rustls::crypto::ring::default_provider;
+use tokio_rustls::rustls::pki_types::pem::PemObject;
+use tokio_rustls::rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use tokio_rustls::server::TlsStream;
+
+/// Load the certificate chain and the private key in pem files
+/// as the acceptor terminating tls on the accepted connections.
+pub fn load_tls_acceptor(tls_cert: &Path, tls_key: &Path) -> Result<TlsAcceptor, Error> {
+    let certs = CertificateDer::pem_file_iter(tls_cert)
+        .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+        .map_err(|e| Error::Tls(format!("fail to load certificate {tls_cert:?}: {e}")))?;
+    let key = PrivateKeyDer::from_pem_file(tls_key)
+        .map_err(|e| Error::Tls(format!("fail to load private key {tls_key:?}: {e}")))?;
+    let tls_server_config = TlsServerConfig::builder_with_provider(Arc::new(default_provider()))
+        .with_safe_default_protocol_versions()
+        .map_err(|e| Error::Tls(e.to_string()))?
+        .with_no_client_auth()
+        .with_single_cert(certs, key)
+        .map_err(|e| Error::Tls(e.to_string()))?;
+    Ok(TlsAcceptor::from(Arc::new(tls_server_config)))
+}
+
+/// The stream accepted from the client, it is wrapped by tls
+/// when the server terminates tls on the accepted connections.
+#[derive(Debug)]
+pub enum IncomingStream {
+    Plain(TcpStream),
+    Tls(Box<TlsStream<TcpStream>>),
+}
+
+impl IncomingStream {
+    /// The tcp stream under the tls, for the socket level operations
+    pub fn tcp_stream(&self) -> &TcpStream {
+        match self {
+            IncomingStream::Plain(tcp_stream) => tcp_stream,
+            IncomingStream::Tls(tls_stream) => tls_stream.get_ref().0,
+        }
+    }
+}
+
+impl AsTcpStream for IncomingStream {
+    fn as_tcp_stream(&self) -> Option<&TcpStream> {
+        Some(self.tcp_stream())
+    }
+}
+
+impl AsyncRead for IncomingStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        match self.get_mut() {
+            IncomingStream::Plain(tcp_stream) => Pin::new(tcp_stream).poll_read(cx, buf),
+            IncomingStream::Tls(tls_stream) => Pin::new(tls_stream.as_mut()).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for IncomingStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        match self.get_mut() {
+            IncomingStream::Plain(tcp_stream) => Pin::new(tcp_stream).poll_write(cx, buf),
+            IncomingStream::Tls(tls_stream) => Pin::new(tls_stream.as_mut()).poll_write(cx, buf),
+        }
+    }
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        match self.get_mut() {
+            IncomingStream::Plain(tcp_stream) => Pin::new(tcp_stream).poll_flush(cx),
+            IncomingStream::Tls(tls_stream) => Pin::new(tls_stream.as_mut()).poll_flush(cx),
+        }
+    }
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        match self.get_mut() {
+            IncomingStream::Plain(tcp_stream) => Pin::new(tcp_stream).poll_shutdown(cx),
+            IncomingStream::Tls(tls_stream) => Pin::new(tls_stream.as_mut()).poll_shutdown(cx),
+        }
+    }
+}
+
+#[tokio::test]
+async fn test_tls_incoming_stream() -> Result<(), Error> {
+    use crate::stream::PeekableStream;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+    use tokio_rustls::TlsConnector;
+    use tokio_rustls::rustls::{ClientConfig, RootCertStore};
+    let certified_key = rcgen::generate_simple_self_signed(vec!["localhost".to_string()])
+        .map_err(|e| Error::Tls(e.to_string()))?;
+    let tls_dir = std::env::temp_dir().join(format!("ppaass-tls-test-{}", std::process::id()));
+    std::fs::create_dir_all(&tls_dir)?;
+    let tls_cert = tls_dir.join("cert.pem");
+    let tls_key = tls_dir.join("key.pem");
+    std::fs::write(&tls_cert, certified_key.cert.pem())?;
+    std::fs::write(&tls_key, certified_key.signing_key.serialize_pem())?;
+    let tls_acceptor = load_tls_acceptor(&tls_cert, &tls_key)?;
+    std::fs::remove_dir_all(&tls_dir)?;
+    let listener = TcpListener::bind("127.0.0.1:0").await?;
+    let listening_address = listener.local_addr()?;
+    let client_task = tokio::spawn(async move {
+        let mut root_cert_store = RootCertStore::empty();
+        root_cert_store
+            .add(certified_key.cert.der().clone())
+            .map_err(|e| Error::Tls(e.to_string()))?;
+        let client_config = ClientConfig::builder_with_provider(Arc::new(default_provider()))
+            .with_safe_default_protocol_versions()
+            .map_err(|e| Error::Tls(e.to_string()))?
+            .with_root_certificates(root_cert_store)
+            .with_no_client_auth();
+        let tcp_stream = TcpStream::connect(listening_address).await?;
+        let mut tls_stream = TlsConnector::from(Arc::new(client_config))
+            .connect("localhost".try_into().unwrap(), tcp_stream)
+            .await?;
+        tls_stream.write_all(&[5, 1, 0]).await?;
+        let mut reply = [0u8; 2];
+        tls_stream.read_exact(&mut reply).await?;
+        Ok::<_, Error>(reply)
+    });
+    let (tcp_stream, _) = listener.accept().await?;
+    let mut incoming_stream = PeekableStream::new(IncomingStream::Tls(Box::new(
+        tls_acceptor.accept(tcp_stream).await?,
+    )));
+    // The peeked bytes are decrypted and read again afterwards
+    let mut protocol_flag = [0u8; 1];
+    assert_eq!(1, incoming_stream.peek(&mut protocol_flag).await?);
+    assert_eq!([5], protocol_flag);
+    let mut request = [0u8; 3];
+    incoming_stream.read_exact(&mut request).await?;
+    assert_eq!([5, 1, 0], request);
+    incoming_stream.write_all(&[5, 0]).await?;
+    incoming_stream.flush().await?;
+    assert_eq!([5, 0], client_task.await.unwrap()?);
+    Ok(())
+}
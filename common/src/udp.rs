@@ -0,0 +1,153 @@
+use crate::Error;
+use ppaass_protocol::{Relay, UnifiedAddress};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio_util::bytes::Bytes;
+
+/// The default max size of one udp payload relayed between agent and proxy
+pub const DEFAULT_UDP_RELAY_BUFFER_SIZE: usize = 64 * 1024;
+
+/// Write one udp payload to the relay, the payload is prefixed with its
+/// length so the peer can reassemble it when it is split into multiple frames.
+pub async fn write_udp_relay_payload<W>(relay: &mut W, payload: &[u8]) -> Result<(), Error>
+where
+    W: AsyncWrite + Unpin,
+{
+    let payload_length = u32::try_from(payload.len())
+        .map_err(|_| Error::UdpPayloadTooLarge(payload.len(), u32::MAX as usize))?;
+    relay.write_u32(payload_length).await?;
+    relay.write_all(payload).await?;
+    relay.flush().await?;
+    Ok(())
+}
+
+/// Read one udp payload from the relay, the relay is drained until the
+/// whole payload announced by the length prefix arrives, a payload larger
+/// than `max_payload_size` is rejected before it is buffered.
+pub async fn read_udp_relay_payload<R>(
+    relay: &mut R,
+    max_payload_size: usize,
+) -> Result<Vec<u8>, Error>
+where
+    R: AsyncRead + Unpin,
+{
+    let payload_length = relay.read_u32().await? as usize;
+    if payload_length > max_payload_size {
+        return Err(Error::UdpPayloadTooLarge(payload_length, max_payload_size));
+    }
+    let mut payload = vec![0u8; payload_length];
+    relay.read_exact(&mut payload).await?;
+    Ok(payload)
+}
+
+/// One datagram of the udp association relayed between agent and proxy,
+/// the addresses let the peer demultiplex the datagrams of the association.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UdpRelayPacket {
+    /// The client source from agent to proxy, the replying destination from proxy to agent
+    pub src_addr: UnifiedAddress,
+    /// The destination from agent to proxy, the client source from proxy to agent
+    pub dst_addr: UnifiedAddress,
+    pub payload: Bytes,
+}
+
+/// Write one datagram of the udp association to the relay
+pub async fn write_udp_relay_packet<W>(relay: &mut W, packet: UdpRelayPacket) -> Result<(), Error>
+where
+    W: AsyncWrite + Unpin,
+{
+    let UdpRelayPacket {
+        src_addr,
+        dst_addr,
+        payload,
+    } = packet;
+    let packet_bytes: Vec<u8> = Relay::Udp {
+        src_addr,
+        dst_addr,
+        payload,
+    }
+    .try_into()?;
+    write_udp_relay_payload(relay, &packet_bytes).await
+}
+
+/// Read one datagram of the udp association from the relay, the
+/// `max_packet_size` covers the addresses together with the payload.
+pub async fn read_udp_relay_packet<R>(
+    relay: &mut R,
+    max_packet_size: usize,
+) -> Result<UdpRelayPacket, Error>
+where
+    R: AsyncRead + Unpin,
+{
+    let packet_bytes = read_udp_relay_payload(relay, max_packet_size).await?;
+    match Relay::try_from(Bytes::from(packet_bytes))? {
+        Relay::Udp {
+            src_addr,
+            dst_addr,
+            payload,
+        } => Ok(UdpRelayPacket {
+            src_addr,
+            dst_addr,
+            payload,
+        }),
+        Relay::Tcp(_) => Err(Error::UnexpectedTcpRelay),
+    }
+}
+
+#[tokio::test]
+async fn test_udp_relay_payload() -> Result<(), Error> {
+    let (mut writer, mut reader) = tokio::io::duplex(1024);
+    let payload = (0..200_000).map(|i| (i % 251) as u8).collect::<Vec<u8>>();
+    let expected_payload = payload.clone();
+    let write_task = tokio::spawn(async move {
+        write_udp_relay_payload(&mut writer, &payload).await?;
+        write_udp_relay_payload(&mut writer, &payload).await
+    });
+    assert_eq!(
+        expected_payload,
+        read_udp_relay_payload(&mut reader, 256 * 1024).await?
+    );
+    assert!(matches!(
+        read_udp_relay_payload(&mut reader, DEFAULT_UDP_RELAY_BUFFER_SIZE).await,
+        Err(Error::UdpPayloadTooLarge(
+            200_000,
+            DEFAULT_UDP_RELAY_BUFFER_SIZE
+        ))
+    ));
+    drop(reader);
+    let _ = write_task.await;
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_udp_relay_packet() -> Result<(), Error> {
+    let (mut writer, mut reader) = tokio::io::duplex(1024);
+    let packets = (0..3)
+        .map(|i| UdpRelayPacket {
+            src_addr: "127.0.0.1:10080".try_into().unwrap(),
+            dst_addr: UnifiedAddress::Domain {
+                host: format!("dns{i}.example.com"),
+                port: 53,
+            },
+            payload: Bytes::from(vec![i as u8; 100 * (i + 1)]),
+        })
+        .collect::<Vec<_>>();
+    let sent_packets = packets.clone();
+    let write_task = tokio::spawn(async move {
+        for packet in sent_packets {
+            write_udp_relay_packet(&mut writer, packet).await?;
+        }
+        write_udp_relay_payload(&mut writer, &Vec::<u8>::try_from(Relay::Tcp(Bytes::new()))?).await
+    });
+    for packet in packets {
+        assert_eq!(
+            packet,
+            read_udp_relay_packet(&mut reader, DEFAULT_UDP_RELAY_BUFFER_SIZE).await?
+        );
+    }
+    assert!(matches!(
+        read_udp_relay_packet(&mut reader, DEFAULT_UDP_RELAY_BUFFER_SIZE).await,
+        Err(Error::UnexpectedTcpRelay)
+    ));
+    write_task.await.unwrap()?;
+    Ok(())
+}
@@ -1,12 +1,17 @@
 pub mod repo;
+#[cfg(feature = "sqlite")]
+pub mod sqlite;
 
 use crate::Error;
 use crate::config::UserRepoConfig;
 use chrono::{DateTime, Utc};
 use ppaass_crypto::RsaCrypto;
 use ppaass_protocol::Username;
+use serde::{Deserialize, Serialize};
 use std::net::SocketAddr;
 use std::ops::Deref;
+use std::sync::Arc;
+use std::sync::atomic::AtomicUsize;
 
 /// The base user
 pub trait User {
@@ -24,10 +29,69 @@ pub trait UserWithExpiredTime: User {
     fn expired_time(&self) -> Option<&DateTime<Utc>>;
 }
 
+/// The proxy server of the user, the connections are spread over the proxy
+/// servers of the user in proportion to their weights. It is configured as
+/// the address alone or as `{ address = "...", weight = 3 }`.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(from = "ProxyServerEntry")]
+pub struct ProxyServer {
+    pub address: SocketAddr,
+    /// The share of the connections, `0` only takes the connections
+    /// when all the other proxy servers fail
+    pub weight: u32,
+}
+
+impl From<SocketAddr> for ProxyServer {
+    fn from(address: SocketAddr) -> Self {
+        Self {
+            address,
+            weight: DEFAULT_PROXY_SERVER_WEIGHT,
+        }
+    }
+}
+
+const DEFAULT_PROXY_SERVER_WEIGHT: u32 = 1;
+
+fn default_proxy_server_weight() -> u32 {
+    DEFAULT_PROXY_SERVER_WEIGHT
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum ProxyServerEntry {
+    Address(SocketAddr),
+    Weighted {
+        address: SocketAddr,
+        #[serde(default = "default_proxy_server_weight")]
+        weight: u32,
+    },
+}
+
+impl From<ProxyServerEntry> for ProxyServer {
+    fn from(value: ProxyServerEntry) -> Self {
+        match value {
+            ProxyServerEntry::Address(address) => address.into(),
+            ProxyServerEntry::Weighted { address, weight } => Self { address, weight },
+        }
+    }
+}
+
 /// The user with proxy servers
 pub trait UserWithProxyServers: User {
-    /// The proxy server addresses
-    fn proxy_servers(&self) -> &[SocketAddr];
+    /// The proxy servers
+    fn proxy_servers(&self) -> &[ProxyServer];
+    /// The position the next round robin proxy connection of the user starts
+    /// from, every user keeps its own cursor, which restarts when the user
+    /// is reloaded
+    fn round_robin_next(&self) -> &AtomicUsize;
+}
+
+/// Check the user has at least one proxy server to connect to
+pub fn validate_proxy_servers<U: UserWithProxyServers>(user: &U) -> Result<(), Error> {
+    if user.proxy_servers().is_empty() {
+        return Err(Error::NoProxyServersConfigured(user.username().clone()));
+    }
+    Ok(())
 }
 
 /// The user repository
@@ -41,8 +105,74 @@ where
     fn new<T>(config: T) -> Result<Self, Error>
     where
         T: Deref<Target = Self::UserRepoConfigType> + Send + Sync + 'static;
-    /// Find the user by username
-    fn find_user(&self, username: &Username) -> Option<&Self::UserInfoType>;
+    /// Find the user by username, the user is shared so it stays
+    /// valid when the repository refreshes its users
+    fn find_user(&self, username: &Username) -> Option<Arc<Self::UserInfoType>>;
     /// Save a user into the repository
     fn save_user(&mut self, user: Self::UserInfoType);
 }
+
+#[test]
+fn test_username_consistent() {
+    use crate::config::UserConfig;
+    struct TestUser {
+        username: Username,
+    }
+    impl User for TestUser {
+        fn username(&self) -> &Username {
+            &self.username
+        }
+        fn rsa_crypto(&self) -> Option<&RsaCrypto> {
+            None
+        }
+        fn set_rsa_crypto(&mut self, _rsa_crypto: RsaCrypto) {}
+    }
+    impl UserConfig for TestUser {
+        fn username(&self) -> &Username {
+            &self.username
+        }
+    }
+    fn usernames<T: User + UserConfig>(value: &T) -> (&Username, &Username) {
+        (User::username(value), UserConfig::username(value))
+    }
+    let user = TestUser {
+        username: Username("user1".to_string()),
+    };
+    let (user_username, config_username) = usernames(&user);
+    assert_eq!(user_username, config_username);
+    assert_eq!("user1", user_username.as_str());
+}
+
+#[test]
+fn test_deserialize_proxy_servers() {
+    #[derive(Deserialize)]
+    struct UserInfo {
+        proxy_servers: Vec<ProxyServer>,
+    }
+    let user_info: UserInfo = toml::from_str(
+        r#"proxy_servers = [
+            "10.0.0.1:80",
+            { address = "10.0.0.2:80", weight = 3 },
+            { address = "10.0.0.3:80" },
+        ]"#,
+    )
+    .unwrap();
+    assert_eq!(
+        vec![
+            ProxyServer {
+                address: "10.0.0.1:80".parse().unwrap(),
+                weight: 1,
+            },
+            ProxyServer {
+                address: "10.0.0.2:80".parse().unwrap(),
+                weight: 3,
+            },
+            ProxyServer {
+                address: "10.0.0.3:80".parse().unwrap(),
+                weight: 1,
+            },
+        ],
+        user_info.proxy_servers
+    );
+    assert!(toml::from_str::<UserInfo>(r#"proxy_servers = ["not an address"]"#).is_err());
+}
@@ -6,17 +6,29 @@ use ppaass_crypto::RsaCrypto;
 use ppaass_protocol::Username;
 use serde::de::DeserializeOwned;
 use std::collections::HashMap;
+use std::collections::hash_map::Entry;
 use std::marker::PhantomData;
+use std::num::NonZeroUsize;
 use std::ops::Deref;
-use tracing::error;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, PoisonError, RwLock, Weak};
+use std::time::Duration;
+use tracing::{debug, error, warn};
 
+type UserStorage<U> = HashMap<Username, Arc<U>>;
+
+/// The user repository loaded from the user directories, the users are
+/// reloaded every `refresh_interval_sec` seconds in a background thread
+/// and the storage is swapped at once, `0` disables the refresh. The users
+/// found before a swap stay valid because they are shared with `Arc`.
 #[derive(Debug)]
 pub struct FileSystemUserRepository<U, C>
 where
     U: User + Send + Sync + DeserializeOwned + 'static,
     C: FsUserRepoConfig + Send + Sync + 'static,
 {
-    storage: HashMap<Username, U>,
+    storage: Arc<RwLock<UserStorage<U>>>,
     _config_mark: PhantomData<C>,
 }
 
@@ -25,8 +37,116 @@ where
     U: User + Send + Sync + DeserializeOwned + 'static,
     C: FsUserRepoConfig + Send + Sync + 'static,
 {
+    fn load_storage(config: &C) -> Result<UserStorage<U>, Error> {
+        let mut storage = HashMap::new();
+        Self::fill_storage(config, &mut storage)?;
+        Ok(storage
+            .into_iter()
+            .map(|(username, user_info)| (username, Arc::new(user_info)))
+            .collect())
+    }
+
+    /// Reload the users every refresh interval until the repository is
+    /// dropped, the current users are kept when the reloading fails or loads
+    /// no user, so a user directory briefly empty or unreadable during a
+    /// deploy does not reject every handshake until the next refresh.
+    fn refresh_storage<T>(config: T, storage: Weak<RwLock<UserStorage<U>>>, interval: Duration)
+    where
+        T: Deref<Target = C>,
+    {
+        loop {
+            std::thread::sleep(interval);
+            let Some(storage) = storage.upgrade() else {
+                return;
+            };
+            match Self::load_storage(&config) {
+                Ok(refreshed_storage)
+                    if refreshed_storage.is_empty()
+                        && !storage
+                            .read()
+                            .unwrap_or_else(PoisonError::into_inner)
+                            .is_empty() =>
+                {
+                    warn!(
+                        "Refresh no user from user repository directory [{:?}], keep the current users",
+                        config.user_repo_directory()
+                    );
+                }
+                Ok(refreshed_storage) => {
+                    debug!(
+                        "Refresh {} users from user repository directory [{:?}]",
+                        refreshed_storage.len(),
+                        config.user_repo_directory()
+                    );
+                    *storage.write().unwrap_or_else(PoisonError::into_inner) = refreshed_storage;
+                }
+                Err(e) => {
+                    error!("Fail to refresh user repository storage, keep the current users: {e}");
+                }
+            }
+        }
+    }
+
     fn fill_storage(config: &C, storage: &mut HashMap<Username, U>) -> Result<(), Error> {
         let user_repo_directory_path = config.user_repo_directory();
+        let user_dirs = Self::list_user_dirs(user_repo_directory_path)?;
+        let parallelism = config
+            .user_load_parallelism()
+            .unwrap_or_else(|| {
+                std::thread::available_parallelism()
+                    .map(NonZeroUsize::get)
+                    .unwrap_or(1)
+            })
+            .max(1);
+        let reach_max_users = |storage: &HashMap<Username, U>| match config.max_users() {
+            Some(max_users) if storage.len() >= max_users => {
+                warn!(
+                    "Stop loading users from user repository directory [{user_repo_directory_path:?}] because of reaching max users: {max_users}"
+                );
+                true
+            }
+            _ => false,
+        };
+        let mut next_user_dir = 0;
+        while next_user_dir < user_dirs.len() {
+            if reach_max_users(storage) {
+                return Ok(());
+            }
+            let needed_users = config
+                .max_users()
+                .map(|max_users| max_users - storage.len());
+            let (users, loaded_user_dirs) = Self::load_users(
+                config,
+                &user_dirs[next_user_dir..],
+                parallelism,
+                needed_users,
+            );
+            next_user_dir += loaded_user_dirs;
+            // Insert in the directory name order, so the duplicate
+            // username always resolves to the same user directory
+            for user_info in users {
+                if reach_max_users(storage) {
+                    return Ok(());
+                }
+                match storage.entry(user_info.username().clone()) {
+                    Entry::Occupied(entry) => {
+                        warn!(
+                            "Skip duplicate user [{}] in user repository directory [{user_repo_directory_path:?}]",
+                            entry.key()
+                        );
+                    }
+                    Entry::Vacant(entry) => {
+                        entry.insert(user_info);
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// List the user directories sorted by the directory name
+    fn list_user_dirs(user_repo_directory_path: &Path) -> Result<Vec<PathBuf>, Error> {
+        let mut user_dirs = Vec::new();
         let mut user_repo_directory = std::fs::read_dir(user_repo_directory_path)?;
         while let Some(Ok(sub_entry)) = user_repo_directory.next() {
             let file_type = match sub_entry.file_type() {
@@ -51,49 +171,107 @@ where
             if file_name.starts_with("\\.") {
                 continue;
             }
-            let user_dir_path = sub_entry.path();
-            let public_key_file_path = user_dir_path.join(config.public_key_file_name());
-            let public_key_file = match std::fs::File::open(public_key_file_path) {
-                Ok(public_key_file) => public_key_file,
-                Err(e) => {
-                    error!("Fail to read public key file: {e:?}");
-                    continue;
-                }
-            };
-            let private_key_file_path = user_dir_path.join(config.private_key_file_name());
-            let private_key_file = match std::fs::File::open(private_key_file_path) {
-                Ok(private_key_file) => private_key_file,
-                Err(e) => {
-                    error!("Fail to read private key file: {e:?}");
-                    continue;
-                }
-            };
-            let user_rsa_crypto = match RsaCrypto::new(public_key_file, private_key_file) {
-                Ok(user_rsa_crypto) => user_rsa_crypto,
-                Err(e) => {
-                    error!("Fail to create user rsa crypto: {e:?}");
-                    continue;
-                }
-            };
-            let user_info_file_path = user_dir_path.join(config.user_info_file_name());
-            let user_info_file_content = match std::fs::read_to_string(&user_info_file_path) {
-                Ok(content) => content,
-                Err(e) => {
-                    error!("Fail to read user info file content: {e:?}");
-                    continue;
-                }
-            };
-            let mut user_info = match toml::from_str::<U>(&user_info_file_content) {
-                Ok(user_info) => user_info,
-                Err(e) => {
-                    error!("Fail to deserialize the user info: {e:?}");
-                    continue;
-                }
-            };
-            user_info.set_rsa_crypto(user_rsa_crypto);
-            storage.insert(user_info.username().clone(), user_info);
+            user_dirs.push(sub_entry.path());
         }
-        Ok(())
+        user_dirs.sort();
+        Ok(user_dirs)
+    }
+
+    /// Load the users of the directories with `parallelism` threads, the
+    /// threads stop taking directories once `needed_users` users are
+    /// loaded. Returns the loaded users in the directory order and the
+    /// number of the directories taken, they are always the leading ones.
+    fn load_users(
+        config: &C,
+        user_dirs: &[PathBuf],
+        parallelism: usize,
+        needed_users: Option<usize>,
+    ) -> (Vec<U>, usize) {
+        let next_user_dir = AtomicUsize::new(0);
+        let loaded_users = AtomicUsize::new(0);
+        let mut users = std::thread::scope(|scope| {
+            let loaders = (0..parallelism.min(user_dirs.len()))
+                .map(|_| {
+                    scope.spawn(|| {
+                        let mut users = Vec::new();
+                        loop {
+                            if let Some(needed_users) = needed_users
+                                && loaded_users.load(Ordering::SeqCst) >= needed_users
+                            {
+                                return users;
+                            }
+                            let index = next_user_dir.fetch_add(1, Ordering::SeqCst);
+                            let Some(user_dir_path) = user_dirs.get(index) else {
+                                return users;
+                            };
+                            if let Some(user_info) = Self::load_user(config, user_dir_path) {
+                                loaded_users.fetch_add(1, Ordering::SeqCst);
+                                users.push((index, user_info));
+                            }
+                        }
+                    })
+                })
+                .collect::<Vec<_>>();
+            loaders
+                .into_iter()
+                .flat_map(|loader| match loader.join() {
+                    Ok(users) => users,
+                    Err(_) => {
+                        error!("User loading thread panicked.");
+                        Vec::new()
+                    }
+                })
+                .collect::<Vec<_>>()
+        });
+        users.sort_by_key(|(index, _)| *index);
+        let taken_user_dirs = next_user_dir.into_inner().min(user_dirs.len());
+        (
+            users.into_iter().map(|(_, user_info)| user_info).collect(),
+            taken_user_dirs,
+        )
+    }
+
+    fn load_user(config: &C, user_dir_path: &Path) -> Option<U> {
+        let public_key_file_path = user_dir_path.join(config.public_key_file_name());
+        let public_key_file = match std::fs::File::open(public_key_file_path) {
+            Ok(public_key_file) => public_key_file,
+            Err(e) => {
+                error!("Fail to read public key file: {e:?}");
+                return None;
+            }
+        };
+        let private_key_file_path = user_dir_path.join(config.private_key_file_name());
+        let private_key_file = match std::fs::File::open(private_key_file_path) {
+            Ok(private_key_file) => private_key_file,
+            Err(e) => {
+                error!("Fail to read private key file: {e:?}");
+                return None;
+            }
+        };
+        let user_rsa_crypto = match RsaCrypto::new(public_key_file, private_key_file) {
+            Ok(user_rsa_crypto) => user_rsa_crypto,
+            Err(e) => {
+                error!("Fail to create user rsa crypto: {e:?}");
+                return None;
+            }
+        };
+        let user_info_file_path = user_dir_path.join(config.user_info_file_name());
+        let user_info_file_content = match std::fs::read_to_string(&user_info_file_path) {
+            Ok(content) => content,
+            Err(e) => {
+                error!("Fail to read user info file content: {e:?}");
+                return None;
+            }
+        };
+        let mut user_info = match toml::from_str::<U>(&user_info_file_content) {
+            Ok(user_info) => user_info,
+            Err(e) => {
+                error!("Fail to deserialize the user info: {e:?}");
+                return None;
+            }
+        };
+        user_info.set_rsa_crypto(user_rsa_crypto);
+        Some(user_info)
     }
 }
 
@@ -108,22 +286,276 @@ where
     where
         T: Deref<Target = Self::UserRepoConfigType> + Send + Sync + 'static,
     {
-        let mut storage = HashMap::new();
-        if let Err(e) = Self::fill_storage(&config, &mut storage) {
+        let storage = Self::load_storage(&config).unwrap_or_else(|e| {
             error!("Failed to fill user repository storage: {}", e);
-        };
+            HashMap::new()
+        });
+        let storage = Arc::new(RwLock::new(storage));
+        let refresh_interval_sec = config.refresh_interval_sec();
+        if refresh_interval_sec > 0 {
+            let refreshed_storage = Arc::downgrade(&storage);
+            std::thread::Builder::new()
+                .name("user-repo-refresh".to_string())
+                .spawn(move || {
+                    Self::refresh_storage(
+                        config,
+                        refreshed_storage,
+                        Duration::from_secs(refresh_interval_sec),
+                    )
+                })?;
+        }
         Ok(Self {
             storage,
             _config_mark: Default::default(),
         })
     }
 
-    fn find_user(&self, username: &Username) -> Option<&Self::UserInfoType> {
-        let user_info = self.storage.get(username)?;
-        Some(user_info)
+    fn find_user(&self, username: &Username) -> Option<Arc<Self::UserInfoType>> {
+        self.storage
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .get(username)
+            .cloned()
     }
 
+    /// Save the user until the next refresh, the refresh only keeps
+    /// the users of the user directories.
     fn save_user(&mut self, user: Self::UserInfoType) {
-        self.storage.insert(user.username().to_owned(), user);
+        self.storage
+            .write()
+            .unwrap_or_else(PoisonError::into_inner)
+            .insert(user.username().to_owned(), Arc::new(user));
+    }
+}
+
+/// The user of the user repository tests
+#[cfg(test)]
+#[derive(serde::Deserialize)]
+pub(crate) struct TestUser {
+    pub(crate) username: Username,
+    /// The user directory the user is loaded from
+    #[serde(default)]
+    pub(crate) user_dir_name: String,
+    pub(crate) expired_time: Option<chrono::DateTime<chrono::Utc>>,
+    /// The field of the user info stored along the user in sqlite
+    #[cfg(feature = "sqlite")]
+    #[serde(default)]
+    pub(crate) observe_mode: bool,
+    #[serde(skip)]
+    rsa_crypto: Option<RsaCrypto>,
+}
+
+#[cfg(test)]
+impl User for TestUser {
+    fn username(&self) -> &Username {
+        &self.username
+    }
+    fn rsa_crypto(&self) -> Option<&RsaCrypto> {
+        self.rsa_crypto.as_ref()
+    }
+    fn set_rsa_crypto(&mut self, rsa_crypto: RsaCrypto) {
+        self.rsa_crypto = Some(rsa_crypto)
+    }
+}
+
+#[cfg(test)]
+impl crate::user::UserWithExpiredTime for TestUser {
+    fn expired_time(&self) -> Option<&chrono::DateTime<chrono::Utc>> {
+        self.expired_time.as_ref()
     }
 }
+
+/// The config of the user repository tests, the sqlite user repository
+/// reads the users from the database path instead of the directory
+#[cfg(test)]
+#[derive(Default)]
+pub(crate) struct TestConfig {
+    pub(crate) user_repo_directory: PathBuf,
+    #[cfg(feature = "sqlite")]
+    pub(crate) database_path: PathBuf,
+    pub(crate) max_users: Option<usize>,
+    pub(crate) user_load_parallelism: Option<usize>,
+    pub(crate) refresh_interval_sec: u64,
+}
+
+#[cfg(test)]
+impl crate::config::UserRepoConfig for TestConfig {
+    fn refresh_interval_sec(&self) -> u64 {
+        self.refresh_interval_sec
+    }
+}
+
+#[cfg(test)]
+impl FsUserRepoConfig for TestConfig {
+    fn user_repo_directory(&self) -> &Path {
+        &self.user_repo_directory
+    }
+    fn public_key_file_name(&self) -> &str {
+        "AgentPublicKey.pem"
+    }
+    fn private_key_file_name(&self) -> &str {
+        "ProxyPrivateKey.pem"
+    }
+    fn user_info_file_name(&self) -> &str {
+        "user_info.toml"
+    }
+    fn max_users(&self) -> Option<usize> {
+        self.max_users
+    }
+    fn user_load_parallelism(&self) -> Option<usize> {
+        self.user_load_parallelism
+    }
+}
+
+/// Create the user repository directory of the tests, the user
+/// directory names and the usernames are given in pairs
+#[cfg(test)]
+fn create_test_user_repo(name: &str, users: &[(String, String)]) -> Result<PathBuf, Error> {
+    let user_repo_directory = std::env::temp_dir().join(name);
+    let _ = std::fs::remove_dir_all(&user_repo_directory);
+    for (user_dir_name, username) in users {
+        create_test_user_repo_user(&user_repo_directory, user_dir_name, username)?;
+    }
+    Ok(user_repo_directory)
+}
+
+/// Create one user directory in the user repository directory of the tests
+#[cfg(test)]
+fn create_test_user_repo_user(
+    user_repo_directory: &Path,
+    user_dir_name: &str,
+    username: &str,
+) -> Result<(), Error> {
+    let source_user_dir = Path::new("../resources/proxy/user/user1");
+    let user_dir = user_repo_directory.join(user_dir_name);
+    std::fs::create_dir_all(&user_dir)?;
+    for key_file_name in ["AgentPublicKey.pem", "ProxyPrivateKey.pem"] {
+        std::fs::copy(
+            source_user_dir.join(key_file_name),
+            user_dir.join(key_file_name),
+        )?;
+    }
+    std::fs::write(
+        user_dir.join("user_info.toml"),
+        format!("username = \"{username}\"\nuser_dir_name = \"{user_dir_name}\""),
+    )?;
+    Ok(())
+}
+
+#[test]
+fn test_max_users() -> Result<(), Error> {
+    let users = (0..5)
+        .map(|index| (format!("user{index}"), format!("user{index}")))
+        .collect::<Vec<_>>();
+    let user_repo_directory = create_test_user_repo("ppaass-test-max-users", &users)?;
+    let load_users = |max_users, user_load_parallelism| {
+        let mut storage = HashMap::new();
+        let config = TestConfig {
+            user_repo_directory: user_repo_directory.clone(),
+            max_users,
+            user_load_parallelism,
+            ..Default::default()
+        };
+        FileSystemUserRepository::<TestUser, TestConfig>::fill_storage(&config, &mut storage)
+            .map(|_| storage.len())
+    };
+    let capped_users = load_users(Some(2), Some(1));
+    let all_users = load_users(None, Some(1));
+    let parallel_capped_users = load_users(Some(2), Some(4));
+    let parallel_all_users = load_users(None, Some(4));
+    std::fs::remove_dir_all(&user_repo_directory)?;
+    assert_eq!(2, capped_users?);
+    assert_eq!(5, all_users?);
+    assert_eq!(2, parallel_capped_users?);
+    assert_eq!(5, parallel_all_users?);
+    Ok(())
+}
+
+#[test]
+fn test_parallel_load_duplicate_users() -> Result<(), Error> {
+    // Every username is used by 2 user directories, the directory
+    // with the smaller name must win whatever thread loads it first
+    let users = (0..16)
+        .map(|index| (format!("dir{index:02}"), format!("user{}", index % 8)))
+        .collect::<Vec<_>>();
+    let user_repo_directory = create_test_user_repo("ppaass-test-duplicate-users", &users)?;
+    let mut storages = Vec::new();
+    for user_load_parallelism in [1, 4, 16] {
+        let mut storage = HashMap::new();
+        let config = TestConfig {
+            user_repo_directory: user_repo_directory.clone(),
+            max_users: None,
+            user_load_parallelism: Some(user_load_parallelism),
+            ..Default::default()
+        };
+        FileSystemUserRepository::<TestUser, TestConfig>::fill_storage(&config, &mut storage)?;
+        storages.push(storage);
+    }
+    let users_of_capped_repo = {
+        let mut storage = HashMap::new();
+        let config = TestConfig {
+            user_repo_directory: user_repo_directory.clone(),
+            max_users: Some(3),
+            user_load_parallelism: Some(4),
+            ..Default::default()
+        };
+        FileSystemUserRepository::<TestUser, TestConfig>::fill_storage(&config, &mut storage)?;
+        let mut usernames = storage.into_keys().collect::<Vec<_>>();
+        usernames.sort_by(|a, b| a.as_str().cmp(b.as_str()));
+        usernames
+    };
+    std::fs::remove_dir_all(&user_repo_directory)?;
+    for storage in storages {
+        assert_eq!(8, storage.len());
+        for (username, user_info) in storage {
+            let index = username.trim_start_matches("user");
+            assert_eq!(format!("dir0{index}"), user_info.user_dir_name);
+        }
+    }
+    assert_eq!(
+        vec![
+            Username::from("user0"),
+            Username::from("user1"),
+            Username::from("user2")
+        ],
+        users_of_capped_repo
+    );
+    Ok(())
+}
+
+#[test]
+fn test_refresh_storage() -> Result<(), Error> {
+    let users = [("user1".to_string(), "user1".to_string())];
+    let user_repo_directory = create_test_user_repo("ppaass-test-refresh-users", &users)?;
+    let user_repo = FileSystemUserRepository::<TestUser, TestConfig>::new(Box::new(TestConfig {
+        user_repo_directory: user_repo_directory.clone(),
+        user_load_parallelism: None,
+        refresh_interval_sec: 1,
+        ..Default::default()
+    }))?;
+    let user1 = user_repo.find_user(&Username::from("user1"));
+    assert!(user1.is_some());
+    assert!(user_repo.find_user(&Username::from("user2")).is_none());
+    // Revoke user1 and add user2, the repository picks them up without restart
+    std::fs::remove_dir_all(user_repo_directory.join("user1"))?;
+    create_test_user_repo_user(&user_repo_directory, "user2", "user2")?;
+    let refreshed = (0..50).any(|_| {
+        std::thread::sleep(Duration::from_millis(100));
+        user_repo.find_user(&Username::from("user2")).is_some()
+    });
+    let revoked_user1 = user_repo.find_user(&Username::from("user1"));
+    // The refresh loading no user keeps the current users
+    std::fs::remove_dir_all(user_repo_directory.join("user2"))?;
+    std::thread::sleep(Duration::from_millis(2500));
+    let kept_user2 = user_repo.find_user(&Username::from("user2"));
+    std::fs::remove_dir_all(&user_repo_directory)?;
+    assert!(refreshed);
+    assert!(revoked_user1.is_none());
+    assert!(kept_user2.is_some());
+    // The user found before the refresh stays valid
+    assert_eq!(
+        Some("user1"),
+        user1.as_ref().map(|user1| user1.username().as_str())
+    );
+    Ok(())
+}
@@ -0,0 +1,257 @@
+use crate::Error;
+use crate::config::SqliteUserRepoConfig;
+use crate::user::User;
+use crate::user::UserRepository;
+use ppaass_crypto::RsaCrypto;
+use ppaass_protocol::Username;
+use rusqlite::{Connection, OpenFlags};
+use serde::de::DeserializeOwned;
+use std::collections::HashMap;
+use std::marker::PhantomData;
+use std::ops::Deref;
+use std::sync::Arc;
+use tracing::error;
+
+/// The columns of the user table, `user_info` holds the other fields of
+/// the user in toml, `expired_time` is the RFC 3339 timestamp if any.
+const SELECT_USERS_SQL: &str = "SELECT username, public_key_pem, private_key_pem, expired_time, user_info FROM users ORDER BY username LIMIT ?1";
+
+/// The user repository backed by the `users` table of a sqlite database:
+///
+/// ```sql
+/// CREATE TABLE users (
+///     username TEXT PRIMARY KEY,
+///     public_key_pem TEXT NOT NULL,
+///     private_key_pem TEXT NOT NULL,
+///     expired_time TEXT,
+///     user_info TEXT
+/// );
+/// ```
+///
+/// The users are loaded into a cache keyed by username when the repository
+/// is created, `find_user` only reads the cache so it never touches the
+/// database. The rows changed in the database afterwards are not seen until
+/// the repository is created again, and `save_user` only updates the cache,
+/// the same as `FileSystemUserRepository`.
+#[derive(Debug)]
+pub struct SqliteUserRepository<U, C>
+where
+    U: User + Send + Sync + DeserializeOwned + 'static,
+    C: SqliteUserRepoConfig + Send + Sync + 'static,
+{
+    storage: HashMap<Username, Arc<U>>,
+    _config_mark: PhantomData<C>,
+}
+
+impl<U, C> SqliteUserRepository<U, C>
+where
+    U: User + Send + Sync + DeserializeOwned + 'static,
+    C: SqliteUserRepoConfig + Send + Sync + 'static,
+{
+    fn fill_storage(config: &C, storage: &mut HashMap<Username, Arc<U>>) -> Result<(), Error> {
+        let connection =
+            Connection::open_with_flags(config.database_path(), OpenFlags::SQLITE_OPEN_READ_ONLY)?;
+        let mut statement = connection.prepare(SELECT_USERS_SQL)?;
+        // The negative limit means no limit in sqlite
+        let max_users = config
+            .max_users()
+            .and_then(|max_users| i64::try_from(max_users).ok())
+            .unwrap_or(-1);
+        let mut rows = statement.query([max_users])?;
+        while let Some(row) = rows.next()? {
+            let username: String = row.get(0)?;
+            let public_key_pem: String = row.get(1)?;
+            let private_key_pem: String = row.get(2)?;
+            let expired_time: Option<String> = row.get(3)?;
+            let user_info: Option<String> = row.get(4)?;
+            if let Some(user_info) = Self::load_user(
+                &username,
+                &public_key_pem,
+                &private_key_pem,
+                expired_time,
+                user_info.as_deref(),
+            ) {
+                storage.insert(user_info.username().clone(), Arc::new(user_info));
+            }
+        }
+        Ok(())
+    }
+
+    fn load_user(
+        username: &str,
+        public_key_pem: &str,
+        private_key_pem: &str,
+        expired_time: Option<String>,
+        user_info: Option<&str>,
+    ) -> Option<U> {
+        let user_rsa_crypto =
+            match RsaCrypto::new(public_key_pem.as_bytes(), private_key_pem.as_bytes()) {
+                Ok(user_rsa_crypto) => user_rsa_crypto,
+                Err(e) => {
+                    error!("Fail to create rsa crypto of user [{username}]: {e:?}");
+                    return None;
+                }
+            };
+        let mut user_info_table = match toml::from_str::<toml::Table>(user_info.unwrap_or_default())
+        {
+            Ok(user_info_table) => user_info_table,
+            Err(e) => {
+                error!("Fail to parse the user info of user [{username}]: {e:?}");
+                return None;
+            }
+        };
+        user_info_table.insert("username".to_string(), username.into());
+        if let Some(expired_time) = expired_time {
+            user_info_table.insert("expired_time".to_string(), expired_time.into());
+        }
+        let mut user_info = match toml::Value::Table(user_info_table).try_into::<U>() {
+            Ok(user_info) => user_info,
+            Err(e) => {
+                error!("Fail to deserialize the user info of user [{username}]: {e:?}");
+                return None;
+            }
+        };
+        user_info.set_rsa_crypto(user_rsa_crypto);
+        Some(user_info)
+    }
+}
+
+impl<U, C> UserRepository for SqliteUserRepository<U, C>
+where
+    U: User + Send + Sync + DeserializeOwned + 'static,
+    C: SqliteUserRepoConfig + Send + Sync + 'static,
+{
+    type UserInfoType = U;
+    type UserRepoConfigType = C;
+    fn new<T>(config: T) -> Result<Self, Error>
+    where
+        T: Deref<Target = Self::UserRepoConfigType> + Send + Sync + 'static,
+    {
+        let mut storage = HashMap::new();
+        if let Err(e) = Self::fill_storage(&config, &mut storage) {
+            error!(
+                "Failed to fill user repository storage from database: {}",
+                e
+            );
+        };
+        Ok(Self {
+            storage,
+            _config_mark: Default::default(),
+        })
+    }
+
+    fn find_user(&self, username: &Username) -> Option<Arc<Self::UserInfoType>> {
+        self.storage.get(username).cloned()
+    }
+
+    fn save_user(&mut self, user: Self::UserInfoType) {
+        self.storage
+            .insert(user.username().to_owned(), Arc::new(user));
+    }
+}
+
+#[cfg(test)]
+impl SqliteUserRepoConfig for crate::user::repo::TestConfig {
+    fn database_path(&self) -> &std::path::Path {
+        &self.database_path
+    }
+    fn max_users(&self) -> Option<usize> {
+        self.max_users
+    }
+}
+
+#[test]
+fn test_sqlite_user_repository() -> Result<(), Error> {
+    use crate::user::UserWithExpiredTime;
+    use crate::user::repo::{TestConfig, TestUser};
+    use chrono::{DateTime, Utc};
+    use std::path::Path;
+    let source_user_dir = Path::new("../resources/proxy/user/user1");
+    let public_key_pem = std::fs::read_to_string(source_user_dir.join("AgentPublicKey.pem"))?;
+    let private_key_pem = std::fs::read_to_string(source_user_dir.join("ProxyPrivateKey.pem"))?;
+    let database_path = std::env::temp_dir().join(format!(
+        "ppaass-sqlite-user-repository-test-{}.db",
+        std::process::id()
+    ));
+    let _ = std::fs::remove_file(&database_path);
+    let connection = Connection::open(&database_path)?;
+    connection.execute_batch(
+        "CREATE TABLE users (
+            username TEXT PRIMARY KEY,
+            public_key_pem TEXT NOT NULL,
+            private_key_pem TEXT NOT NULL,
+            expired_time TEXT,
+            user_info TEXT
+        )",
+    )?;
+    let insert_user_sql = "INSERT INTO users VALUES (?1, ?2, ?3, ?4, ?5)";
+    connection.execute(
+        insert_user_sql,
+        (
+            "user1",
+            &public_key_pem,
+            &private_key_pem,
+            "2030-01-01T00:00:00Z",
+            "observe_mode = true",
+        ),
+    )?;
+    connection.execute(
+        insert_user_sql,
+        (
+            "user2",
+            &public_key_pem,
+            &private_key_pem,
+            None::<String>,
+            None::<String>,
+        ),
+    )?;
+    // The user with the broken key is skipped
+    connection.execute(
+        insert_user_sql,
+        (
+            "user3",
+            "broken public key",
+            &private_key_pem,
+            None::<String>,
+            None::<String>,
+        ),
+    )?;
+    drop(connection);
+    let user_repo = SqliteUserRepository::<TestUser, TestConfig>::new(Box::new(TestConfig {
+        database_path: database_path.clone(),
+        ..Default::default()
+    }))?;
+    let user1 = user_repo
+        .find_user(&Username::from("user1"))
+        .expect("user1 should be loaded");
+    assert!(user1.observe_mode);
+    assert!(user1.rsa_crypto().is_some());
+    assert_eq!(
+        Some(&"2030-01-01T00:00:00Z".parse::<DateTime<Utc>>().unwrap()),
+        user1.expired_time()
+    );
+    let user2 = user_repo
+        .find_user(&Username::from("user2"))
+        .expect("user2 should be loaded");
+    assert!(!user2.observe_mode);
+    assert_eq!(None, user2.expired_time());
+    assert!(user_repo.find_user(&Username::from("user3")).is_none());
+    let limited_user_repo =
+        SqliteUserRepository::<TestUser, TestConfig>::new(Box::new(TestConfig {
+            database_path: database_path.clone(),
+            max_users: Some(1),
+            ..Default::default()
+        }))?;
+    assert!(
+        limited_user_repo
+            .find_user(&Username::from("user1"))
+            .is_some()
+    );
+    assert!(
+        limited_user_repo
+            .find_user(&Username::from("user2"))
+            .is_none()
+    );
+    let _ = std::fs::remove_file(&database_path);
+    Ok(())
+}
@@ -0,0 +1,80 @@
+use criterion::{BenchmarkId, Criterion, Throughput, criterion_group, criterion_main};
+use crypto::{
+    RsaCrypto, decrypt_with_aes, decrypt_with_blowfish, encrypt_with_aes, encrypt_with_blowfish,
+    generate_aes_encryption_token, generate_blowfish_encryption_token,
+};
+use std::hint::black_box;
+use std::io::Cursor;
+
+const PAYLOAD_SIZES: [usize; 3] = [64, 1024, 64 * 1024];
+const RSA_KEY_SIZES: [usize; 2] = [2048, 4096];
+
+fn bench_symmetric(c: &mut Criterion) {
+    let aes_token = generate_aes_encryption_token();
+    let blowfish_token = generate_blowfish_encryption_token();
+    let mut group = c.benchmark_group("symmetric");
+    for payload_size in PAYLOAD_SIZES {
+        let payload = vec![7u8; payload_size];
+        group.throughput(Throughput::Bytes(payload_size as u64));
+        let aes_encrypted = encrypt_with_aes(&aes_token, 0, &payload).unwrap();
+        let blowfish_encrypted = encrypt_with_blowfish(&blowfish_token, &payload).unwrap();
+        group.bench_with_input(
+            BenchmarkId::new("aes_encrypt", payload_size),
+            &payload,
+            |b, payload| b.iter(|| encrypt_with_aes(&aes_token, 0, black_box(payload)).unwrap()),
+        );
+        group.bench_with_input(
+            BenchmarkId::new("aes_decrypt", payload_size),
+            &aes_encrypted,
+            |b, encrypted| {
+                b.iter(|| decrypt_with_aes(&aes_token, 0, black_box(encrypted)).unwrap())
+            },
+        );
+        group.bench_with_input(
+            BenchmarkId::new("blowfish_encrypt", payload_size),
+            &payload,
+            |b, payload| {
+                b.iter(|| encrypt_with_blowfish(&blowfish_token, black_box(payload)).unwrap())
+            },
+        );
+        group.bench_with_input(
+            BenchmarkId::new("blowfish_decrypt", payload_size),
+            &blowfish_encrypted,
+            |b, encrypted| {
+                b.iter(|| decrypt_with_blowfish(&blowfish_token, black_box(encrypted)).unwrap())
+            },
+        );
+    }
+    group.finish();
+}
+
+fn generate_rsa_crypto(key_size: usize) -> RsaCrypto {
+    let (public_key_pem, private_key_pem) = RsaCrypto::generate(key_size).unwrap();
+    RsaCrypto::new(
+        Cursor::new(public_key_pem.into_bytes()),
+        Cursor::new(private_key_pem.into_bytes()),
+    )
+    .unwrap()
+}
+
+fn bench_rsa(c: &mut Criterion) {
+    // The handshake encrypts the encryption token of the connection with RSA
+    let token = generate_aes_encryption_token();
+    let mut group = c.benchmark_group("rsa");
+    for key_size in RSA_KEY_SIZES {
+        let rsa_crypto = generate_rsa_crypto(key_size);
+        let encrypted_token = rsa_crypto.encrypt(&token).unwrap();
+        group.bench_with_input(BenchmarkId::new("encrypt", key_size), &token, |b, token| {
+            b.iter(|| rsa_crypto.encrypt(black_box(token)).unwrap())
+        });
+        group.bench_with_input(
+            BenchmarkId::new("decrypt", key_size),
+            &encrypted_token,
+            |b, encrypted_token| b.iter(|| rsa_crypto.decrypt(black_box(encrypted_token)).unwrap()),
+        );
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench_symmetric, bench_rsa);
+criterion_main!(benches);
@@ -4,33 +4,188 @@ use aes::Aes256;
 use bytes::Bytes;
 use cipher::block_padding::Pkcs7;
 use cipher::{BlockDecryptMut, BlockEncryptMut, KeyIvInit};
+use hkdf::Hkdf;
+use hmac::{Hmac, Mac};
+use rand::Rng;
+use sha2::Sha256;
 
 type Aes256CbcEncryptor = cbc::Encryptor<Aes256>;
 type Aes256CbcDecryptor = cbc::Decryptor<Aes256>;
+type HmacSha256 = Hmac<Sha256>;
+
+/// The length of the AES key at the head of the encryption token
+const AES_KEY_LENGTH: usize = 32;
+/// The length of the AES iv
+const AES_IV_LENGTH: usize = 16;
+/// The length of the HMAC-SHA256 tag appended to the AES ciphertext
+const AES_MAC_LENGTH: usize = 32;
+/// The HKDF info used to derive the HMAC key from the AES token
+const AES_MAC_KEY_INFO: &[u8] = b"ppaass-aes-hmac-sha256";
+
+/// Derive the HMAC key from the AES token with HKDF-SHA256
+#[inline(always)]
+fn aes_mac(encryption_token: &[u8]) -> Result<HmacSha256, Error> {
+    let mut mac_key = [0u8; 32];
+    Hkdf::<Sha256>::new(None, encryption_token)
+        .expand(AES_MAC_KEY_INFO, &mut mac_key)
+        .map_err(|_| Error::InvalidLength(cipher::InvalidLength))?;
+    Ok(HmacSha256::new_from_slice(&mac_key)?)
+}
 
 /// Generate the encryption token for AES
 /// The first 32 bytes is the key
 /// The last 16 bytes is the iv
 #[inline(always)]
 pub fn generate_aes_encryption_token() -> Bytes {
-    random_n_bytes::<48>().into()
+    generate_aes_encryption_token_with(&mut rand::rng())
+}
+
+/// Generate the encryption token for AES with the given random generator
+#[inline(always)]
+pub fn generate_aes_encryption_token_with<R: Rng + ?Sized>(rng: &mut R) -> Bytes {
+    random_n_bytes::<{ AES_KEY_LENGTH + AES_IV_LENGTH }, R>(rng).into()
+}
+
+/// Generate the key only encryption token for AES with a per-frame iv,
+/// the token is the 32 bytes key
+#[inline(always)]
+pub fn generate_aes_key_encryption_token_with<R: Rng + ?Sized>(rng: &mut R) -> Bytes {
+    random_n_bytes::<AES_KEY_LENGTH, R>(rng).into()
+}
+
+/// The AES cipher of one encryption token, the token is checked and
+/// the HMAC key is derived once, so a connection creates it once and
+/// uses it for all of its frames.
+#[derive(Clone)]
+pub struct AesCipher {
+    key: [u8; AES_KEY_LENGTH],
+    /// The iv of the token, `None` when every frame carries its own iv
+    iv: Option<[u8; AES_IV_LENGTH]>,
+    mac: HmacSha256,
 }
 
-/// Encrypt the target bytes with AES
+impl AesCipher {
+    /// Create the cipher using the key and the iv of the 48 bytes token
+    pub fn new(encryption_token: &[u8]) -> Result<Self, Error> {
+        let (key, iv) = encryption_token
+            .split_first_chunk::<AES_KEY_LENGTH>()
+            .and_then(|(key, iv)| Some((*key, <[u8; AES_IV_LENGTH]>::try_from(iv).ok()?)))
+            .ok_or(Error::InvalidLength(cipher::InvalidLength))?;
+        Ok(Self {
+            key,
+            iv: Some(iv),
+            mac: aes_mac(encryption_token)?,
+        })
+    }
+
+    /// Create the cipher using only the key of the token, every frame is
+    /// encrypted with a fresh random iv carried in the frame. The key only
+    /// token is expected, the iv of a 48 bytes token is ignored.
+    pub fn with_per_frame_iv(encryption_token: &[u8]) -> Result<Self, Error> {
+        let key = *encryption_token
+            .first_chunk::<AES_KEY_LENGTH>()
+            .ok_or(Error::InvalidLength(cipher::InvalidLength))?;
+        Ok(Self {
+            key,
+            iv: None,
+            mac: aes_mac(&key)?,
+        })
+    }
+
+    /// Encrypt the target bytes, the HMAC-SHA256 tag of the frame counter
+    /// and the ciphertext is appended to it (encrypt-then-MAC), so a frame
+    /// replayed, reordered or dropped in the stream fails the tag. With a
+    /// per-frame iv the iv is prepended to the ciphertext and the tag is
+    /// computed over both. The frame counter must never repeat for the
+    /// same encryption token.
+    pub fn encrypt(&self, frame_counter: u64, target: &[u8]) -> Result<Bytes, Error> {
+        let mut result = match &self.iv {
+            Some(iv) => Aes256CbcEncryptor::new_from_slices(&self.key, iv)?
+                .encrypt_padded_vec_mut::<Pkcs7>(target),
+            None => {
+                let iv = rand::rng().random::<[u8; AES_IV_LENGTH]>();
+                let aes_encryptor = Aes256CbcEncryptor::new_from_slices(&self.key, &iv)?;
+                let mut result = iv.to_vec();
+                result.extend_from_slice(&aes_encryptor.encrypt_padded_vec_mut::<Pkcs7>(target));
+                result
+            }
+        };
+        let mut mac = self.mac.clone();
+        mac.update(&frame_counter.to_be_bytes());
+        mac.update(&result);
+        result.extend_from_slice(&mac.finalize().into_bytes());
+        Ok(result.into())
+    }
+
+    /// Decrypt the target bytes, the HMAC-SHA256 tag is verified in
+    /// constant time before decrypting, it fails when the target is
+    /// tampered or is not sealed with the same frame counter
+    pub fn decrypt(&self, frame_counter: u64, target: &[u8]) -> Result<Bytes, Error> {
+        let iv_length = if self.iv.is_some() { 0 } else { AES_IV_LENGTH };
+        let authenticated_length = target
+            .len()
+            .checked_sub(AES_MAC_LENGTH)
+            .filter(|authenticated_length| *authenticated_length >= iv_length)
+            .ok_or(Error::MacMismatch)?;
+        let (authenticated, tag) = target.split_at(authenticated_length);
+        let mut mac = self.mac.clone();
+        mac.update(&frame_counter.to_be_bytes());
+        mac.update(authenticated);
+        mac.verify_slice(tag).map_err(|_| Error::MacMismatch)?;
+        let (frame_iv, ciphertext) = authenticated.split_at(iv_length);
+        let iv = match &self.iv {
+            Some(iv) => iv.as_slice(),
+            None => frame_iv,
+        };
+        let aes_decrypt = Aes256CbcDecryptor::new_from_slices(&self.key, iv)?;
+        let result = aes_decrypt.decrypt_padded_vec_mut::<Pkcs7>(ciphertext)?;
+        Ok(result.into())
+    }
+}
+
+/// Encrypt the target bytes with AES, the HMAC-SHA256 tag of the frame
+/// counter and the ciphertext is appended to it (encrypt-then-MAC)
+#[inline(always)]
+pub fn encrypt_with_aes(
+    encryption_token: &[u8],
+    frame_counter: u64,
+    target: &[u8],
+) -> Result<Bytes, Error> {
+    AesCipher::new(encryption_token)?.encrypt(frame_counter, target)
+}
+
+/// Decrypt the target bytes with AES, the HMAC-SHA256 tag is verified
+/// in constant time before decrypting
+#[inline(always)]
+pub fn decrypt_with_aes(
+    encryption_token: &[u8],
+    frame_counter: u64,
+    target: &[u8],
+) -> Result<Bytes, Error> {
+    AesCipher::new(encryption_token)?.decrypt(frame_counter, target)
+}
+
+/// Encrypt the target bytes with AES and a fresh random iv, only the key
+/// of the encryption token is used, the iv is prepended to the ciphertext
+/// and the HMAC-SHA256 tag is computed over both
 #[inline(always)]
-pub fn encrypt_with_aes(encryption_token: &[u8], target: &[u8]) -> Result<Bytes, Error> {
-    let aes_encryptor =
-        Aes256CbcEncryptor::new_from_slices(&encryption_token[..32], &encryption_token[32..])?;
-    Ok(aes_encryptor.encrypt_padded_vec_mut::<Pkcs7>(target).into())
+pub fn encrypt_with_aes_per_frame_iv(
+    encryption_token: &[u8],
+    frame_counter: u64,
+    target: &[u8],
+) -> Result<Bytes, Error> {
+    AesCipher::with_per_frame_iv(encryption_token)?.encrypt(frame_counter, target)
 }
 
-/// Decrypt the target bytes with AES
+/// Decrypt the target bytes encrypted by `encrypt_with_aes_per_frame_iv`,
+/// the HMAC-SHA256 tag is verified in constant time before decrypting
 #[inline(always)]
-pub fn decrypt_with_aes(encryption_token: &[u8], target: &[u8]) -> Result<Bytes, Error> {
-    let aes_decrypt =
-        Aes256CbcDecryptor::new_from_slices(&encryption_token[..32], &encryption_token[32..])?;
-    let result = aes_decrypt.decrypt_padded_vec_mut::<Pkcs7>(target)?;
-    Ok(result.into())
+pub fn decrypt_with_aes_per_frame_iv(
+    encryption_token: &[u8],
+    frame_counter: u64,
+    target: &[u8],
+) -> Result<Bytes, Error> {
+    AesCipher::with_per_frame_iv(encryption_token)?.decrypt(frame_counter, target)
 }
 
 #[test]
@@ -39,16 +194,113 @@ fn test() -> Result<(), Error> {
     let target = "hello world! this is my plaintext.".as_bytes().to_vec();
     let data_len = target.len();
     println!("Data length: {}", data_len);
-    let encrypt_result = encrypt_with_aes(&encryption_token, &target)?;
+    let encrypt_result = encrypt_with_aes(&encryption_token, 0, &target)?;
     println!(
         "Encrypt result: [{:?}]",
         String::from_utf8_lossy(&encrypt_result)
     );
     let encrypt_result = encrypt_result.to_vec();
-    let decrypted_result = decrypt_with_aes(&encryption_token, &encrypt_result)?;
+    let decrypted_result = decrypt_with_aes(&encryption_token, 0, &encrypt_result)?;
     println!(
         "Decrypted result: [{:?}]",
         String::from_utf8_lossy(&decrypted_result)
     );
     Ok(())
 }
+
+#[test]
+fn test_tampered() -> Result<(), Error> {
+    let encryption_token = generate_aes_encryption_token();
+    let target = "hello world! this is my plaintext.".as_bytes().to_vec();
+    let encrypt_result = encrypt_with_aes(&encryption_token, 0, &target)?;
+    assert_eq!(
+        target,
+        decrypt_with_aes(&encryption_token, 0, &encrypt_result)?.to_vec()
+    );
+    let mut tampered = encrypt_result.to_vec();
+    tampered[0] ^= 1;
+    assert!(matches!(
+        decrypt_with_aes(&encryption_token, 0, &tampered),
+        Err(Error::MacMismatch)
+    ));
+    assert!(matches!(
+        decrypt_with_aes(&encryption_token, 0, &encrypt_result[..AES_MAC_LENGTH - 1]),
+        Err(Error::MacMismatch)
+    ));
+    Ok(())
+}
+
+#[test]
+fn test_per_frame_iv() -> Result<(), Error> {
+    let encryption_token = generate_aes_encryption_token();
+    let target = "hello world! this is my plaintext.".as_bytes().to_vec();
+    let encrypt_result_1 = encrypt_with_aes_per_frame_iv(&encryption_token, 0, &target)?;
+    let encrypt_result_2 = encrypt_with_aes_per_frame_iv(&encryption_token, 0, &target)?;
+    assert_ne!(encrypt_result_1, encrypt_result_2);
+    assert_eq!(
+        target,
+        decrypt_with_aes_per_frame_iv(&encryption_token, 0, &encrypt_result_1)?.to_vec()
+    );
+    // The key only token is enough
+    assert_eq!(
+        target,
+        decrypt_with_aes_per_frame_iv(&encryption_token[..AES_KEY_LENGTH], 0, &encrypt_result_2)?
+            .to_vec()
+    );
+    let mut tampered = encrypt_result_1.to_vec();
+    tampered[0] ^= 1;
+    assert!(matches!(
+        decrypt_with_aes_per_frame_iv(&encryption_token, 0, &tampered),
+        Err(Error::MacMismatch)
+    ));
+    Ok(())
+}
+
+#[test]
+fn test_short_token() -> Result<(), Error> {
+    let encryption_token = generate_aes_encryption_token();
+    let target = b"hello world!";
+    for short_token in [&[][..], &encryption_token[..16], &encryption_token[..40]] {
+        assert!(matches!(
+            encrypt_with_aes(short_token, 0, target),
+            Err(Error::InvalidLength(_))
+        ));
+        assert!(matches!(
+            decrypt_with_aes(short_token, 0, target),
+            Err(Error::InvalidLength(_))
+        ));
+    }
+    assert!(matches!(
+        AesCipher::with_per_frame_iv(&encryption_token[..AES_KEY_LENGTH - 1]),
+        Err(Error::InvalidLength(_))
+    ));
+    // The key only token has no iv for the token iv mode
+    let key_token = generate_aes_key_encryption_token_with(&mut rand::rng());
+    assert_eq!(AES_KEY_LENGTH, key_token.len());
+    assert!(AesCipher::new(&key_token).is_err());
+    let cipher = AesCipher::with_per_frame_iv(&key_token)?;
+    assert_eq!(target, &cipher.decrypt(0, &cipher.encrypt(0, target)?)?[..]);
+    Ok(())
+}
+
+#[test]
+fn test_frame_counter() -> Result<(), Error> {
+    let target = "hello world! this is my plaintext.".as_bytes().to_vec();
+    for cipher in [
+        AesCipher::new(&generate_aes_encryption_token())?,
+        AesCipher::with_per_frame_iv(&generate_aes_encryption_token())?,
+    ] {
+        let encrypt_result = cipher.encrypt(7, &target)?;
+        assert_eq!(target, cipher.decrypt(7, &encrypt_result)?.to_vec());
+        // The frame opened at another position of the stream fails the tag
+        assert!(matches!(
+            cipher.decrypt(8, &encrypt_result),
+            Err(Error::MacMismatch)
+        ));
+        assert!(matches!(
+            cipher.decrypt(6, &encrypt_result),
+            Err(Error::MacMismatch)
+        ));
+    }
+    Ok(())
+}
@@ -0,0 +1,74 @@
+use clap::Parser;
+use crypto::{
+    DEFAULT_AGENT_PRIVATE_KEY_PATH, DEFAULT_AGENT_PUBLIC_KEY_PATH, DEFAULT_PROXY_PRIVATE_KEY_PATH,
+    DEFAULT_PROXY_PUBLIC_KEY_PATH, Error, RsaCrypto,
+};
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+/// Generate the agent and the proxy key pairs of a user
+#[derive(Parser)]
+#[command(version, about, long_about = None)]
+struct CommandArgs {
+    /// The directory to write the key files into
+    #[arg(short = 'd', long)]
+    directory: PathBuf,
+    /// The bits of the generated keys
+    #[arg(short = 'b', long, default_value_t = 2048)]
+    bits: usize,
+}
+
+/// Write the key file, the existing key file is never overwritten and
+/// the private key file is readable by the owner only
+fn write_key_file(path: &Path, pem: &str, private: bool) -> Result<(), Error> {
+    let mut open_options = OpenOptions::new();
+    open_options.write(true).create_new(true);
+    #[cfg(unix)]
+    if private {
+        use std::os::unix::fs::OpenOptionsExt;
+        open_options.mode(0o600);
+    }
+    #[cfg(not(unix))]
+    let _ = private;
+    let mut file = open_options.open(path)?;
+    file.write_all(pem.as_bytes())?;
+    println!("Write key file: {}", path.display());
+    Ok(())
+}
+
+/// Generate the key files of a user, the agent keeps the agent private key
+/// and the proxy public key while the proxy keeps the other two.
+fn main() -> Result<(), Error> {
+    let command_args = CommandArgs::parse();
+    std::fs::create_dir_all(&command_args.directory)?;
+    let key_path = |file_name| command_args.directory.join(file_name);
+    // Nothing is written when any key file exists, so a user never ends up
+    // with the key files of different generations
+    for file_name in [
+        DEFAULT_AGENT_PUBLIC_KEY_PATH,
+        DEFAULT_AGENT_PRIVATE_KEY_PATH,
+        DEFAULT_PROXY_PUBLIC_KEY_PATH,
+        DEFAULT_PROXY_PRIVATE_KEY_PATH,
+    ] {
+        let path = key_path(file_name);
+        if path.symlink_metadata().is_ok() {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::AlreadyExists,
+                format!("Key file already exists: {}", path.display()),
+            )
+            .into());
+        }
+    }
+    let (agent_public_key, agent_private_key) = RsaCrypto::generate(command_args.bits)?;
+    let (proxy_public_key, proxy_private_key) = RsaCrypto::generate(command_args.bits)?;
+    for (file_name, pem, private) in [
+        (DEFAULT_AGENT_PUBLIC_KEY_PATH, &agent_public_key, false),
+        (DEFAULT_AGENT_PRIVATE_KEY_PATH, &agent_private_key, true),
+        (DEFAULT_PROXY_PUBLIC_KEY_PATH, &proxy_public_key, false),
+        (DEFAULT_PROXY_PRIVATE_KEY_PATH, &proxy_private_key, true),
+    ] {
+        write_key_file(&key_path(file_name), pem, private)?;
+    }
+    Ok(())
+}
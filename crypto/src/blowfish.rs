@@ -4,6 +4,7 @@ use blowfish::Blowfish;
 use bytes::Bytes;
 use cipher::block_padding::Pkcs7;
 use cipher::{BlockDecryptMut, BlockEncryptMut, KeyIvInit};
+use rand::Rng;
 
 type BlowfishCbcEncryptor = cbc::Encryptor<Blowfish>;
 type BlowfishCbcDecryptor = cbc::Decryptor<Blowfish>;
@@ -13,7 +14,13 @@ type BlowfishCbcDecryptor = cbc::Decryptor<Blowfish>;
 /// The last 8 bytes is the iv
 #[inline(always)]
 pub fn generate_blowfish_encryption_token() -> Bytes {
-    random_n_bytes::<64>().into()
+    generate_blowfish_encryption_token_with(&mut rand::rng())
+}
+
+/// Generate the encryption token for Blowfish with the given random generator
+#[inline(always)]
+pub fn generate_blowfish_encryption_token_with<R: Rng + ?Sized>(rng: &mut R) -> Bytes {
+    random_n_bytes::<64, R>(rng).into()
 }
 
 /// Encrypt the target bytes with Blowfish
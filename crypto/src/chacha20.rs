@@ -0,0 +1,92 @@
+use crate::error::Error;
+use crate::random_n_bytes;
+use bytes::Bytes;
+use chacha20poly1305::aead::Aead;
+use chacha20poly1305::{ChaCha20Poly1305, KeyInit, Nonce};
+use rand::Rng;
+
+const CHACHA20_KEY_LENGTH: usize = 32;
+const CHACHA20_NONCE_LENGTH: usize = 12;
+
+/// Generate the encryption token for ChaCha20-Poly1305
+/// The first 32 bytes is the key
+/// The last 12 bytes is the nonce base
+#[inline(always)]
+pub fn generate_chacha20_encryption_token() -> Bytes {
+    generate_chacha20_encryption_token_with(&mut rand::rng())
+}
+
+/// Generate the encryption token for ChaCha20-Poly1305 with the given random generator
+#[inline(always)]
+pub fn generate_chacha20_encryption_token_with<R: Rng + ?Sized>(rng: &mut R) -> Bytes {
+    random_n_bytes::<{ CHACHA20_KEY_LENGTH + CHACHA20_NONCE_LENGTH }, R>(rng).into()
+}
+
+/// Derive the nonce of one frame, the frame counter is xor-ed into
+/// the last 8 bytes of the nonce base, so every frame sealed with the
+/// same key uses a different nonce.
+#[inline(always)]
+fn frame_nonce(encryption_token: &[u8], frame_counter: u64) -> Result<Nonce, Error> {
+    let nonce_base = encryption_token
+        .get(CHACHA20_KEY_LENGTH..)
+        .filter(|nonce_base| nonce_base.len() == CHACHA20_NONCE_LENGTH)
+        .ok_or(Error::InvalidLength(cipher::InvalidLength))?;
+    let mut nonce = Nonce::clone_from_slice(nonce_base);
+    for (nonce_byte, counter_byte) in nonce[CHACHA20_NONCE_LENGTH - 8..]
+        .iter_mut()
+        .zip(frame_counter.to_be_bytes())
+    {
+        *nonce_byte ^= counter_byte;
+    }
+    Ok(nonce)
+}
+
+/// Encrypt the target bytes with ChaCha20-Poly1305, the frame counter
+/// must never repeat for the same encryption token
+#[inline(always)]
+pub fn encrypt_with_chacha20(
+    encryption_token: &[u8],
+    frame_counter: u64,
+    target: &[u8],
+) -> Result<Bytes, Error> {
+    let nonce = frame_nonce(encryption_token, frame_counter)?;
+    let chacha20 = ChaCha20Poly1305::new_from_slice(&encryption_token[..CHACHA20_KEY_LENGTH])?;
+    let result = chacha20.encrypt(&nonce, target).map_err(|_| Error::Aead)?;
+    Ok(result.into())
+}
+
+/// Decrypt the target bytes with ChaCha20-Poly1305, fails when the
+/// target is tampered or is not sealed with the same frame counter
+#[inline(always)]
+pub fn decrypt_with_chacha20(
+    encryption_token: &[u8],
+    frame_counter: u64,
+    target: &[u8],
+) -> Result<Bytes, Error> {
+    let nonce = frame_nonce(encryption_token, frame_counter)?;
+    let chacha20 = ChaCha20Poly1305::new_from_slice(&encryption_token[..CHACHA20_KEY_LENGTH])?;
+    let result = chacha20.decrypt(&nonce, target).map_err(|_| Error::Aead)?;
+    Ok(result.into())
+}
+
+#[test]
+fn test() -> Result<(), Error> {
+    let encryption_token = generate_chacha20_encryption_token();
+    let target = "hello world! this is my plaintext.".as_bytes().to_vec();
+    let encrypt_result_1 = encrypt_with_chacha20(&encryption_token, 0, &target)?;
+    let encrypt_result_2 = encrypt_with_chacha20(&encryption_token, 1, &target)?;
+    assert_ne!(encrypt_result_1, encrypt_result_2);
+    let decrypted_result = decrypt_with_chacha20(&encryption_token, 0, &encrypt_result_1)?;
+    assert_eq!(target, decrypted_result.to_vec());
+    assert!(matches!(
+        decrypt_with_chacha20(&encryption_token, 1, &encrypt_result_1),
+        Err(Error::Aead)
+    ));
+    let mut tampered = encrypt_result_2.to_vec();
+    tampered[0] ^= 1;
+    assert!(matches!(
+        decrypt_with_chacha20(&encryption_token, 1, &tampered),
+        Err(Error::Aead)
+    ));
+    Ok(())
+}
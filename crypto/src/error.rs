@@ -6,6 +6,10 @@ pub enum Error {
     InvalidLength(#[from] InvalidLength),
     #[error(transparent)]
     Unpad(#[from] cipher::block_padding::UnpadError),
+    #[error("The message authentication code mismatch")]
+    MacMismatch,
+    #[error("Fail to seal or open the aead data")]
+    Aead,
     #[error(transparent)]
     Rsa(#[from] rsa::errors::Error),
     #[error(transparent)]
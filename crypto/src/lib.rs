@@ -1,14 +1,16 @@
 mod aes;
 mod blowfish;
+mod chacha20;
 mod error;
 mod rsa;
 pub use aes::*;
 pub use blowfish::*;
+pub use chacha20::*;
 pub use error::Error;
-use rand::random;
+use rand::Rng;
 pub use rsa::*;
 #[inline(always)]
-fn random_n_bytes<const N: usize>() -> Vec<u8> {
-    let random_n_bytes = random::<[u8; N]>();
+fn random_n_bytes<const N: usize, R: Rng + ?Sized>(rng: &mut R) -> Vec<u8> {
+    let random_n_bytes = rng.random::<[u8; N]>();
     random_n_bytes.to_vec()
 }
@@ -5,10 +5,11 @@ pub use rsa::pkcs8::EncodePublicKey;
 pub use rsa::pkcs8::LineEnding;
 pub use rsa::rand_core::OsRng;
 use rsa::{
-    Pkcs1v15Encrypt,
+    Oaep, Pkcs1v15Encrypt, Pkcs1v15Sign,
     pkcs8::{DecodePrivateKey, DecodePublicKey},
 };
 pub use rsa::{RsaPrivateKey, RsaPublicKey};
+use sha2::{Digest, Sha256};
 use std::fmt::Debug;
 use std::io::Read;
 
@@ -17,6 +18,15 @@ pub static DEFAULT_AGENT_PUBLIC_KEY_PATH: &str = "AgentPublicKey.pem";
 pub static DEFAULT_PROXY_PRIVATE_KEY_PATH: &str = "ProxyPrivateKey.pem";
 pub static DEFAULT_PROXY_PUBLIC_KEY_PATH: &str = "ProxyPublicKey.pem";
 
+/// The padding of the RSA encryption, both sides must use the same padding
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum RsaPadding {
+    #[default]
+    Pkcs1v15,
+    /// OAEP with SHA-256 as both the hash and the mask generation hash
+    OaepSha256,
+}
+
 /// The util to do RSA encryption and decryption.
 #[derive(Debug)]
 pub struct RsaCrypto {
@@ -43,16 +53,134 @@ impl RsaCrypto {
             private_key,
         })
     }
-    /// Encrypt the target bytes with RSA public key
+    /// Generate a new key pair of the bits, the public key and the private
+    /// key are returned as PEM strings in the format read by [`RsaCrypto::new`]
+    pub fn generate(bits: usize) -> Result<(String, String), Error> {
+        let private_key = RsaPrivateKey::new(&mut OsRng, bits)?;
+        let public_key_pem = RsaPublicKey::from(&private_key).to_public_key_pem(LineEnding::LF)?;
+        let private_key_pem = private_key.to_pkcs8_pem(LineEnding::LF)?;
+        Ok((public_key_pem, private_key_pem.to_string()))
+    }
+    /// Encrypt the target bytes with RSA public key in PKCS#1 v1.5 padding
     pub fn encrypt(&self, target: &[u8]) -> Result<Bytes, Error> {
-        let result = self
-            .public_key
-            .encrypt(&mut OsRng, Pkcs1v15Encrypt, target.as_ref())?;
-        Ok(result.into())
+        self.encrypt_with_padding(target, RsaPadding::Pkcs1v15)
     }
-    /// Decrypt the target bytes with RSA private key
+    /// Decrypt the target bytes with RSA private key in PKCS#1 v1.5 padding
     pub fn decrypt(&self, target: &[u8]) -> Result<Bytes, Error> {
-        let result = self.private_key.decrypt(Pkcs1v15Encrypt, target.as_ref())?;
+        self.decrypt_with_padding(target, RsaPadding::Pkcs1v15)
+    }
+    /// Encrypt the target bytes with RSA public key in the given padding
+    pub fn encrypt_with_padding(&self, target: &[u8], padding: RsaPadding) -> Result<Bytes, Error> {
+        let result = match padding {
+            RsaPadding::Pkcs1v15 => self
+                .public_key
+                .encrypt(&mut OsRng, Pkcs1v15Encrypt, target)?,
+            RsaPadding::OaepSha256 => {
+                self.public_key
+                    .encrypt(&mut OsRng, Oaep::new::<Sha256>(), target)?
+            }
+        };
+        Ok(result.into())
+    }
+    /// Decrypt the target bytes with RSA private key in the given padding
+    pub fn decrypt_with_padding(&self, target: &[u8], padding: RsaPadding) -> Result<Bytes, Error> {
+        let result = match padding {
+            RsaPadding::Pkcs1v15 => self.private_key.decrypt(Pkcs1v15Encrypt, target)?,
+            RsaPadding::OaepSha256 => self.private_key.decrypt(Oaep::new::<Sha256>(), target)?,
+        };
+        Ok(result.into())
+    }
+    /// Sign the SHA-256 digest of the message with RSA private key, the
+    /// signature carries the digest algorithm as PKCS#1 v1.5 specifies
+    pub fn sign_sha256(&self, message: &[u8]) -> Result<Bytes, Error> {
+        let result = self
+            .private_key
+            .sign(Pkcs1v15Sign::new::<Sha256>(), &Sha256::digest(message))?;
         Ok(result.into())
     }
+    /// Verify the signature of the SHA-256 digest of the message with RSA public key
+    pub fn verify_sha256(&self, message: &[u8], signature: &[u8]) -> Result<(), Error> {
+        self.public_key.verify(
+            Pkcs1v15Sign::new::<Sha256>(),
+            &Sha256::digest(message),
+            signature,
+        )?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+fn test_rsa_crypto() -> Result<RsaCrypto, Error> {
+    use std::fs::File;
+    // The key pair of the proxy, the agent encrypts with the public key
+    let agent_crypto = RsaCrypto::new(
+        File::open("../resources/agent/user/user1/ProxyPublicKey.pem")?,
+        File::open("../resources/agent/user/user1/AgentPrivateKey.pem")?,
+    )?;
+    let proxy_crypto = RsaCrypto::new(
+        File::open("../resources/proxy/user/user1/AgentPublicKey.pem")?,
+        File::open("../resources/proxy/user/user1/ProxyPrivateKey.pem")?,
+    )?;
+    Ok(RsaCrypto {
+        private_key: proxy_crypto.private_key,
+        public_key: agent_crypto.public_key,
+    })
+}
+
+#[test]
+fn test_padding_round_trip() -> Result<(), Error> {
+    let rsa_crypto = test_rsa_crypto()?;
+    let token = b"the aes token of the connection";
+    for padding in [RsaPadding::Pkcs1v15, RsaPadding::OaepSha256] {
+        let encrypted = rsa_crypto.encrypt_with_padding(token, padding)?;
+        assert_ne!(token.as_slice(), encrypted.as_ref());
+        assert_eq!(
+            token.as_slice(),
+            rsa_crypto
+                .decrypt_with_padding(&encrypted, padding)?
+                .as_ref()
+        );
+    }
+    assert_eq!(
+        token.as_slice(),
+        rsa_crypto.decrypt(&rsa_crypto.encrypt(token)?)?.as_ref()
+    );
+    Ok(())
+}
+
+#[test]
+fn test_padding_mismatch() -> Result<(), Error> {
+    let rsa_crypto = test_rsa_crypto()?;
+    let token = b"the aes token of the connection";
+    let oaep_encrypted = rsa_crypto.encrypt_with_padding(token, RsaPadding::OaepSha256)?;
+    assert!(matches!(
+        rsa_crypto.decrypt_with_padding(&oaep_encrypted, RsaPadding::Pkcs1v15),
+        Err(Error::Rsa(_))
+    ));
+    let pkcs1v15_encrypted = rsa_crypto.encrypt(token)?;
+    assert!(matches!(
+        rsa_crypto.decrypt_with_padding(&pkcs1v15_encrypted, RsaPadding::OaepSha256),
+        Err(Error::Rsa(_))
+    ));
+    Ok(())
+}
+
+#[test]
+fn test_generate() -> Result<(), Error> {
+    let (public_key_pem, private_key_pem) = RsaCrypto::generate(2048)?;
+    let rsa_crypto = RsaCrypto::new(public_key_pem.as_bytes(), private_key_pem.as_bytes())?;
+    let token = b"the aes token of the connection";
+    assert_eq!(
+        token.as_slice(),
+        rsa_crypto.decrypt(&rsa_crypto.encrypt(token)?)?.as_ref()
+    );
+    let signature = rsa_crypto.sign_sha256(token)?;
+    rsa_crypto.verify_sha256(token, &signature)?;
+    // The signature does not verify another message
+    assert!(
+        rsa_crypto
+            .verify_sha256(b"another message", &signature)
+            .is_err()
+    );
+    Ok(())
 }
@@ -5,6 +5,15 @@ use std::net::{SocketAddr, ToSocketAddrs};
 
 const HTTP_PORT: u16 = 80;
 
+/// Check the domain host is not empty and only contains
+/// valid hostname characters.
+fn validate_domain_host(host: &str) -> bool {
+    !host.is_empty()
+        && host
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '.' || c == '_')
+}
+
 /// The unified address which can support both
 /// IP V4, IP V6 and Domain
 #[derive(Debug, Clone, Serialize, Deserialize, Hash, Eq, PartialEq)]
@@ -13,6 +22,15 @@ pub enum UnifiedAddress {
     SocketAddress(SocketAddr),
 }
 
+impl UnifiedAddress {
+    pub fn port(&self) -> u16 {
+        match self {
+            UnifiedAddress::Domain { port, .. } => *port,
+            UnifiedAddress::SocketAddress(socket_addr) => socket_addr.port(),
+        }
+    }
+}
+
 impl Display for UnifiedAddress {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
         match self {
@@ -21,8 +39,9 @@ impl Display for UnifiedAddress {
                 SocketAddr::V4(ip_v4_addr) => {
                     write!(f, "{}:{}", ip_v4_addr.ip(), socket_addr.port())
                 }
+                // The ipv6 address is bracketed so the displayed address can be parsed back
                 SocketAddr::V6(ip_v6_addr) => {
-                    write!(f, "{}:{}", ip_v6_addr.ip(), socket_addr.port())
+                    write!(f, "[{}]:{}", ip_v6_addr.ip(), socket_addr.port())
                 }
             },
         }
@@ -40,6 +59,9 @@ impl TryFrom<&str> for UnifiedAddress {
                 parts_num if parts_num > 2 => Err(Error::Parse(value.to_string())),
                 2 => {
                     let domain = domain_parts[0];
+                    if !validate_domain_host(domain) {
+                        return Err(Error::Parse(value.to_string()));
+                    }
                     let port = domain_parts[1]
                         .parse::<u16>()
                         .map_err(|_| Error::Parse(value.to_string()))?;
@@ -50,6 +72,9 @@ impl TryFrom<&str> for UnifiedAddress {
                 }
                 _ => {
                     let domain = domain_parts[0];
+                    if !validate_domain_host(domain) {
+                        return Err(Error::Parse(value.to_string()));
+                    }
                     Ok(Self::Domain {
                         host: domain.to_string(),
                         port: HTTP_PORT,
@@ -99,3 +124,74 @@ impl From<&SocketAddr> for UnifiedAddress {
         UnifiedAddress::SocketAddress(*value)
     }
 }
+
+#[test]
+fn test_parse_domain() -> Result<(), Error> {
+    let address: UnifiedAddress = "www.example.com:8080".try_into()?;
+    assert_eq!(
+        UnifiedAddress::Domain {
+            host: "www.example.com".to_string(),
+            port: 8080
+        },
+        address
+    );
+    let address: UnifiedAddress = "www.example.com".try_into()?;
+    assert_eq!(
+        UnifiedAddress::Domain {
+            host: "www.example.com".to_string(),
+            port: HTTP_PORT
+        },
+        address
+    );
+    Ok(())
+}
+
+#[test]
+fn test_parse_invalid_domain() {
+    assert!(matches!(
+        UnifiedAddress::try_from(":80"),
+        Err(Error::Parse(_))
+    ));
+    assert!(matches!(UnifiedAddress::try_from(""), Err(Error::Parse(_))));
+    assert!(matches!(
+        UnifiedAddress::try_from("   :80"),
+        Err(Error::Parse(_))
+    ));
+    assert!(matches!(
+        UnifiedAddress::try_from("www.exa mple.com:80"),
+        Err(Error::Parse(_))
+    ));
+    assert!(matches!(
+        UnifiedAddress::try_from("www.example.com:65536"),
+        Err(Error::Parse(_))
+    ));
+}
+
+#[cfg(test)]
+impl proptest::arbitrary::Arbitrary for UnifiedAddress {
+    type Parameters = ();
+    type Strategy = proptest::strategy::BoxedStrategy<Self>;
+    fn arbitrary_with(_: Self::Parameters) -> Self::Strategy {
+        use proptest::prelude::*;
+        use std::net::SocketAddrV6;
+        prop_oneof![
+            // The host starts with a letter, a host of an ip literal is parsed as socket address
+            ("[a-z][a-z0-9._-]{0,62}", any::<u16>())
+                .prop_map(|(host, port)| UnifiedAddress::Domain { host, port }),
+            any::<std::net::SocketAddrV4>().prop_map(|addr| SocketAddr::V4(addr).into()),
+            // The serde encoding of the ipv6 address keeps only the ip and the port
+            (any::<std::net::Ipv6Addr>(), any::<u16>()).prop_map(|(ip, port)| {
+                SocketAddr::V6(SocketAddrV6::new(ip, port, 0, 0)).into()
+            }),
+        ]
+        .boxed()
+    }
+}
+
+#[cfg(test)]
+proptest::proptest! {
+    #[test]
+    fn test_display_round_trip(address in proptest::arbitrary::any::<UnifiedAddress>()) {
+        proptest::prop_assert_eq!(&address, &UnifiedAddress::try_from(address.to_string())?);
+    }
+}
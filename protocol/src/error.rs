@@ -9,4 +9,10 @@ pub enum Error {
     Encode(#[from] bincode::error::EncodeError),
     #[error("Fail to parse: {0}")]
     Parse(String),
+    #[error("Invalid username: {0:?}")]
+    InvalidUsername(String),
+    #[error("Username length {0} exceeds the max username length {1}")]
+    UsernameTooLong(u64, usize),
+    #[error("Unsupported protocol version: {0}")]
+    UnsupportedProtocolVersion(u16),
 }
@@ -6,6 +6,92 @@ pub use address::*;
 pub use error::*;
 pub use packet::*;
 use serde::{Deserialize, Serialize};
+use std::fmt::{Display, Formatter};
+use std::ops::Deref;
 
 #[derive(Debug, Serialize, Deserialize, Clone, Hash, Eq, PartialEq)]
 pub struct Username(pub String);
+
+/// The default maximum length in bytes of a username received from the peer.
+pub const USERNAME_MAX_LENGTH: usize = 64;
+
+impl Username {
+    /// Create the username from untrusted input, the username must not be empty,
+    /// must not exceed `max_length` bytes and can only contain ascii alphanumeric
+    /// characters, `-`, `_`, `.` or `@`, so it is safe to log and to use as lookup key.
+    pub fn try_new(value: String, max_length: usize) -> Result<Self, Error> {
+        if value.is_empty()
+            || value.len() > max_length
+            || !value
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.' | '@'))
+        {
+            return Err(Error::InvalidUsername(value));
+        }
+        Ok(Self(value))
+    }
+
+    /// The username as a string slice
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+impl Deref for Username {
+    type Target = str;
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+impl AsRef<str> for Username {
+    fn as_ref(&self) -> &str {
+        &self.0
+    }
+}
+
+impl Display for Username {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+/// Create the username from trusted input without validation,
+/// use `Username::try_new` for the input received from the peer.
+impl From<&str> for Username {
+    fn from(value: &str) -> Self {
+        Self(value.to_string())
+    }
+}
+
+/// Create the username from trusted input without validation,
+/// use `Username::try_new` for the input received from the peer.
+impl From<String> for Username {
+    fn from(value: String) -> Self {
+        Self(value)
+    }
+}
+
+#[test]
+fn test_username_conversions() {
+    let username = Username::from("user1");
+    assert_eq!(Username("user1".to_string()), username);
+    assert_eq!(username, Username::from("user1".to_string()));
+    assert_eq!("user1", &*username);
+    assert_eq!("user1", username.as_ref());
+    assert_eq!(5, username.len());
+    assert!(username.starts_with("user"));
+    assert_eq!("user1", username.to_string());
+    assert_eq!("[user1]", format!("[{username}]"));
+}
+
+#[cfg(test)]
+impl proptest::arbitrary::Arbitrary for Username {
+    type Parameters = ();
+    type Strategy = proptest::strategy::BoxedStrategy<Self>;
+    fn arbitrary_with(_: Self::Parameters) -> Self::Strategy {
+        use proptest::strategy::Strategy;
+        // The username decoded from the peer is validated
+        "[A-Za-z0-9._@-]{1,64}".prop_map(Username).boxed()
+    }
+}
@@ -1,8 +1,26 @@
 use crate::address::UnifiedAddress;
-use crate::{Error, Username};
+use crate::{Error, USERNAME_MAX_LENGTH, Username};
 use bincode::config::Configuration;
-use bytes::{Bytes, BytesMut};
+use bytes::{BufMut, Bytes, BytesMut};
 use serde::{Deserialize, Serialize};
+use std::fmt::{Display, Formatter};
+
+/// The version of the wire format, it is bumped on every incompatible change
+pub const PROTOCOL_VERSION: u16 = 5;
+/// The oldest version of the peer which can still be talked to, the
+/// versions before the frame counter in the AES tag are not accepted
+/// because their frames can be replayed and reordered in transit
+pub const MIN_PROTOCOL_VERSION: u16 = 5;
+
+/// Check the version of the peer, the peer older than [MIN_PROTOCOL_VERSION]
+/// is rejected. Every accepted peer talks [PROTOCOL_VERSION], the newer peer
+/// downgrades itself to the version sent back in the handshake.
+pub fn check_peer_protocol_version(peer_version: u16) -> Result<(), Error> {
+    if peer_version < MIN_PROTOCOL_VERSION {
+        return Err(Error::UnsupportedProtocolVersion(peer_version));
+    }
+    Ok(())
+}
 
 /// Represents different types of encryption that can be applied to data.
 ///
@@ -11,6 +29,7 @@ use serde::{Deserialize, Serialize};
 /// * `Plain` - Indicates that no encryption is used. Data is stored and transmitted in plain text.
 /// * `Aes(Bytes)` - Specifies that AES (Advanced Encryption Standard) encryption is used. The `Bytes` parameter holds the encrypted data.
 /// * `Blowfish(Bytes)` - Indicates the use of Blowfish encryption. Similar to `Aes`, the `Bytes` parameter here contains the data after being encrypted with the Blowfish algorithm.
+/// * `ChaCha20Poly1305(Bytes)` - Indicates the use of ChaCha20-Poly1305 authenticated encryption, the `Bytes` parameter holds the key and the nonce base.
 ///
 /// # Examples
 ///
@@ -24,7 +43,7 @@ use serde::{Deserialize, Serialize};
 /// ```
 ///
 /// This enum is useful for scenarios where you need to handle or specify different encryption methods within your application, allowing for flexibility in security implementations.
-#[derive(Debug, Serialize, Deserialize, Clone)]
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
 pub enum Encryption {
     /// No encryption
     Plain,
@@ -32,6 +51,8 @@ pub enum Encryption {
     Aes(Bytes),
     /// Blowfish encryption
     Blowfish(Bytes),
+    /// ChaCha20-Poly1305 encryption
+    ChaCha20Poly1305(Bytes),
 }
 
 /// Represents a request for initiating a handshake in the system.
@@ -46,6 +67,10 @@ pub enum Encryption {
 ///   attempting to initiate the handshake.
 /// * `encryption` - An `Encryption` enum value indicating the type of
 ///   encryption the client prefers or is capable of using.
+/// * `tag` - An optional opaque label of the connection, the proxy records
+///   it to distinguish the traffic of different agent purposes.
+/// * `challenge` - The random bytes the proxy must sign with its private key,
+///   so the agent can verify it talks to the real proxy.
 ///
 /// # Examples
 ///
@@ -67,23 +92,45 @@ pub enum Encryption {
 /// - `Deserialize`: To reconstruct the struct from a serialized format,
 ///   enabling easy data exchange and storage.
 ///
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
 pub struct HandshakeRequest {
+    /// The protocol version of the agent, it is the first field so
+    /// it can be read whatever the fields after it become
+    pub version: u16,
     pub username: Username,
     pub encryption: Encryption,
+    pub tag: Option<String>,
+    pub challenge: Bytes,
+    /// Whether the agent asks to compress the relay frames
+    pub compression: bool,
 }
 
-impl TryFrom<Bytes> for HandshakeRequest {
-    type Error = Error;
-    fn try_from(value: Bytes) -> Result<Self, Self::Error> {
-        let (result, _) = bincode::serde::decode_from_slice::<HandshakeRequest, Configuration>(
-            &value,
+impl HandshakeRequest {
+    /// Decode the handshake request received from the peer, the length
+    /// prefix of the username is checked before the username is allocated.
+    pub fn decode(value: &[u8], username_max_length: usize) -> Result<Self, Error> {
+        // The username follows the version, so its length prefix is read first
+        let ((_, username_length), _) =
+            bincode::decode_from_slice::<(u16, u64), _>(value, bincode::config::standard())?;
+        if username_length > username_max_length as u64 {
+            return Err(Error::UsernameTooLong(username_length, username_max_length));
+        }
+        let (mut result, _) = bincode::serde::decode_from_slice::<HandshakeRequest, Configuration>(
+            value,
             bincode::config::standard(),
         )?;
+        result.username = Username::try_new(result.username.0, username_max_length)?;
         Ok(result)
     }
 }
 
+impl TryFrom<Bytes> for HandshakeRequest {
+    type Error = Error;
+    fn try_from(value: Bytes) -> Result<Self, Self::Error> {
+        Self::decode(&value, USERNAME_MAX_LENGTH)
+    }
+}
+
 impl TryFrom<BytesMut> for HandshakeRequest {
     type Error = Error;
     fn try_from(value: BytesMut) -> Result<Self, Self::Error> {
@@ -99,9 +146,52 @@ impl TryFrom<HandshakeRequest> for Vec<u8> {
     }
 }
 
-#[derive(Debug, Serialize, Deserialize)]
-pub struct HandshakeResponse {
-    pub encryption: Encryption,
+/// The reason why the proxy rejects the handshake of the agent
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+pub enum HandshakeError {
+    /// The username is not known by the proxy
+    UserNotExist,
+    /// The proxy has no rsa keys of the user
+    UserRsaCryptoNotExist,
+    /// The user is expired
+    UserExpired,
+    /// The protocol version of the agent is too old
+    UnsupportedProtocolVersion,
+    /// The encryption of the agent can not be decrypted with the keys of the user
+    InvalidEncryption,
+    /// The proxy rejects the user without telling the detailed reason
+    Rejected,
+}
+
+impl Display for HandshakeError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let reason = match self {
+            HandshakeError::UserNotExist => "user not exist",
+            HandshakeError::UserRsaCryptoNotExist => "user rsa crypto not exist",
+            HandshakeError::UserExpired => "user expired",
+            HandshakeError::UnsupportedProtocolVersion => "unsupported protocol version",
+            HandshakeError::InvalidEncryption => "invalid encryption",
+            HandshakeError::Rejected => "rejected",
+        };
+        f.write_str(reason)
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub enum HandshakeResponse {
+    Success {
+        /// The protocol version negotiated for the connection
+        version: u16,
+        encryption: Encryption,
+        /// The signature of the handshake transcript with the proxy private key,
+        /// it covers the challenge, both versions, the encryption and the compression
+        challenge_signature: Bytes,
+        /// Whether the relay frames are compressed, the proxy only
+        /// accepts it when the agent asks and compression is enabled
+        compression: bool,
+    },
+    /// The proxy rejects the handshake and closes the connection
+    Failure { reason: HandshakeError },
 }
 
 impl TryFrom<Bytes> for HandshakeResponse {
@@ -130,7 +220,7 @@ impl TryFrom<HandshakeResponse> for Vec<u8> {
     }
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
 pub enum ConnectDestinationRequest {
     /// Connect the TCP destination
     Tcp(UnifiedAddress),
@@ -164,11 +254,56 @@ impl TryFrom<ConnectDestinationRequest> for Vec<u8> {
     }
 }
 
+/// The connect destination request with the options of the destination
+/// connection, the agent always wraps the connect destination request in it
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct ExtendedConnectDestinationRequest {
+    pub request: ConnectDestinationRequest,
+    /// The milliseconds the agent waits for the destination to be connected,
+    /// the proxy gives up connecting the destination after the shorter one
+    /// of it and its own connect timeout
+    pub connect_timeout_hint_millis: Option<u64>,
+}
+
+/// The longest domain name DNS resolves
+pub const DOMAIN_HOST_MAX_LENGTH: usize = 253;
+/// The bound of the encoded [`ExtendedConnectDestinationRequest`] to a domain
+/// of [DOMAIN_HOST_MAX_LENGTH] bytes, the largest control message of the relay.
+/// It is decoded from one frame, so every relay frame must be able to carry it.
+pub const MAX_CONTROL_MESSAGE_LENGTH: usize = 271;
+
+impl TryFrom<Bytes> for ExtendedConnectDestinationRequest {
+    type Error = Error;
+    fn try_from(value: Bytes) -> Result<Self, Self::Error> {
+        let (result, _) = bincode::serde::decode_from_slice::<
+            ExtendedConnectDestinationRequest,
+            Configuration,
+        >(&value, bincode::config::standard())?;
+        Ok(result)
+    }
+}
+
+impl TryFrom<BytesMut> for ExtendedConnectDestinationRequest {
+    type Error = Error;
+    fn try_from(value: BytesMut) -> Result<Self, Self::Error> {
+        Self::try_from(value.freeze())
+    }
+}
+
+impl TryFrom<ExtendedConnectDestinationRequest> for Vec<u8> {
+    type Error = Error;
+    fn try_from(value: ExtendedConnectDestinationRequest) -> Result<Self, Self::Error> {
+        let result = bincode::serde::encode_to_vec(value, bincode::config::standard())?;
+        Ok(result)
+    }
+}
+
 /// Represents the response from a connection attempt to a destination.
 ///
-/// This enum can have one of two values:
+/// This enum can have one of three values:
 /// - `Success`: Indicates that the connection to the destination was successful.
 /// - `Fail`: Indicates that the connection to the destination failed.
+/// - `Refused`: Indicates that the proxy observes the destination without connecting.
 ///
 /// # Examples
 ///
@@ -181,18 +316,23 @@ impl TryFrom<ConnectDestinationRequest> for Vec<u8> {
 ///     Success,
 ///     /// Connect to destination fail
 ///     Fail,
+///     /// The proxy is in observe mode and refuses the destination
+///     Refused,
 /// }
 ///
 /// let response = ConnectDestinationResponse::Success;
 /// println!("{:?}", response); // Outputs: Success
 /// ```
 ///
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
 pub enum ConnectDestinationResponse {
     /// Connect to destination success
     Success,
     /// Connect to destination fail
     Fail,
+    /// The proxy is in observe mode, it logs the destination and
+    /// refuses it without connecting
+    Refused,
 }
 
 impl TryFrom<Bytes> for ConnectDestinationResponse {
@@ -221,7 +361,7 @@ impl TryFrom<ConnectDestinationResponse> for Vec<u8> {
     }
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
 pub enum Relay {
     /// Relay TCP data
     Tcp(Bytes),
@@ -233,9 +373,71 @@ pub enum Relay {
     },
 }
 
+/// The bincode variant index of `Relay::Tcp`
+const RELAY_TCP_VARIANT: u8 = 0;
+/// The bincode varint markers of the length encoded in 2, 4 and 8 bytes
+const VARINT_U16_MARKER: u8 = 251;
+const VARINT_U32_MARKER: u8 = 252;
+const VARINT_U64_MARKER: u8 = 253;
+
+impl Relay {
+    /// Encode the TCP relay payload directly into the buffer without wrapping it
+    /// into a `Relay::Tcp` first, the result is identical to the bincode encoding.
+    pub fn encode_tcp(payload: &[u8], dst: &mut BytesMut) {
+        dst.reserve(payload.len() + 10);
+        dst.put_u8(RELAY_TCP_VARIANT);
+        let len = payload.len() as u64;
+        if len < VARINT_U16_MARKER as u64 {
+            dst.put_u8(len as u8);
+        } else if len <= u16::MAX as u64 {
+            dst.put_u8(VARINT_U16_MARKER);
+            dst.put_u16_le(len as u16);
+        } else if len <= u32::MAX as u64 {
+            dst.put_u8(VARINT_U32_MARKER);
+            dst.put_u32_le(len as u32);
+        } else {
+            dst.put_u8(VARINT_U64_MARKER);
+            dst.put_u64_le(len);
+        }
+        dst.put_slice(payload);
+    }
+
+    /// Decode the `Relay::Tcp` payload as a slice of the buffer without copying,
+    /// `None` means the buffer is not a `Relay::Tcp` that can be decoded directly.
+    fn decode_tcp(value: &Bytes) -> Option<Bytes> {
+        let (&variant, rest) = value.split_first()?;
+        if variant != RELAY_TCP_VARIANT {
+            return None;
+        }
+        let (&marker, rest) = rest.split_first()?;
+        let (len, len_size) = match marker {
+            VARINT_U16_MARKER => (
+                u16::from_le_bytes(rest.get(..2)?.try_into().ok()?) as u64,
+                2,
+            ),
+            VARINT_U32_MARKER => (
+                u32::from_le_bytes(rest.get(..4)?.try_into().ok()?) as u64,
+                4,
+            ),
+            VARINT_U64_MARKER => (u64::from_le_bytes(rest.get(..8)?.try_into().ok()?), 8),
+            marker if marker < VARINT_U16_MARKER => (marker as u64, 0),
+            _ => return None,
+        };
+        let start: usize = 2 + len_size;
+        let end = start.checked_add(usize::try_from(len).ok()?)?;
+        if end > value.len() {
+            return None;
+        }
+        Some(value.slice(start..end))
+    }
+}
+
 impl TryFrom<Bytes> for Relay {
     type Error = Error;
     fn try_from(value: Bytes) -> Result<Self, Self::Error> {
+        if let Some(payload) = Self::decode_tcp(&value) {
+            return Ok(Relay::Tcp(payload));
+        }
         let (result, _) = bincode::serde::decode_from_slice::<Relay, Configuration>(
             &value,
             bincode::config::standard(),
@@ -254,7 +456,498 @@ impl TryFrom<BytesMut> for Relay {
 impl TryFrom<Relay> for Vec<u8> {
     type Error = Error;
     fn try_from(value: Relay) -> Result<Self, Self::Error> {
+        if let Relay::Tcp(payload) = &value {
+            let mut result = BytesMut::new();
+            Relay::encode_tcp(payload, &mut result);
+            return Ok(result.to_vec());
+        }
         let result = bincode::serde::encode_to_vec(value, bincode::config::standard())?;
         Ok(result)
     }
 }
+
+#[test]
+fn test_handshake_request_tag() -> Result<(), Error> {
+    let handshake_request = HandshakeRequest {
+        version: PROTOCOL_VERSION,
+        username: Username::from("user1"),
+        encryption: Encryption::Plain,
+        tag: Some("app1".to_string()),
+        challenge: Bytes::from_static(b"challenge"),
+        compression: false,
+    };
+    let handshake_request_bytes: Vec<u8> = handshake_request.try_into()?;
+    let handshake_request: HandshakeRequest = Bytes::from(handshake_request_bytes).try_into()?;
+    assert_eq!(Username::from("user1"), handshake_request.username);
+    assert_eq!(Some("app1".to_string()), handshake_request.tag);
+    assert_eq!(
+        Bytes::from_static(b"challenge"),
+        handshake_request.challenge
+    );
+    Ok(())
+}
+
+#[test]
+fn test_check_peer_protocol_version() {
+    assert!(check_peer_protocol_version(PROTOCOL_VERSION).is_ok());
+    // The newer peer downgrades itself
+    assert!(check_peer_protocol_version(PROTOCOL_VERSION + 1).is_ok());
+    assert!(matches!(
+        check_peer_protocol_version(MIN_PROTOCOL_VERSION - 1),
+        Err(Error::UnsupportedProtocolVersion(version)) if version == MIN_PROTOCOL_VERSION - 1
+    ));
+}
+
+#[test]
+fn test_handshake_request_invalid_username() -> Result<(), Error> {
+    for username in ["user1\nadmin".to_string(), String::new()] {
+        let handshake_request = HandshakeRequest {
+            version: PROTOCOL_VERSION,
+            username: Username(username),
+            encryption: Encryption::Plain,
+            tag: None,
+            challenge: Bytes::new(),
+            compression: false,
+        };
+        let handshake_request_bytes: Vec<u8> = handshake_request.try_into()?;
+        let result = HandshakeRequest::try_from(Bytes::from(handshake_request_bytes));
+        assert!(matches!(result, Err(Error::InvalidUsername(_))));
+    }
+    let username = "a".repeat(USERNAME_MAX_LENGTH);
+    assert_eq!(
+        Username(username.clone()),
+        Username::try_new(username, USERNAME_MAX_LENGTH)?
+    );
+    Ok(())
+}
+
+#[test]
+fn test_handshake_request_username_max_length() -> Result<(), Error> {
+    let handshake_request = |username: String| HandshakeRequest {
+        version: PROTOCOL_VERSION,
+        username: Username(username),
+        encryption: Encryption::Plain,
+        tag: None,
+        challenge: Bytes::new(),
+        compression: false,
+    };
+    let long_username = "a".repeat(USERNAME_MAX_LENGTH + 1);
+    let handshake_request_bytes: Vec<u8> = handshake_request(long_username.clone()).try_into()?;
+    assert!(matches!(
+        HandshakeRequest::try_from(Bytes::from(handshake_request_bytes.clone())),
+        Err(Error::UsernameTooLong(length, USERNAME_MAX_LENGTH)) if length == 65
+    ));
+    // The limit is configurable
+    assert_eq!(
+        Username(long_username),
+        HandshakeRequest::decode(&handshake_request_bytes, USERNAME_MAX_LENGTH + 1)?.username
+    );
+    // The huge length prefix is rejected before any username byte is read
+    let huge_username_bytes =
+        bincode::encode_to_vec((PROTOCOL_VERSION, u64::MAX), bincode::config::standard())?;
+    assert!(matches!(
+        HandshakeRequest::decode(&huge_username_bytes, USERNAME_MAX_LENGTH),
+        Err(Error::UsernameTooLong(u64::MAX, USERNAME_MAX_LENGTH))
+    ));
+    Ok(())
+}
+
+#[test]
+fn test_relay_tcp_encoding() -> Result<(), Error> {
+    for payload_len in [0, 1, 250, 251, 65535, 65536, 100_000] {
+        let payload = Bytes::from((0..payload_len).map(|i| i as u8).collect::<Vec<u8>>());
+        let bincode_bytes = bincode::serde::encode_to_vec(
+            Relay::Tcp(payload.clone()),
+            bincode::config::standard(),
+        )?;
+        let mut relay_bytes = BytesMut::new();
+        Relay::encode_tcp(&payload, &mut relay_bytes);
+        assert_eq!(bincode_bytes, relay_bytes.to_vec());
+        let relay_vec: Vec<u8> = Relay::Tcp(payload.clone()).try_into()?;
+        assert_eq!(bincode_bytes, relay_vec);
+        match Relay::try_from(Bytes::from(bincode_bytes))? {
+            Relay::Tcp(decoded_payload) => assert_eq!(payload, decoded_payload),
+            Relay::Udp { .. } => panic!("Relay::Tcp decoded as Relay::Udp"),
+        }
+    }
+    let mut truncated_bytes = BytesMut::new();
+    Relay::encode_tcp(&[1u8; 300], &mut truncated_bytes);
+    truncated_bytes.truncate(100);
+    assert!(Relay::try_from(truncated_bytes).is_err());
+    Ok(())
+}
+
+/// Encode the bytes as lowercase hex, the format of the golden files
+#[cfg(test)]
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{byte:02x}")).collect()
+}
+
+/// The golden encoded bytes are the wire format contract, they must only
+/// change together with a deliberate change of the protocol.
+#[test]
+fn test_wire_format_golden() -> Result<(), Error> {
+    use std::net::SocketAddr;
+    fn assert_golden<T>(name: &str, golden: &str, packet: T) -> Result<(), Error>
+    where
+        T: TryInto<Vec<u8>, Error = Error>
+            + TryFrom<Bytes, Error = Error>
+            + Clone
+            + Eq
+            + std::fmt::Debug,
+    {
+        let encoded: Vec<u8> = packet.clone().try_into()?;
+        assert_eq!(
+            golden.trim(),
+            to_hex(&encoded),
+            "wire format of {name} changed"
+        );
+        let golden_bytes = (0..golden.trim().len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&golden.trim()[i..i + 2], 16).unwrap())
+            .collect::<Vec<u8>>();
+        assert_eq!(packet, T::try_from(Bytes::from(golden_bytes))?);
+        Ok(())
+    }
+    let src_addr: SocketAddr = "192.168.1.2:5353".parse().unwrap();
+    let dst_addr: SocketAddr = "[2001:db8::1]:53".parse().unwrap();
+    assert_golden(
+        "handshake_request",
+        include_str!("../golden/handshake_request.hex"),
+        HandshakeRequest {
+            version: 1,
+            username: Username::from("user1"),
+            encryption: Encryption::Aes(Bytes::from_static(&[0x11; 8])),
+            tag: Some("mobile".to_string()),
+            challenge: Bytes::from_static(&[0x22; 16]),
+            compression: true,
+        },
+    )?;
+    assert_golden(
+        "handshake_response",
+        include_str!("../golden/handshake_response.hex"),
+        HandshakeResponse::Success {
+            version: 1,
+            encryption: Encryption::ChaCha20Poly1305(Bytes::from_static(&[0x33; 8])),
+            challenge_signature: Bytes::from_static(&[0x44; 8]),
+            compression: false,
+        },
+    )?;
+    assert_golden(
+        "handshake_response_failure",
+        include_str!("../golden/handshake_response_failure.hex"),
+        HandshakeResponse::Failure {
+            reason: HandshakeError::UserExpired,
+        },
+    )?;
+    assert_golden(
+        "connect_destination_request_tcp",
+        include_str!("../golden/connect_destination_request_tcp.hex"),
+        ConnectDestinationRequest::Tcp(UnifiedAddress::Domain {
+            host: "www.example.com".to_string(),
+            port: 443,
+        }),
+    )?;
+    assert_golden(
+        "connect_destination_request_udp",
+        include_str!("../golden/connect_destination_request_udp.hex"),
+        ConnectDestinationRequest::Udp("8.8.8.8:53".parse::<SocketAddr>().unwrap().into()),
+    )?;
+    assert_golden(
+        "extended_connect_destination_request",
+        include_str!("../golden/extended_connect_destination_request.hex"),
+        ExtendedConnectDestinationRequest {
+            request: ConnectDestinationRequest::Tcp(UnifiedAddress::Domain {
+                host: "www.example.com".to_string(),
+                port: 443,
+            }),
+            connect_timeout_hint_millis: Some(3000),
+        },
+    )?;
+    assert_golden(
+        "connect_destination_response_success",
+        include_str!("../golden/connect_destination_response_success.hex"),
+        ConnectDestinationResponse::Success,
+    )?;
+    assert_golden(
+        "connect_destination_response_fail",
+        include_str!("../golden/connect_destination_response_fail.hex"),
+        ConnectDestinationResponse::Fail,
+    )?;
+    assert_golden(
+        "connect_destination_response_refused",
+        include_str!("../golden/connect_destination_response_refused.hex"),
+        ConnectDestinationResponse::Refused,
+    )?;
+    assert_golden(
+        "relay_tcp",
+        include_str!("../golden/relay_tcp.hex"),
+        Relay::Tcp(Bytes::from_static(b"hello")),
+    )?;
+    assert_golden(
+        "relay_tcp_long",
+        include_str!("../golden/relay_tcp_long.hex"),
+        Relay::Tcp(Bytes::from_static(&[0xab; 300])),
+    )?;
+    assert_golden(
+        "relay_udp",
+        include_str!("../golden/relay_udp.hex"),
+        Relay::Udp {
+            src_addr: src_addr.into(),
+            dst_addr: dst_addr.into(),
+            payload: Bytes::from_static(b"query"),
+        },
+    )?;
+    Ok(())
+}
+
+#[cfg(test)]
+use proptest::prelude::*;
+
+/// The bytes up to 300 bytes, the encoded length crosses the 1 byte varint bound
+#[cfg(test)]
+fn arbitrary_bytes() -> impl Strategy<Value = Bytes> {
+    proptest::collection::vec(any::<u8>(), 0..300).prop_map(Bytes::from)
+}
+
+/// The relay payloads, the large ones cross the 2 and 4 bytes varint bounds
+#[cfg(test)]
+fn arbitrary_relay_payload() -> impl Strategy<Value = Bytes> {
+    prop_oneof![
+        arbitrary_bytes(),
+        (0usize..100_000, any::<u8>()).prop_map(|(len, seed)| {
+            Bytes::from(
+                (0..len)
+                    .map(|i| seed.wrapping_add(i as u8))
+                    .collect::<Vec<u8>>(),
+            )
+        }),
+    ]
+}
+
+#[cfg(test)]
+impl Arbitrary for Encryption {
+    type Parameters = ();
+    type Strategy = BoxedStrategy<Self>;
+    fn arbitrary_with(_: Self::Parameters) -> Self::Strategy {
+        prop_oneof![
+            Just(Encryption::Plain),
+            arbitrary_bytes().prop_map(Encryption::Aes),
+            arbitrary_bytes().prop_map(Encryption::Blowfish),
+            arbitrary_bytes().prop_map(Encryption::ChaCha20Poly1305),
+        ]
+        .boxed()
+    }
+}
+
+#[cfg(test)]
+impl Arbitrary for HandshakeRequest {
+    type Parameters = ();
+    type Strategy = BoxedStrategy<Self>;
+    fn arbitrary_with(_: Self::Parameters) -> Self::Strategy {
+        (
+            any::<u16>(),
+            any::<Username>(),
+            any::<Encryption>(),
+            any::<Option<String>>(),
+            arbitrary_bytes(),
+            any::<bool>(),
+        )
+            .prop_map(
+                |(version, username, encryption, tag, challenge, compression)| HandshakeRequest {
+                    version,
+                    username,
+                    encryption,
+                    tag,
+                    challenge,
+                    compression,
+                },
+            )
+            .boxed()
+    }
+}
+
+#[cfg(test)]
+impl Arbitrary for HandshakeResponse {
+    type Parameters = ();
+    type Strategy = BoxedStrategy<Self>;
+    fn arbitrary_with(_: Self::Parameters) -> Self::Strategy {
+        prop_oneof![
+            (
+                any::<u16>(),
+                any::<Encryption>(),
+                arbitrary_bytes(),
+                any::<bool>(),
+            )
+                .prop_map(|(version, encryption, challenge_signature, compression)| {
+                    HandshakeResponse::Success {
+                        version,
+                        encryption,
+                        challenge_signature,
+                        compression,
+                    }
+                }),
+            prop_oneof![
+                Just(HandshakeError::UserNotExist),
+                Just(HandshakeError::UserRsaCryptoNotExist),
+                Just(HandshakeError::UserExpired),
+                Just(HandshakeError::UnsupportedProtocolVersion),
+                Just(HandshakeError::InvalidEncryption),
+                Just(HandshakeError::Rejected),
+            ]
+            .prop_map(|reason| HandshakeResponse::Failure { reason }),
+        ]
+        .boxed()
+    }
+}
+
+#[cfg(test)]
+impl Arbitrary for ConnectDestinationRequest {
+    type Parameters = ();
+    type Strategy = BoxedStrategy<Self>;
+    fn arbitrary_with(_: Self::Parameters) -> Self::Strategy {
+        prop_oneof![
+            any::<UnifiedAddress>().prop_map(ConnectDestinationRequest::Tcp),
+            any::<UnifiedAddress>().prop_map(ConnectDestinationRequest::Udp),
+        ]
+        .boxed()
+    }
+}
+
+#[cfg(test)]
+impl Arbitrary for ExtendedConnectDestinationRequest {
+    type Parameters = ();
+    type Strategy = BoxedStrategy<Self>;
+    fn arbitrary_with(_: Self::Parameters) -> Self::Strategy {
+        (any::<ConnectDestinationRequest>(), any::<Option<u64>>())
+            .prop_map(
+                |(request, connect_timeout_hint_millis)| ExtendedConnectDestinationRequest {
+                    request,
+                    connect_timeout_hint_millis,
+                },
+            )
+            .boxed()
+    }
+}
+
+#[cfg(test)]
+impl Arbitrary for ConnectDestinationResponse {
+    type Parameters = ();
+    type Strategy = BoxedStrategy<Self>;
+    fn arbitrary_with(_: Self::Parameters) -> Self::Strategy {
+        prop_oneof![
+            Just(ConnectDestinationResponse::Success),
+            Just(ConnectDestinationResponse::Fail),
+            Just(ConnectDestinationResponse::Refused),
+        ]
+        .boxed()
+    }
+}
+
+#[cfg(test)]
+impl Arbitrary for Relay {
+    type Parameters = ();
+    type Strategy = BoxedStrategy<Self>;
+    fn arbitrary_with(_: Self::Parameters) -> Self::Strategy {
+        prop_oneof![
+            arbitrary_relay_payload().prop_map(Relay::Tcp),
+            (
+                any::<UnifiedAddress>(),
+                any::<UnifiedAddress>(),
+                arbitrary_relay_payload()
+            )
+                .prop_map(|(src_addr, dst_addr, payload)| Relay::Udp {
+                    src_addr,
+                    dst_addr,
+                    payload,
+                }),
+        ]
+        .boxed()
+    }
+}
+
+#[test]
+fn test_max_control_message_length() -> Result<(), Error> {
+    for (request, connect_timeout_hint_millis) in [
+        (
+            ConnectDestinationRequest::Tcp as fn(UnifiedAddress) -> _,
+            Some(u64::MAX),
+        ),
+        (ConnectDestinationRequest::Udp, Some(u64::MAX)),
+        (ConnectDestinationRequest::Tcp, None),
+    ] {
+        let request_bytes: Vec<u8> = ExtendedConnectDestinationRequest {
+            request: request(UnifiedAddress::Domain {
+                host: "a".repeat(DOMAIN_HOST_MAX_LENGTH),
+                port: u16::MAX,
+            }),
+            connect_timeout_hint_millis,
+        }
+        .try_into()?;
+        assert!(request_bytes.len() <= MAX_CONTROL_MESSAGE_LENGTH);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+proptest! {
+    #[test]
+    fn test_handshake_request_round_trip(handshake_request in any::<HandshakeRequest>()) {
+        let handshake_request_bytes: Vec<u8> = handshake_request.clone().try_into()?;
+        let decoded = HandshakeRequest::try_from(BytesMut::from(&handshake_request_bytes[..]))?;
+        prop_assert_eq!(handshake_request, decoded);
+    }
+
+    #[test]
+    fn test_handshake_response_round_trip(handshake_response in any::<HandshakeResponse>()) {
+        let handshake_response_bytes: Vec<u8> = handshake_response.clone().try_into()?;
+        let decoded = HandshakeResponse::try_from(BytesMut::from(&handshake_response_bytes[..]))?;
+        prop_assert_eq!(handshake_response, decoded);
+    }
+
+    #[test]
+    fn test_connect_destination_request_round_trip(
+        connect_destination_request in any::<ConnectDestinationRequest>()
+    ) {
+        let connect_destination_request_bytes: Vec<u8> =
+            connect_destination_request.clone().try_into()?;
+        let decoded = ConnectDestinationRequest::try_from(BytesMut::from(
+            &connect_destination_request_bytes[..],
+        ))?;
+        prop_assert_eq!(connect_destination_request, decoded);
+    }
+
+    #[test]
+    fn test_extended_connect_destination_request_round_trip(
+        extended_connect_destination_request in any::<ExtendedConnectDestinationRequest>()
+    ) {
+        let extended_connect_destination_request_bytes: Vec<u8> =
+            extended_connect_destination_request.clone().try_into()?;
+        let decoded = ExtendedConnectDestinationRequest::try_from(BytesMut::from(
+            &extended_connect_destination_request_bytes[..],
+        ))?;
+        prop_assert_eq!(extended_connect_destination_request, decoded);
+    }
+
+    #[test]
+    fn test_connect_destination_response_round_trip(
+        connect_destination_response in any::<ConnectDestinationResponse>()
+    ) {
+        let connect_destination_response_bytes: Vec<u8> =
+            connect_destination_response.clone().try_into()?;
+        let decoded = ConnectDestinationResponse::try_from(BytesMut::from(
+            &connect_destination_response_bytes[..],
+        ))?;
+        prop_assert_eq!(connect_destination_response, decoded);
+    }
+
+    #[test]
+    fn test_relay_round_trip(relay in any::<Relay>()) {
+        let relay_bytes: Vec<u8> = relay.clone().try_into()?;
+        // The direct tcp encoding must stay identical to the bincode encoding
+        let bincode_bytes = bincode::serde::encode_to_vec(&relay, bincode::config::standard())?;
+        prop_assert_eq!(&bincode_bytes, &relay_bytes);
+        let decoded = Relay::try_from(BytesMut::from(&relay_bytes[..]))?;
+        prop_assert_eq!(relay, decoded);
+    }
+}
@@ -1,29 +1,33 @@
-use common::{ServerState, build_server_runtime, log, start_server};
-use proxy::config::get_config;
+use common::{Shutdown, build_server_runtime, log, set_encryption_preference};
+use proxy::config::Config;
 use proxy::error::Error;
-use proxy::tunnel;
-use tokio::signal;
-use tracing::{debug, error, info};
-
-/// Handle the incoming client connection
-async fn handle_agent_connection(server_state: ServerState) -> Result<(), Error> {
-    debug!("Handling agent connection: {server_state:?}.");
-    tunnel::process(server_state).await?;
-    Ok(())
-}
+use proxy::server::{ProxyContext, start_proxy_server};
+use std::sync::Arc;
+use std::time::Duration;
+use tracing::{error, info};
 
 /// Start the proxy server
 fn main() -> Result<(), Error> {
-    let _log_guard = log::init(get_config().common())?;
-    let server_runtime = build_server_runtime(get_config().common())?;
+    let config = Config::from_command_line();
+    let log_guard = log::init(config.common())?;
+    set_encryption_preference(config.common().encryption_preference);
+    let server_runtime = build_server_runtime(config.common())?;
+    let context = Arc::new(ProxyContext::new(config)?);
     server_runtime.block_on(async move {
-        let server_guard = start_server(get_config().common(), handle_agent_connection);
-        if let Err(e) = signal::ctrl_c().await {
+        let server_guard = start_proxy_server(context.clone()).await?;
+        let shutdown_timeout = Duration::from_secs(context.config().common().shutdown_timeout);
+        let mut shutdown = Shutdown::new();
+        shutdown.register("server", move || async move {
+            server_guard.shutdown(shutdown_timeout).await
+        });
+        shutdown.register("log", move || async move { drop(log_guard) });
+        if let Err(e) = shutdown.wait_for_signal().await {
             error!("Error happen when listening stop signal: {}", e);
-            return;
+            return Ok(());
         }
         info!("Receive stop signal, going to stop server gracefully.");
-        server_guard.stop_signal.cancel();
-    });
+        shutdown.shutdown().await;
+        Ok::<(), Error>(())
+    })?;
     Ok(())
 }
@@ -1,26 +1,53 @@
-use common::SecureLengthDelimitedCodec;
+use common::memory::{BufferedRelayBytes, framed_buffered_bytes};
+use common::{BatchedFlushWriter, IncomingStream, SecureLengthDelimitedCodec};
 use std::io::Error;
 use std::pin::Pin;
 use std::task::{Context, Poll};
+use std::time::Duration;
 use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
-use tokio::net::TcpStream;
+
 use tokio::pin;
 use tokio_util::bytes::BytesMut;
-use tokio_util::codec::Framed;
-use tokio_util::io::{SinkWriter, StreamReader};
+use tokio_util::codec::{Framed, FramedParts};
 
 pub struct ClientTcpRelayEndpoint<'a> {
-    client_read_write:
-        SinkWriter<StreamReader<Framed<TcpStream, SecureLengthDelimitedCodec<'a>>, BytesMut>>,
+    client_read_write: BatchedFlushWriter<IncomingStream, SecureLengthDelimitedCodec<'a>>,
+    buffered_relay_bytes: BufferedRelayBytes,
 }
 
 impl<'a> ClientTcpRelayEndpoint<'a> {
-    pub fn new(client_stream: TcpStream, codec: SecureLengthDelimitedCodec<'a>) -> Self {
-        let client_framed = Framed::new(client_stream, codec);
+    /// Create the relay endpoint, the `read_buf` contains the bytes already read
+    /// from the client during destination setup, for example the initial payload
+    /// sent by the agent together with the connect destination request.
+    /// The `write_buffer_size` bounds the bytes of frames buffered before
+    /// they are forced to be written to the client, and the `flush_interval`
+    /// defers the flushes so the frames within it are written together.
+    pub fn new(
+        client_stream: IncomingStream,
+        codec: SecureLengthDelimitedCodec<'a>,
+        read_buf: BytesMut,
+        write_buffer_size: Option<usize>,
+        flush_interval: Option<Duration>,
+    ) -> Self {
+        let mut client_framed_parts = FramedParts::new::<&[u8]>(client_stream, codec);
+        client_framed_parts.read_buf = read_buf;
+        let mut client_framed = Framed::from_parts(client_framed_parts);
+        if let Some(write_buffer_size) = write_buffer_size {
+            client_framed.set_backpressure_boundary(write_buffer_size);
+        }
+        let client_read_write = BatchedFlushWriter::new(client_framed, flush_interval);
+        let mut buffered_relay_bytes = BufferedRelayBytes::new();
+        buffered_relay_bytes.update(framed_buffered_bytes(&client_read_write));
         Self {
-            client_read_write: SinkWriter::new(StreamReader::new(client_framed)),
+            client_read_write,
+            buffered_relay_bytes,
         }
     }
+
+    fn report_buffered_relay_bytes(&mut self) {
+        self.buffered_relay_bytes
+            .update(framed_buffered_bytes(&self.client_read_write));
+    }
 }
 
 impl<'a> AsyncRead for ClientTcpRelayEndpoint<'a> {
@@ -29,9 +56,12 @@ impl<'a> AsyncRead for ClientTcpRelayEndpoint<'a> {
         cx: &mut Context<'_>,
         buf: &mut ReadBuf<'_>,
     ) -> Poll<std::io::Result<()>> {
-        let client_read_write = &mut self.get_mut().client_read_write;
+        let this = self.get_mut();
+        let client_read_write = &mut this.client_read_write;
         pin!(client_read_write);
-        client_read_write.poll_read(cx, buf)
+        let result = client_read_write.poll_read(cx, buf);
+        this.report_buffered_relay_bytes();
+        result
     }
 }
 
@@ -41,18 +71,145 @@ impl<'a> AsyncWrite for ClientTcpRelayEndpoint<'a> {
         cx: &mut Context<'_>,
         buf: &[u8],
     ) -> Poll<Result<usize, Error>> {
-        let client_read_write = &mut self.get_mut().client_read_write;
+        let this = self.get_mut();
+        let client_read_write = &mut this.client_read_write;
         pin!(client_read_write);
-        client_read_write.poll_write(cx, buf)
+        let result = client_read_write.poll_write(cx, buf);
+        this.report_buffered_relay_bytes();
+        result
     }
     fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Error>> {
-        let client_read_write = &mut self.get_mut().client_read_write;
+        let this = self.get_mut();
+        let client_read_write = &mut this.client_read_write;
         pin!(client_read_write);
-        client_read_write.poll_flush(cx)
+        let result = client_read_write.poll_flush(cx);
+        this.report_buffered_relay_bytes();
+        result
     }
     fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Error>> {
-        let client_read_write = &mut self.get_mut().client_read_write;
+        let this = self.get_mut();
+        let client_read_write = &mut this.client_read_write;
         pin!(client_read_write);
-        client_read_write.poll_shutdown(cx)
+        let result = client_read_write.poll_shutdown(cx);
+        this.report_buffered_relay_bytes();
+        result
+    }
+}
+
+#[tokio::test]
+async fn test_write_buffer_size() -> Result<(), Error> {
+    use common::get_handshake_encryption;
+    use futures_util::StreamExt;
+    use std::borrow::Cow;
+    use tokio::io::AsyncWriteExt;
+    let codec = || {
+        SecureLengthDelimitedCodec::new(
+            Cow::Borrowed(get_handshake_encryption()),
+            Cow::Borrowed(get_handshake_encryption()),
+        )
+    };
+    let data = (0..200_000).map(|i| (i % 251) as u8).collect::<Vec<u8>>();
+    // The deferred flushes still deliver all the frames
+    for write_buffer_size in [1, 1024 * 1024] {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+        let agent_stream = tokio::net::TcpStream::connect(listener.local_addr()?).await?;
+        let (client_stream, _) = listener.accept().await?;
+        let client_stream = IncomingStream::Plain(client_stream);
+        let mut client_tcp_relay_endpoint = ClientTcpRelayEndpoint::new(
+            client_stream,
+            codec(),
+            BytesMut::new(),
+            Some(write_buffer_size),
+            Some(Duration::from_millis(2)),
+        );
+        let expected_data = data.clone();
+        let agent_task = tokio::spawn(async move {
+            let mut agent_framed = Framed::new(agent_stream, codec());
+            let mut received_data = Vec::new();
+            while let Some(frame) = agent_framed.next().await {
+                received_data.extend_from_slice(&frame.unwrap());
+            }
+            assert_eq!(expected_data, received_data);
+        });
+        for chunk in data.chunks(1000) {
+            client_tcp_relay_endpoint.write_all(chunk).await?;
+        }
+        client_tcp_relay_endpoint.shutdown().await?;
+        drop(client_tcp_relay_endpoint);
+        agent_task.await?;
     }
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_decrypt_failure_mid_relay() -> Result<(), Error> {
+    use common::random_generate_encryption;
+    use futures_util::SinkExt;
+    use std::borrow::Cow;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    let encryption = random_generate_encryption();
+    let codec = || {
+        SecureLengthDelimitedCodec::new(
+            Cow::Owned(encryption.clone()),
+            Cow::Owned(encryption.clone()),
+        )
+    };
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+    let agent_stream = tokio::net::TcpStream::connect(listener.local_addr()?).await?;
+    let (client_stream, _) = listener.accept().await?;
+    let client_stream = IncomingStream::Plain(client_stream);
+    let mut client_tcp_relay_endpoint =
+        ClientTcpRelayEndpoint::new(client_stream, codec(), BytesMut::new(), None, None);
+    let mut agent_framed = Framed::new(agent_stream, codec());
+    agent_framed.send(b"hello".as_slice()).await?;
+    // A frame whose body is not a valid cipher text
+    let agent_stream = agent_framed.get_mut();
+    agent_stream.write_all(&7u32.to_be_bytes()).await?;
+    agent_stream.write_all(b"corrupt").await?;
+    let mut buf = [0u8; 5];
+    client_tcp_relay_endpoint.read_exact(&mut buf).await?;
+    assert_eq!(b"hello", &buf);
+    let read_error = client_tcp_relay_endpoint
+        .read(&mut buf)
+        .await
+        .expect_err("Corrupt frame should fail to decrypt");
+    assert!(common::Error::is_decrypt_failure(&read_error));
+    let disconnect_error = std::io::Error::from(std::io::ErrorKind::ConnectionReset);
+    assert!(!common::Error::is_decrypt_failure(&disconnect_error));
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_udp_relay_payload_reassembled() -> Result<(), common::Error> {
+    use common::{random_generate_encryption, read_udp_relay_payload, write_udp_relay_payload};
+    use std::borrow::Cow;
+    let encryption = random_generate_encryption();
+    let codec = || {
+        SecureLengthDelimitedCodec::new(
+            Cow::Owned(encryption.clone()),
+            Cow::Owned(encryption.clone()),
+        )
+        .with_max_chunk_size(Some(16 * 1024))
+    };
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+    let agent_stream = tokio::net::TcpStream::connect(listener.local_addr()?).await?;
+    let (client_stream, _) = listener.accept().await?;
+    let client_stream = IncomingStream::Plain(client_stream);
+    let mut client_tcp_relay_endpoint =
+        ClientTcpRelayEndpoint::new(client_stream, codec(), BytesMut::new(), None, None);
+    let payload = (0..200_000).map(|i| (i % 251) as u8).collect::<Vec<u8>>();
+    let agent_payload = payload.clone();
+    let agent_codec = codec();
+    let agent_task = tokio::spawn(async move {
+        use tokio_util::io::{SinkWriter, StreamReader};
+        // The payload is split into many frames by the max chunk size
+        let mut agent_read_write =
+            SinkWriter::new(StreamReader::new(Framed::new(agent_stream, agent_codec)));
+        write_udp_relay_payload(&mut agent_read_write, &agent_payload).await
+    });
+    let received_payload =
+        read_udp_relay_payload(&mut client_tcp_relay_endpoint, 256 * 1024).await?;
+    assert_eq!(payload, received_payload);
+    agent_task.await.unwrap()?;
+    Ok(())
 }
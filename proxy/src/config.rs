@@ -1,40 +1,25 @@
 use crate::command::CommandArgs;
+use crate::destination::resolve::DEFAULT_MAX_CONCURRENT_DNS_RESOLUTIONS;
+use crate::destination::udp::DEFAULT_UDP_ASSOCIATION_IDLE_TIMEOUT;
 use clap::Parser;
-use common::config::CommonConfig;
-use common::{FsUserRepoConfig, UserConfig, UserRepoConfig};
+use common::balance::ProxyServerPolicy;
+use common::config::{
+    CommonConfig, default_handshake_max_frame_length, default_handshake_retry_delay_millis,
+    default_relay_length_field_length, deserialize_relay_length_field_length,
+    deserialize_relay_max_chunk_size,
+};
+use common::{
+    DEFAULT_UDP_RELAY_BUFFER_SIZE, FsUserRepoConfig, ProxyConnectionConfig, SocketOptions,
+    UserConfig, UserRepoConfig,
+};
 use core::panic;
-use protocol::Username;
+use protocol::{USERNAME_MAX_LENGTH, Username};
 use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
 use std::fs::read_to_string;
 use std::path::{Path, PathBuf};
-use std::sync::OnceLock;
 
 const DEFAULT_CONFIG_FILE: &str = "./resources/proxy.toml";
-static CONFIG: OnceLock<Config> = OnceLock::new();
-
-pub fn get_config() -> &'static Config {
-    CONFIG.get_or_init(|| {
-        let command_line = CommandArgs::parse();
-        let config_content = match &command_line.config_file_path {
-            None => read_to_string(DEFAULT_CONFIG_FILE).unwrap_or_else(|_| {
-                panic!(
-                    "Fail to read proxy configuration file content from: {:?}",
-                    DEFAULT_CONFIG_FILE
-                )
-            }),
-            Some(path) => read_to_string(path).unwrap_or_else(|_| {
-                panic!(
-                    "Fail to read proxy configuration file content from: {:?}",
-                    path
-                )
-            }),
-        };
-        let mut config = toml::from_str::<Config>(&config_content)
-            .expect("Fail to initialize proxy configuration");
-        config.merge_command_args(command_line);
-        config
-    })
-}
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct ForwardConfig {
@@ -45,12 +30,86 @@ pub struct ForwardConfig {
     user_repo_directory: PathBuf,
     user_repo_refresh_interval: u64,
     username: Username,
+    #[serde(default, deserialize_with = "deserialize_relay_max_chunk_size")]
+    relay_max_chunk_size: Option<usize>,
+    #[serde(default)]
+    relay_write_buffer_size: Option<usize>,
+    #[serde(default)]
+    relay_flush_interval_millis: Option<u64>,
+    connection_tag: Option<String>,
+    #[serde(default)]
+    startup_check: ForwardStartupCheck,
+    #[serde(default)]
+    max_users: Option<usize>,
+    #[serde(default)]
+    user_load_parallelism: Option<usize>,
+    #[serde(default = "default_handshake_max_frame_length")]
+    handshake_max_frame_length: usize,
+    #[serde(flatten)]
+    socket_options: SocketOptions,
+    #[serde(default)]
+    relay_per_frame_iv: bool,
+    #[serde(
+        default = "default_relay_length_field_length",
+        deserialize_with = "deserialize_relay_length_field_length"
+    )]
+    relay_length_field_length: usize,
+    #[serde(default)]
+    handshake_decrypt_retries: u32,
+    #[serde(default = "default_handshake_retry_delay_millis")]
+    handshake_retry_delay_millis: u64,
+    #[serde(default)]
+    relay_compression_level: Option<i32>,
+    #[serde(default)]
+    proxy_server_policy: ProxyServerPolicy,
 }
 
 impl ForwardConfig {
-    pub fn proxy_connect_timeout(&self) -> u64 {
+    pub fn startup_check(&self) -> ForwardStartupCheck {
+        self.startup_check
+    }
+}
+
+impl ProxyConnectionConfig for ForwardConfig {
+    fn proxy_connect_timeout(&self) -> u64 {
         self.proxy_connect_timeout
     }
+    fn relay_max_chunk_size(&self) -> Option<usize> {
+        self.relay_max_chunk_size
+    }
+    fn connection_tag(&self) -> Option<&str> {
+        self.connection_tag.as_deref()
+    }
+    fn relay_write_buffer_size(&self) -> Option<usize> {
+        self.relay_write_buffer_size
+    }
+    fn relay_flush_interval_millis(&self) -> Option<u64> {
+        self.relay_flush_interval_millis
+    }
+    fn handshake_max_frame_length(&self) -> usize {
+        self.handshake_max_frame_length
+    }
+    fn socket_options(&self) -> SocketOptions {
+        self.socket_options
+    }
+    fn relay_per_frame_iv(&self) -> bool {
+        self.relay_per_frame_iv
+    }
+    fn relay_length_field_length(&self) -> usize {
+        self.relay_length_field_length
+    }
+    fn handshake_decrypt_retries(&self) -> u32 {
+        self.handshake_decrypt_retries
+    }
+    fn handshake_retry_delay_millis(&self) -> u64 {
+        self.handshake_retry_delay_millis
+    }
+    fn relay_compression_level(&self) -> Option<i32> {
+        self.relay_compression_level
+    }
+    fn proxy_server_policy(&self) -> ProxyServerPolicy {
+        self.proxy_server_policy
+    }
 }
 
 impl UserConfig for ForwardConfig {
@@ -78,6 +137,39 @@ impl FsUserRepoConfig for ForwardConfig {
     fn user_info_file_name(&self) -> &str {
         &self.user_info_file_name
     }
+    fn max_users(&self) -> Option<usize> {
+        self.max_users
+    }
+    fn user_load_parallelism(&self) -> Option<usize> {
+        self.user_load_parallelism
+    }
+}
+
+/// The action when the forward upstream is not reachable at startup
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ForwardStartupCheck {
+    /// Do not check the forward upstream at startup
+    #[default]
+    Disabled,
+    /// Log a warning for each unreachable forward upstream
+    Warn,
+    /// Fail the startup when any forward upstream is unreachable
+    Fail,
+}
+
+/// The preferred address family to connect the destination
+/// when the destination domain resolves to several addresses
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum AddressPreference {
+    /// Keep the order returned by the system resolver
+    #[default]
+    System,
+    /// Try the IPv4 addresses first
+    PreferIpv4,
+    /// Try the IPv6 addresses first
+    PreferIpv6,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -85,16 +177,144 @@ pub struct Config {
     #[serde(flatten)]
     common_config: CommonConfig,
     destination_connect_timeout: u64,
+    #[serde(default)]
+    destination_address_preference: AddressPreference,
+    /// Log the requested destinations and refuse them
+    /// instead of connecting, for all users
+    #[serde(default)]
+    observe_mode: bool,
+    /// The destination ports the clients can connect, they are checked
+    /// after the destination aliases are applied, empty means all the
+    /// ports are allowed
+    #[serde(default)]
+    allowed_destination_ports: Vec<u16>,
+    /// Close the relay when no data flows within
+    /// these seconds after the destination is connected
+    #[serde(default)]
+    relay_first_byte_timeout: Option<u64>,
+    /// The max size of one udp payload relayed for the client
+    #[serde(default = "default_udp_relay_buffer_size")]
+    udp_relay_buffer_size: usize,
+    /// Close the udp association when no datagram moves within these seconds,
+    /// the relay idle timeout takes precedence when it is set
+    #[serde(default = "default_udp_association_idle_timeout")]
+    udp_association_idle_timeout: u64,
+    /// The unix domain socket streaming the connection
+    /// events to the local tooling, disabled when not set
+    #[serde(default)]
+    connection_event_socket: Option<PathBuf>,
+    /// The max number of the destination domains resolving at the same time
+    #[serde(default = "default_max_concurrent_dns_resolutions")]
+    max_concurrent_dns_resolutions: usize,
+    /// Shut down both sides of the ended relay and drain them for
+    /// these seconds so the peers see FIN instead of RST
+    #[serde(default)]
+    relay_graceful_close_timeout: Option<u64>,
+    /// Log the sni of the tls client hello at the start of the tcp and forward relays,
+    /// it reveals the sites the users reach so it is disabled by default
+    #[serde(default)]
+    log_tls_sni: bool,
+    /// Tell the client why its user is rejected in handshake, it helps to
+    /// debug the agent setup but lets anyone probe which usernames exist,
+    /// so a generic reason is sent by default
+    #[serde(default)]
+    detailed_handshake_failure: bool,
+    /// The max length in bytes of the username in the handshake request,
+    /// the longer username is rejected before it is read
+    #[serde(default = "default_username_max_length")]
+    username_max_length: usize,
+    /// The alias domains rewritten to their backends before connecting,
+    /// the backend is the domain or the ip with an optional port
+    #[serde(default)]
+    destination_aliases: HashMap<String, String>,
     forward: Option<ForwardConfig>,
 }
 
+fn default_udp_relay_buffer_size() -> usize {
+    DEFAULT_UDP_RELAY_BUFFER_SIZE
+}
+
+fn default_udp_association_idle_timeout() -> u64 {
+    DEFAULT_UDP_ASSOCIATION_IDLE_TIMEOUT
+}
+
+fn default_max_concurrent_dns_resolutions() -> usize {
+    DEFAULT_MAX_CONCURRENT_DNS_RESOLUTIONS
+}
+
+fn default_username_max_length() -> usize {
+    USERNAME_MAX_LENGTH
+}
+
 impl Config {
     pub fn destination_connect_timeout(&self) -> u64 {
         self.destination_connect_timeout
     }
+    pub fn destination_address_preference(&self) -> AddressPreference {
+        self.destination_address_preference
+    }
     pub fn common(&self) -> &CommonConfig {
         &self.common_config
     }
+    pub fn observe_mode(&self) -> bool {
+        self.observe_mode
+    }
+    pub fn allowed_destination_ports(&self) -> &[u16] {
+        &self.allowed_destination_ports
+    }
+    pub fn relay_first_byte_timeout(&self) -> Option<u64> {
+        self.relay_first_byte_timeout
+    }
+    pub fn udp_relay_buffer_size(&self) -> usize {
+        self.udp_relay_buffer_size
+    }
+    pub fn udp_association_idle_timeout(&self) -> u64 {
+        self.udp_association_idle_timeout
+    }
+    pub fn connection_event_socket(&self) -> Option<&Path> {
+        self.connection_event_socket.as_deref()
+    }
+    pub fn max_concurrent_dns_resolutions(&self) -> usize {
+        self.max_concurrent_dns_resolutions
+    }
+    pub fn relay_graceful_close_timeout(&self) -> Option<u64> {
+        self.relay_graceful_close_timeout
+    }
+    pub fn log_tls_sni(&self) -> bool {
+        self.log_tls_sni
+    }
+    pub fn detailed_handshake_failure(&self) -> bool {
+        self.detailed_handshake_failure
+    }
+    pub fn username_max_length(&self) -> usize {
+        self.username_max_length
+    }
+    pub fn destination_aliases(&self) -> &HashMap<String, String> {
+        &self.destination_aliases
+    }
+    /// Load the configuration file given in the command line, or the default
+    /// one, and override it with the command line arguments.
+    pub fn from_command_line() -> Self {
+        let command_line = CommandArgs::parse();
+        let config_content = match &command_line.config_file_path {
+            None => read_to_string(DEFAULT_CONFIG_FILE).unwrap_or_else(|_| {
+                panic!(
+                    "Fail to read proxy configuration file content from: {:?}",
+                    DEFAULT_CONFIG_FILE
+                )
+            }),
+            Some(path) => read_to_string(path).unwrap_or_else(|_| {
+                panic!(
+                    "Fail to read proxy configuration file content from: {:?}",
+                    path
+                )
+            }),
+        };
+        let mut config = toml::from_str::<Config>(&config_content)
+            .expect("Fail to initialize proxy configuration");
+        config.merge_command_args(command_line);
+        config
+    }
     pub fn merge_command_args(&mut self, command: CommandArgs) {
         if let Some(listening_address) = command.listening_address {
             self.common_config.listening_address = listening_address;
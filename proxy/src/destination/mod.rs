@@ -3,6 +3,8 @@ use crate::destination::udp::UdpDestEndpoint;
 use common::proxy::{ProxyConnection, ProxyFramedReadWrite};
 use protocol::UnifiedAddress;
 
+pub(crate) mod resolve;
+pub mod rewrite;
 pub(crate) mod tcp;
 pub(crate) mod udp;
 
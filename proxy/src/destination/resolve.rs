@@ -0,0 +1,83 @@
+use crate::error::Error;
+use protocol::UnifiedAddress;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use tokio::sync::Semaphore;
+
+/// The default max number of the destination domains resolving at the same time
+pub const DEFAULT_MAX_CONCURRENT_DNS_RESOLUTIONS: usize = 64;
+
+/// Resolve the destination address, the domain is resolved on the blocking
+/// pool once a permit is acquired so a burst of connections queues instead of
+/// stampeding the resolver, the socket address skips the permits.
+pub async fn resolve_destination(
+    unified_dst_addr: &UnifiedAddress,
+    dns_resolutions: &Arc<Semaphore>,
+) -> Result<Vec<SocketAddr>, Error> {
+    match unified_dst_addr {
+        UnifiedAddress::SocketAddress(dst_addr) => Ok(vec![*dst_addr]),
+        UnifiedAddress::Domain { .. } => {
+            let unified_dst_addr = unified_dst_addr.clone();
+            resolve_with_permit(dns_resolutions, move || {
+                Ok(Vec::<SocketAddr>::try_from(unified_dst_addr)?)
+            })
+            .await
+        }
+    }
+}
+
+/// Run the blocking resolution holding one permit, the permit is released
+/// when the resolution finishes even if the caller stops waiting for it.
+async fn resolve_with_permit<F>(
+    resolutions: &Arc<Semaphore>,
+    resolve: F,
+) -> Result<Vec<SocketAddr>, Error>
+where
+    F: FnOnce() -> Result<Vec<SocketAddr>, Error> + Send + 'static,
+{
+    let permit = resolutions
+        .clone()
+        .acquire_owned()
+        .await
+        .map_err(|e| Error::Unknown(format!("Dns resolution permits closed: {e}")))?;
+    tokio::task::spawn_blocking(move || {
+        let resolved = resolve();
+        drop(permit);
+        resolved
+    })
+    .await
+    .map_err(|e| Error::Unknown(format!("Dns resolution task failed: {e}")))?
+}
+
+#[tokio::test]
+async fn test_resolve_with_permit() -> Result<(), Error> {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::Duration;
+    let resolutions = Arc::new(Semaphore::new(2));
+    let resolving = Arc::new(AtomicUsize::new(0));
+    let max_resolving = Arc::new(AtomicUsize::new(0));
+    let dst_addr: SocketAddr = "127.0.0.1:80".parse().unwrap();
+    let mut resolve_tasks = Vec::new();
+    for _ in 0..6 {
+        let resolutions = resolutions.clone();
+        let resolving = resolving.clone();
+        let max_resolving = max_resolving.clone();
+        resolve_tasks.push(tokio::spawn(async move {
+            resolve_with_permit(&resolutions, move || {
+                let current = resolving.fetch_add(1, Ordering::SeqCst) + 1;
+                max_resolving.fetch_max(current, Ordering::SeqCst);
+                std::thread::sleep(Duration::from_millis(100));
+                resolving.fetch_sub(1, Ordering::SeqCst);
+                Ok(vec![dst_addr])
+            })
+            .await
+        }));
+    }
+    for resolve_task in resolve_tasks {
+        assert_eq!(vec![dst_addr], resolve_task.await.unwrap()?);
+    }
+    // The resolutions beyond the limit are queued
+    assert_eq!(2, max_resolving.load(Ordering::SeqCst));
+    assert_eq!(2, resolutions.available_permits());
+    Ok(())
+}
@@ -0,0 +1,169 @@
+use crate::error::Error;
+use protocol::{ConnectDestinationRequest, UnifiedAddress};
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use tracing::debug;
+
+/// Rewrite the destination requested by the client before connecting,
+/// for example to route an internal alias domain to the real backend.
+/// The allowed destination ports of the proxy are checked against the
+/// rewritten destination, so a rewrite can not reach a disallowed port.
+pub trait DestinationRewriter: Send + Sync {
+    fn rewrite(&self, dst_addr: UnifiedAddress) -> UnifiedAddress;
+}
+
+/// The backend of an alias domain, the port of the
+/// requested destination is kept when it has no port
+#[derive(Debug, PartialEq, Eq)]
+enum AliasTarget {
+    Domain { host: String, port: Option<u16> },
+    Ip { ip: IpAddr, port: Option<u16> },
+}
+
+impl AliasTarget {
+    fn parse(value: &str) -> Result<Self, Error> {
+        if let Ok(socket_addr) = value.parse::<SocketAddr>() {
+            return Ok(AliasTarget::Ip {
+                ip: socket_addr.ip(),
+                port: Some(socket_addr.port()),
+            });
+        }
+        if let Ok(ip) = value.parse::<IpAddr>() {
+            return Ok(AliasTarget::Ip { ip, port: None });
+        }
+        let (host, port) = match UnifiedAddress::try_from(value) {
+            Ok(UnifiedAddress::Domain { host, port }) if value.contains(':') => (host, Some(port)),
+            Ok(UnifiedAddress::Domain { host, .. }) => (host, None),
+            _ => return Err(Error::InvalidDestinationAlias(value.to_string())),
+        };
+        Ok(AliasTarget::Domain { host, port })
+    }
+}
+
+/// Rewrite the alias domains to their backends, the domains are case-insensitive.
+pub struct AliasMapRewriter {
+    aliases: HashMap<String, AliasTarget>,
+}
+
+impl AliasMapRewriter {
+    /// Create the rewriter from the alias domains and their backends,
+    /// the backend is the domain or the ip with an optional port.
+    pub fn new(destination_aliases: &HashMap<String, String>) -> Result<Self, Error> {
+        let aliases = destination_aliases
+            .iter()
+            .map(|(alias, target)| Ok((alias.to_ascii_lowercase(), AliasTarget::parse(target)?)))
+            .collect::<Result<HashMap<_, _>, Error>>()?;
+        Ok(Self { aliases })
+    }
+}
+
+impl DestinationRewriter for AliasMapRewriter {
+    fn rewrite(&self, dst_addr: UnifiedAddress) -> UnifiedAddress {
+        let UnifiedAddress::Domain { host, port } = &dst_addr else {
+            return dst_addr;
+        };
+        match self.aliases.get(&host.to_ascii_lowercase()) {
+            None => dst_addr,
+            Some(AliasTarget::Domain {
+                host,
+                port: target_port,
+            }) => UnifiedAddress::Domain {
+                host: host.clone(),
+                port: target_port.unwrap_or(*port),
+            },
+            Some(AliasTarget::Ip {
+                ip,
+                port: target_port,
+            }) => SocketAddr::new(*ip, target_port.unwrap_or(*port)).into(),
+        }
+    }
+}
+
+/// Apply the destination rewriter of the proxy to the requested destination
+pub fn rewrite_connect_destination_request(
+    connect_destination_request: ConnectDestinationRequest,
+    rewriter: Option<&dyn DestinationRewriter>,
+) -> ConnectDestinationRequest {
+    match rewriter {
+        Some(rewriter) => rewrite_with(connect_destination_request, rewriter),
+        None => connect_destination_request,
+    }
+}
+
+fn rewrite_with(
+    connect_destination_request: ConnectDestinationRequest,
+    rewriter: &dyn DestinationRewriter,
+) -> ConnectDestinationRequest {
+    let rewrite = |dst_addr: UnifiedAddress| {
+        let rewritten_dst_addr = rewriter.rewrite(dst_addr.clone());
+        if rewritten_dst_addr != dst_addr {
+            debug!("Rewrite destination [{dst_addr}] to [{rewritten_dst_addr}]");
+        }
+        rewritten_dst_addr
+    };
+    match connect_destination_request {
+        ConnectDestinationRequest::Tcp(dst_addr) => {
+            ConnectDestinationRequest::Tcp(rewrite(dst_addr))
+        }
+        ConnectDestinationRequest::Udp(dst_addr) => {
+            ConnectDestinationRequest::Udp(rewrite(dst_addr))
+        }
+    }
+}
+
+#[test]
+fn test_alias_map_rewriter() -> Result<(), Error> {
+    let destination_aliases = HashMap::from([
+        (
+            "api.internal".to_string(),
+            "backend.example.com".to_string(),
+        ),
+        ("db.internal".to_string(), "db.example.com:5432".to_string()),
+        ("cache.internal".to_string(), "10.0.0.5".to_string()),
+        ("v6.internal".to_string(), "[2001:db8::1]:8080".to_string()),
+    ]);
+    let rewriter = AliasMapRewriter::new(&destination_aliases)?;
+    let domain = |host: &str, port: u16| UnifiedAddress::Domain {
+        host: host.to_string(),
+        port,
+    };
+    // The aliased domain is rewritten to its backend before connect
+    assert_eq!(
+        ConnectDestinationRequest::Tcp(domain("backend.example.com", 443)),
+        rewrite_with(
+            ConnectDestinationRequest::Tcp(domain("API.internal", 443)),
+            &rewriter
+        )
+    );
+    assert_eq!(
+        ConnectDestinationRequest::Tcp(domain("db.example.com", 5432)),
+        rewrite_with(
+            ConnectDestinationRequest::Tcp(domain("db.internal", 1)),
+            &rewriter
+        )
+    );
+    assert_eq!(
+        ConnectDestinationRequest::Udp("10.0.0.5:53".parse::<SocketAddr>().unwrap().into()),
+        rewrite_with(
+            ConnectDestinationRequest::Udp(domain("cache.internal", 53)),
+            &rewriter
+        )
+    );
+    assert_eq!(
+        UnifiedAddress::from("[2001:db8::1]:8080".parse::<SocketAddr>().unwrap()),
+        rewriter.rewrite(domain("v6.internal", 80))
+    );
+    // The other destinations are kept
+    assert_eq!(
+        domain("www.example.com", 443),
+        rewriter.rewrite(domain("www.example.com", 443))
+    );
+    let socket_addr: UnifiedAddress = "1.1.1.1:443".parse::<SocketAddr>().unwrap().into();
+    assert_eq!(socket_addr, rewriter.rewrite(socket_addr.clone()));
+    let invalid_aliases = HashMap::from([("bad.internal".to_string(), "bad host:x".to_string())]);
+    assert!(matches!(
+        AliasMapRewriter::new(&invalid_aliases),
+        Err(Error::InvalidDestinationAlias(_))
+    ));
+    Ok(())
+}
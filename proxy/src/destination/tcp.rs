@@ -1,15 +1,19 @@
+use crate::config::AddressPreference;
+use crate::destination::resolve::resolve_destination;
 use crate::error::Error;
 use common::Error as CommonError;
+use common::SocketOptions;
 use protocol::UnifiedAddress;
 use std::io::Error as StdIoError;
 use std::net::SocketAddr;
 use std::pin::Pin;
+use std::sync::Arc;
 use std::task::{Context, Poll};
 use std::time::Duration;
 use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
 use tokio::net::TcpStream;
 use tokio::pin;
-use tokio::sync::oneshot::channel;
+use tokio::sync::Semaphore;
 use tokio::time::timeout;
 use tracing::error;
 
@@ -18,50 +22,93 @@ pub struct TcpDestEndpoint {
     tcp_stream: TcpStream,
 }
 
+/// Order the resolved destination addresses so the preferred
+/// address family is tried first, the order inside one family is kept.
+fn order_by_preference(dst_addrs: &mut [SocketAddr], address_preference: AddressPreference) {
+    match address_preference {
+        AddressPreference::System => {}
+        AddressPreference::PreferIpv4 => dst_addrs.sort_by_key(|dst_addr| !dst_addr.is_ipv4()),
+        AddressPreference::PreferIpv6 => dst_addrs.sort_by_key(|dst_addr| !dst_addr.is_ipv6()),
+    }
+}
+
+/// Wait until the client closes its side of the connection, data pipelined
+/// by the client means it is still alive, so stop watching in that case.
+async fn client_closed(client_stream: &TcpStream) {
+    let mut buf = [0u8; 1];
+    match client_stream.peek(&mut buf).await {
+        Ok(0) | Err(_) => {}
+        Ok(_) => std::future::pending().await,
+    }
+}
+
+/// Race the destination setup against the client liveness, the setup
+/// is dropped (and so aborted) once the client goes away first.
+async fn abort_on_client_closed<F, T>(
+    client_stream: &TcpStream,
+    client_addr: SocketAddr,
+    setup: F,
+) -> Result<T, Error>
+where
+    F: Future<Output = Result<T, Error>>,
+{
+    tokio::select! {
+        result = setup => result,
+        _ = client_closed(client_stream) => Err(Error::ClientDisconnected(client_addr)),
+    }
+}
+
 impl TcpDestEndpoint {
     pub async fn connect(
         unified_dst_addr: UnifiedAddress,
-        connect_timeout: u64,
+        connect_timeout: Duration,
+        address_preference: AddressPreference,
+        socket_options: SocketOptions,
+        dns_resolutions: &Arc<Semaphore>,
     ) -> Result<Self, Error> {
-        let (dst_connection_tx, dst_connection_rx) = channel();
-        tokio::spawn(async move {
-            let dst_addrs: Vec<SocketAddr> = match unified_dst_addr.try_into() {
-                Ok(dst_addrs) => dst_addrs,
-                Err(e) => {
-                    error!("Fail to convert destination address: {e:?}");
-                    return;
-                }
-            };
-            let tcp_stream = match timeout(
-                Duration::from_secs(connect_timeout),
-                TcpStream::connect(&dst_addrs[..]),
-            )
+        let mut dst_addrs = resolve_destination(&unified_dst_addr, dns_resolutions).await?;
+        order_by_preference(&mut dst_addrs, address_preference);
+        let tcp_stream = timeout(connect_timeout, socket_options.connect_any(&dst_addrs))
             .await
-            .map_err(|_| CommonError::ConnectTimeout(connect_timeout))
-            {
-                Ok(Ok(tcp_stream)) => tcp_stream,
-                Ok(Err(e)) => {
-                    error!("Fail to connect destination {dst_addrs:?} because of error: {e}");
-                    return;
-                }
-                Err(_) => {
-                    error!("Connect to destination timeout.");
-                    return;
-                }
-            };
-            if dst_connection_tx.send(tcp_stream).is_err() {
-                error!("Fail to send destination tcp stream");
-            };
-        });
-        let tcp_stream = dst_connection_rx
-            .await
-            .map_err(|_| Error::Unknown("Fail to receive destination tcp stream".to_string()))?;
+            .map_err(|_| {
+                CommonError::ConnectTimeout(connect_timeout.as_millis().div_ceil(1000) as u64)
+            })?
+            .map_err(|e| {
+                error!("Fail to connect destination {dst_addrs:?} because of error: {e}");
+                Error::DestinationUnreachable(unified_dst_addr.clone())
+            })?;
+        socket_options.apply(&tcp_stream)?;
         let dst_addr = tcp_stream.peer_addr()?;
         Ok(Self {
             dst_addr,
             tcp_stream,
         })
     }
+
+    /// Connect to the destination unless the client disconnects before
+    /// the connection is established.
+    pub async fn connect_for_client(
+        client_stream: &TcpStream,
+        client_addr: SocketAddr,
+        unified_dst_addr: UnifiedAddress,
+        connect_timeout: Duration,
+        address_preference: AddressPreference,
+        socket_options: SocketOptions,
+        dns_resolutions: &Arc<Semaphore>,
+    ) -> Result<Self, Error> {
+        abort_on_client_closed(
+            client_stream,
+            client_addr,
+            Self::connect(
+                unified_dst_addr,
+                connect_timeout,
+                address_preference,
+                socket_options,
+                dns_resolutions,
+            ),
+        )
+        .await
+    }
 }
 
 impl AsyncRead for TcpDestEndpoint {
@@ -97,3 +144,99 @@ impl AsyncWrite for TcpDestEndpoint {
         tcp_stream.poll_shutdown(cx)
     }
 }
+
+#[test]
+fn test_order_by_preference() {
+    let ipv4_addr_1: SocketAddr = "10.0.0.1:443".parse().unwrap();
+    let ipv4_addr_2: SocketAddr = "10.0.0.2:443".parse().unwrap();
+    let ipv6_addr_1: SocketAddr = "[fd00::1]:443".parse().unwrap();
+    let ipv6_addr_2: SocketAddr = "[fd00::2]:443".parse().unwrap();
+    let resolved = vec![ipv6_addr_1, ipv4_addr_1, ipv6_addr_2, ipv4_addr_2];
+    let mut dst_addrs = resolved.clone();
+    order_by_preference(&mut dst_addrs, AddressPreference::System);
+    assert_eq!(resolved, dst_addrs);
+    order_by_preference(&mut dst_addrs, AddressPreference::PreferIpv4);
+    assert_eq!(
+        vec![ipv4_addr_1, ipv4_addr_2, ipv6_addr_1, ipv6_addr_2],
+        dst_addrs
+    );
+    order_by_preference(&mut dst_addrs, AddressPreference::PreferIpv6);
+    assert_eq!(
+        vec![ipv6_addr_1, ipv6_addr_2, ipv4_addr_1, ipv4_addr_2],
+        dst_addrs
+    );
+}
+
+#[tokio::test]
+async fn test_abort_on_client_closed() {
+    use std::sync::Arc;
+    use tokio::net::TcpListener;
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let client = TcpStream::connect(listener.local_addr().unwrap())
+        .await
+        .unwrap();
+    let (client_stream, client_addr) = listener.accept().await.unwrap();
+    let connect_in_progress = Arc::new(());
+    let slow_connect = {
+        let connect_in_progress = connect_in_progress.clone();
+        async move {
+            let _connect_in_progress = connect_in_progress;
+            tokio::time::sleep(Duration::from_secs(60)).await;
+            Ok(())
+        }
+    };
+    tokio::spawn(async move {
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        drop(client);
+    });
+    let result = timeout(
+        Duration::from_secs(5),
+        abort_on_client_closed(&client_stream, client_addr, slow_connect),
+    )
+    .await
+    .unwrap();
+    assert!(matches!(result, Err(Error::ClientDisconnected(addr)) if addr == client_addr));
+    assert_eq!(1, Arc::strong_count(&connect_in_progress));
+}
+
+#[tokio::test]
+async fn test_connect_destination_unreachable() {
+    let closed_listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let closed_addr: UnifiedAddress = closed_listener.local_addr().unwrap().into();
+    drop(closed_listener);
+    let result = TcpDestEndpoint::connect(
+        closed_addr.clone(),
+        Duration::from_secs(5),
+        AddressPreference::System,
+        SocketOptions::default(),
+        &Arc::new(Semaphore::new(1)),
+    )
+    .await;
+    assert!(matches!(result, Err(Error::DestinationUnreachable(addr)) if addr == closed_addr));
+}
+
+#[tokio::test]
+async fn test_connect_timeout() {
+    use tokio::net::TcpSocket;
+    // The listener never accepts, the connects beyond its backlog hang
+    let listener = TcpSocket::new_v4().unwrap();
+    listener.bind("127.0.0.1:0".parse().unwrap()).unwrap();
+    let listener = listener.listen(0).unwrap();
+    let unreachable_addr = listener.local_addr().unwrap();
+    let _queued = TcpStream::connect(unreachable_addr).await.unwrap();
+    let dns_resolutions = Arc::new(Semaphore::new(1));
+    let started = std::time::Instant::now();
+    let result = TcpDestEndpoint::connect(
+        unreachable_addr.into(),
+        Duration::from_millis(200),
+        AddressPreference::System,
+        SocketOptions::default(),
+        &dns_resolutions,
+    )
+    .await;
+    assert!(matches!(
+        result,
+        Err(Error::Common(CommonError::ConnectTimeout(1)))
+    ));
+    assert!(started.elapsed() < Duration::from_secs(2));
+}
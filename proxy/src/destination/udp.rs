@@ -1,6 +1,16 @@
 use crate::error::Error;
 use std::net::{IpAddr, Ipv4Addr, SocketAddr};
-use tokio::net::{ToSocketAddrs, UdpSocket};
+use tokio::net::UdpSocket;
+
+/// The max size of one datagram received from the udp destinations
+pub(crate) const UDP_DATAGRAM_MAX_SIZE: usize = 65536;
+
+/// The default seconds a udp association waits without datagrams before
+/// it is closed, a lost reply would otherwise hold the association forever
+pub const DEFAULT_UDP_ASSOCIATION_IDLE_TIMEOUT: u64 = 120;
+
+/// The udp socket of one association, it is closed when the association is
+/// dropped, which happens once the association idle timeout elapses without datagrams.
 pub struct UdpDestEndpoint {
     udp_socket: UdpSocket,
 }
@@ -12,14 +22,44 @@ impl UdpDestEndpoint {
         Ok(Self { udp_socket })
     }
 
-    pub async fn replay_to<A: ToSocketAddrs>(
-        &self,
-        dst_addr: A,
-        buf: &[u8],
-    ) -> Result<Vec<u8>, Error> {
+    /// Send the datagram to the destination, the endpoint can send to many destinations
+    pub async fn send_to(&self, dst_addr: SocketAddr, buf: &[u8]) -> Result<(), Error> {
         self.udp_socket.send_to(buf, dst_addr).await?;
-        let mut dst_udp_data = [0u8; 65536];
-        self.udp_socket.recv(&mut dst_udp_data).await?;
-        Ok(dst_udp_data.to_vec())
+        Ok(())
+    }
+
+    /// Receive the datagram replied by any destination together with the destination address
+    pub async fn recv_from(&self, buf: &mut [u8]) -> Result<(usize, SocketAddr), Error> {
+        Ok(self.udp_socket.recv_from(buf).await?)
+    }
+}
+
+#[tokio::test]
+async fn test_send_to_recv_from() -> Result<(), Error> {
+    let mut echo_addrs = Vec::new();
+    for _ in 0..2 {
+        let echo_socket = UdpSocket::bind("127.0.0.1:0").await?;
+        echo_addrs.push(echo_socket.local_addr()?);
+        tokio::spawn(async move {
+            let mut buf = [0u8; 1024];
+            while let Ok((size, src_addr)) = echo_socket.recv_from(&mut buf).await {
+                let _ = echo_socket.send_to(&buf[..size], src_addr).await;
+            }
+        });
+    }
+    // One endpoint services many destinations, every reply carries its source
+    let dst_udp_endpoint = UdpDestEndpoint::bind().await?;
+    let mut dst_udp_data = vec![0u8; UDP_DATAGRAM_MAX_SIZE];
+    for (echo_addr, payload) in echo_addrs.iter().zip([b"first".as_slice(), b"second"]) {
+        dst_udp_endpoint.send_to(*echo_addr, payload).await?;
+        let (size, src_addr) = tokio::time::timeout(
+            std::time::Duration::from_secs(5),
+            dst_udp_endpoint.recv_from(&mut dst_udp_data),
+        )
+        .await
+        .expect("Echo destination should reply")?;
+        assert_eq!(*echo_addr, src_addr);
+        assert_eq!(payload, &dst_udp_data[..size]);
     }
+    Ok(())
 }
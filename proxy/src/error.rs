@@ -1,5 +1,8 @@
 use common::Error as CommonError;
 use protocol::Error as ProtocolError;
+use protocol::{UnifiedAddress, Username};
+use std::net::SocketAddr;
+use std::time::Duration;
 use thiserror::Error;
 
 #[derive(Error, Debug)]
@@ -12,6 +15,22 @@ pub enum Error {
     Common(#[from] CommonError),
     #[error(transparent)]
     Protocol(#[from] ProtocolError),
+    #[error("Destination refused in observe mode: {0}")]
+    ObserveMode(UnifiedAddress),
+    #[error("Destination port not allowed: {0}")]
+    DestinationPortNotAllowed(UnifiedAddress),
+    #[error("Forward upstream unreachable: {0:?}")]
+    ForwardUpstreamUnreachable(Vec<SocketAddr>),
+    #[error("No relay data flows within the first byte timeout: {0:?}")]
+    FirstByteTimeout(Duration),
+    #[error("Invalid destination alias backend: {0}")]
+    InvalidDestinationAlias(String),
+    #[error("User expired: {0}")]
+    UserExpired(Username),
+    #[error("Client disconnected before destination connected: {0}")]
+    ClientDisconnected(SocketAddr),
+    #[error("Destination unreachable: {0}")]
+    DestinationUnreachable(UnifiedAddress),
     #[error("Unknown error: {0}")]
     Unknown(String),
 }
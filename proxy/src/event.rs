@@ -0,0 +1,209 @@
+use crate::error::Error;
+use protocol::Username;
+use serde::Serialize;
+use std::net::SocketAddr;
+use std::path::Path;
+use tokio::io::{AsyncWrite, AsyncWriteExt};
+use tokio::sync::broadcast::error::RecvError;
+use tokio::sync::broadcast::{Receiver, Sender, channel};
+use tracing::{debug, error, warn};
+
+/// The events buffered for each consumer, the oldest events
+/// are dropped when the consumer falls behind
+const CONNECTION_EVENT_BUFFER_SIZE: usize = 1024;
+
+/// The lifecycle event of the client connection
+#[derive(Debug, Clone, Serialize, PartialEq, Eq)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum ConnectionEvent {
+    /// The client connection is accepted
+    Accept { client_addr: SocketAddr },
+    /// The client passes the handshake
+    HandshakeOk {
+        client_addr: SocketAddr,
+        username: Username,
+        /// The connection tag sent by the client in the handshake
+        tag: Option<String>,
+    },
+    /// The destination of the client is set up
+    DestinationSetup {
+        client_addr: SocketAddr,
+        destination: String,
+        destination_type: &'static str,
+    },
+    /// The relay of the client ends, also when it fails or idles out
+    RelayEnd {
+        client_addr: SocketAddr,
+        client_to_destination_bytes: u64,
+        destination_to_client_bytes: u64,
+    },
+}
+
+/// Create the channel of the connection events of one proxy, the events
+/// sent without any consumer subscribed are dropped
+pub fn connection_event_channel() -> Sender<ConnectionEvent> {
+    channel(CONNECTION_EVENT_BUFFER_SIZE).0
+}
+
+/// Listen on the unix domain socket and stream the connection events
+/// to every consumer connected as newline-delimited json.
+#[cfg(unix)]
+pub fn start_connection_event_server(
+    socket_path: &Path,
+    connection_events: Sender<ConnectionEvent>,
+) -> Result<(), Error> {
+    use std::os::unix::fs::FileTypeExt;
+    // The socket file left by the previous run blocks the binding, any other
+    // file at the path is never removed
+    match std::fs::symlink_metadata(socket_path) {
+        Ok(metadata) if metadata.file_type().is_socket() => std::fs::remove_file(socket_path)?,
+        Ok(_) => {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::AlreadyExists,
+                format!("Connection event socket path {socket_path:?} is not a socket"),
+            )
+            .into());
+        }
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+        Err(e) => return Err(e.into()),
+    }
+    let listener = tokio::net::UnixListener::bind(socket_path)?;
+    tokio::spawn(async move {
+        loop {
+            let consumer = match listener.accept().await {
+                Ok((consumer, _)) => consumer,
+                Err(e) => {
+                    error!("Fail to accept connection event consumer: {e:?}");
+                    continue;
+                }
+            };
+            debug!("Connection event consumer connected.");
+            tokio::spawn(stream_connection_events(
+                connection_events.subscribe(),
+                consumer,
+            ));
+        }
+    });
+    Ok(())
+}
+
+/// Write the events to the consumer until it disconnects
+async fn stream_connection_events<W>(mut events: Receiver<ConnectionEvent>, mut consumer: W)
+where
+    W: AsyncWrite + Unpin,
+{
+    loop {
+        let event = match events.recv().await {
+            Ok(event) => event,
+            Err(RecvError::Lagged(dropped_events)) => {
+                warn!("Drop {dropped_events} connection events because the consumer is slow.");
+                continue;
+            }
+            Err(RecvError::Closed) => return,
+        };
+        let mut event_line = match serde_json::to_vec(&event) {
+            Ok(event_line) => event_line,
+            Err(e) => {
+                error!("Fail to serialize connection event {event:?}: {e:?}");
+                continue;
+            }
+        };
+        event_line.push(b'\n');
+        if let Err(e) = consumer.write_all(&event_line).await {
+            debug!("Connection event consumer disconnected: {e:?}");
+            return;
+        }
+    }
+}
+
+#[tokio::test]
+async fn test_stream_connection_events() -> Result<(), Error> {
+    use tokio::io::{AsyncBufReadExt, BufReader};
+    let client_addr: SocketAddr = "127.0.0.1:20001".parse().unwrap();
+    let (events_tx, events_rx) = channel(2);
+    let (consumer, event_stream) = tokio::io::duplex(64 * 1024);
+    for event in [
+        ConnectionEvent::Accept { client_addr },
+        ConnectionEvent::HandshakeOk {
+            client_addr,
+            username: Username::from("user1"),
+            tag: None,
+        },
+        ConnectionEvent::DestinationSetup {
+            client_addr,
+            destination: "www.example.com:443".to_string(),
+            destination_type: "tcp",
+        },
+        ConnectionEvent::RelayEnd {
+            client_addr,
+            client_to_destination_bytes: 100,
+            destination_to_client_bytes: 2000,
+        },
+    ] {
+        events_tx.send(event).unwrap();
+    }
+    // The consumer is slower than the buffer, the oldest events are dropped
+    tokio::spawn(stream_connection_events(events_rx, consumer));
+    drop(events_tx);
+    let mut event_lines = BufReader::new(event_stream).lines();
+    let mut received_events = Vec::new();
+    while let Some(event_line) = event_lines.next_line().await? {
+        received_events.push(event_line);
+    }
+    assert_eq!(
+        vec![
+            r#"{"event":"destination_setup","client_addr":"127.0.0.1:20001","destination":"www.example.com:443","destination_type":"tcp"}"#,
+            r#"{"event":"relay_end","client_addr":"127.0.0.1:20001","client_to_destination_bytes":100,"destination_to_client_bytes":2000}"#,
+        ],
+        received_events
+    );
+    // The consumer in time receives the whole sequence
+    let (events_tx, events_rx) = channel(CONNECTION_EVENT_BUFFER_SIZE);
+    let (consumer, event_stream) = tokio::io::duplex(64 * 1024);
+    tokio::spawn(stream_connection_events(events_rx, consumer));
+    events_tx
+        .send(ConnectionEvent::Accept { client_addr })
+        .unwrap();
+    events_tx
+        .send(ConnectionEvent::HandshakeOk {
+            client_addr,
+            username: Username::from("user1"),
+            tag: Some("office-laptop".to_string()),
+        })
+        .unwrap();
+    drop(events_tx);
+    let mut event_lines = BufReader::new(event_stream).lines();
+    assert_eq!(
+        Some(r#"{"event":"accept","client_addr":"127.0.0.1:20001"}"#.to_string()),
+        event_lines.next_line().await?
+    );
+    assert_eq!(
+        Some(
+            r#"{"event":"handshake_ok","client_addr":"127.0.0.1:20001","username":"user1","tag":"office-laptop"}"#
+                .to_string()
+        ),
+        event_lines.next_line().await?
+    );
+    assert_eq!(None, event_lines.next_line().await?);
+    Ok(())
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn test_connection_event_socket_path() -> Result<(), Error> {
+    let socket_dir =
+        std::env::temp_dir().join(format!("ppaass-event-socket-test-{}", std::process::id()));
+    std::fs::create_dir_all(&socket_dir)?;
+    // The regular file at the path is kept and the server fails to start
+    let file_path = socket_dir.join("not-a-socket");
+    std::fs::write(&file_path, b"keep me")?;
+    assert!(start_connection_event_server(&file_path, connection_event_channel()).is_err());
+    assert_eq!(b"keep me".to_vec(), std::fs::read(&file_path)?);
+    // The stale socket of the previous run is replaced
+    let socket_path = socket_dir.join("events.sock");
+    drop(std::os::unix::net::UnixListener::bind(&socket_path)?);
+    start_connection_event_server(&socket_path, connection_event_channel())?;
+    tokio::net::UnixStream::connect(&socket_path).await?;
+    std::fs::remove_dir_all(&socket_dir)?;
+    Ok(())
+}
@@ -3,5 +3,8 @@ pub mod command;
 pub mod config;
 pub mod destination;
 pub mod error;
+pub mod event;
+pub mod server;
+pub mod sni;
 pub mod tunnel;
 pub mod user;
@@ -0,0 +1,158 @@
+use crate::config::{Config, ForwardConfig};
+use crate::destination::rewrite::{AliasMapRewriter, DestinationRewriter};
+use crate::error::Error;
+#[cfg(unix)]
+use crate::event::start_connection_event_server;
+use crate::event::{ConnectionEvent, connection_event_channel};
+use crate::tunnel;
+use crate::user::{ForwardUser, ProxyUser, check_configured_forward_upstream};
+use common::config::CommonConfig;
+use common::user::UserRepository;
+use common::user::repo::FileSystemUserRepository;
+use common::{ServerGuard, start_server};
+use std::sync::Arc;
+use tokio::sync::Semaphore;
+use tokio::sync::broadcast::Sender;
+use tracing::debug;
+#[cfg(unix)]
+use tracing::info;
+
+/// The state of one proxy instance, the proxies in the same process share
+/// nothing but the process-wide log, metrics and encryption preference.
+pub struct ProxyContext {
+    config: Config,
+    user_repo: FileSystemUserRepository<ProxyUser, CommonConfig>,
+    /// The repository of the forwarding user, `None` when forwarding is not configured
+    forward_user_repo: Option<FileSystemUserRepository<ForwardUser, ForwardConfig>>,
+    destination_rewriter: Option<Box<dyn DestinationRewriter>>,
+    /// The permits of the dns resolutions shared by all the destinations
+    dns_resolutions: Arc<Semaphore>,
+    connection_events: Sender<ConnectionEvent>,
+}
+
+impl ProxyContext {
+    /// Create the proxy with the configuration, the users are loaded here and
+    /// the alias map of the configuration is installed as the destination rewriter.
+    pub fn new(config: Config) -> Result<Self, Error> {
+        let user_repo = FileSystemUserRepository::<ProxyUser, CommonConfig>::new(Arc::new(
+            config.common().clone(),
+        ))?;
+        let forward_user_repo = config.forward().and_then(|forward_config| {
+            FileSystemUserRepository::<ForwardUser, ForwardConfig>::new(Arc::new(
+                forward_config.clone(),
+            ))
+            .ok()
+        });
+        let destination_aliases = config.destination_aliases();
+        let destination_rewriter: Option<Box<dyn DestinationRewriter>> =
+            if destination_aliases.is_empty() {
+                None
+            } else {
+                Some(Box::new(AliasMapRewriter::new(destination_aliases)?))
+            };
+        let dns_resolutions = Arc::new(Semaphore::new(
+            config.max_concurrent_dns_resolutions().max(1),
+        ));
+        Ok(Self {
+            config,
+            user_repo,
+            forward_user_repo,
+            destination_rewriter,
+            dns_resolutions,
+            connection_events: connection_event_channel(),
+        })
+    }
+
+    /// Replace the destination rewriter, it must be set before the server starts
+    pub fn set_destination_rewriter(&mut self, rewriter: Box<dyn DestinationRewriter>) {
+        self.destination_rewriter = Some(rewriter);
+    }
+
+    pub fn config(&self) -> &Config {
+        &self.config
+    }
+
+    pub fn user_repo(&self) -> &FileSystemUserRepository<ProxyUser, CommonConfig> {
+        &self.user_repo
+    }
+
+    pub fn forward_user_repo(
+        &self,
+    ) -> Option<&FileSystemUserRepository<ForwardUser, ForwardConfig>> {
+        self.forward_user_repo.as_ref()
+    }
+
+    pub fn destination_rewriter(&self) -> Option<&dyn DestinationRewriter> {
+        self.destination_rewriter.as_deref()
+    }
+
+    pub fn dns_resolutions(&self) -> &Arc<Semaphore> {
+        &self.dns_resolutions
+    }
+
+    /// The connection events of the proxy, subscribe to receive them
+    pub fn connection_events(&self) -> &Sender<ConnectionEvent> {
+        &self.connection_events
+    }
+
+    /// Emit the connection event to the consumers, nothing
+    /// happens when no consumer is subscribed
+    pub fn emit_connection_event(&self, event: ConnectionEvent) {
+        // Fail only when there is no consumer
+        let _ = self.connection_events.send(event);
+    }
+}
+
+/// Start the proxy server of the context inside the runtime, the forward
+/// upstream and the connection event socket are set up before accepting.
+pub async fn start_proxy_server(context: Arc<ProxyContext>) -> Result<ServerGuard, Error> {
+    check_configured_forward_upstream(context.config().forward(), context.forward_user_repo())
+        .await?;
+    #[cfg(unix)]
+    if let Some(connection_event_socket) = context.config().connection_event_socket() {
+        start_connection_event_server(
+            connection_event_socket,
+            context.connection_events().clone(),
+        )?;
+        info!("Stream connection events to: {connection_event_socket:?}");
+    }
+    let server_context = context.clone();
+    Ok(start_server(
+        context.config().common(),
+        move |server_state| {
+            let context = server_context.clone();
+            async move {
+                debug!("Handling agent connection: {server_state:?}.");
+                tunnel::process(&context, server_state).await
+            }
+        },
+    ))
+}
+
+/// The proxy configuration of the tests, the users are loaded from the given directory
+#[cfg(test)]
+pub(crate) fn test_proxy_config(
+    user_repo_directory: &std::path::Path,
+    detailed_handshake_failure: bool,
+) -> Config {
+    toml::from_str(&format!(
+        r#"
+        listening_address = "127.0.0.1:0"
+        client_max_connections = 16
+        worker_threads = 1
+        log_directory = "log"
+        log_name_prefix = "ppaass-proxy-test.log"
+        max_log_level = "ERROR"
+        user_repo_directory = "{}"
+        user_repo_refresh_interval = 0
+        user_info_file_name = "user_info.toml"
+        user_info_public_key_file_name = "AgentPublicKey.pem"
+        user_info_private_key_file_name = "ProxyPrivateKey.pem"
+        destination_connect_timeout = 5
+        destination_address_preference = "system"
+        detailed_handshake_failure = {detailed_handshake_failure}
+        "#,
+        user_repo_directory.display()
+    ))
+    .expect("Fail to parse test proxy configuration")
+}
@@ -0,0 +1,303 @@
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio_util::bytes::BytesMut;
+use tracing::info;
+
+const TLS_RECORD_HEADER_LENGTH: usize = 5;
+const TLS_CONTENT_TYPE_HANDSHAKE: u8 = 0x16;
+const TLS_HANDSHAKE_TYPE_CLIENT_HELLO: u8 = 0x01;
+const TLS_EXTENSION_SERVER_NAME: u16 = 0x0000;
+const TLS_SERVER_NAME_TYPE_HOST_NAME: u8 = 0x00;
+/// The max length of the tls record carrying the client hello
+const TLS_MAX_RECORD_LENGTH: usize = 16 * 1024;
+
+/// The result of reading the sni from the first bytes of the relay
+#[derive(Debug, PartialEq, Eq)]
+pub enum ClientHelloSni {
+    /// More bytes are needed to finish the client hello record
+    Incomplete,
+    /// The bytes are not a tls client hello or it has no sni
+    NotFound,
+    Found(String),
+}
+
+/// The cursor reading the fields of the client hello, `None` means the field
+/// runs over the record so the client hello is malformed
+struct ClientHelloCursor<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> ClientHelloCursor<'a> {
+    fn take(&mut self, length: usize) -> Option<&'a [u8]> {
+        if self.data.len() < length {
+            return None;
+        }
+        let (taken, remaining) = self.data.split_at(length);
+        self.data = remaining;
+        Some(taken)
+    }
+    fn u8(&mut self) -> Option<u8> {
+        Some(self.take(1)?[0])
+    }
+    fn u16(&mut self) -> Option<u16> {
+        let bytes = self.take(2)?;
+        Some(u16::from_be_bytes([bytes[0], bytes[1]]))
+    }
+    fn u24(&mut self) -> Option<usize> {
+        let bytes = self.take(3)?;
+        Some(u32::from_be_bytes([0, bytes[0], bytes[1], bytes[2]]) as usize)
+    }
+    /// Take the field prefixed with its length of 1 byte
+    fn vec_u8(&mut self) -> Option<&'a [u8]> {
+        let length = self.u8()? as usize;
+        self.take(length)
+    }
+    /// Take the field prefixed with its length of 2 bytes
+    fn vec_u16(&mut self) -> Option<&'a [u8]> {
+        let length = self.u16()? as usize;
+        self.take(length)
+    }
+}
+
+/// Read the host name in the sni extension of the tls client hello without
+/// decrypting anything, only the client hello in the first tls record is read.
+pub fn parse_client_hello_sni(data: &[u8]) -> ClientHelloSni {
+    if data.len() < TLS_RECORD_HEADER_LENGTH {
+        return ClientHelloSni::Incomplete;
+    }
+    if data[0] != TLS_CONTENT_TYPE_HANDSHAKE || data[1] != 0x03 {
+        return ClientHelloSni::NotFound;
+    }
+    let record_length = u16::from_be_bytes([data[3], data[4]]) as usize;
+    if record_length > TLS_MAX_RECORD_LENGTH {
+        return ClientHelloSni::NotFound;
+    }
+    let Some(record) = data.get(TLS_RECORD_HEADER_LENGTH..TLS_RECORD_HEADER_LENGTH + record_length)
+    else {
+        return ClientHelloSni::Incomplete;
+    };
+    match read_client_hello_sni(record) {
+        Some(host_name) => ClientHelloSni::Found(host_name),
+        None => ClientHelloSni::NotFound,
+    }
+}
+
+fn read_client_hello_sni(record: &[u8]) -> Option<String> {
+    let mut cursor = ClientHelloCursor { data: record };
+    if cursor.u8()? != TLS_HANDSHAKE_TYPE_CLIENT_HELLO {
+        return None;
+    }
+    let client_hello_length = cursor.u24()?;
+    let mut cursor = ClientHelloCursor {
+        data: cursor.take(client_hello_length)?,
+    };
+    // The legacy version and the random
+    cursor.take(2 + 32)?;
+    // The session id, the cipher suites and the compression methods
+    cursor.vec_u8()?;
+    cursor.vec_u16()?;
+    cursor.vec_u8()?;
+    let mut extensions = ClientHelloCursor {
+        data: cursor.vec_u16()?,
+    };
+    while !extensions.data.is_empty() {
+        let extension_type = extensions.u16()?;
+        let extension_data = extensions.vec_u16()?;
+        if extension_type != TLS_EXTENSION_SERVER_NAME {
+            continue;
+        }
+        let mut server_names = ClientHelloCursor {
+            data: ClientHelloCursor {
+                data: extension_data,
+            }
+            .vec_u16()?,
+        };
+        while !server_names.data.is_empty() {
+            let name_type = server_names.u8()?;
+            let name = server_names.vec_u16()?;
+            if name_type == TLS_SERVER_NAME_TYPE_HOST_NAME {
+                return String::from_utf8(name.to_vec()).ok();
+            }
+        }
+        return None;
+    }
+    None
+}
+
+/// The client relay endpoint logging the tls sni of the first bytes read
+/// from it, the bytes are relayed unchanged and the endpoint stops looking
+/// once the client hello is read or the bytes turn out not to be tls.
+pub struct SniLogging<'a, T> {
+    inner: &'a mut T,
+    /// The first bytes read, `None` once the sni lookup is done or disabled
+    first_bytes: Option<BytesMut>,
+    sni: Option<String>,
+    client_addr: SocketAddr,
+}
+
+impl<'a, T> SniLogging<'a, T> {
+    pub fn new(inner: &'a mut T, enabled: bool, client_addr: SocketAddr) -> Self {
+        Self {
+            inner,
+            first_bytes: enabled.then(BytesMut::new),
+            sni: None,
+            client_addr,
+        }
+    }
+
+    /// The sni found in the first bytes of the relay
+    pub fn sni(&self) -> Option<&str> {
+        self.sni.as_deref()
+    }
+}
+
+impl<T> AsyncRead for SniLogging<'_, T>
+where
+    T: AsyncRead + Unpin,
+{
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        let this = self.get_mut();
+        let filled = buf.filled().len();
+        let result = Pin::new(&mut *this.inner).poll_read(cx, buf);
+        let Some(first_bytes) = this.first_bytes.as_mut() else {
+            return result;
+        };
+        if buf.filled().len() == filled {
+            return result;
+        }
+        first_bytes.extend_from_slice(&buf.filled()[filled..]);
+        match parse_client_hello_sni(first_bytes) {
+            ClientHelloSni::Incomplete => {}
+            ClientHelloSni::NotFound => this.first_bytes = None,
+            ClientHelloSni::Found(sni) => {
+                info!("Client [{}] relays tls with sni [{sni}]", this.client_addr);
+                this.sni = Some(sni);
+                this.first_bytes = None;
+            }
+        }
+        result
+    }
+}
+
+impl<T> AsyncWrite for SniLogging<'_, T>
+where
+    T: AsyncWrite + Unpin,
+{
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        Pin::new(&mut *self.get_mut().inner).poll_write(cx, buf)
+    }
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut *self.get_mut().inner).poll_flush(cx)
+    }
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut *self.get_mut().inner).poll_shutdown(cx)
+    }
+}
+
+/// Capture the client hello a real tls client sends for the server name
+#[cfg(test)]
+async fn capture_client_hello(server_name: &str) -> std::io::Result<Vec<u8>> {
+    use std::sync::Arc;
+    use tokio::io::AsyncReadExt;
+    use tokio_rustls::TlsConnector;
+    use tokio_rustls::rustls::pki_types::ServerName;
+    use tokio_rustls::rustls::{ClientConfig, RootCertStore, crypto::ring};
+    let client_config = ClientConfig::builder_with_provider(Arc::new(ring::default_provider()))
+        .with_safe_default_protocol_versions()
+        .map_err(std::io::Error::other)?
+        .with_root_certificates(RootCertStore::empty())
+        .with_no_client_auth();
+    let server_name =
+        ServerName::try_from(server_name.to_string()).map_err(std::io::Error::other)?;
+    let (client, mut server) = tokio::io::duplex(64 * 1024);
+    let tls_client = tokio::spawn(async move {
+        let _ = TlsConnector::from(Arc::new(client_config))
+            .connect(server_name, client)
+            .await;
+    });
+    let mut record_header = [0u8; TLS_RECORD_HEADER_LENGTH];
+    server.read_exact(&mut record_header).await?;
+    let mut client_hello =
+        vec![0u8; u16::from_be_bytes([record_header[3], record_header[4]]) as usize];
+    server.read_exact(&mut client_hello).await?;
+    drop(server);
+    tls_client.await.map_err(std::io::Error::other)?;
+    Ok([record_header.as_slice(), &client_hello].concat())
+}
+
+#[tokio::test]
+async fn test_parse_client_hello_sni() -> std::io::Result<()> {
+    let client_hello = capture_client_hello("www.example.com").await?;
+    assert_eq!(
+        ClientHelloSni::Found("www.example.com".to_string()),
+        parse_client_hello_sni(&client_hello)
+    );
+    assert_eq!(
+        ClientHelloSni::Incomplete,
+        parse_client_hello_sni(&client_hello[..client_hello.len() - 1])
+    );
+    assert_eq!(
+        ClientHelloSni::NotFound,
+        parse_client_hello_sni(b"GET / HTTP/1.1\r\nHost: www.example.com\r\n\r\n")
+    );
+    let mut truncated_client_hello = client_hello.clone();
+    truncated_client_hello[4] = 10;
+    assert_eq!(
+        ClientHelloSni::NotFound,
+        parse_client_hello_sni(&truncated_client_hello[..15])
+    );
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_sni_logging_relays_unchanged() -> std::io::Result<()> {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    let client_hello = capture_client_hello("www.example.com").await?;
+    let relayed_data = [client_hello.as_slice(), b"application data"].concat();
+    let (mut client, mut client_endpoint) = tokio::io::duplex(64 * 1024);
+    let writer = {
+        let relayed_data = relayed_data.clone();
+        tokio::spawn(async move {
+            // The client hello arrives in small pieces
+            for piece in relayed_data.chunks(7) {
+                client.write_all(piece).await?;
+                tokio::task::yield_now().await;
+            }
+            client.shutdown().await
+        })
+    };
+    let mut sni_logging = SniLogging::new(
+        &mut client_endpoint,
+        true,
+        "127.0.0.1:10000".parse().unwrap(),
+    );
+    let mut received = Vec::new();
+    sni_logging.read_to_end(&mut received).await?;
+    writer.await.map_err(std::io::Error::other)??;
+    assert_eq!(relayed_data, received);
+    assert_eq!(Some("www.example.com"), sni_logging.sni());
+    assert!(sni_logging.first_bytes.is_none());
+    let (mut client, mut client_endpoint) = tokio::io::duplex(1024);
+    client.write_all(&client_hello).await?;
+    drop(client);
+    let mut disabled = SniLogging::new(
+        &mut client_endpoint,
+        false,
+        "127.0.0.1:10000".parse().unwrap(),
+    );
+    let mut received = Vec::new();
+    disabled.read_to_end(&mut received).await?;
+    assert_eq!(client_hello, received);
+    assert_eq!(None, disabled.sni());
+    Ok(())
+}
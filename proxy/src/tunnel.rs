@@ -1,49 +1,240 @@
 use crate::client::ClientTcpRelayEndpoint;
-use crate::config::get_config;
 use crate::destination;
 use crate::destination::Destination;
-use crate::destination::udp::UdpDestEndpoint;
+use crate::destination::resolve::resolve_destination;
+use crate::destination::rewrite::{DestinationRewriter, rewrite_connect_destination_request};
+use crate::destination::udp::{UDP_DATAGRAM_MAX_SIZE, UdpDestEndpoint};
 use crate::error::Error;
-use crate::user::{get_forward_user_repo, get_user_repo};
+use crate::event::ConnectionEvent;
+use crate::server::ProxyContext;
+use crate::sni::SniLogging;
+use chrono::{DateTime, Utc};
 use common::Error as CommonError;
 use common::config::UserConfig;
+use common::metrics::{
+    Counter, HandshakeFailureReason, record_handshake_failure, relay_bytes_counters,
+};
 use common::proxy::{DestinationType, ProxyConnection};
 use common::user::User;
 use common::user::UserRepository;
+use common::user::UserWithExpiredTime;
 use common::{
-    SecureLengthDelimitedCodec, ServerState, get_handshake_encryption, random_generate_encryption,
-    rsa_decrypt_encryption, rsa_encrypt_encryption,
+    HandshakeTranscript, RelayIdleTimer, SecureLengthDelimitedCodec, ServerState, UdpRelayPacket,
+    close_gracefully, copy_bidirectional_with_idle_timeout, get_handshake_encryption,
+    random_generate_relay_encryption, read_udp_relay_packet, rsa_decrypt_encryption,
+    rsa_encrypt_encryption, sign_handshake_transcript, write_udp_relay_packet,
 };
 use destination::tcp::TcpDestEndpoint;
 use futures_util::{SinkExt, StreamExt};
+use protocol::Error as ProtocolError;
 use protocol::{
-    ConnectDestinationRequest, ConnectDestinationResponse, Encryption, HandshakeRequest,
-    HandshakeResponse, Username,
+    ConnectDestinationRequest, ConnectDestinationResponse, Encryption,
+    ExtendedConnectDestinationRequest, HandshakeError, HandshakeRequest, HandshakeResponse,
+    PROTOCOL_VERSION, UnifiedAddress, Username, check_peer_protocol_version,
 };
 use std::borrow::Cow;
+use std::collections::HashMap;
 use std::net::SocketAddr;
-use tokio::io::{AsyncReadExt, AsyncWriteExt, copy_bidirectional};
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::net::TcpStream;
+use tokio::sync::Semaphore;
+use tokio::sync::broadcast::Sender;
+use tokio_util::bytes::{Bytes, BytesMut};
 use tokio_util::codec::{Framed, FramedParts};
-use tracing::debug;
+use tracing::{debug, error, info};
 
 struct HandshakeResult {
     client_username: Username,
+    client_tag: Option<String>,
     client_encryption: Encryption,
     server_encryption: Encryption,
+    observe_mode: bool,
+    /// The zstd level to compress the relay frames when it is negotiated
+    compression_level: Option<i32>,
 }
 
 struct ConnectDestinationResult<'a> {
     codec: SecureLengthDelimitedCodec<'a>,
+    client_read_buf: BytesMut,
     destination: Destination<'a>,
 }
 
-async fn process_handshake(server_state: &mut ServerState) -> Result<HandshakeResult, Error> {
+/// The bytes relayed in both directions of one client connection
+#[derive(Default)]
+struct RelayedBytes {
+    client_to_destination: AtomicU64,
+    destination_to_client: AtomicU64,
+}
+
+impl RelayedBytes {
+    fn is_active(&self) -> bool {
+        self.client_to_destination.load(Ordering::Relaxed) > 0
+            || self.destination_to_client.load(Ordering::Relaxed) > 0
+    }
+}
+
+/// Emit the relay end event with the bytes relayed so far once the relay of
+/// the client connection is dropped, whichever way the relay exits.
+struct RelayEndGuard<'a> {
+    connection_events: &'a Sender<ConnectionEvent>,
+    client_addr: SocketAddr,
+    relayed_bytes: RelayedBytes,
+}
+
+impl Drop for RelayEndGuard<'_> {
+    fn drop(&mut self) {
+        // Fail only when there is no consumer
+        let _ = self.connection_events.send(ConnectionEvent::RelayEnd {
+            client_addr: self.client_addr,
+            client_to_destination_bytes: self
+                .relayed_bytes
+                .client_to_destination
+                .load(Ordering::Relaxed),
+            destination_to_client_bytes: self
+                .relayed_bytes
+                .destination_to_client
+                .load(Ordering::Relaxed),
+        });
+    }
+}
+
+/// The relay endpoint counting the bytes read from it
+struct RelayActivity<'a, T> {
+    inner: &'a mut T,
+    read_bytes: &'a AtomicU64,
+}
+
+impl<T> AsyncRead for RelayActivity<'_, T>
+where
+    T: AsyncRead + Unpin,
+{
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        let this = self.get_mut();
+        let filled = buf.filled().len();
+        let result = Pin::new(&mut *this.inner).poll_read(cx, buf);
+        if buf.filled().len() > filled {
+            this.read_bytes
+                .fetch_add((buf.filled().len() - filled) as u64, Ordering::Relaxed);
+        }
+        result
+    }
+}
+
+impl<T> AsyncWrite for RelayActivity<'_, T>
+where
+    T: AsyncWrite + Unpin,
+{
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        Pin::new(&mut *self.get_mut().inner).poll_write(cx, buf)
+    }
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut *self.get_mut().inner).poll_flush(cx)
+    }
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut *self.get_mut().inner).poll_shutdown(cx)
+    }
+}
+
+/// Relay the data between client and destination, when the first byte timeout
+/// is given and no byte flows in either direction within it, the relay is closed,
+/// the relay is also closed once it stays idle for the idle timeout.
+/// The bytes relayed from client to destination and back are counted into the
+/// relayed bytes, also when the relay fails.
+async fn relay_with_first_byte_timeout<C, D>(
+    client: &mut C,
+    destination: &mut D,
+    relayed_bytes: &RelayedBytes,
+    first_byte_timeout: Option<Duration>,
+    idle_timeout: Option<Duration>,
+) -> Result<(), Error>
+where
+    C: AsyncRead + AsyncWrite + Unpin,
+    D: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut client = RelayActivity {
+        inner: client,
+        read_bytes: &relayed_bytes.client_to_destination,
+    };
+    let mut destination = RelayActivity {
+        inner: destination,
+        read_bytes: &relayed_bytes.destination_to_client,
+    };
+    let relay = copy_bidirectional_with_idle_timeout(&mut client, &mut destination, idle_timeout);
+    let Some(first_byte_timeout) = first_byte_timeout else {
+        relay.await?;
+        return Ok(());
+    };
+    tokio::pin!(relay);
+    tokio::select! {
+        relay_result = &mut relay => {
+            relay_result?;
+        }
+        _ = tokio::time::sleep(first_byte_timeout) => {
+            if !relayed_bytes.is_active() {
+                return Err(Error::FirstByteTimeout(first_byte_timeout));
+            }
+            relay.await?;
+        }
+    };
+    Ok(())
+}
+
+/// Close both sides of the ended relay with FIN when the graceful close
+/// timeout is given, otherwise they are dropped as they are.
+async fn close_relay<C, D>(
+    client: &mut C,
+    destination: &mut D,
+    graceful_close_timeout: Option<Duration>,
+) where
+    C: AsyncRead + AsyncWrite + Unpin,
+    D: AsyncRead + AsyncWrite + Unpin,
+{
+    if let Some(graceful_close_timeout) = graceful_close_timeout {
+        tokio::join!(
+            close_gracefully(client, graceful_close_timeout),
+            close_gracefully(destination, graceful_close_timeout)
+        );
+    }
+}
+
+/// Log the relay failure caused by a frame failing to decrypt distinctly
+/// from the normal disconnection, it means the relay is corrupted or out of sync
+fn log_relay_decrypt_failure<T>(
+    relay_result: Result<T, Error>,
+    client_addr: SocketAddr,
+) -> Result<T, Error> {
+    if let Err(Error::Io(e)) = &relay_result
+        && CommonError::is_decrypt_failure(e)
+    {
+        error!("Frame decrypt failed mid-relay of client [{client_addr}]: {e:?}");
+    }
+    relay_result
+}
+
+async fn process_handshake(
+    context: &ProxyContext,
+    server_state: &mut ServerState,
+) -> Result<HandshakeResult, Error> {
     let mut handshake_framed = Framed::new(
         &mut server_state.incoming_stream,
         SecureLengthDelimitedCodec::new(
             Cow::Borrowed(get_handshake_encryption()),
             Cow::Borrowed(get_handshake_encryption()),
-        ),
+        )
+        .with_max_frame_length(context.config().common().handshake_max_frame_length),
     );
     debug!(
         "Waiting for receive handshake from client [{}]",
@@ -57,16 +248,76 @@ async fn process_handshake(server_state: &mut ServerState) -> Result<HandshakeRe
                 "Fail to read handshake message from agent: {}",
                 server_state.incoming_connection_addr
             )))??;
+    let handshake_request_bytes = handshake_request_bytes.freeze();
+    let handshake_request = HandshakeRequest::decode(
+        &handshake_request_bytes,
+        context.config().username_max_length(),
+    )?;
+    let (handshake_response, handshake_result) = match accept_handshake(
+        context,
+        handshake_request,
+        &handshake_request_bytes,
+        server_state.incoming_connection_addr,
+    ) {
+        Ok(accepted) => accepted,
+        Err(e) => {
+            if let Some(reason) =
+                handshake_error_reason(&e, context.config().detailed_handshake_failure())
+            {
+                let handshake_failure_bytes: Vec<u8> =
+                    HandshakeResponse::Failure { reason }.try_into()?;
+                if let Err(send_error) = handshake_framed.send(&handshake_failure_bytes).await {
+                    debug!(
+                        "Fail to send handshake failure [{reason}] to client [{}]: {send_error:?}",
+                        server_state.incoming_connection_addr
+                    );
+                }
+            }
+            return Err(e);
+        }
+    };
+    let handshake_response_bytes: Vec<u8> = handshake_response.try_into()?;
+    handshake_framed.send(&handshake_response_bytes).await?;
+    info!(
+        "Client [{}] passes handshake, username: {}, tag: {:?}",
+        server_state.incoming_connection_addr,
+        handshake_result.client_username,
+        handshake_result.client_tag
+    );
+    debug!(
+        "Send handshake to client [{}], username: {:?}, client_encryption: {:?}, server_encryption: {:?}",
+        server_state.incoming_connection_addr,
+        handshake_result.client_username,
+        handshake_result.client_encryption,
+        handshake_result.server_encryption
+    );
+    Ok(handshake_result)
+}
+
+/// Check the handshake request of the client and build the response to it
+fn accept_handshake(
+    context: &ProxyContext,
+    handshake_request: HandshakeRequest,
+    handshake_request_bytes: &[u8],
+    client_addr: SocketAddr,
+) -> Result<(HandshakeResponse, HandshakeResult), Error> {
     let HandshakeRequest {
+        version: client_version,
         username: client_username,
         encryption: client_encryption,
-    } = handshake_request_bytes.try_into()?;
+        tag: client_tag,
+        challenge,
+        compression: client_compression,
+    } = handshake_request;
     debug!(
-        "Receive client handshake, client username: {client_username:?}, client encryption: {client_encryption:?}"
+        "Receive client handshake, client version: {client_version}, client username: {client_username:?}, client tag: {client_tag:?}, client encryption: {client_encryption:?}"
     );
-    let proxy_user_info = get_user_repo()
+    check_peer_protocol_version(client_version)?;
+    let proxy_user_info = context
+        .user_repo()
         .find_user(&client_username)
         .ok_or(CommonError::UserNotExist(client_username.clone()))?;
+    reject_expired_user(&*proxy_user_info, Utc::now())?;
     let client_encryption = rsa_decrypt_encryption(
         client_encryption,
         proxy_user_info
@@ -74,49 +325,134 @@ async fn process_handshake(server_state: &mut ServerState) -> Result<HandshakeRe
             .ok_or(CommonError::UserRsaCryptoNotExist(client_username.clone()))?,
     )?;
     debug!(
-        "Receive handshake from client [{}], username: {client_username:?}, client_encryption: {client_encryption:?}",
-        server_state.incoming_connection_addr
+        "Receive handshake from client [{client_addr}], username: {client_username:?}, client_encryption: {client_encryption:?}"
     );
-    let server_encryption = random_generate_encryption();
-    let rsa_encrypted_server_encryption = rsa_encrypt_encryption(
-        &server_encryption,
-        proxy_user_info
-            .rsa_crypto()
-            .ok_or(CommonError::UserRsaCryptoNotExist(client_username.clone()))?,
+    let server_encryption =
+        random_generate_relay_encryption(context.config().common().relay_per_frame_iv);
+    let proxy_rsa_crypto = proxy_user_info
+        .rsa_crypto()
+        .ok_or(CommonError::UserRsaCryptoNotExist(client_username.clone()))?;
+    let rsa_encrypted_server_encryption =
+        rsa_encrypt_encryption(&server_encryption, proxy_rsa_crypto)?;
+    let compression_level = context
+        .config()
+        .common()
+        .relay_compression_level
+        .filter(|_| client_compression);
+    let rsa_encrypted_server_encryption = rsa_encrypted_server_encryption.into_owned();
+    let challenge_signature = sign_handshake_transcript(
+        HandshakeTranscript {
+            challenge: &challenge,
+            request: handshake_request_bytes,
+            version: PROTOCOL_VERSION,
+            encryption: &rsa_encrypted_server_encryption,
+            compression: compression_level.is_some(),
+        },
+        proxy_rsa_crypto,
     )?;
-    let handshake_response = HandshakeResponse {
-        encryption: rsa_encrypted_server_encryption.into_owned(),
+    let handshake_response = HandshakeResponse::Success {
+        version: PROTOCOL_VERSION,
+        encryption: rsa_encrypted_server_encryption,
+        challenge_signature,
+        compression: compression_level.is_some(),
     };
-    let handshake_response_bytes: Vec<u8> = handshake_response.try_into()?;
-    handshake_framed.send(&handshake_response_bytes).await?;
-    debug!(
-        "Send handshake to client [{}], username: {client_username:?}, client_encryption: {client_encryption:?}, server_encryption: {server_encryption:?}",
-        server_state.incoming_connection_addr
-    );
-    Ok(HandshakeResult {
-        client_username,
-        client_encryption,
-        server_encryption,
-    })
+    Ok((
+        handshake_response,
+        HandshakeResult {
+            client_username,
+            client_tag,
+            client_encryption,
+            server_encryption,
+            observe_mode: context.config().observe_mode() || proxy_user_info.observe_mode(),
+            compression_level,
+        },
+    ))
+}
+
+/// The reason sent to the client when the handshake is rejected, the
+/// failures of the connection itself close it without any response.
+/// The reasons about the user are generic unless the detailed reasons are
+/// enabled, so the response does not tell whether the username exists.
+fn handshake_error_reason(error: &Error, detailed: bool) -> Option<HandshakeError> {
+    let user_reason = |reason| {
+        Some(if detailed {
+            reason
+        } else {
+            HandshakeError::Rejected
+        })
+    };
+    match error {
+        Error::Common(CommonError::UserNotExist(_)) => user_reason(HandshakeError::UserNotExist),
+        Error::Common(CommonError::UserRsaCryptoNotExist(_)) => {
+            user_reason(HandshakeError::UserRsaCryptoNotExist)
+        }
+        Error::Common(CommonError::Crypto(_)) => user_reason(HandshakeError::InvalidEncryption),
+        Error::UserExpired(_) => user_reason(HandshakeError::UserExpired),
+        Error::Protocol(ProtocolError::UnsupportedProtocolVersion(_)) => {
+            Some(HandshakeError::UnsupportedProtocolVersion)
+        }
+        _ => None,
+    }
+}
+
+/// The reason label counting the failed handshake in the metrics
+fn handshake_failure_reason(error: &Error) -> HandshakeFailureReason {
+    match error {
+        Error::UserExpired(_) => HandshakeFailureReason::Expired,
+        Error::Common(e) => HandshakeFailureReason::from(e),
+        Error::Protocol(ProtocolError::UnsupportedProtocolVersion(_)) => {
+            HandshakeFailureReason::Version
+        }
+        Error::Io(e) if CommonError::is_decrypt_failure(e) => HandshakeFailureReason::Decrypt,
+        _ => HandshakeFailureReason::Other,
+    }
+}
+
+/// Reject the user whose expired time has passed, the handshake fails
+/// and the connection is closed before any destination is set up.
+fn reject_expired_user<U>(user: &U, now: DateTime<Utc>) -> Result<(), Error>
+where
+    U: UserWithExpiredTime,
+{
+    match user.expired_time() {
+        Some(expired_time) if *expired_time <= now => {
+            error!(
+                "Reject the handshake of user [{}] expired at {expired_time}",
+                user.username()
+            );
+            Err(Error::UserExpired(user.username().clone()))
+        }
+        _ => Ok(()),
+    }
 }
 
 async fn process_connect_destination<'a>(
+    context: &'a ProxyContext,
     server_state: &mut ServerState,
     handshake_result: HandshakeResult,
 ) -> Result<ConnectDestinationResult<'a>, Error> {
     let HandshakeResult {
         client_username,
+        client_tag,
         client_encryption,
         server_encryption,
+        observe_mode,
+        compression_level,
     } = handshake_result;
-    debug!("Begin to setup destination for client user: {client_username:?}");
-    let mut connect_destination_frame = Framed::new(
-        &mut server_state.incoming_stream,
-        SecureLengthDelimitedCodec::new(
-            Cow::Owned(client_encryption),
-            Cow::Owned(server_encryption),
-        ),
+    debug!(
+        "Begin to setup destination for client user: {client_username:?}, client tag: {client_tag:?}"
     );
+    let mut relay_codec = SecureLengthDelimitedCodec::new(
+        Cow::Owned(client_encryption),
+        Cow::Owned(server_encryption),
+    )
+    .with_max_chunk_size(context.config().common().relay_max_chunk_size)
+    .with_per_frame_iv(context.config().common().relay_per_frame_iv)
+    .with_length_field_length(context.config().common().relay_length_field_length)?;
+    if let Some(compression_level) = compression_level {
+        relay_codec = relay_codec.with_compression(compression_level);
+    }
+    let mut connect_destination_frame = Framed::new(&mut server_state.incoming_stream, relay_codec);
     let connect_destination_request_bytes =
         connect_destination_frame
             .next()
@@ -125,33 +461,106 @@ async fn process_connect_destination<'a>(
                 "Fail to read destination setup message from agent: {}",
                 server_state.incoming_connection_addr
             )))??;
-    let connect_destination_request = connect_destination_request_bytes.try_into()?;
-    let destination = match (get_config().forward(), get_forward_user_repo()) {
+    let (connect_destination_request, connect_timeout_hint_millis) =
+        decode_connect_destination_request(connect_destination_request_bytes)?;
+    if observe_mode {
+        let dst_addr = refuse_observed_destination(
+            &mut connect_destination_frame,
+            connect_destination_request,
+            &client_username,
+            server_state.incoming_connection_addr,
+        )
+        .await?;
+        return Err(Error::ObserveMode(dst_addr));
+    }
+    let connect_destination_request = rewrite_and_check_destination(
+        &mut connect_destination_frame,
+        connect_destination_request,
+        context.destination_rewriter(),
+        context.config().allowed_destination_ports(),
+        &client_username,
+        server_state.incoming_connection_addr,
+    )
+    .await?;
+    let (destination_event_addr, destination_event_type) = match &connect_destination_request {
+        ConnectDestinationRequest::Tcp(dst_addr) => (dst_addr.to_string(), "tcp"),
+        ConnectDestinationRequest::Udp(dst_addr) => (dst_addr.to_string(), "udp"),
+    };
+    let destination = connect_destination(
+        context,
+        connect_destination_frame.get_ref().tcp_stream(),
+        server_state.incoming_connection_addr,
+        connect_destination_request,
+        connect_timeout_hint_millis,
+    )
+    .await;
+    let destination = reply_connect_destination(
+        &mut connect_destination_frame,
+        destination,
+        server_state.incoming_connection_addr,
+    )
+    .await?;
+    context.emit_connection_event(ConnectionEvent::DestinationSetup {
+        client_addr: server_state.incoming_connection_addr,
+        destination: destination_event_addr,
+        destination_type: destination_event_type,
+    });
+    let FramedParts {
+        codec,
+        read_buf: client_read_buf,
+        ..
+    } = connect_destination_frame.into_parts();
+    Ok(ConnectDestinationResult {
+        codec,
+        client_read_buf,
+        destination,
+    })
+}
+
+/// Connect the destination of the request directly or through the forward
+/// upstream, the result is not replied to the client here.
+async fn connect_destination<'a>(
+    context: &ProxyContext,
+    client_stream: &TcpStream,
+    client_addr: SocketAddr,
+    connect_destination_request: ConnectDestinationRequest,
+    connect_timeout_hint_millis: Option<u64>,
+) -> Result<Destination<'a>, Error> {
+    let destination = match (context.config().forward(), context.forward_user_repo()) {
         (Some(forward_config), Some(forward_user_repository)) => {
             let forward_user_info = forward_user_repository
                 .find_user(forward_config.username())
                 .ok_or(CommonError::UserNotExist(forward_config.username().clone()))?;
-            match connect_destination_request {
-                ConnectDestinationRequest::Tcp(dst_addr) => {
-                    let proxy_connection = ProxyConnection::new(
-                        forward_user_info,
-                        forward_config.proxy_connect_timeout(),
-                    )
-                    .await?;
-                    let proxy_connection = proxy_connection
-                        .connect_destination(dst_addr, DestinationType::Tcp)
-                        .await?;
-                    Destination::Forward(Box::new(proxy_connection))
-                }
-                ConnectDestinationRequest::Udp { .. } => {
-                    unimplemented!("UDP still not support")
-                }
-            }
+            // The udp relay packets are forwarded as they are, the upstream
+            // proxy relays them to the udp destinations.
+            let (dst_addr, destination_type) = match connect_destination_request {
+                ConnectDestinationRequest::Tcp(dst_addr) => (dst_addr, DestinationType::Tcp),
+                ConnectDestinationRequest::Udp(dst_addr) => (dst_addr, DestinationType::Udp),
+            };
+            // The upstream proxy clamps the hint of the client with its own timeout
+            let proxy_connection = ProxyConnection::new(&*forward_user_info, forward_config)
+                .await?
+                .with_connect_timeout_hint(connect_timeout_hint_millis.map(Duration::from_millis));
+            let proxy_connection = proxy_connection
+                .connect_destination(dst_addr, destination_type)
+                .await?;
+            Destination::Forward(Box::new(proxy_connection))
         }
         _ => match connect_destination_request {
             ConnectDestinationRequest::Tcp(dst_addr) => Destination::Tcp(
-                TcpDestEndpoint::connect(dst_addr, get_config().destination_connect_timeout())
-                    .await?,
+                TcpDestEndpoint::connect_for_client(
+                    client_stream,
+                    client_addr,
+                    dst_addr,
+                    destination_connect_timeout(
+                        context.config().destination_connect_timeout(),
+                        connect_timeout_hint_millis,
+                    ),
+                    context.config().destination_address_preference(),
+                    context.config().common().socket_options,
+                    context.dns_resolutions(),
+                )
+                .await?,
             ),
             ConnectDestinationRequest::Udp(dst_addr) => Destination::Udp {
                 dst_udp_endpoint: UdpDestEndpoint::bind().await?,
@@ -159,65 +568,1382 @@ async fn process_connect_destination<'a>(
             },
         },
     };
-    let connect_destination_response = ConnectDestinationResponse::Success;
+    Ok(destination)
+}
+
+/// Reply the result of connecting the destination to the client, the
+/// failure is replied with [`ConnectDestinationResponse::Fail`] unless
+/// the client has disconnected, then the failure is returned.
+async fn reply_connect_destination<S, T>(
+    connect_destination_frame: &mut Framed<S, SecureLengthDelimitedCodec<'_>>,
+    destination: Result<T, Error>,
+    client_addr: SocketAddr,
+) -> Result<T, Error>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let connect_destination_response = match &destination {
+        Ok(_) => ConnectDestinationResponse::Success,
+        Err(Error::ClientDisconnected(_)) => return destination,
+        Err(e) => {
+            error!("Fail to connect destination for client [{client_addr}]: {e:?}");
+            ConnectDestinationResponse::Fail
+        }
+    };
     let connect_destination_response_bytes: Vec<u8> = connect_destination_response.try_into()?;
     connect_destination_frame
         .send(&connect_destination_response_bytes)
         .await?;
-    let FramedParts { codec, .. } = connect_destination_frame.into_parts();
-    Ok(ConnectDestinationResult { codec, destination })
+    destination
+}
+
+/// The shortest connect timeout hint followed, a shorter hint from a buggy
+/// client would fail every destination connect at once.
+const MIN_CONNECT_TIMEOUT_HINT_MILLIS: u64 = 500;
+
+/// Decode the connect destination request with the connect timeout hint, the
+/// hint `0` is treated as no hint and the other ones are floored at
+/// [MIN_CONNECT_TIMEOUT_HINT_MILLIS].
+fn decode_connect_destination_request(
+    connect_destination_request_bytes: BytesMut,
+) -> Result<(ConnectDestinationRequest, Option<u64>), Error> {
+    let ExtendedConnectDestinationRequest {
+        request,
+        connect_timeout_hint_millis,
+    } = connect_destination_request_bytes.try_into()?;
+    let connect_timeout_hint_millis = connect_timeout_hint_millis
+        .filter(|connect_timeout_hint_millis| *connect_timeout_hint_millis > 0)
+        .map(|connect_timeout_hint_millis| {
+            connect_timeout_hint_millis.max(MIN_CONNECT_TIMEOUT_HINT_MILLIS)
+        });
+    Ok((request, connect_timeout_hint_millis))
+}
+
+/// The timeout connecting the destination, the hint of the client can only
+/// shorten the configured timeout of the proxy.
+fn destination_connect_timeout(
+    configured_connect_timeout_secs: u64,
+    connect_timeout_hint_millis: Option<u64>,
+) -> Duration {
+    let configured_connect_timeout = Duration::from_secs(configured_connect_timeout_secs);
+    match connect_timeout_hint_millis {
+        Some(connect_timeout_hint_millis) => {
+            configured_connect_timeout.min(Duration::from_millis(connect_timeout_hint_millis))
+        }
+        None => configured_connect_timeout,
+    }
+}
+
+/// Log the destination requested by the client and refuse it without
+/// connecting anywhere, the operator can collect the destination patterns
+/// from the log to build the allow-list.
+async fn refuse_observed_destination<S>(
+    connect_destination_frame: &mut Framed<S, SecureLengthDelimitedCodec<'_>>,
+    connect_destination_request: ConnectDestinationRequest,
+    client_username: &Username,
+    client_addr: SocketAddr,
+) -> Result<UnifiedAddress, Error>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let (dst_addr, destination_type) = match connect_destination_request {
+        ConnectDestinationRequest::Tcp(dst_addr) => (dst_addr, "tcp"),
+        ConnectDestinationRequest::Udp(dst_addr) => (dst_addr, "udp"),
+    };
+    info!(
+        "Observe mode, refuse {destination_type} destination [{dst_addr}] requested by client [{client_addr}], username: {client_username}"
+    );
+    let connect_destination_response_bytes: Vec<u8> =
+        ConnectDestinationResponse::Refused.try_into()?;
+    connect_destination_frame
+        .send(&connect_destination_response_bytes)
+        .await?;
+    Ok(dst_addr)
+}
+
+/// Rewrite the requested destination and refuse it when the port of the
+/// rewritten destination is not allowed, the port allowlist always checks
+/// the destination which is actually connected.
+async fn rewrite_and_check_destination<S>(
+    connect_destination_frame: &mut Framed<S, SecureLengthDelimitedCodec<'_>>,
+    connect_destination_request: ConnectDestinationRequest,
+    destination_rewriter: Option<&dyn DestinationRewriter>,
+    allowed_destination_ports: &[u16],
+    client_username: &Username,
+    client_addr: SocketAddr,
+) -> Result<ConnectDestinationRequest, Error>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let connect_destination_request =
+        rewrite_connect_destination_request(connect_destination_request, destination_rewriter);
+    refuse_disallowed_destination_port(
+        connect_destination_frame,
+        &connect_destination_request,
+        allowed_destination_ports,
+        client_username,
+        client_addr,
+    )
+    .await?;
+    Ok(connect_destination_request)
+}
+
+/// Refuse the destination when its port is not allowed, the empty
+/// allowed ports allow the destinations on all the ports.
+async fn refuse_disallowed_destination_port<S>(
+    connect_destination_frame: &mut Framed<S, SecureLengthDelimitedCodec<'_>>,
+    connect_destination_request: &ConnectDestinationRequest,
+    allowed_destination_ports: &[u16],
+    client_username: &Username,
+    client_addr: SocketAddr,
+) -> Result<(), Error>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let dst_addr = match connect_destination_request {
+        ConnectDestinationRequest::Tcp(dst_addr) | ConnectDestinationRequest::Udp(dst_addr) => {
+            dst_addr
+        }
+    };
+    if allowed_destination_ports.is_empty() || allowed_destination_ports.contains(&dst_addr.port())
+    {
+        return Ok(());
+    }
+    error!(
+        "Refuse destination [{dst_addr}] requested by client [{client_addr}] because the port is not allowed, username: {client_username}"
+    );
+    let connect_destination_response_bytes: Vec<u8> =
+        ConnectDestinationResponse::Fail.try_into()?;
+    connect_destination_frame
+        .send(&connect_destination_response_bytes)
+        .await?;
+    Err(Error::DestinationPortNotAllowed(dst_addr.clone()))
 }
 
 async fn process_relay<'a>(
+    context: &ProxyContext,
     server_state: ServerState,
     setup_target_endpoint_result: ConnectDestinationResult<'a>,
 ) -> Result<(), Error> {
-    let ConnectDestinationResult { codec, destination } = setup_target_endpoint_result;
+    let ConnectDestinationResult {
+        codec,
+        client_read_buf,
+        destination,
+    } = setup_target_endpoint_result;
     let ServerState {
         incoming_stream: client_stream,
         incoming_connection_addr: client_addr,
     } = server_state;
+    let first_byte_timeout = context
+        .config()
+        .relay_first_byte_timeout()
+        .map(Duration::from_secs);
+    let idle_timeout = context
+        .config()
+        .common()
+        .relay_idle_timeout_secs
+        .map(Duration::from_secs);
+    let graceful_close_timeout = context
+        .config()
+        .relay_graceful_close_timeout()
+        .map(Duration::from_secs);
+    let relay_flush_interval = context
+        .config()
+        .common()
+        .relay_flush_interval_millis
+        .map(Duration::from_millis);
+    let relay_end_guard = RelayEndGuard {
+        connection_events: context.connection_events(),
+        client_addr,
+        relayed_bytes: RelayedBytes::default(),
+    };
+    let relayed_bytes = &relay_end_guard.relayed_bytes;
     match destination {
         Destination::Tcp(mut dst_tcp_endpoint) => {
             debug!(
                 "Begin to relay tcp data from client [{client_addr}] to destination [{}]",
                 dst_tcp_endpoint.dst_addr
             );
-            let mut client_tcp_relay_endpoint = ClientTcpRelayEndpoint::new(client_stream, codec);
-            copy_bidirectional(&mut client_tcp_relay_endpoint, &mut dst_tcp_endpoint).await?;
+            let mut client_tcp_relay_endpoint = ClientTcpRelayEndpoint::new(
+                client_stream,
+                codec,
+                client_read_buf,
+                context.config().common().relay_write_buffer_size,
+                relay_flush_interval,
+            );
+            let relay_result = relay_with_first_byte_timeout(
+                &mut SniLogging::new(
+                    &mut client_tcp_relay_endpoint,
+                    context.config().log_tls_sni(),
+                    client_addr,
+                ),
+                &mut dst_tcp_endpoint,
+                relayed_bytes,
+                first_byte_timeout,
+                idle_timeout,
+            )
+            .await;
+            close_relay(
+                &mut client_tcp_relay_endpoint,
+                &mut dst_tcp_endpoint,
+                graceful_close_timeout,
+            )
+            .await;
+            log_relay_decrypt_failure(relay_result, client_addr)?;
         }
         Destination::Forward(mut forward_proxy_connection) => {
-            let mut client_tcp_relay_endpoint = ClientTcpRelayEndpoint::new(client_stream, codec);
-            copy_bidirectional(
+            let mut client_tcp_relay_endpoint = ClientTcpRelayEndpoint::new(
+                client_stream,
+                codec,
+                client_read_buf,
+                context.config().common().relay_write_buffer_size,
+                relay_flush_interval,
+            );
+            let relay_result = relay_with_first_byte_timeout(
+                &mut SniLogging::new(
+                    &mut client_tcp_relay_endpoint,
+                    context.config().log_tls_sni(),
+                    client_addr,
+                ),
+                &mut forward_proxy_connection,
+                relayed_bytes,
+                first_byte_timeout,
+                idle_timeout,
+            )
+            .await;
+            close_relay(
                 &mut client_tcp_relay_endpoint,
                 &mut forward_proxy_connection,
+                graceful_close_timeout,
             )
-            .await?;
+            .await;
+            log_relay_decrypt_failure(relay_result, client_addr)?;
         }
         Destination::Udp {
             dst_udp_endpoint,
             dst_addr,
         } => {
-            let mut client_tcp_relay_endpoint = ClientTcpRelayEndpoint::new(client_stream, codec);
-            let mut client_data = [0u8; 65536];
-            AsyncReadExt::read(&mut client_tcp_relay_endpoint, &mut client_data).await?;
-            let dst_sock_addrs: Vec<SocketAddr> = (&dst_addr).try_into()?;
-            let dst_udp_data = dst_udp_endpoint
-                .replay_to(&dst_sock_addrs[..], &client_data)
-                .await?;
-            AsyncWriteExt::write(&mut client_tcp_relay_endpoint, &dst_udp_data).await?;
+            let client_tcp_relay_endpoint = ClientTcpRelayEndpoint::new(
+                client_stream,
+                codec,
+                client_read_buf,
+                context.config().common().relay_write_buffer_size,
+                relay_flush_interval,
+            );
+            debug!(
+                "Begin to relay udp association of client [{client_addr}], first destination [{dst_addr}]"
+            );
+            relay_udp_association(
+                client_tcp_relay_endpoint,
+                &dst_udp_endpoint,
+                context.config().allowed_destination_ports(),
+                context.config().udp_relay_buffer_size(),
+                context.dns_resolutions(),
+                relayed_bytes,
+                idle_timeout.unwrap_or(Duration::from_secs(
+                    context.config().udp_association_idle_timeout(),
+                )),
+            )
+            .await?;
         }
     }
     Ok(())
 }
 
-pub async fn process(mut server_state: ServerState) -> Result<(), Error> {
+/// The max number of destinations tracked by one udp association
+const MAX_UDP_ASSOCIATION_PEERS: usize = 1024;
+
+/// The state shared by both directions of one udp association
+struct UdpAssociation {
+    /// The client source which sent the latest datagram to each destination
+    client_sources: Mutex<HashMap<SocketAddr, UnifiedAddress>>,
+    idle_timer: RelayIdleTimer,
+    /// The metrics of the datagram payload bytes relayed in both directions
+    client_to_destination_bytes: Counter,
+    destination_to_client_bytes: Counter,
+}
+
+/// Relay the datagrams of the udp association until the client closes the
+/// relay or no datagram moves within the idle timeout, the datagrams replied
+/// by a destination are sent back to the client source which sent the latest
+/// datagram to that destination.
+async fn relay_udp_association<C>(
+    client_relay: C,
+    dst_udp_endpoint: &UdpDestEndpoint,
+    allowed_destination_ports: &[u16],
+    max_packet_size: usize,
+    dns_resolutions: &Arc<Semaphore>,
+    relayed_bytes: &RelayedBytes,
+    idle_timeout: Duration,
+) -> Result<(), Error>
+where
+    C: AsyncRead + AsyncWrite,
+{
+    let (mut client_reader, mut client_writer) = tokio::io::split(client_relay);
+    let (client_to_destination_bytes, destination_to_client_bytes) = relay_bytes_counters();
+    let udp_association = UdpAssociation {
+        client_sources: Mutex::new(HashMap::new()),
+        idle_timer: RelayIdleTimer::new(),
+        client_to_destination_bytes,
+        destination_to_client_bytes,
+    };
+    tokio::select! {
+        result = relay_udp_client_to_destination(
+            &mut client_reader,
+            dst_udp_endpoint,
+            allowed_destination_ports,
+            max_packet_size,
+            dns_resolutions,
+            &udp_association,
+            &relayed_bytes.client_to_destination,
+        ) => result,
+        result = relay_udp_destination_to_client(
+            &mut client_writer,
+            dst_udp_endpoint,
+            &udp_association,
+            &relayed_bytes.destination_to_client,
+        ) => result,
+        e = udp_association.idle_timer.expired(Some(idle_timeout)) => Err(e.into()),
+    }
+}
+
+async fn relay_udp_client_to_destination<R>(
+    client_reader: &mut R,
+    dst_udp_endpoint: &UdpDestEndpoint,
+    allowed_destination_ports: &[u16],
+    max_packet_size: usize,
+    dns_resolutions: &Arc<Semaphore>,
+    udp_association: &UdpAssociation,
+    relayed_bytes: &AtomicU64,
+) -> Result<(), Error>
+where
+    R: AsyncRead + Unpin,
+{
+    let mut resolved_destinations = HashMap::new();
+    loop {
+        let packet = match read_udp_relay_packet(client_reader, max_packet_size).await {
+            Ok(packet) => packet,
+            Err(CommonError::Io(e)) if e.kind() == std::io::ErrorKind::UnexpectedEof => {
+                return Ok(());
+            }
+            Err(e) => return Err(e.into()),
+        };
+        udp_association.idle_timer.mark_moved();
+        if !allowed_destination_ports.is_empty()
+            && !allowed_destination_ports.contains(&packet.dst_addr.port())
+        {
+            error!(
+                "Drop udp datagram to destination [{}] because the port is not allowed",
+                packet.dst_addr
+            );
+            continue;
+        }
+        // Resolve each destination once per association, the endpoint is bound on ipv4
+        let dst_sock_addr = match resolved_destinations.get(&packet.dst_addr) {
+            Some(dst_sock_addr) => Some(*dst_sock_addr),
+            None => match resolve_destination(&packet.dst_addr, dns_resolutions).await {
+                Ok(dst_sock_addrs) => dst_sock_addrs.into_iter().find(SocketAddr::is_ipv4),
+                Err(e) => {
+                    error!(
+                        "Drop udp datagram because fail to resolve destination [{}]: {e:?}",
+                        packet.dst_addr
+                    );
+                    continue;
+                }
+            },
+        };
+        let Some(dst_sock_addr) = dst_sock_addr else {
+            error!(
+                "Drop udp datagram because no ipv4 address of destination [{}]",
+                packet.dst_addr
+            );
+            continue;
+        };
+        insert_bounded(
+            &mut resolved_destinations,
+            packet.dst_addr.clone(),
+            dst_sock_addr,
+        );
+        insert_bounded(
+            &mut *udp_association
+                .client_sources
+                .lock()
+                .map_err(|e| CommonError::Lock(e.to_string()))?,
+            dst_sock_addr,
+            packet.src_addr,
+        );
+        // A single undeliverable datagram should not end the whole association
+        relayed_bytes.fetch_add(packet.payload.len() as u64, Ordering::Relaxed);
+        udp_association
+            .client_to_destination_bytes
+            .increment(packet.payload.len() as u64);
+        if let Err(e) = dst_udp_endpoint
+            .send_to(dst_sock_addr, &packet.payload)
+            .await
+        {
+            error!(
+                "Drop udp datagram because fail to send to destination [{dst_sock_addr}]: {e:?}"
+            );
+        }
+    }
+}
+
+/// Insert into a per association map, evicting an arbitrary entry once
+/// [MAX_UDP_ASSOCIATION_PEERS] is reached so a client spraying destinations
+/// can not grow it without bound.
+fn insert_bounded<K, V>(map: &mut HashMap<K, V>, key: K, value: V)
+where
+    K: std::hash::Hash + Eq + Clone,
+{
+    if map.len() >= MAX_UDP_ASSOCIATION_PEERS
+        && !map.contains_key(&key)
+        && let Some(evicted) = map.keys().next().cloned()
+    {
+        map.remove(&evicted);
+    }
+    map.insert(key, value);
+}
+
+async fn relay_udp_destination_to_client<W>(
+    client_writer: &mut W,
+    dst_udp_endpoint: &UdpDestEndpoint,
+    udp_association: &UdpAssociation,
+    relayed_bytes: &AtomicU64,
+) -> Result<(), Error>
+where
+    W: AsyncWrite + Unpin,
+{
+    let mut dst_udp_data = vec![0u8; UDP_DATAGRAM_MAX_SIZE];
+    loop {
+        let (dst_udp_data_size, dst_sock_addr) =
+            dst_udp_endpoint.recv_from(&mut dst_udp_data).await?;
+        udp_association.idle_timer.mark_moved();
+        let client_source = udp_association
+            .client_sources
+            .lock()
+            .map_err(|e| CommonError::Lock(e.to_string()))?
+            .get(&dst_sock_addr)
+            .cloned();
+        let Some(client_source) = client_source else {
+            debug!("Drop udp datagram from [{dst_sock_addr}] which no client sent to");
+            continue;
+        };
+        relayed_bytes.fetch_add(dst_udp_data_size as u64, Ordering::Relaxed);
+        udp_association
+            .destination_to_client_bytes
+            .increment(dst_udp_data_size as u64);
+        write_udp_relay_packet(
+            client_writer,
+            UdpRelayPacket {
+                src_addr: dst_sock_addr.into(),
+                dst_addr: client_source,
+                payload: Bytes::copy_from_slice(&dst_udp_data[..dst_udp_data_size]),
+            },
+        )
+        .await?;
+    }
+}
+
+pub async fn process(context: &ProxyContext, mut server_state: ServerState) -> Result<(), Error> {
+    context.emit_connection_event(ConnectionEvent::Accept {
+        client_addr: server_state.incoming_connection_addr,
+    });
     // Process handshake
-    let handshake_result = process_handshake(&mut server_state).await?;
+    let handshake_result = process_handshake(context, &mut server_state)
+        .await
+        .inspect_err(|e| record_handshake_failure(handshake_failure_reason(e)))?;
+    context.emit_connection_event(ConnectionEvent::HandshakeOk {
+        client_addr: server_state.incoming_connection_addr,
+        username: handshake_result.client_username.clone(),
+        tag: handshake_result.client_tag.clone(),
+    });
     // Process destination setup
     let connect_destination_result =
-        process_connect_destination(&mut server_state, handshake_result).await?;
+        process_connect_destination(context, &mut server_state, handshake_result).await?;
     // Process relay
-    process_relay(server_state, connect_destination_result).await?;
+    process_relay(context, server_state, connect_destination_result).await?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_refuse_observed_destination() -> Result<(), Error> {
+    use common::IncomingStream;
+    use std::sync::{Arc, Mutex};
+    use tokio::net::TcpListener;
+    /// The log lines written while the test runs
+    #[derive(Clone, Default)]
+    struct CapturedLog(Arc<Mutex<Vec<u8>>>);
+    impl std::io::Write for CapturedLog {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+    let captured_log = CapturedLog::default();
+    let log_writer = captured_log.clone();
+    let _log_guard = tracing::subscriber::set_default(
+        tracing_subscriber::fmt()
+            .with_ansi(false)
+            .with_writer(move || log_writer.clone())
+            .finish(),
+    );
+    let context = ProxyContext::new(crate::server::test_proxy_config(
+        std::path::Path::new("../resources/proxy/user"),
+        false,
+    ))?;
+    let codec = || {
+        SecureLengthDelimitedCodec::new(
+            Cow::Owned(Encryption::Plain),
+            Cow::Owned(Encryption::Plain),
+        )
+        .with_max_chunk_size(context.config().common().relay_max_chunk_size)
+        .with_per_frame_iv(context.config().common().relay_per_frame_iv)
+        .with_length_field_length(context.config().common().relay_length_field_length)
+    };
+    // The destination must never be connected in observe mode
+    let destination_listener = TcpListener::bind("127.0.0.1:0").await?;
+    let dst_addr: UnifiedAddress = destination_listener.local_addr()?.into();
+    let listener = TcpListener::bind("127.0.0.1:0").await?;
+    let client_stream = TcpStream::connect(listener.local_addr()?).await?;
+    let (incoming_stream, incoming_connection_addr) = listener.accept().await?;
+    let mut server_state = ServerState {
+        incoming_stream: IncomingStream::Plain(incoming_stream),
+        incoming_connection_addr,
+    };
+    let mut client_frame = Framed::new(client_stream, codec()?);
+    let connect_destination_request_bytes: Vec<u8> = ExtendedConnectDestinationRequest {
+        request: ConnectDestinationRequest::Tcp(dst_addr.clone()),
+        connect_timeout_hint_millis: None,
+    }
+    .try_into()?;
+    client_frame
+        .send(&connect_destination_request_bytes)
+        .await?;
+    let connect_destination_result = process_connect_destination(
+        &context,
+        &mut server_state,
+        HandshakeResult {
+            client_username: Username::from("user1"),
+            client_tag: None,
+            client_encryption: Encryption::Plain,
+            server_encryption: Encryption::Plain,
+            observe_mode: true,
+            compression_level: None,
+        },
+    )
+    .await;
+    assert!(matches!(
+        connect_destination_result,
+        Err(Error::ObserveMode(refused_dst_addr)) if refused_dst_addr == dst_addr
+    ));
+    let connect_destination_response: ConnectDestinationResponse =
+        client_frame.next().await.unwrap()?.try_into()?;
+    assert_eq!(
+        ConnectDestinationResponse::Refused,
+        connect_destination_response
+    );
+    assert!(
+        tokio::time::timeout(Duration::from_millis(200), destination_listener.accept())
+            .await
+            .is_err()
+    );
+    let captured_log = String::from_utf8(captured_log.0.lock().unwrap().clone()).unwrap();
+    assert!(captured_log.contains(&format!(
+        "Observe mode, refuse tcp destination [{dst_addr}] requested by client [{incoming_connection_addr}], username: user1"
+    )));
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_refuse_disallowed_destination_port() -> Result<(), Error> {
+    let codec = || {
+        SecureLengthDelimitedCodec::new(
+            Cow::Borrowed(get_handshake_encryption()),
+            Cow::Borrowed(get_handshake_encryption()),
+        )
+    };
+    let (client_stream, proxy_stream) = tokio::io::duplex(1024);
+    let mut proxy_frame = Framed::new(proxy_stream, codec());
+    let mut client_frame = Framed::new(client_stream, codec());
+    let username = Username::from("user1");
+    let client_addr = "127.0.0.1:10080".parse().unwrap();
+    let request = |port| {
+        ConnectDestinationRequest::Tcp(UnifiedAddress::Domain {
+            host: "www.example.com".to_string(),
+            port,
+        })
+    };
+    // The allowed port and the empty allowed ports proceed without any response
+    for allowed_destination_ports in [&[80, 443][..], &[]] {
+        refuse_disallowed_destination_port(
+            &mut proxy_frame,
+            &request(443),
+            allowed_destination_ports,
+            &username,
+            client_addr,
+        )
+        .await?;
+    }
+    let refused = refuse_disallowed_destination_port(
+        &mut proxy_frame,
+        &request(22),
+        &[80, 443],
+        &username,
+        client_addr,
+    )
+    .await;
+    assert!(matches!(
+        refused,
+        Err(Error::DestinationPortNotAllowed(UnifiedAddress::Domain {
+            port: 22,
+            ..
+        }))
+    ));
+    // The first response the client receives is the refusal of the disallowed port
+    let connect_destination_response_bytes = client_frame.next().await.unwrap()?;
+    let connect_destination_response: ConnectDestinationResponse =
+        connect_destination_response_bytes.try_into()?;
+    assert!(matches!(
+        connect_destination_response,
+        ConnectDestinationResponse::Fail
+    ));
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_relay_first_byte_timeout() -> Result<(), Error> {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    let first_byte_timeout = Some(Duration::from_millis(100));
+    let connection_events = crate::event::connection_event_channel();
+    let mut events = connection_events.subscribe();
+    let client_addr: SocketAddr = "127.0.0.1:20001".parse().unwrap();
+    let relay_end_guard = || RelayEndGuard {
+        connection_events: &connection_events,
+        client_addr,
+        relayed_bytes: RelayedBytes::default(),
+    };
+    let (mut client, mut client_peer) = tokio::io::duplex(1024);
+    let (mut destination, _destination_peer) = tokio::io::duplex(1024);
+    let timed_out_relay = relay_end_guard();
+    let result = relay_with_first_byte_timeout(
+        &mut client,
+        &mut destination,
+        &timed_out_relay.relayed_bytes,
+        first_byte_timeout,
+        None,
+    )
+    .await;
+    assert!(matches!(result, Err(Error::FirstByteTimeout(_))));
+    // The failed relay still ends with the event
+    drop(timed_out_relay);
+    assert_eq!(
+        ConnectionEvent::RelayEnd {
+            client_addr,
+            client_to_destination_bytes: 0,
+            destination_to_client_bytes: 0,
+        },
+        events.try_recv().unwrap()
+    );
+    let (mut client, mut client_peer_active) = tokio::io::duplex(1024);
+    let (mut destination, mut destination_peer) = tokio::io::duplex(1024);
+    let client_task = tokio::spawn(async move {
+        client_peer_active.write_all(b"first").await?;
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        client_peer_active.write_all(b"second").await?;
+        client_peer_active.shutdown().await
+    });
+    let destination_task = tokio::spawn(async move {
+        let mut received = Vec::new();
+        destination_peer.read_to_end(&mut received).await?;
+        Ok::<_, std::io::Error>(received)
+    });
+    let active_relay = relay_end_guard();
+    relay_with_first_byte_timeout(
+        &mut client,
+        &mut destination,
+        &active_relay.relayed_bytes,
+        first_byte_timeout,
+        None,
+    )
+    .await?;
+    drop(active_relay);
+    drop(destination);
+    client_task.await.unwrap()?;
+    assert_eq!(b"firstsecond".to_vec(), destination_task.await.unwrap()?);
+    assert_eq!(
+        ConnectionEvent::RelayEnd {
+            client_addr,
+            client_to_destination_bytes: 11,
+            destination_to_client_bytes: 0,
+        },
+        events.try_recv().unwrap()
+    );
+    client_peer.shutdown().await?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_relay_udp_association() -> Result<(), Error> {
+    use crate::destination::udp::DEFAULT_UDP_ASSOCIATION_IDLE_TIMEOUT;
+    use common::DEFAULT_UDP_RELAY_BUFFER_SIZE;
+    use tokio::net::UdpSocket;
+    // 2 udp echo destinations replying with their own name
+    let mut dst_addrs = Vec::new();
+    for name in ["dst0", "dst1"] {
+        let dst_socket = UdpSocket::bind("127.0.0.1:0").await?;
+        dst_addrs.push(dst_socket.local_addr()?);
+        tokio::spawn(async move {
+            let mut buf = [0u8; 1024];
+            while let Ok((size, src_addr)) = dst_socket.recv_from(&mut buf).await {
+                let reply = [name.as_bytes(), &buf[..size]].concat();
+                let _ = dst_socket.send_to(&reply, src_addr).await;
+            }
+        });
+    }
+    let (agent_stream, client_relay) = tokio::io::duplex(64 * 1024);
+    let (mut agent_reader, mut agent_writer) = tokio::io::split(agent_stream);
+    let dst_udp_endpoint = UdpDestEndpoint::bind().await?;
+    let allowed_destination_ports = [0, dst_addrs[0].port(), dst_addrs[1].port()];
+    let relay_task = tokio::spawn(async move {
+        relay_udp_association(
+            client_relay,
+            &dst_udp_endpoint,
+            &allowed_destination_ports,
+            DEFAULT_UDP_RELAY_BUFFER_SIZE,
+            &Arc::new(Semaphore::new(1)),
+            &RelayedBytes::default(),
+            Duration::from_secs(DEFAULT_UDP_ASSOCIATION_IDLE_TIMEOUT),
+        )
+        .await
+    });
+    let client_sources: [UnifiedAddress; 2] =
+        ["127.0.0.1:20001".try_into()?, "127.0.0.1:20002".try_into()?];
+    // The datagram to the disallowed port is dropped without a reply
+    let disallowed_dst_addr: SocketAddr = "127.0.0.1:22".parse().unwrap();
+    // The datagram which can not be sent is dropped without ending the association
+    let unsendable_dst_addr: SocketAddr = "127.0.0.1:0".parse().unwrap();
+    for (client_source, dst_addr) in [
+        (&client_sources[0], disallowed_dst_addr),
+        (&client_sources[0], unsendable_dst_addr),
+        (&client_sources[0], dst_addrs[0]),
+        (&client_sources[1], dst_addrs[1]),
+        (&client_sources[0], dst_addrs[0]),
+    ] {
+        write_udp_relay_packet(
+            &mut agent_writer,
+            UdpRelayPacket {
+                src_addr: client_source.clone(),
+                dst_addr: dst_addr.into(),
+                payload: Bytes::from_static(b"ping"),
+            },
+        )
+        .await?;
+    }
+    let mut replies = Vec::new();
+    for _ in 0..3 {
+        let reply = tokio::time::timeout(
+            Duration::from_secs(5),
+            read_udp_relay_packet(&mut agent_reader, DEFAULT_UDP_RELAY_BUFFER_SIZE),
+        )
+        .await
+        .expect("Udp reply should be relayed")?;
+        replies.push((reply.src_addr, reply.dst_addr, reply.payload));
+    }
+    replies.sort_by_key(|(src_addr, dst_addr, _)| (src_addr.to_string(), dst_addr.to_string()));
+    let mut expected_replies = vec![
+        (
+            UnifiedAddress::from(dst_addrs[0]),
+            client_sources[0].clone(),
+            Bytes::from_static(b"dst0ping"),
+        ),
+        (
+            UnifiedAddress::from(dst_addrs[0]),
+            client_sources[0].clone(),
+            Bytes::from_static(b"dst0ping"),
+        ),
+        (
+            UnifiedAddress::from(dst_addrs[1]),
+            client_sources[1].clone(),
+            Bytes::from_static(b"dst1ping"),
+        ),
+    ];
+    expected_replies
+        .sort_by_key(|(src_addr, dst_addr, _)| (src_addr.to_string(), dst_addr.to_string()));
+    assert_eq!(expected_replies, replies);
+    // The association ends once the agent closes the relay
+    drop(agent_writer);
+    drop(agent_reader);
+    tokio::time::timeout(Duration::from_secs(5), relay_task)
+        .await
+        .expect("Udp association should end with the relay")
+        .unwrap()?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_relay_udp_association_idle_timeout() -> Result<(), Error> {
+    use common::DEFAULT_UDP_RELAY_BUFFER_SIZE;
+    use tokio::net::UdpSocket;
+    let dst_socket = UdpSocket::bind("127.0.0.1:0").await?;
+    let dst_addr = dst_socket.local_addr()?;
+    tokio::spawn(async move {
+        let mut buf = [0u8; 1024];
+        while let Ok((size, src_addr)) = dst_socket.recv_from(&mut buf).await {
+            let _ = dst_socket.send_to(&buf[..size], src_addr).await;
+        }
+    });
+    let (agent_stream, client_relay) = tokio::io::duplex(64 * 1024);
+    let (mut agent_reader, mut agent_writer) = tokio::io::split(agent_stream);
+    let dst_udp_endpoint = UdpDestEndpoint::bind().await?;
+    let relay_task = tokio::spawn(async move {
+        relay_udp_association(
+            client_relay,
+            &dst_udp_endpoint,
+            &[],
+            DEFAULT_UDP_RELAY_BUFFER_SIZE,
+            &Arc::new(Semaphore::new(1)),
+            &RelayedBytes::default(),
+            Duration::from_millis(300),
+        )
+        .await
+    });
+    // The active association lives longer than the idle timeout
+    for _ in 0..5 {
+        write_udp_relay_packet(
+            &mut agent_writer,
+            UdpRelayPacket {
+                src_addr: "127.0.0.1:20001".try_into()?,
+                dst_addr: dst_addr.into(),
+                payload: Bytes::from_static(b"ping"),
+            },
+        )
+        .await?;
+        let reply = tokio::time::timeout(
+            Duration::from_secs(5),
+            read_udp_relay_packet(&mut agent_reader, DEFAULT_UDP_RELAY_BUFFER_SIZE),
+        )
+        .await
+        .expect("Udp reply should be relayed")?;
+        assert_eq!(Bytes::from_static(b"ping"), reply.payload);
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+    assert!(!relay_task.is_finished());
+    // The idle association ends while the agent still holds the relay open
+    let relay_result = tokio::time::timeout(Duration::from_secs(5), relay_task)
+        .await
+        .expect("Idle udp association should end")
+        .unwrap();
+    assert!(matches!(
+        relay_result,
+        Err(Error::Io(e)) if e.kind() == std::io::ErrorKind::TimedOut
+    ));
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_relay_udp_association_silent_destination() -> Result<(), Error> {
+    use common::DEFAULT_UDP_RELAY_BUFFER_SIZE;
+    use tokio::net::UdpSocket;
+    // The destination receives the datagram but never replies
+    let dst_socket = UdpSocket::bind("127.0.0.1:0").await?;
+    let dst_addr = dst_socket.local_addr()?;
+    let (agent_stream, client_relay) = tokio::io::duplex(64 * 1024);
+    let (_agent_reader, mut agent_writer) = tokio::io::split(agent_stream);
+    let dst_udp_endpoint = UdpDestEndpoint::bind().await?;
+    let relay_task = tokio::spawn(async move {
+        relay_udp_association(
+            client_relay,
+            &dst_udp_endpoint,
+            &[],
+            DEFAULT_UDP_RELAY_BUFFER_SIZE,
+            &Arc::new(Semaphore::new(1)),
+            &RelayedBytes::default(),
+            Duration::from_millis(300),
+        )
+        .await
+    });
+    write_udp_relay_packet(
+        &mut agent_writer,
+        UdpRelayPacket {
+            src_addr: "127.0.0.1:20001".try_into()?,
+            dst_addr: dst_addr.into(),
+            payload: Bytes::from_static(b"ping"),
+        },
+    )
+    .await?;
+    let mut buf = [0u8; 1024];
+    let (size, _) = dst_socket.recv_from(&mut buf).await?;
+    assert_eq!(b"ping", &buf[..size]);
+    // The lost reply ends the association instead of holding it forever
+    let relay_result = tokio::time::timeout(Duration::from_secs(5), relay_task)
+        .await
+        .expect("Udp association to a silent destination should time out")
+        .unwrap();
+    assert!(matches!(
+        relay_result,
+        Err(Error::Io(e)) if e.kind() == std::io::ErrorKind::TimedOut
+    ));
+    Ok(())
+}
+
+#[test]
+fn test_insert_bounded() {
+    let mut map = HashMap::new();
+    for key in 0..MAX_UDP_ASSOCIATION_PEERS * 2 {
+        insert_bounded(&mut map, key, key);
+    }
+    assert_eq!(MAX_UDP_ASSOCIATION_PEERS, map.len());
+    // Updating a tracked key never evicts another one
+    let tracked = *map.keys().next().unwrap();
+    insert_bounded(&mut map, tracked, 0);
+    assert_eq!(MAX_UDP_ASSOCIATION_PEERS, map.len());
+    assert_eq!(Some(&0), map.get(&tracked));
+}
+
+#[test]
+fn test_reject_expired_user() {
+    use crate::user::ProxyUser;
+    let now = Utc::now();
+    let user = |expired_time: Option<DateTime<Utc>>| {
+        let user_info = match expired_time {
+            Some(expired_time) => format!(
+                "username = \"user1\"\nexpired_time = \"{}\"",
+                expired_time.to_rfc3339()
+            ),
+            None => "username = \"user1\"".to_string(),
+        };
+        toml::from_str::<ProxyUser>(&user_info).unwrap()
+    };
+    let expired_user = user(Some(now - chrono::Duration::seconds(1)));
+    assert!(matches!(
+        reject_expired_user(&expired_user, now),
+        Err(Error::UserExpired(username)) if username.as_str() == "user1"
+    ));
+    let valid_user = user(Some(now + chrono::Duration::seconds(60)));
+    assert!(reject_expired_user(&valid_user, now).is_ok());
+    let never_expired_user = user(None);
+    assert!(reject_expired_user(&never_expired_user, now).is_ok());
+}
+
+#[tokio::test]
+async fn test_handshake_expired_user() -> Result<(), Error> {
+    use common::IncomingStream;
+    use tokio::net::TcpListener;
+    let user_repo_directory = std::env::temp_dir().join(format!(
+        "ppaass-proxy-expired-user-test-{}",
+        std::process::id()
+    ));
+    let user_directory = user_repo_directory.join("user1");
+    std::fs::create_dir_all(&user_directory)?;
+    for key_file_name in ["AgentPublicKey.pem", "ProxyPrivateKey.pem"] {
+        std::fs::copy(
+            format!("../resources/proxy/user/user1/{key_file_name}"),
+            user_directory.join(key_file_name),
+        )?;
+    }
+    std::fs::write(
+        user_directory.join("user_info.toml"),
+        "username = \"user1\"\nexpired_time = \"2000-01-01T00:00:00Z\"",
+    )?;
+    // The expired reason is only sent when the detailed reasons are enabled
+    for (detailed_handshake_failure, expected_reason) in [
+        (false, HandshakeError::Rejected),
+        (true, HandshakeError::UserExpired),
+    ] {
+        let context = ProxyContext::new(crate::server::test_proxy_config(
+            &user_repo_directory,
+            detailed_handshake_failure,
+        ))?;
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let client_stream = TcpStream::connect(listener.local_addr()?).await?;
+        let (incoming_stream, incoming_connection_addr) = listener.accept().await?;
+        let mut server_state = ServerState {
+            incoming_stream: IncomingStream::Plain(incoming_stream),
+            incoming_connection_addr,
+        };
+        let mut client_frame = Framed::new(
+            client_stream,
+            SecureLengthDelimitedCodec::new(
+                Cow::Borrowed(get_handshake_encryption()),
+                Cow::Borrowed(get_handshake_encryption()),
+            ),
+        );
+        // The user is rejected before its encryption is decrypted
+        let handshake_request_bytes: Vec<u8> = HandshakeRequest {
+            version: PROTOCOL_VERSION,
+            username: Username::from("user1"),
+            encryption: Encryption::Plain,
+            tag: None,
+            challenge: common::generate_handshake_challenge(),
+            compression: false,
+        }
+        .try_into()?;
+        client_frame.send(&handshake_request_bytes).await?;
+        let handshake_result = process_handshake(&context, &mut server_state).await;
+        assert!(matches!(
+            handshake_result,
+            Err(Error::UserExpired(username)) if username.as_str() == "user1"
+        ));
+        let handshake_response: HandshakeResponse =
+            client_frame.next().await.unwrap()?.try_into()?;
+        assert!(matches!(
+            handshake_response,
+            HandshakeResponse::Failure { reason } if reason == expected_reason
+        ));
+    }
+    std::fs::remove_dir_all(&user_repo_directory)?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_handshake_connection_tag() -> Result<(), Error> {
+    use common::IncomingStream;
+    use tokio::net::TcpListener;
+    let context = ProxyContext::new(crate::server::test_proxy_config(
+        std::path::Path::new("../resources/proxy/user"),
+        false,
+    ))?;
+    let mut connection_events = context.connection_events().subscribe();
+    let listener = TcpListener::bind("127.0.0.1:0").await?;
+    let client_stream = TcpStream::connect(listener.local_addr()?).await?;
+    let (incoming_stream, incoming_connection_addr) = listener.accept().await?;
+    let server_state = ServerState {
+        incoming_stream: IncomingStream::Plain(incoming_stream),
+        incoming_connection_addr,
+    };
+    let client_task = async move {
+        let mut client_frame = Framed::new(
+            client_stream,
+            SecureLengthDelimitedCodec::new(
+                Cow::Borrowed(get_handshake_encryption()),
+                Cow::Borrowed(get_handshake_encryption()),
+            ),
+        );
+        let handshake_request_bytes: Vec<u8> = HandshakeRequest {
+            version: PROTOCOL_VERSION,
+            username: Username::from("user1"),
+            encryption: Encryption::Plain,
+            tag: Some("office-laptop".to_string()),
+            challenge: common::generate_handshake_challenge(),
+            compression: false,
+        }
+        .try_into()?;
+        client_frame.send(&handshake_request_bytes).await?;
+        let handshake_response: HandshakeResponse =
+            client_frame.next().await.unwrap()?.try_into()?;
+        // The client leaves before setting up the destination
+        Ok::<_, Error>(handshake_response)
+    };
+    let (process_result, handshake_response) =
+        tokio::join!(process(&context, server_state), client_task);
+    assert!(matches!(
+        handshake_response?,
+        HandshakeResponse::Success { .. }
+    ));
+    assert!(process_result.is_err());
+    assert_eq!(
+        ConnectionEvent::Accept {
+            client_addr: incoming_connection_addr
+        },
+        connection_events.try_recv().unwrap()
+    );
+    assert_eq!(
+        ConnectionEvent::HandshakeOk {
+            client_addr: incoming_connection_addr,
+            username: Username::from("user1"),
+            tag: Some("office-laptop".to_string()),
+        },
+        connection_events.try_recv().unwrap()
+    );
+    Ok(())
+}
+
+#[test]
+fn test_handshake_error_reason() {
+    let username = Username("user1".to_string());
+    let user_errors = || -> [(Error, HandshakeError); 4] {
+        [
+            (
+                CommonError::UserNotExist(username.clone()).into(),
+                HandshakeError::UserNotExist,
+            ),
+            (
+                CommonError::UserRsaCryptoNotExist(username.clone()).into(),
+                HandshakeError::UserRsaCryptoNotExist,
+            ),
+            (
+                Error::UserExpired(username.clone()),
+                HandshakeError::UserExpired,
+            ),
+            (
+                CommonError::Crypto(crypto::Error::MacMismatch).into(),
+                HandshakeError::InvalidEncryption,
+            ),
+        ]
+    };
+    for (error, detailed_reason) in user_errors() {
+        assert_eq!(Some(detailed_reason), handshake_error_reason(&error, true));
+        // The generic reason does not tell whether the username exists
+        assert_eq!(
+            Some(HandshakeError::Rejected),
+            handshake_error_reason(&error, false)
+        );
+    }
+    for detailed in [true, false] {
+        assert_eq!(
+            Some(HandshakeError::UnsupportedProtocolVersion),
+            handshake_error_reason(
+                &ProtocolError::UnsupportedProtocolVersion(0).into(),
+                detailed
+            )
+        );
+        assert_eq!(
+            None,
+            handshake_error_reason(
+                &std::io::Error::from(std::io::ErrorKind::UnexpectedEof).into(),
+                detailed
+            )
+        );
+    }
+}
+
+#[test]
+fn test_handshake_failure_reason() {
+    let username = Username("user1".to_string());
+    let decrypt_failure: std::io::Error = CommonError::Crypto(crypto::Error::MacMismatch).into();
+    let failures: [(Error, HandshakeFailureReason); 8] = [
+        (
+            CommonError::UserNotExist(username.clone()).into(),
+            HandshakeFailureReason::UnknownUser,
+        ),
+        (
+            CommonError::UserRsaCryptoNotExist(username.clone()).into(),
+            HandshakeFailureReason::UnknownUser,
+        ),
+        (
+            Error::UserExpired(username.clone()),
+            HandshakeFailureReason::Expired,
+        ),
+        (
+            CommonError::Crypto(crypto::Error::MacMismatch).into(),
+            HandshakeFailureReason::Decrypt,
+        ),
+        (decrypt_failure.into(), HandshakeFailureReason::Decrypt),
+        (
+            ProtocolError::UnsupportedProtocolVersion(0).into(),
+            HandshakeFailureReason::Version,
+        ),
+        (
+            CommonError::Crypto(crypto::encrypt_with_aes(&[], 0, b"hello").unwrap_err()).into(),
+            HandshakeFailureReason::Cipher,
+        ),
+        (
+            std::io::Error::from(std::io::ErrorKind::UnexpectedEof).into(),
+            HandshakeFailureReason::Other,
+        ),
+    ];
+    for (error, reason) in failures {
+        assert_eq!(reason, handshake_failure_reason(&error), "{error:?}");
+    }
+}
+
+#[test]
+fn test_destination_connect_timeout() {
+    assert_eq!(
+        Duration::from_secs(20),
+        destination_connect_timeout(20, None)
+    );
+    assert_eq!(
+        Duration::from_millis(1500),
+        destination_connect_timeout(20, Some(1500))
+    );
+    // The hint never extends the configured timeout
+    assert_eq!(
+        Duration::from_secs(20),
+        destination_connect_timeout(20, Some(60_000))
+    );
+}
+
+#[test]
+fn test_decode_connect_timeout_hint() -> Result<(), Error> {
+    let decode_hint = |connect_timeout_hint_millis| {
+        let request_bytes: Vec<u8> = ExtendedConnectDestinationRequest {
+            request: ConnectDestinationRequest::Tcp(UnifiedAddress::Domain {
+                host: "localhost".to_string(),
+                port: 80,
+            }),
+            connect_timeout_hint_millis,
+        }
+        .try_into()?;
+        let (_, connect_timeout_hint_millis) =
+            decode_connect_destination_request(BytesMut::from(request_bytes.as_slice()))?;
+        Ok::<_, Error>(connect_timeout_hint_millis)
+    };
+    assert_eq!(None, decode_hint(None)?);
+    // The zero hint is no hint and the tiny hint is floored
+    assert_eq!(None, decode_hint(Some(0))?);
+    assert_eq!(Some(MIN_CONNECT_TIMEOUT_HINT_MILLIS), decode_hint(Some(1))?);
+    assert_eq!(Some(1500), decode_hint(Some(1500))?);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_reply_connect_destination() -> Result<(), Error> {
+    let codec = || {
+        SecureLengthDelimitedCodec::new(
+            Cow::Borrowed(get_handshake_encryption()),
+            Cow::Borrowed(get_handshake_encryption()),
+        )
+    };
+    let (client_stream, proxy_stream) = tokio::io::duplex(1024);
+    let mut proxy_frame = Framed::new(proxy_stream, codec());
+    let mut client_frame = Framed::new(client_stream, codec());
+    let client_addr: SocketAddr = "127.0.0.1:10080".parse().unwrap();
+    let dst_addr = UnifiedAddress::Domain {
+        host: "www.example.com".to_string(),
+        port: 443,
+    };
+    let mut next_response = async || -> Result<ConnectDestinationResponse, Error> {
+        Ok(client_frame.next().await.unwrap()?.try_into()?)
+    };
+    reply_connect_destination(&mut proxy_frame, Ok(()), client_addr).await?;
+    assert!(matches!(
+        next_response().await?,
+        ConnectDestinationResponse::Success
+    ));
+    let unreachable = reply_connect_destination(
+        &mut proxy_frame,
+        Err::<(), _>(Error::DestinationUnreachable(dst_addr.clone())),
+        client_addr,
+    )
+    .await;
+    assert!(matches!(unreachable, Err(Error::DestinationUnreachable(addr)) if addr == dst_addr));
+    assert!(matches!(
+        next_response().await?,
+        ConnectDestinationResponse::Fail
+    ));
+    // Nothing is replied to the client which has gone away
+    let disconnected = reply_connect_destination(
+        &mut proxy_frame,
+        Err::<(), _>(Error::ClientDisconnected(client_addr)),
+        client_addr,
+    )
+    .await;
+    assert!(matches!(disconnected, Err(Error::ClientDisconnected(_))));
+    drop(proxy_frame);
+    assert!(client_frame.next().await.is_none());
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_rewritten_destination_port_checked() -> Result<(), Error> {
+    use crate::destination::rewrite::AliasMapRewriter;
+    use std::collections::HashMap;
+    let codec = || {
+        SecureLengthDelimitedCodec::new(
+            Cow::Borrowed(get_handshake_encryption()),
+            Cow::Borrowed(get_handshake_encryption()),
+        )
+    };
+    let (client_stream, proxy_stream) = tokio::io::duplex(1024);
+    let mut proxy_frame = Framed::new(proxy_stream, codec());
+    let mut client_frame = Framed::new(client_stream, codec());
+    let rewriter = AliasMapRewriter::new(&HashMap::from([(
+        "db.internal".to_string(),
+        "db.example.com:5432".to_string(),
+    )]))?;
+    // The alias on the allowed port is refused for the port of its backend
+    let refused = rewrite_and_check_destination(
+        &mut proxy_frame,
+        ConnectDestinationRequest::Tcp(UnifiedAddress::Domain {
+            host: "db.internal".to_string(),
+            port: 443,
+        }),
+        Some(&rewriter),
+        &[443],
+        &Username::from("user1"),
+        "127.0.0.1:10080".parse().unwrap(),
+    )
+    .await;
+    assert!(matches!(
+        refused,
+        Err(Error::DestinationPortNotAllowed(UnifiedAddress::Domain {
+            port: 5432,
+            ..
+        }))
+    ));
+    let connect_destination_response: ConnectDestinationResponse =
+        client_frame.next().await.unwrap()?.try_into()?;
+    assert!(matches!(
+        connect_destination_response,
+        ConnectDestinationResponse::Fail
+    ));
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_custom_rewriter_port_checked() -> Result<(), Error> {
+    struct PortRewriter;
+    impl DestinationRewriter for PortRewriter {
+        fn rewrite(&self, dst_addr: UnifiedAddress) -> UnifiedAddress {
+            match dst_addr {
+                UnifiedAddress::Domain { host, .. } => UnifiedAddress::Domain { host, port: 22 },
+                dst_addr => dst_addr,
+            }
+        }
+    }
+    let codec = || {
+        SecureLengthDelimitedCodec::new(
+            Cow::Borrowed(get_handshake_encryption()),
+            Cow::Borrowed(get_handshake_encryption()),
+        )
+    };
+    let (client_stream, proxy_stream) = tokio::io::duplex(1024);
+    let mut proxy_frame = Framed::new(proxy_stream, codec());
+    let mut client_frame = Framed::new(client_stream, codec());
+    let request = ConnectDestinationRequest::Tcp(UnifiedAddress::Domain {
+        host: "www.example.com".to_string(),
+        port: 443,
+    });
+    let username = Username::from("user1");
+    let client_addr = "127.0.0.1:10080".parse().unwrap();
+    // Without the rewriter the destination passes the allowlist untouched
+    assert_eq!(
+        request,
+        rewrite_and_check_destination(
+            &mut proxy_frame,
+            request.clone(),
+            None,
+            &[443],
+            &username,
+            client_addr,
+        )
+        .await?
+    );
+    let refused = rewrite_and_check_destination(
+        &mut proxy_frame,
+        request,
+        Some(&PortRewriter),
+        &[443],
+        &username,
+        client_addr,
+    )
+    .await;
+    assert!(matches!(
+        refused,
+        Err(Error::DestinationPortNotAllowed(UnifiedAddress::Domain {
+            port: 22,
+            ..
+        }))
+    ));
+    let connect_destination_response: ConnectDestinationResponse =
+        client_frame.next().await.unwrap()?.try_into()?;
+    assert!(matches!(
+        connect_destination_response,
+        ConnectDestinationResponse::Fail
+    ));
     Ok(())
 }
@@ -1,49 +1,38 @@
-use crate::config::{ForwardConfig, get_config};
+use crate::config::{ForwardConfig, ForwardStartupCheck};
+use crate::error::Error;
 use chrono::{DateTime, Utc};
-use common::config::CommonConfig;
+use common::ProxyConnectionConfig;
+use common::UserConfig;
+use common::proxy::ProxyConnection;
 use common::user::repo::FileSystemUserRepository;
-use common::user::{User, UserRepository, UserWithExpiredTime, UserWithProxyServers};
+use common::user::{ProxyServer, User, UserRepository, UserWithExpiredTime, UserWithProxyServers};
 use crypto::RsaCrypto;
 use protocol::Username;
 use serde::{Deserialize, Serialize};
-use std::net::SocketAddr;
-use std::sync::OnceLock;
-
-static USER_REPO: OnceLock<FileSystemUserRepository<ProxyUser, CommonConfig>> = OnceLock::new();
-static FORWARD_USER_REPO: OnceLock<Option<FileSystemUserRepository<ForwardUser, ForwardConfig>>> =
-    OnceLock::new();
-
-/// Get the repository of the proxy user.
-pub fn get_user_repo() -> &'static FileSystemUserRepository<ProxyUser, CommonConfig> {
-    USER_REPO.get_or_init(|| {
-        FileSystemUserRepository::<ProxyUser, CommonConfig>::new(get_config().common())
-            .expect("Fail to create user repository from file system")
-    })
-}
-
-/// Get the repository of the forwarding user.
-pub fn get_forward_user_repo()
--> Option<&'static FileSystemUserRepository<ForwardUser, ForwardConfig>> {
-    FORWARD_USER_REPO
-        .get_or_init(|| {
-            let forward_user_repo = FileSystemUserRepository::<ForwardUser, ForwardConfig>::new(
-                get_config().forward()?,
-            )
-            .ok()?;
-            Some(forward_user_repo)
-        })
-        .as_ref()
-}
+use std::sync::atomic::AtomicUsize;
+use std::time::Duration;
+use tokio::time::timeout;
+use tracing::{info, warn};
 
 /// The user in proxy side
 #[derive(Serialize, Deserialize, Debug)]
 pub struct ProxyUser {
     username: Username,
     expired_time: Option<DateTime<Utc>>,
+    /// Log the requested destinations of this user
+    /// and refuse them instead of connecting
+    #[serde(default)]
+    observe_mode: bool,
     #[serde(skip)]
     rsa_crypto: Option<RsaCrypto>,
 }
 
+impl ProxyUser {
+    pub fn observe_mode(&self) -> bool {
+        self.observe_mode
+    }
+}
+
 impl User for ProxyUser {
     fn username(&self) -> &Username {
         &self.username
@@ -66,9 +55,11 @@ impl UserWithExpiredTime for ProxyUser {
 #[derive(Serialize, Deserialize, Debug)]
 pub struct ForwardUser {
     username: Username,
-    proxy_servers: Vec<SocketAddr>,
+    proxy_servers: Vec<ProxyServer>,
     #[serde(skip)]
     rsa_crypto: Option<RsaCrypto>,
+    #[serde(skip)]
+    round_robin_next: AtomicUsize,
 }
 
 impl User for ForwardUser {
@@ -84,7 +75,108 @@ impl User for ForwardUser {
 }
 
 impl UserWithProxyServers for ForwardUser {
-    fn proxy_servers(&self) -> &[SocketAddr] {
+    fn proxy_servers(&self) -> &[ProxyServer] {
         &self.proxy_servers
     }
+    fn round_robin_next(&self) -> &AtomicUsize {
+        &self.round_robin_next
+    }
+}
+
+/// Handshake with each proxy server of the forward user to check the forward
+/// upstream is reachable, the unreachable proxy servers are warned or fail the
+/// startup according to the startup check of the forward configuration.
+pub async fn check_forward_upstream(
+    forward_user: &ForwardUser,
+    forward_config: &ForwardConfig,
+) -> Result<(), Error> {
+    if forward_config.startup_check() == ForwardStartupCheck::Disabled {
+        return Ok(());
+    }
+    let check_timeout = forward_config.proxy_connect_timeout();
+    let mut unreachable_proxy_servers = Vec::new();
+    for ProxyServer {
+        address: proxy_server,
+        ..
+    } in forward_user.proxy_servers()
+    {
+        let handshake = ProxyConnection::new_with_proxy_servers(
+            forward_user,
+            forward_config,
+            std::slice::from_ref(proxy_server),
+        );
+        match timeout(Duration::from_secs(check_timeout), handshake).await {
+            Ok(Ok(_)) => info!("Forward upstream [{proxy_server}] is reachable."),
+            Ok(Err(e)) => {
+                warn!("Forward upstream [{proxy_server}] is unreachable because of error: {e:?}");
+                unreachable_proxy_servers.push(*proxy_server);
+            }
+            Err(_) => {
+                warn!(
+                    "Forward upstream [{proxy_server}] is unreachable because of handshake timeout in {check_timeout} seconds"
+                );
+                unreachable_proxy_servers.push(*proxy_server);
+            }
+        }
+    }
+    if forward_config.startup_check() == ForwardStartupCheck::Fail
+        && !unreachable_proxy_servers.is_empty()
+    {
+        return Err(Error::ForwardUpstreamUnreachable(unreachable_proxy_servers));
+    }
+    Ok(())
+}
+
+/// Check the forward upstream of the configured forward user at startup.
+pub async fn check_configured_forward_upstream(
+    forward_config: Option<&ForwardConfig>,
+    forward_user_repo: Option<&FileSystemUserRepository<ForwardUser, ForwardConfig>>,
+) -> Result<(), Error> {
+    let (Some(forward_config), Some(forward_user_repo)) = (forward_config, forward_user_repo)
+    else {
+        return Ok(());
+    };
+    match forward_user_repo.find_user(forward_config.username()) {
+        Some(forward_user) => check_forward_upstream(&forward_user, forward_config).await,
+        None => {
+            warn!(
+                "Forward user [{}] not exist, skip checking forward upstream.",
+                forward_config.username()
+            );
+            Ok(())
+        }
+    }
+}
+
+#[tokio::test]
+async fn test_check_forward_upstream() -> Result<(), Error> {
+    let unreachable_proxy_server = std::net::TcpListener::bind("127.0.0.1:0")?.local_addr()?;
+    let forward_user = ForwardUser {
+        username: Username::from("user1"),
+        proxy_servers: vec![unreachable_proxy_server.into()],
+        rsa_crypto: None,
+        round_robin_next: Default::default(),
+    };
+    let forward_config = |startup_check: &str| {
+        toml::from_str::<ForwardConfig>(&format!(
+            r#"
+            proxy_connect_timeout = 1
+            user_info_file_name = "user_info.toml"
+            user_info_private_key_file_name = "AgentPrivateKey.pem"
+            user_info_public_key_file_name = "ProxyPublicKey.pem"
+            user_repo_directory = "resources/proxy/forward_user"
+            user_repo_refresh_interval = 10
+            username = "user1"
+            startup_check = "{startup_check}"
+            "#
+        ))
+    };
+    check_forward_upstream(&forward_user, &forward_config("disabled")?).await?;
+    check_forward_upstream(&forward_user, &forward_config("warn")?).await?;
+    let result = check_forward_upstream(&forward_user, &forward_config("fail")?).await;
+    assert!(matches!(
+        result,
+        Err(Error::ForwardUpstreamUnreachable(proxy_servers)) if proxy_servers == vec![unreachable_proxy_server]
+    ));
+    Ok(())
 }